@@ -0,0 +1,88 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Index};
+
+// fills each named field from the column of the same name, or each field of a
+// tuple struct from the column at its position
+#[proc_macro_derive(FromRow)]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let body = match struct_fields(&input) {
+        Ok(Fields::Named(fields)) => {
+            let fields = fields.named.iter().map(|field| {
+                let ident = field.ident.as_ref().unwrap();
+                let column = ident.to_string();
+                quote! { #ident: row.get_named(#column)? }
+            });
+            quote! { #name { #(#fields),* } }
+        }
+        Ok(Fields::Unnamed(fields)) => {
+            let fields = (0..fields.unnamed.len()).map(|index| quote! { row.get_as(#index)? });
+            quote! { #name(#(#fields),*) }
+        }
+        Ok(Fields::Unit) => quote! { #name },
+        Err(e) => return e,
+    };
+
+    TokenStream::from(quote! {
+        impl #impl_generics ::db_tutorial::FromRow for #name #type_generics #where_clause {
+            fn from_row(row: &::db_tutorial::Row) -> ::std::result::Result<Self, ::db_tutorial::Error> {
+                ::std::result::Result::Ok(#body)
+            }
+        }
+    })
+}
+
+// supplies a parameter value for each field in order, named after the field when it has a name
+#[proc_macro_derive(ToParams)]
+pub fn derive_to_params(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    let params: Vec<TokenStream2> = match struct_fields(&input) {
+        Ok(Fields::Named(fields)) => fields
+            .named
+            .iter()
+            .map(|field| {
+                let ident = field.ident.as_ref().unwrap();
+                let param = ident.to_string();
+                quote! {
+                    (::std::option::Option::Some(#param), ::db_tutorial::ToValue::to_value(&self.#ident))
+                }
+            })
+            .collect(),
+        Ok(Fields::Unnamed(fields)) => (0..fields.unnamed.len())
+            .map(|index| {
+                let index = Index::from(index);
+                quote! {
+                    (::std::option::Option::None, ::db_tutorial::ToValue::to_value(&self.#index))
+                }
+            })
+            .collect(),
+        Ok(Fields::Unit) => Vec::new(),
+        Err(e) => return e,
+    };
+
+    TokenStream::from(quote! {
+        impl #impl_generics ::db_tutorial::ToParams for #name #type_generics #where_clause {
+            fn to_params(&self) -> ::std::vec::Vec<(::std::option::Option<&'static str>, ::db_tutorial::Value)> {
+                ::std::vec![#(#params),*]
+            }
+        }
+    })
+}
+
+fn struct_fields(input: &DeriveInput) -> Result<&Fields, TokenStream> {
+    match &input.data {
+        Data::Struct(data) => Ok(&data.fields),
+        _ => Err(TokenStream::from(
+            syn::Error::new_spanned(&input.ident, "rows can only be mapped to and from structs")
+                .to_compile_error(),
+        )),
+    }
+}
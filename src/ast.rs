@@ -0,0 +1,446 @@
+use crate::value::Value;
+
+// A parsed statement, kept around so it can be compiled again whenever the schema changes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Select(Select),
+    Insert(Insert),
+    Update(Update),
+    Delete(Delete),
+    Pragma {
+        name: String,
+        value: Option<Expr>,
+    },
+    // describes how a statement would run instead of running it
+    Explain {
+        query_plan: bool,
+        statement: Box<Statement>,
+    },
+    CreateTable(CreateTable),
+    AlterTable(AlterTable),
+    CreateIndex(CreateIndex),
+    CreateView(CreateView),
+    DropView {
+        name: String,
+        if_exists: bool,
+    },
+    CreateTrigger(CreateTrigger),
+    DropTrigger {
+        name: String,
+        if_exists: bool,
+    },
+    // gathers statistics on one table, or on all of them
+    Analyze {
+        table: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    pub with: Option<With>,
+    pub columns: Vec<ResultColumn>,
+    pub from: Vec<TableRef>,
+    pub filter: Option<Expr>,
+    // the queries whose rows follow this one's, each joined on to everything before it
+    pub unions: Vec<Union>,
+    // how many rows the query hands on at most, counting those of every query in the union
+    pub limit: Option<Expr>,
+}
+
+// Tables a query defines for itself, which it and its subqueries read like any other. A
+// recursive one is a union whose later queries read the rows the ones before it produced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct With {
+    pub recursive: bool,
+    pub tables: Vec<CommonTable>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommonTable {
+    pub name: String,
+    // names for the query's columns, in place of its own
+    pub columns: Option<Vec<String>>,
+    pub select: Select,
+}
+
+// UNION leaves out rows that came before; UNION ALL keeps them all
+#[derive(Debug, Clone, PartialEq)]
+pub struct Union {
+    pub all: bool,
+    pub select: Select,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultColumn {
+    // `*`, or `t.*` for every column of just the one table
+    All(Option<String>),
+    Expr {
+        expr: Expr,
+        // the name the column gets in the result: its alias, or the expression as written
+        name: String,
+    },
+}
+
+// A table in the FROM clause, along with how it is joined to the ones before it. A comma and a
+// cross join are inner joins without a condition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+    pub join: JoinKind,
+    pub on: Option<Expr>,
+    // the query whose rows a derived table holds; its name is then the alias, if it has one
+    pub select: Option<Box<Select>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    // every row of the tables before it is kept, with NULLs for this table where nothing matches
+    Left,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insert {
+    pub table: String,
+    // the columns each row gives values for, in order; None means all of them
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<Vec<Expr>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Update {
+    pub table: String,
+    // each column that is set, with the value it gets, worked out from the row as it was
+    pub assignments: Vec<(String, Expr)>,
+    pub filter: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delete {
+    pub table: String,
+    pub filter: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<ColumnSpec>,
+    pub if_not_exists: bool,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlterTable {
+    pub table: String,
+    pub change: TableChange,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableChange {
+    AddColumn(Box<ColumnSpec>),
+    RenameColumn { from: String, to: String },
+    DropColumn(String),
+    RenameTo(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnSpec {
+    pub name: String,
+    // the column's definition as it was written, so a table's columns can be changed one by one
+    pub sql: String,
+    // the type as it was written, if it was; values of any type go in any column
+    pub data_type: String,
+    // an integer primary key is the rowid under another name; any other primary key is unique
+    pub primary_key: bool,
+    pub not_null: bool,
+    // no two rows have the same value, though any number can have NULL
+    pub unique: bool,
+    // a condition every row has to meet, which NULL does
+    pub check: Option<Expr>,
+    // the name CONSTRAINT gave the check, which its errors use
+    pub check_name: Option<String>,
+    pub default: Option<ColumnDefault>,
+    pub references: Option<ForeignKey>,
+}
+
+// A column whose value, unless it's NULL, has to be the key of a row in another table. What
+// happens when that row's key changes or the row goes is up to `on_update` and `on_delete`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignKey {
+    pub table: String,
+    // the other table's integer primary key if None
+    pub column: Option<String>,
+    pub on_delete: ForeignKeyAction,
+    pub on_update: ForeignKeyAction,
+    // checked when the transaction commits, rather than when the statement ends
+    pub deferred: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForeignKeyAction {
+    // the statement fails if a row is left referring to nothing when it ends
+    NoAction,
+    // the statement fails as soon as a row referred to changes
+    Restrict,
+    // rows referring to a row that goes go too, and follow its key if it changes
+    Cascade,
+    SetNull,
+}
+
+// the value a column gets when an insert leaves it out, and how it was written
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDefault {
+    pub value: Expr,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndex {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    // no two entries have the same values in every column, unless one of them is NULL
+    pub unique: bool,
+    pub if_not_exists: bool,
+    // the statement as it was written, which is what the schema keeps
+    pub sql: String,
+}
+
+// a query kept under a name, which is run whenever the name is read like a table
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateView {
+    pub name: String,
+    // names for the query's columns, in place of its own
+    pub columns: Option<Vec<String>>,
+    pub select: Select,
+    pub if_not_exists: bool,
+    pub sql: String,
+}
+
+// Statements run for every row an insert, update or delete on the table changes, reading the
+// row as it was through `old` and as it is going to be through `new`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTrigger {
+    pub name: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    pub table: String,
+    // a condition the row has to meet for the statements to run
+    pub when: Option<Expr>,
+    pub body: Vec<Statement>,
+    pub if_not_exists: bool,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerTiming {
+    Before,
+    After,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEvent {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    // the index of the parameter that supplies the value
+    Param(usize),
+    Column {
+        table: Option<String>,
+        name: String,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    // the first column of the first row the query returns, or NULL if it returns none
+    Subquery(Box<Select>),
+    In {
+        expr: Box<Expr>,
+        select: Box<Select>,
+    },
+    Exists(Box<Select>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+    IsNull,
+    NotNull,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Like,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+}
+
+impl Expr {
+    pub fn binary(op: BinaryOp, left: Expr, right: Expr) -> Self {
+        Expr::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    pub fn unary(op: UnaryOp, expr: Expr) -> Self {
+        Expr::Unary {
+            op,
+            expr: Box::new(expr),
+        }
+    }
+
+    // splits a condition into the terms that all have to be true for it to be
+    pub fn conjuncts(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Binary {
+                op: BinaryOp::And,
+                left,
+                right,
+            } => {
+                let mut terms = left.conjuncts();
+                terms.extend(right.conjuncts());
+                terms
+            }
+            expr => vec![expr],
+        }
+    }
+
+    // calls `f` on this expression and every expression inside it
+    pub fn walk<'e>(&'e self, f: &mut dyn FnMut(&'e Expr)) {
+        f(self);
+        match self {
+            // a subquery's expressions are looked up in a scope of their own
+            Expr::Literal(_)
+            | Expr::Param(_)
+            | Expr::Column { .. }
+            | Expr::Subquery(_)
+            | Expr::Exists(_) => {}
+            Expr::In { expr, .. } => expr.walk(f),
+            Expr::Unary { expr, .. } => expr.walk(f),
+            Expr::Binary { left, right, .. } => {
+                left.walk(f);
+                right.walk(f);
+            }
+            Expr::Function { args, .. } => args.iter().for_each(|arg| arg.walk(f)),
+        }
+    }
+
+    // whether there are more than `depth` expressions inside each other anywhere in this one,
+    // looking no further down than that
+    pub fn deeper_than(&self, depth: usize) -> bool {
+        if depth == 0 {
+            return true;
+        }
+        match self {
+            Expr::Literal(_)
+            | Expr::Param(_)
+            | Expr::Column { .. }
+            | Expr::Subquery(_)
+            | Expr::Exists(_) => false,
+            Expr::In { expr, .. } | Expr::Unary { expr, .. } => expr.deeper_than(depth - 1),
+            Expr::Binary { left, right, .. } => {
+                left.deeper_than(depth - 1) || right.deeper_than(depth - 1)
+            }
+            Expr::Function { args, .. } => args.iter().any(|arg| arg.deeper_than(depth - 1)),
+        }
+    }
+}
+
+impl Select {
+    // whether the query, or any query inside it, reads a table
+    pub fn reads_tables(&self) -> bool {
+        let mut exprs: Vec<&Expr> = self
+            .columns
+            .iter()
+            .filter_map(|column| match column {
+                ResultColumn::Expr { expr, .. } => Some(expr),
+                ResultColumn::All(_) => None,
+            })
+            .collect();
+        exprs.extend(self.filter.iter());
+        exprs.extend(self.limit.iter());
+        exprs.extend(self.from.iter().filter_map(|table| table.on.as_ref()));
+        let mut reads = self.from.iter().any(|table| match &table.select {
+            Some(select) => select.reads_tables(),
+            None => true,
+        });
+        reads |= self.unions.iter().any(|union| union.select.reads_tables());
+        if let Some(with) = &self.with {
+            reads |= with.tables.iter().any(|table| table.select.reads_tables());
+        }
+        for expr in exprs {
+            expr.walk(&mut |expr| match expr {
+                Expr::Subquery(select) | Expr::In { select, .. } | Expr::Exists(select) => {
+                    reads |= select.reads_tables()
+                }
+                _ => {}
+            });
+        }
+        reads
+    }
+}
+
+impl BinaryOp {
+    // the comparison that means the same with its operands swapped around
+    pub fn reversed(self) -> Option<BinaryOp> {
+        match self {
+            BinaryOp::Eq => Some(BinaryOp::Eq),
+            BinaryOp::Lt => Some(BinaryOp::Gt),
+            BinaryOp::Le => Some(BinaryOp::Ge),
+            BinaryOp::Gt => Some(BinaryOp::Lt),
+            BinaryOp::Ge => Some(BinaryOp::Le),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "OR",
+            BinaryOp::And => "AND",
+            BinaryOp::Eq => "=",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Like => "LIKE",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+            BinaryOp::Concat => "||",
+        }
+    }
+}
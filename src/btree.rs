@@ -0,0 +1,648 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use crate::pager::{Page, PageRead, PageWrite};
+use crate::table::TableError;
+
+// A B+tree of entries ordered by their key, each stored along with a payload. Tables are kept in
+// trees keyed by rowid with the row as the payload; indexes in trees keyed by the indexed values
+// followed by the rowid, with nothing else.
+//
+// Every node takes up a whole page. Leaves hold the entries, and interior nodes hold the largest
+// key in each of their children but the last. Nodes are read and written whole, and a node that
+// no longer fits in its page is split in two. Nodes that lose entries are never merged back
+// together; a leaf can even end up empty.
+//
+// An entry has to fit in a quarter of a page. A payload too big for that is kept in a chain of
+// overflow pages of its own instead, leaving only its length and first page in the leaf, but keys
+// are compared as the tree is searched and always stay in the node: the values of an index entry
+// (or of the columns a statement sorts or groups by) together can't take more than about a
+// quarter of a page.
+//
+// node layout: kind (u8), cell count (u16), last child (u32, interior nodes only), then the
+// cells: a leaf cell is key length (u16), key, payload length (u16), payload; an interior cell is
+// child page (u32), key length (u16), key. A payload kept in overflow pages has a length of
+// 0xffff in the leaf, followed by its real length (u32) and its first overflow page (u32).
+//
+// overflow page layout: next page in the chain (u32, 0 for the last one), then as much of the
+// payload as fits
+const LEAF: u8 = 1;
+const INTERIOR: u8 = 2;
+const NODE_HEADER_SIZE: usize = 7;
+const OVERFLOW: u16 = u16::MAX;
+const OVERFLOW_HEADER_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+struct Node {
+    leaf: bool,
+    cells: Vec<Cell>,
+    // the child holding every key larger than those in the cells
+    right: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Cell {
+    key: Vec<u8>,
+    payload: Vec<u8>,
+    // the length of a payload kept in overflow pages, and the first of them
+    overflow: Option<(u32, u32)>,
+    child: u32,
+}
+
+impl Node {
+    fn read(pages: &dyn PageRead, page_num: u32) -> Result<Self, TableError> {
+        let page = pages.page(page_num).map_err(TableError::Pager)?;
+        let buffer = &page.buffer;
+        let leaf = match buffer[0] {
+            LEAF => true,
+            INTERIOR => false,
+            kind => {
+                return Err(TableError::Corrupt(format!(
+                    "page {} is a node of unknown kind {}",
+                    page_num, kind
+                )))
+            }
+        };
+        let count = u16::from_be_bytes(buffer[1..3].try_into().unwrap());
+        let right = u32::from_be_bytes(buffer[3..7].try_into().unwrap());
+        let mut offset = NODE_HEADER_SIZE;
+        // a cell running past the end of the page means the page isn't what it should be
+        let mut read = |len: usize| match buffer.get(offset..offset + len) {
+            Some(bytes) => {
+                offset += len;
+                Ok(bytes)
+            }
+            None => Err(TableError::Corrupt(format!(
+                "page {} has cells past its end",
+                page_num
+            ))),
+        };
+        let mut cells = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let cell = if leaf {
+                let key_len = u16::from_be_bytes(read(2)?.try_into().unwrap());
+                let key = read(key_len as usize)?.to_vec();
+                let payload_len = u16::from_be_bytes(read(2)?.try_into().unwrap());
+                if payload_len == OVERFLOW {
+                    let len = u32::from_be_bytes(read(4)?.try_into().unwrap());
+                    let first = u32::from_be_bytes(read(4)?.try_into().unwrap());
+                    Cell {
+                        key,
+                        payload: Vec::new(),
+                        overflow: Some((len, first)),
+                        child: 0,
+                    }
+                } else {
+                    Cell {
+                        key,
+                        payload: read(payload_len as usize)?.to_vec(),
+                        overflow: None,
+                        child: 0,
+                    }
+                }
+            } else {
+                let child = u32::from_be_bytes(read(4)?.try_into().unwrap());
+                let key_len = u16::from_be_bytes(read(2)?.try_into().unwrap());
+                Cell {
+                    key: read(key_len as usize)?.to_vec(),
+                    payload: Vec::new(),
+                    overflow: None,
+                    child,
+                }
+            };
+            cells.push(cell);
+        }
+        Ok(Node { leaf, cells, right })
+    }
+
+    fn write(&self, pages: &mut dyn PageWrite, page_num: u32) -> Result<(), TableError> {
+        let page_size = pages.page_size();
+        let mut buffer = Vec::with_capacity(page_size);
+        buffer.push(if self.leaf { LEAF } else { INTERIOR });
+        buffer.extend_from_slice(&(self.cells.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&self.right.to_be_bytes());
+        for cell in &self.cells {
+            if self.leaf {
+                buffer.extend_from_slice(&(cell.key.len() as u16).to_be_bytes());
+                buffer.extend_from_slice(&cell.key);
+                match cell.overflow {
+                    Some((len, first)) => {
+                        buffer.extend_from_slice(&OVERFLOW.to_be_bytes());
+                        buffer.extend_from_slice(&len.to_be_bytes());
+                        buffer.extend_from_slice(&first.to_be_bytes());
+                    }
+                    None => {
+                        buffer.extend_from_slice(&(cell.payload.len() as u16).to_be_bytes());
+                        buffer.extend_from_slice(&cell.payload);
+                    }
+                }
+            } else {
+                buffer.extend_from_slice(&cell.child.to_be_bytes());
+                buffer.extend_from_slice(&(cell.key.len() as u16).to_be_bytes());
+                buffer.extend_from_slice(&cell.key);
+            }
+        }
+        // a node that has outgrown its page would lose its last cells
+        if buffer.len() > page_size {
+            return Err(TableError::Corrupt(format!(
+                "node of {} bytes doesn't fit in page {}",
+                buffer.len(),
+                page_num
+            )));
+        }
+        buffer.resize(page_size, 0);
+        let page = pages.page_mut(page_num).map_err(TableError::Pager)?;
+        page.buffer.copy_from_slice(&buffer);
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        NODE_HEADER_SIZE
+            + self
+                .cells
+                .iter()
+                .map(|cell| cell.size(self.leaf))
+                .sum::<usize>()
+    }
+
+    // the position of the first cell whose key isn't smaller than the one given
+    fn position(&self, key: &[u8]) -> usize {
+        self.cells.partition_point(|cell| cell.key.as_slice() < key)
+    }
+
+    fn child(&self, index: usize) -> u32 {
+        match self.cells.get(index) {
+            Some(cell) => cell.child,
+            None => self.right,
+        }
+    }
+}
+
+impl Cell {
+    fn size(&self, leaf: bool) -> usize {
+        if leaf {
+            // the key's and the payload's lengths
+            let payload = match self.overflow {
+                Some(_) => 8,
+                None => self.payload.len(),
+            };
+            4 + self.key.len() + payload
+        } else {
+            // the child page and the key's length
+            6 + self.key.len()
+        }
+    }
+}
+
+// the largest entry a tree takes, so that splitting a full node always leaves two that fit
+pub fn max_entry_size(page_size: usize) -> usize {
+    (page_size - NODE_HEADER_SIZE) / 4
+}
+
+// makes a new, empty tree and returns its root page
+pub fn create(pages: &mut dyn PageWrite) -> Result<u32, TableError> {
+    let root = pages.allocate().map_err(TableError::Pager)?;
+    empty_leaf().write(pages, root)?;
+    Ok(root)
+}
+
+fn empty_leaf() -> Node {
+    Node {
+        leaf: true,
+        cells: Vec::new(),
+        right: 0,
+    }
+}
+
+// adds an entry, replacing the payload of any entry that already has the same key
+pub fn insert(
+    pages: &mut dyn PageWrite,
+    root: u32,
+    key: &[u8],
+    payload: &[u8],
+) -> Result<(), TableError> {
+    let max_size = max_entry_size(pages.page_size());
+    let mut cell = Cell {
+        key: key.to_vec(),
+        payload: payload.to_vec(),
+        overflow: None,
+        child: 0,
+    };
+    if cell.size(true) > max_size {
+        // only the payload's length and first page stay in the leaf, and the key has to fit
+        // along with them
+        cell.payload = Vec::new();
+        cell.overflow = Some((0, 0));
+        if cell.size(true) > max_size {
+            return Err(TableError::TooBig);
+        }
+        let len = payload.len().try_into().map_err(|_| TableError::TooBig)?;
+        cell.overflow = Some((len, write_overflow(pages, payload)?));
+    }
+    insert_into(pages, root, root, cell).map(drop)
+}
+
+// returns the key dividing the node from the new one it was split into, if it had to be split
+fn insert_into(
+    pages: &mut dyn PageWrite,
+    root: u32,
+    page_num: u32,
+    cell: Cell,
+) -> Result<Option<(Vec<u8>, u32)>, TableError> {
+    let mut node = Node::read(pages, page_num)?;
+    let index = node.position(&cell.key);
+    if node.leaf {
+        match node.cells.get_mut(index) {
+            Some(old) if old.key == cell.key => {
+                free_overflow(pages, old)?;
+                *old = cell;
+            }
+            _ => node.cells.insert(index, cell),
+        }
+    } else {
+        let child = node.child(index);
+        match insert_into(pages, root, child, cell)? {
+            Some((separator, new_child)) => {
+                node.cells.insert(
+                    index,
+                    Cell {
+                        key: separator,
+                        payload: Vec::new(),
+                        overflow: None,
+                        child,
+                    },
+                );
+                match node.cells.get_mut(index + 1) {
+                    Some(cell) => cell.child = new_child,
+                    None => node.right = new_child,
+                }
+            }
+            None => return Ok(None),
+        }
+    }
+
+    if node.size() <= pages.page_size() {
+        node.write(pages, page_num)?;
+        return Ok(None);
+    }
+    let (left, separator, right) = split(node);
+    if page_num == root {
+        // the root never moves, so both halves go to new pages underneath it
+        let left_page = pages.allocate().map_err(TableError::Pager)?;
+        let right_page = pages.allocate().map_err(TableError::Pager)?;
+        left.write(pages, left_page)?;
+        right.write(pages, right_page)?;
+        let root_node = Node {
+            leaf: false,
+            cells: vec![Cell {
+                key: separator,
+                payload: Vec::new(),
+                overflow: None,
+                child: left_page,
+            }],
+            right: right_page,
+        };
+        root_node.write(pages, root)?;
+        Ok(None)
+    } else {
+        let right_page = pages.allocate().map_err(TableError::Pager)?;
+        left.write(pages, page_num)?;
+        right.write(pages, right_page)?;
+        Ok(Some((separator, right_page)))
+    }
+}
+
+// splits a node in two halves of about the same size, along with the key that divides them
+fn split(node: Node) -> (Node, Vec<u8>, Node) {
+    let half = node.size() / 2;
+    let mut size = NODE_HEADER_SIZE;
+    let mut middle = 0;
+    while middle < node.cells.len() - 1 && size < half {
+        size += node.cells[middle].size(node.leaf);
+        middle += 1;
+    }
+    let mut left = node.cells;
+    let mut right_cells = left.split_off(middle);
+    if node.leaf {
+        let separator = left.last().unwrap().key.clone();
+        let left = Node {
+            leaf: true,
+            cells: left,
+            right: 0,
+        };
+        let right = Node {
+            leaf: true,
+            cells: right_cells,
+            right: 0,
+        };
+        (left, separator, right)
+    } else {
+        // the middle cell moves up, and its child becomes the last child of the left half
+        let promoted = right_cells.remove(0);
+        let left = Node {
+            leaf: false,
+            cells: left,
+            right: promoted.child,
+        };
+        let right = Node {
+            leaf: false,
+            cells: right_cells,
+            right: node.right,
+        };
+        (left, promoted.key, right)
+    }
+}
+
+// Removes the entry with the key, returning whether there was one. Nothing else changes, so
+// the leaf it was in can be left with few entries or none at all.
+pub fn delete(pages: &mut dyn PageWrite, root: u32, key: &[u8]) -> Result<bool, TableError> {
+    let mut page_num = root;
+    loop {
+        let mut node = Node::read(pages, page_num)?;
+        let index = node.position(key);
+        if !node.leaf {
+            page_num = node.child(index);
+            continue;
+        }
+        if node.cells.get(index).is_none_or(|cell| cell.key != key) {
+            return Ok(false);
+        }
+        let cell = node.cells.remove(index);
+        node.write(pages, page_num)?;
+        free_overflow(pages, &cell)?;
+        return Ok(true);
+    }
+}
+
+// writes a payload to a chain of new overflow pages, returning the first of them
+fn write_overflow(pages: &mut dyn PageWrite, payload: &[u8]) -> Result<u32, TableError> {
+    let chunk_size = pages.page_size() - OVERFLOW_HEADER_SIZE;
+    let mut next = 0u32;
+    // written from the end, so that every page knows the one after it
+    for chunk in payload.chunks(chunk_size).rev() {
+        let page_num = pages.allocate().map_err(TableError::Pager)?;
+        let page = pages.page_mut(page_num).map_err(TableError::Pager)?;
+        page.buffer.fill(0);
+        page.buffer[..OVERFLOW_HEADER_SIZE].copy_from_slice(&next.to_be_bytes());
+        page.buffer[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + chunk.len()]
+            .copy_from_slice(chunk);
+        next = page_num;
+    }
+    Ok(next)
+}
+
+// the overflow pages a payload of the given length is kept in, starting with the first one
+fn overflow_pages(
+    pages: &dyn PageRead,
+    (len, first): (u32, u32),
+) -> Result<Vec<(u32, Arc<Page>)>, TableError> {
+    let chunk_size = pages.page_size() - OVERFLOW_HEADER_SIZE;
+    let count = (len as usize).div_ceil(chunk_size);
+    let mut chain = Vec::new();
+    let mut page_num = first;
+    for _ in 0..count {
+        if page_num == 0 {
+            return Err(TableError::Corrupt(format!(
+                "overflow pages starting at page {} end early",
+                first
+            )));
+        }
+        let page = pages.page(page_num).map_err(TableError::Pager)?;
+        let next = u32::from_be_bytes(page.buffer[..OVERFLOW_HEADER_SIZE].try_into().unwrap());
+        chain.push((page_num, page));
+        page_num = next;
+    }
+    Ok(chain)
+}
+
+fn read_overflow(pages: &dyn PageRead, overflow: (u32, u32)) -> Result<Vec<u8>, TableError> {
+    let mut payload = Vec::new();
+    for (_, page) in overflow_pages(pages, overflow)? {
+        let left = overflow.0 as usize - payload.len();
+        let chunk = &page.buffer[OVERFLOW_HEADER_SIZE..];
+        payload.extend_from_slice(&chunk[..left.min(chunk.len())]);
+    }
+    Ok(payload)
+}
+
+// gives back the overflow pages of an entry that is going away, if it has any
+fn free_overflow(pages: &mut dyn PageWrite, cell: &Cell) -> Result<(), TableError> {
+    if let Some(overflow) = cell.overflow {
+        for (page_num, _) in overflow_pages(pages, overflow)? {
+            pages.free(page_num).map_err(TableError::Pager)?;
+        }
+    }
+    Ok(())
+}
+
+// the largest key in the tree, if it has any entries
+pub fn last_key(pages: &dyn PageRead, root: u32) -> Result<Option<Vec<u8>>, TableError> {
+    let node = Node::read(pages, root)?;
+    if node.leaf {
+        return Ok(node.cells.last().map(|cell| cell.key.clone()));
+    }
+    // children can be empty, so the ones before the last may have to be tried too
+    for index in (0..=node.cells.len()).rev() {
+        if let Some(key) = last_key(pages, node.child(index))? {
+            return Ok(Some(key));
+        }
+    }
+    Ok(None)
+}
+
+// Walks every node of the tree and every chain of overflow pages, describing any that can't be
+// read or whose keys are out of order, and marking the pages it goes through in `used`. Returns
+// the number of entries found.
+pub fn check(
+    pages: &dyn PageRead,
+    root: u32,
+    used: &mut [bool],
+    problems: &mut Vec<String>,
+) -> Result<u64, TableError> {
+    check_node(pages, root, (None, None), used, problems)
+}
+
+// the keys in the node have to be larger than the lower bound and no larger than the upper one
+fn check_node(
+    pages: &dyn PageRead,
+    page_num: u32,
+    (lower, upper): (Option<&[u8]>, Option<&[u8]>),
+    used: &mut [bool],
+    problems: &mut Vec<String>,
+) -> Result<u64, TableError> {
+    if !mark_used(page_num, used, problems) {
+        return Ok(0);
+    }
+    let node = match Node::read(pages, page_num) {
+        Ok(node) => node,
+        Err(TableError::Corrupt(problem)) => {
+            problems.push(problem);
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+    let keys: Vec<&[u8]> = node.cells.iter().map(|cell| cell.key.as_slice()).collect();
+    let in_order = keys.windows(2).all(|pair| pair[0] < pair[1])
+        && keys
+            .first()
+            .is_none_or(|first| lower.is_none_or(|lower| lower < *first))
+        && keys
+            .last()
+            .is_none_or(|last| upper.is_none_or(|upper| *last <= upper));
+    if !in_order {
+        problems.push(format!("page {} has keys out of order", page_num));
+    }
+    if node.leaf {
+        for cell in &node.cells {
+            if let Some(overflow) = cell.overflow {
+                check_overflow(pages, overflow, used, problems)?;
+            }
+        }
+        return Ok(node.cells.len() as u64);
+    }
+    let mut entries = 0;
+    for index in 0..=node.cells.len() {
+        let lower = index.checked_sub(1).map(|before| keys[before]).or(lower);
+        let upper = keys.get(index).copied().or(upper);
+        entries += check_node(pages, node.child(index), (lower, upper), used, problems)?;
+    }
+    Ok(entries)
+}
+
+fn check_overflow(
+    pages: &dyn PageRead,
+    (len, first): (u32, u32),
+    used: &mut [bool],
+    problems: &mut Vec<String>,
+) -> Result<(), TableError> {
+    let chunk_size = pages.page_size() - OVERFLOW_HEADER_SIZE;
+    let mut page_num = first;
+    for _ in 0..(len as usize).div_ceil(chunk_size) {
+        if page_num == 0 {
+            problems.push(format!(
+                "overflow pages starting at page {} end early",
+                first
+            ));
+            return Ok(());
+        }
+        if !mark_used(page_num, used, problems) {
+            return Ok(());
+        }
+        let page = pages.page(page_num).map_err(TableError::Pager)?;
+        page_num = u32::from_be_bytes(page.buffer[..OVERFLOW_HEADER_SIZE].try_into().unwrap());
+    }
+    Ok(())
+}
+
+// marks a page as used, or describes why it can't be and returns false
+pub fn mark_used(page_num: u32, used: &mut [bool], problems: &mut Vec<String>) -> bool {
+    match used.get_mut(page_num as usize) {
+        None => {
+            problems.push(format!("page {} is past the end of the file", page_num));
+            false
+        }
+        Some(true) => {
+            problems.push(format!("page {} is used more than once", page_num));
+            false
+        }
+        Some(used) => {
+            *used = true;
+            true
+        }
+    }
+}
+
+// Points at one entry of a tree, or past the last one. The cursor keeps copies of the nodes it
+// went through, so it doesn't see changes made to the tree after it got to them.
+#[derive(Debug, Clone)]
+pub struct BTreeCursor {
+    root: u32,
+    // every node from the root down to a leaf, along with the cell the cursor is at in each
+    path: Vec<(Node, usize)>,
+    pub eof: bool,
+}
+
+impl BTreeCursor {
+    pub fn new(root: u32) -> Self {
+        BTreeCursor {
+            root,
+            path: Vec::new(),
+            eof: true,
+        }
+    }
+
+    pub fn first(&mut self, pages: &dyn PageRead) -> Result<(), TableError> {
+        self.seek(pages, &[])
+    }
+
+    // moves to the first entry whose key isn't smaller than the one given
+    pub fn seek(&mut self, pages: &dyn PageRead, key: &[u8]) -> Result<(), TableError> {
+        self.path.clear();
+        let mut page_num = self.root;
+        loop {
+            let node = Node::read(pages, page_num)?;
+            let index = node.position(key);
+            let leaf = node.leaf;
+            let child = node.child(index);
+            self.path.push((node, index));
+            if leaf {
+                break;
+            }
+            page_num = child;
+        }
+        self.settle(pages)
+    }
+
+    pub fn next(&mut self, pages: &dyn PageRead) -> Result<(), TableError> {
+        if let Some((_, index)) = self.path.last_mut() {
+            *index += 1;
+        }
+        self.settle(pages)
+    }
+
+    // A cursor that ran off the end of a leaf moves on to the first entry of the next leaf there
+    // is. A payload kept in overflow pages is read in as soon as the cursor gets to its entry,
+    // since the pages can be given back and used for something else once the entry changes.
+    fn settle(&mut self, pages: &dyn PageRead) -> Result<(), TableError> {
+        loop {
+            match self.path.last_mut() {
+                None => {
+                    self.eof = true;
+                    return Ok(());
+                }
+                Some((node, index)) if node.leaf && *index < node.cells.len() => {
+                    let cell = &mut node.cells[*index];
+                    if let Some(overflow) = cell.overflow.take() {
+                        cell.payload = read_overflow(pages, overflow)?;
+                    }
+                    self.eof = false;
+                    return Ok(());
+                }
+                Some((node, index)) if !node.leaf && *index <= node.cells.len() => {
+                    let child = node.child(*index);
+                    let child = Node::read(pages, child)?;
+                    self.path.push((child, 0));
+                }
+                Some(_) => {
+                    self.path.pop();
+                    if let Some((_, index)) = self.path.last_mut() {
+                        *index += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn cell(&self) -> &Cell {
+        let (node, index) = self.path.last().expect("cursor is not on an entry");
+        &node.cells[*index]
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.cell().key
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.cell().payload
+    }
+}
@@ -0,0 +1,3263 @@
+use std::cell::Cell;
+
+use crate::ast::*;
+use crate::constants::*;
+use crate::parser::{
+    prepare_statement, quote, rename_column, rename_column_in, rename_table, StatementError,
+};
+use crate::planner::{self, Access, Comparison, Term};
+use crate::program::{Instruction, PlanStep, Program, Tree};
+use crate::schema::{
+    ColumnDef, IndexDef, Schema, SchemaEntry, TableDef, TriggerDef, ViewDef, DEFAULT_ROWS,
+};
+use crate::value::Value;
+use crate::virtual_machine::check_column;
+
+// Turns a parsed statement into a program for the virtual machine, checking it against the
+// schema as it goes.
+pub fn compile(statement: &Statement, schema: &Schema) -> Result<Program, StatementError> {
+    let mut compiler = Compiler::new(schema);
+    match statement {
+        Statement::Select(select) => {
+            if select.reads_tables() {
+                compiler.transaction(false);
+            }
+            compiler.program.columns =
+                compiler.select(select, None, &mut |compiler, start, count| {
+                    compiler
+                        .program
+                        .emit(Instruction::ResultRow { start, count });
+                    Ok(())
+                })?;
+        }
+        Statement::Insert(insert) => {
+            compiler.transaction(true);
+            compiler.insert(insert, None)?;
+            compiler.check_foreign_keys();
+        }
+        Statement::Update(update) => {
+            compiler.transaction(true);
+            compiler.update(update, None)?;
+            compiler.check_foreign_keys();
+        }
+        Statement::Delete(delete) => {
+            compiler.transaction(true);
+            compiler.delete(delete, None)?;
+            compiler.check_foreign_keys();
+        }
+        Statement::CreateTable(create) => compiler.create_table(create)?,
+        Statement::AlterTable(alter) => compiler.alter_table(alter)?,
+        Statement::CreateIndex(create) => compiler.create_index(create)?,
+        Statement::CreateView(create) => compiler.create_view(create)?,
+        Statement::DropView { name, if_exists } => compiler.drop_view(name, *if_exists)?,
+        Statement::CreateTrigger(create) => compiler.create_trigger(create)?,
+        Statement::DropTrigger { name, if_exists } => compiler.drop_trigger(name, *if_exists)?,
+        Statement::Analyze { table } => compiler.analyze(table.as_deref())?,
+        Statement::Pragma { name, value } => {
+            let value = match value {
+                Some(value) => {
+                    let dest = compiler.program.allocate_registers(1);
+                    compiler.expr(&Scope::default(), value, dest)?;
+                    Some(dest)
+                }
+                None => None,
+            };
+            let program = &mut compiler.program;
+            let cursor = program.allocate_cursor();
+            program.emit(Instruction::Pragma {
+                cursor,
+                name: name.clone(),
+                value,
+            });
+            program.columns = pragma_columns(name, value.is_some());
+            if !program.columns.is_empty() {
+                scan(program, cursor);
+            }
+        }
+        // the statement is compiled but never run; its program or plan is what comes back
+        Statement::Explain {
+            query_plan: false,
+            statement,
+        } => {
+            let explained = compile(statement, schema)?;
+            let rows = explained
+                .instructions
+                .iter()
+                .enumerate()
+                .map(|(address, instruction)| {
+                    let mut row = vec![Value::Integer(address as i64)];
+                    row.extend(instruction.explain().iter().cloned());
+                    row
+                })
+                .collect();
+            output(
+                &mut compiler.program,
+                &["addr", "opcode", "p1", "p2", "p3", "p4", "comment"],
+                rows,
+            );
+        }
+        Statement::Explain {
+            query_plan: true,
+            statement,
+        } => {
+            let rows = compile(statement, schema)?
+                .plan
+                .into_iter()
+                .map(|step| {
+                    vec![
+                        Value::Integer(step.id as i64),
+                        Value::Integer(step.parent as i64),
+                        Value::Integer(0),
+                        Value::Text(step.detail),
+                    ]
+                })
+                .collect();
+            output(
+                &mut compiler.program,
+                &["id", "parent", "notused", "detail"],
+                rows,
+            );
+        }
+    }
+    let mut program = compiler.program;
+    program.emit(Instruction::Halt);
+    Ok(program)
+}
+
+struct Compiler<'s> {
+    program: Program,
+    schema: &'s Schema,
+    // the plan step that what is being compiled runs inside of
+    parent: usize,
+    // the tables WITH clauses have made that can be read from here, innermost last
+    ctes: Vec<CommonTableDef>,
+    // the triggers whose statements are being compiled, which don't set themselves off again
+    firing: Vec<String>,
+    // the tables whose rows are being deleted further out, each with the cursor on the rowids
+    // its loop is working through
+    deleting: Vec<(String, usize)>,
+}
+
+// Fails unless every table, view and trigger in the schema makes sense against the rest of it,
+// the way each was checked when it was made.
+fn check_schema(schema: &Schema) -> Result<(), StatementError> {
+    let in_entry = |kind: &str, name: &str| {
+        let entry = format!("{} {}", kind, name);
+        move |error| StatementError::ErrorIn {
+            entry,
+            error: Box::new(error),
+        }
+    };
+    for table in &schema.tables {
+        let mut compiler = Compiler::new(schema);
+        compiler
+            .check_table(table)
+            .map_err(in_entry("table", &table.name))?;
+        for key in table.columns.iter().filter_map(|c| c.references.as_ref()) {
+            compiler
+                .parent_key(table, key)
+                .map_err(in_entry("table", &table.name))?;
+        }
+    }
+    for view in &schema.views {
+        Compiler::new(schema)
+            .check_view(&view.name, view.columns.as_ref(), &view.select)
+            .map_err(in_entry("view", &view.name))?;
+    }
+    for trigger in &schema.triggers {
+        let check = match schema.table(&trigger.table) {
+            Some(table) => Compiler::new(schema).check_trigger(trigger, table),
+            None => Err(StatementError::NoSuchTable(trigger.table.clone())),
+        };
+        check.map_err(in_entry("trigger", &trigger.name))?;
+    }
+    Ok(())
+}
+
+// a scan of the schema table stopped at one entry
+struct FoundEntry {
+    cursor: usize,
+    top: usize,
+    next: Pending,
+    end: Pending,
+}
+
+// what ALTER TABLE does to the rows already in the table
+enum RowChange {
+    DropColumn(usize),
+    AddColumn(Expr),
+}
+
+#[derive(Clone)]
+struct CommonTableDef {
+    // the cursor the table's rows are gathered in
+    cursor: usize,
+    table: TableDef,
+}
+
+// what is done with each row a query produces, given the registers it is in
+type RowHandler<'h, 's> =
+    dyn FnMut(&mut Compiler<'s>, usize, usize) -> Result<(), StatementError> + 'h;
+
+// The tables a query reads, which is where the names in its expressions are looked up. Names
+// that aren't found there are looked up in the scope of the query it is a subquery of.
+#[derive(Default)]
+struct Scope<'a> {
+    sources: Vec<Source<'a>>,
+    outer: Option<&'a Scope<'a>>,
+    // a bit for every source a name has been found in, so a subquery can tell what it reads
+    referenced: Cell<u64>,
+}
+
+struct Source<'a> {
+    // the table's alias, or its own name if it has none
+    name: String,
+    table: &'a TableDef,
+    cursor: usize,
+    // an index read in place of the table, with the cursor reading it
+    covering: Option<(usize, &'a IndexDef)>,
+    // for a table made from a subquery, the cursor its rows are gathered in
+    derived: Option<usize>,
+}
+
+// where a name was found: in which source of a scope `depth` scopes out, and which column of
+// it, with None for the rowid
+struct Resolved<'r, 'a> {
+    scope: &'r Scope<'a>,
+    depth: usize,
+    source: usize,
+    column: Option<usize>,
+}
+
+// a jump whose target isn't known yet
+type Pending = Vec<usize>;
+
+// the registers a row's columns start at, and the register its rowid is in
+type RowRegisters = (usize, usize);
+
+// a loop over one table, while its body is being compiled
+struct OpenLoop {
+    // the address the body starts at and the cursor that moves the loop on, unless it only
+    // ever finds one row
+    top: Option<(usize, usize)>,
+    // jumps to where the loop moves on to the next row, and to where it ends
+    next: Pending,
+    end: Pending,
+    left: Option<LeftJoin>,
+}
+
+// a loop over the rows an update or delete changes, while its body is being compiled
+struct EachRow {
+    top: usize,
+    next: Pending,
+    end: usize,
+}
+
+// A LEFT JOIN keeps track of whether any row matched. If none did once the loop ends, its
+// cursors are set to read NULLs and the body runs once more, starting from where `matched` is set.
+struct LeftJoin {
+    matched: usize,
+    set: usize,
+    cursors: Vec<usize>,
+}
+
+impl<'s> Compiler<'s> {
+    fn new(schema: &'s Schema) -> Self {
+        Compiler {
+            program: Program::new(),
+            schema,
+            parent: 0,
+            ctes: Vec::new(),
+            firing: Vec::new(),
+            deleting: Vec::new(),
+        }
+    }
+
+    fn common_table_named(&self, name: &str) -> Option<(usize, TableDef)> {
+        self.ctes
+            .iter()
+            .rev()
+            .find(|cte| cte.table.name.eq_ignore_ascii_case(name))
+            .map(|cte| (cte.cursor, cte.table.clone()))
+    }
+
+    fn table(&self, name: &str) -> Result<&'s TableDef, StatementError> {
+        self.schema
+            .table(name)
+            .ok_or_else(|| StatementError::NoSuchTable(name.to_string()))
+    }
+
+    fn transaction(&mut self, write: bool) {
+        self.program.emit(Instruction::Transaction {
+            write,
+            schema_version: self.schema.version,
+        });
+    }
+
+    fn patch(&mut self, jumps: Pending) {
+        let to = self.program.next_address();
+        for jump in jumps {
+            self.program.set_target(jump, to);
+        }
+    }
+
+    // Compiles a query, handing each row it produces to `row`, and returns the names of its
+    // columns. Names the query's own tables don't have are looked up in `outer`.
+    fn select(
+        &mut self,
+        select: &Select,
+        outer: Option<&Scope>,
+        row: &mut RowHandler<'_, 's>,
+    ) -> Result<Vec<String>, StatementError> {
+        let ctes = self.ctes.len();
+        if let Some(with) = &select.with {
+            for table in &with.tables {
+                self.common_table(table, with.recursive, outer)?;
+            }
+        }
+        let names = match &select.limit {
+            Some(limit) => self.limit(limit).and_then(|left| {
+                let mut stops = Vec::new();
+                let names = self.union(select, outer, &mut |compiler, start, count| {
+                    stops.push(take_row(&mut compiler.program, left));
+                    row(compiler, start, count)
+                });
+                self.patch(stops);
+                names
+            }),
+            None => self.union(select, outer, row),
+        };
+        self.ctes.truncate(ctes);
+        names
+    }
+
+    // Works out how many rows a query hands on at most, into a register that counts down as
+    // they are
+    fn limit(&mut self, limit: &Expr) -> Result<usize, StatementError> {
+        let left = self.program.allocate_registers(1);
+        self.expr(&Scope::default(), limit, left)?;
+        self.program.emit(Instruction::MustBeInt { src: left });
+        Ok(left)
+    }
+
+    // Every query of a union hands its rows on in turn. Rows a UNION leaves out are the ones
+    // already in a temporary index of the rows so far.
+    fn union(
+        &mut self,
+        select: &Select,
+        outer: Option<&Scope>,
+        row: &mut RowHandler<'_, 's>,
+    ) -> Result<Vec<String>, StatementError> {
+        let selects: Vec<&Select> = std::iter::once(select)
+            .chain(select.unions.iter().map(|union| &union.select))
+            .collect();
+        // everything up to the last UNION is without duplicates
+        let distinct = match select.unions.iter().rposition(|union| !union.all) {
+            Some(last) => {
+                let cursor = self.program.allocate_cursor();
+                self.program.emit(Instruction::OpenEphemeral { cursor });
+                Some((last + 1, cursor))
+            }
+            None => None,
+        };
+        let mut names = Vec::new();
+        for (i, select) in selects.into_iter().enumerate() {
+            let columns = match distinct {
+                Some((last, seen)) if i <= last => {
+                    self.simple_select(select, outer, &mut |compiler, start, count| {
+                        let new = distinct_row(&mut compiler.program, seen, start, count);
+                        row(compiler, start, count)?;
+                        compiler.patch(vec![new]);
+                        Ok(())
+                    })?
+                }
+                _ => self.simple_select(select, outer, row)?,
+            };
+            if i == 0 {
+                names = columns;
+            } else if columns.len() != names.len() {
+                return Err(StatementError::UnionColumns);
+            }
+        }
+        Ok(names)
+    }
+
+    // Gathers the rows of a table the WITH clause defines into a temporary tree, where the
+    // queries after it find them. A recursive table runs the queries that read it on each of
+    // its rows in turn, starting with the ones the other queries produced, and adds what they
+    // produce on to the end until there is nothing left to run them on, or until it has as
+    // many rows as its LIMIT allows.
+    fn common_table(
+        &mut self,
+        table: &CommonTable,
+        recursive: bool,
+        outer: Option<&Scope>,
+    ) -> Result<(), StatementError> {
+        let cursor = self.program.allocate_cursor();
+        let id = self.program.emit(Instruction::OpenEphemeral { cursor });
+        self.program.plan.push(PlanStep {
+            id,
+            parent: self.parent,
+            detail: format!("MATERIALIZE {}", table.name),
+        });
+        let parent = std::mem::replace(&mut self.parent, id);
+        let select = &table.select;
+        let selects: Vec<&Select> = std::iter::once(select)
+            .chain(select.unions.iter().map(|union| &union.select))
+            .collect();
+        let reads_itself = |select: &Select| {
+            select.from.iter().any(|table_ref| {
+                table_ref.select.is_none() && table_ref.name.eq_ignore_ascii_case(&table.name)
+            })
+        };
+        let compiled = if recursive && selects.iter().any(|select| reads_itself(select)) {
+            let ctes = self.ctes.len();
+            if let Some(with) = &select.with {
+                for table in &with.tables {
+                    self.common_table(table, with.recursive, outer)?;
+                }
+            }
+            let compiled = self.recursive_table(table, &selects, &reads_itself, cursor, outer);
+            self.ctes.truncate(ctes);
+            compiled
+        } else {
+            self.select(select, outer, &mut |compiler, start, count| {
+                append(&mut compiler.program, cursor, start, count);
+                Ok(())
+            })
+            .and_then(|columns| derived_table(&table.name, columns, table.columns.as_ref()))
+        };
+        self.parent = parent;
+        self.ctes.push(CommonTableDef {
+            cursor,
+            table: compiled?,
+        });
+        Ok(())
+    }
+
+    fn recursive_table(
+        &mut self,
+        table: &CommonTable,
+        selects: &[&Select],
+        reads_itself: &dyn Fn(&Select) -> bool,
+        cursor: usize,
+        outer: Option<&Scope>,
+    ) -> Result<TableDef, StatementError> {
+        let left = match &table.select.limit {
+            Some(limit) => Some(self.limit(limit)?),
+            None => None,
+        };
+        let seen = if table.select.unions.iter().any(|union| !union.all) {
+            let seen = self.program.allocate_cursor();
+            self.program
+                .emit(Instruction::OpenEphemeral { cursor: seen });
+            Some(seen)
+        } else {
+            None
+        };
+        let mut stops = Vec::new();
+        let mut add = |compiler: &mut Self, start: usize, count: usize| {
+            let new = seen.map(|seen| distinct_row(&mut compiler.program, seen, start, count));
+            stops.extend(left.map(|left| take_row(&mut compiler.program, left)));
+            append(&mut compiler.program, cursor, start, count);
+            compiler.patch(new.into_iter().collect());
+            Ok(())
+        };
+        let mut columns = None;
+        for select in selects.iter().filter(|select| !reads_itself(select)) {
+            let names = self.simple_select(select, outer, &mut add)?;
+            match &columns {
+                Some(columns) if names.len() != Vec::len(columns) => {
+                    return Err(StatementError::UnionColumns)
+                }
+                Some(_) => {}
+                None => columns = Some(names),
+            }
+        }
+        let columns =
+            columns.ok_or_else(|| StatementError::CircularReference(table.name.clone()))?;
+        let def = derived_table(&table.name, columns, table.columns.as_ref())?;
+        let count = def.columns.len();
+
+        // the table reads just the row it's on, which is kept in a tree of its own
+        let program = &mut self.program;
+        let queue = program.allocate_cursor();
+        program.emit(Instruction::OpenDup {
+            cursor: queue,
+            from: cursor,
+        });
+        let current = program.allocate_cursor();
+        program.emit(Instruction::OpenEphemeral { cursor: current });
+        let (one, rowid) = (program.allocate_registers(1), program.allocate_registers(1));
+        program.emit(Instruction::Integer {
+            value: 1,
+            dest: one,
+        });
+        program.emit(Instruction::Integer {
+            value: 1,
+            dest: rowid,
+        });
+        let top = program.next_address();
+        let end = program.emit(Instruction::SeekRowid {
+            cursor: queue,
+            src: rowid,
+            target: 0,
+        });
+        let start = program.allocate_registers(count);
+        for column in 0..count {
+            program.emit(Instruction::Column {
+                cursor: queue,
+                column,
+                dest: start + column,
+            });
+        }
+        program.emit(Instruction::Insert {
+            cursor: current,
+            start,
+            count,
+            rowid: Some(one),
+        });
+        self.ctes.push(CommonTableDef {
+            cursor: current,
+            table: def,
+        });
+        for select in selects.iter().filter(|select| reads_itself(select)) {
+            let names = self.simple_select(select, outer, &mut add)?;
+            if names.len() != count {
+                return Err(StatementError::UnionColumns);
+            }
+        }
+        let def = self.ctes.pop().unwrap().table;
+        let program = &mut self.program;
+        program.emit(Instruction::Binary {
+            op: BinaryOp::Add,
+            left: rowid,
+            right: one,
+            dest: rowid,
+        });
+        program.emit(Instruction::Goto { target: top });
+        stops.push(end);
+        self.patch(stops);
+        Ok(def)
+    }
+
+    // A view's rows are gathered afresh every time a query reads it. Its query only sees what
+    // is in the schema, whatever the query reading it is inside of.
+    fn view(&mut self, view: &ViewDef) -> Result<(usize, TableDef), StatementError> {
+        let cursor = self.program.allocate_cursor();
+        let id = self.program.emit(Instruction::OpenEphemeral { cursor });
+        self.program.plan.push(PlanStep {
+            id,
+            parent: self.parent,
+            detail: format!("MATERIALIZE {}", view.name),
+        });
+        let parent = std::mem::replace(&mut self.parent, id);
+        let ctes = std::mem::take(&mut self.ctes);
+        let columns = self.select(&view.select, None, &mut |compiler, start, count| {
+            append(&mut compiler.program, cursor, start, count);
+            Ok(())
+        });
+        self.ctes = ctes;
+        self.parent = parent;
+        Ok((
+            cursor,
+            derived_table(&view.name, columns?, view.columns.as_ref())?,
+        ))
+    }
+
+    fn simple_select(
+        &mut self,
+        select: &Select,
+        outer: Option<&Scope>,
+        row: &mut RowHandler<'_, 's>,
+    ) -> Result<Vec<String>, StatementError> {
+        if select.from.len() > MAX_JOIN_TABLES {
+            return Err(StatementError::TooManyTables);
+        }
+        // the rows of each table made from a subquery are gathered before anything else is
+        // read, while those of a table the WITH clause made already have been
+        let mut derived = Vec::new();
+        for table_ref in &select.from {
+            if let Some(inner) = &table_ref.select {
+                let name = table_ref.alias.as_ref().unwrap_or(&table_ref.name);
+                let cursor = self.program.allocate_cursor();
+                let id = self.program.emit(Instruction::OpenEphemeral { cursor });
+                self.program.plan.push(PlanStep {
+                    id,
+                    parent: self.parent,
+                    detail: format!("MATERIALIZE {}", name),
+                });
+                let parent = std::mem::replace(&mut self.parent, id);
+                let columns = self.select(inner, outer, &mut |compiler, start, count| {
+                    append(&mut compiler.program, cursor, start, count);
+                    Ok(())
+                })?;
+                self.parent = parent;
+                derived.push(Some((cursor, derived_table(name, columns, None)?)));
+            } else if let Some(cte) = self.common_table_named(&table_ref.name) {
+                derived.push(Some(cte));
+            } else if let Some(view) = self.schema.view(&table_ref.name) {
+                derived.push(Some(self.view(view)?));
+            } else {
+                derived.push(None);
+            }
+        }
+        let mut scope = Scope {
+            outer,
+            ..Scope::default()
+        };
+        for (table_ref, derived) in select.from.iter().zip(&derived) {
+            let (table, derived) = match derived {
+                Some((cursor, table)) => (table, Some(*cursor)),
+                None => (self.table(&table_ref.name)?, None),
+            };
+            scope.sources.push(Source {
+                name: table_ref
+                    .alias
+                    .clone()
+                    .unwrap_or_else(|| table.name.clone()),
+                table,
+                cursor: self.program.allocate_cursor(),
+                covering: None,
+                derived,
+            });
+        }
+
+        let mut columns = Vec::new();
+        for column in &select.columns {
+            match column {
+                ResultColumn::All(table) => {
+                    let mut found = false;
+                    for source in &scope.sources {
+                        if table
+                            .as_ref()
+                            .is_some_and(|table| !table.eq_ignore_ascii_case(&source.name))
+                        {
+                            continue;
+                        }
+                        found = true;
+                        for column in &source.table.columns {
+                            let expr = Expr::Column {
+                                table: Some(source.name.clone()),
+                                name: column.name.clone(),
+                            };
+                            columns.push((expr, column.name.clone()));
+                        }
+                    }
+                    if !found {
+                        return Err(match table {
+                            Some(table) => StatementError::NoSuchTable(table.clone()),
+                            None => StatementError::Sql,
+                        });
+                    }
+                }
+                ResultColumn::Expr { expr, name } => columns.push((expr.clone(), name.clone())),
+            }
+        }
+
+        let conjuncts = match &select.filter {
+            Some(filter) => filter.conjuncts(),
+            None => Vec::new(),
+        };
+        let mut terms = Vec::new();
+        for expr in conjuncts {
+            terms.push(self.term(&scope, expr)?);
+        }
+        // an inner join's ON clause might as well be part of the WHERE clause
+        for (i, table_ref) in select.from.iter().enumerate() {
+            let on = match &table_ref.on {
+                Some(on) => on,
+                None => continue,
+            };
+            for expr in on.conjuncts() {
+                let mut term = self.term(&scope, expr)?;
+                // it can only read the tables up to its own
+                if term.sources >> (i + 1) != 0 {
+                    return Err(StatementError::Sql);
+                }
+                if table_ref.join == JoinKind::Left {
+                    term.on = Some(i);
+                }
+                terms.push(term);
+            }
+        }
+        let mut read = vec![Some(Vec::new()); scope.sources.len()];
+        for expr in columns
+            .iter()
+            .map(|(expr, _)| expr)
+            .chain(terms.iter().map(|t| t.expr))
+        {
+            scope.columns_read(expr, &mut read)?;
+        }
+        let mut sources: Vec<planner::Source> = Vec::new();
+        // every table after a LEFT JOIN is read inside it
+        let mut left_joins = 0u64;
+        for ((i, source), columns) in scope.sources.iter().enumerate().zip(read) {
+            let left = select.from[i].join == JoinKind::Left;
+            sources.push(planner::Source {
+                table: source.table,
+                indexes: self.schema.indexes_of(&source.table.name).collect(),
+                columns,
+                left,
+                must_follow: if left { (1 << i) - 1 } else { left_joins },
+            });
+            if left {
+                left_joins |= 1 << i;
+            }
+        }
+        let plan = planner::plan(&sources, &terms);
+
+        let mut index_cursors = Vec::new();
+        for planned in &plan.loops {
+            let source = &mut scope.sources[planned.source];
+            let index_cursor = match &planned.access {
+                Access::Index { .. } => Some(self.program.allocate_cursor()),
+                _ => None,
+            };
+            let covering = matches!(planned.access, Access::Index { covering: true, .. });
+            let tree = tree(source.table);
+            let id = match (&planned.access, index_cursor) {
+                (Access::Index { index, .. }, Some(cursor)) => {
+                    let id = if covering {
+                        None
+                    } else {
+                        Some(self.program.emit(Instruction::OpenRead {
+                            cursor: source.cursor,
+                            tree,
+                        }))
+                    };
+                    let index_id = self.program.emit(Instruction::OpenRead {
+                        cursor,
+                        tree: Tree::Index(index.root),
+                    });
+                    if covering {
+                        source.covering = Some((cursor, index));
+                    }
+                    id.unwrap_or(index_id)
+                }
+                // the whole table goes into the hash table before anything else is read
+                (Access::Hash { column, .. }, _) => {
+                    let program = &mut self.program;
+                    let id = program.emit(Instruction::OpenHash {
+                        cursor: source.cursor,
+                    });
+                    let build = program.allocate_cursor();
+                    program.emit(match source.derived {
+                        Some(from) => Instruction::OpenDup {
+                            cursor: build,
+                            from,
+                        },
+                        None => Instruction::OpenRead {
+                            cursor: build,
+                            tree,
+                        },
+                    });
+                    let count = source.table.columns.len();
+                    let row = program.allocate_registers(count + 1);
+                    let rewind = program.emit(Instruction::Rewind {
+                        cursor: build,
+                        target: 0,
+                    });
+                    let top = program.next_address();
+                    program.emit(Instruction::Rowid {
+                        cursor: build,
+                        dest: row,
+                    });
+                    for column in 0..count {
+                        program.emit(Instruction::Column {
+                            cursor: build,
+                            column,
+                            dest: row + 1 + column,
+                        });
+                    }
+                    program.emit(Instruction::HashInsert {
+                        cursor: source.cursor,
+                        key: row + 1 + column,
+                        start: row,
+                        count: count + 1,
+                    });
+                    program.emit(Instruction::Next {
+                        cursor: build,
+                        target: top,
+                    });
+                    let end = program.next_address();
+                    program.set_target(rewind, end);
+                    id
+                }
+                _ => self.program.emit(match source.derived {
+                    Some(from) => Instruction::OpenDup {
+                        cursor: source.cursor,
+                        from,
+                    },
+                    None => Instruction::OpenRead {
+                        cursor: source.cursor,
+                        tree,
+                    },
+                }),
+            };
+            self.program.plan.push(PlanStep {
+                id,
+                parent: self.parent,
+                detail: planned.access.describe(source.table, &source.name),
+            });
+            index_cursors.push(index_cursor);
+        }
+
+        let used: Vec<usize> = plan.loops.iter().flat_map(|l| l.used.clone()).collect();
+        let mut checked = vec![false; terms.len()];
+        // conditions that don't read any table are checked once, before anything is read
+        let mut done = Pending::new();
+        for (i, term) in terms.iter().enumerate() {
+            if term.sources == 0 && term.on.is_none() {
+                let dest = self.program.allocate_registers(1);
+                self.expr(&scope, term.expr, dest)?;
+                done.push(self.program.emit(Instruction::IfNot {
+                    src: dest,
+                    target: 0,
+                }));
+                checked[i] = true;
+            }
+        }
+
+        let mut loops = Vec::new();
+        let mut available = 0u64;
+        for (planned, index_cursor) in plan.loops.iter().zip(&index_cursors) {
+            let cursor = scope.sources[planned.source].cursor;
+            let mut next = Pending::new();
+            let mut end = Pending::new();
+            let matched = if sources[planned.source].left {
+                let matched = self.program.allocate_registers(1);
+                self.program.emit(Instruction::Integer {
+                    value: 0,
+                    dest: matched,
+                });
+                Some(matched)
+            } else {
+                None
+            };
+            let top = match &planned.access {
+                Access::Scan => {
+                    end.push(self.program.emit(Instruction::Rewind { cursor, target: 0 }));
+                    Some((self.program.next_address(), cursor))
+                }
+                Access::Rowid(value) => {
+                    let src = self.program.allocate_registers(1);
+                    self.expr(&scope, value, src)?;
+                    end.push(self.program.emit(Instruction::SeekRowid {
+                        cursor,
+                        src,
+                        target: 0,
+                    }));
+                    None
+                }
+                Access::Index {
+                    eq,
+                    lower,
+                    upper,
+                    covering,
+                    ..
+                } => {
+                    let index_cursor = index_cursor.unwrap();
+                    let count = eq.len();
+                    let start = self.program.allocate_registers(count + 1);
+                    for (i, value) in eq.iter().enumerate() {
+                        self.expr(&scope, value, start + i)?;
+                        // nothing equals NULL
+                        end.push(self.program.emit(Instruction::IsNull {
+                            src: start + i,
+                            target: 0,
+                        }));
+                    }
+                    let limit = match upper {
+                        Some(upper) => {
+                            let limit = self.program.allocate_registers(count + 1);
+                            for i in 0..count {
+                                self.program.emit(Instruction::Copy {
+                                    src: start + i,
+                                    dest: limit + i,
+                                });
+                            }
+                            self.expr(&scope, upper.value, limit + count)?;
+                            end.push(self.program.emit(Instruction::IsNull {
+                                src: limit + count,
+                                target: 0,
+                            }));
+                            Some((limit, upper.inclusive))
+                        }
+                        None => None,
+                    };
+                    let seek = match (lower, upper) {
+                        (Some(lower), _) => {
+                            self.expr(&scope, lower.value, start + count)?;
+                            end.push(self.program.emit(Instruction::IsNull {
+                                src: start + count,
+                                target: 0,
+                            }));
+                            let (cursor, count, target) = (index_cursor, count + 1, 0);
+                            if lower.inclusive {
+                                Instruction::SeekGE {
+                                    cursor,
+                                    start,
+                                    count,
+                                    target,
+                                }
+                            } else {
+                                Instruction::SeekGT {
+                                    cursor,
+                                    start,
+                                    count,
+                                    target,
+                                }
+                            }
+                        }
+                        // NULLs come first in an index, and are never less than anything
+                        (None, Some(_)) => {
+                            self.program.emit(Instruction::Null {
+                                dest: start + count,
+                            });
+                            Instruction::SeekGT {
+                                cursor: index_cursor,
+                                start,
+                                count: count + 1,
+                                target: 0,
+                            }
+                        }
+                        (None, None) if count > 0 => Instruction::SeekGE {
+                            cursor: index_cursor,
+                            start,
+                            count,
+                            target: 0,
+                        },
+                        (None, None) => Instruction::Rewind {
+                            cursor: index_cursor,
+                            target: 0,
+                        },
+                    };
+                    end.push(self.program.emit(seek));
+                    let top = self.program.next_address();
+                    match limit {
+                        Some((limit, true)) => end.push(self.program.emit(Instruction::IdxGT {
+                            cursor: index_cursor,
+                            start: limit,
+                            count: count + 1,
+                            target: 0,
+                        })),
+                        Some((limit, false)) => end.push(self.program.emit(Instruction::IdxGE {
+                            cursor: index_cursor,
+                            start: limit,
+                            count: count + 1,
+                            target: 0,
+                        })),
+                        None if count > 0 => end.push(self.program.emit(Instruction::IdxGT {
+                            cursor: index_cursor,
+                            start,
+                            count,
+                            target: 0,
+                        })),
+                        None => {}
+                    }
+                    if !covering {
+                        let rowid = self.program.allocate_registers(1);
+                        self.program.emit(Instruction::Rowid {
+                            cursor: index_cursor,
+                            dest: rowid,
+                        });
+                        next.push(self.program.emit(Instruction::SeekRowid {
+                            cursor,
+                            src: rowid,
+                            target: 0,
+                        }));
+                    }
+                    Some((top, index_cursor))
+                }
+                Access::Hash { value, .. } => {
+                    let key = self.program.allocate_registers(1);
+                    self.expr(&scope, value, key)?;
+                    end.push(self.program.emit(Instruction::HashSeek {
+                        cursor,
+                        key,
+                        target: 0,
+                    }));
+                    Some((self.program.next_address(), cursor))
+                }
+            };
+            available |= 1 << planned.source;
+            // the ON clause of a LEFT JOIN decides whether there's a match, and the rest
+            // whether the row, or the row of NULLs standing in for it, is returned
+            let on = |term: &Term| term.on == Some(planned.source);
+            for (i, term) in terms.iter().enumerate() {
+                if on(term) && !checked[i] && !used.contains(&i) {
+                    let dest = self.program.allocate_registers(1);
+                    self.expr(&scope, term.expr, dest)?;
+                    next.push(self.program.emit(Instruction::IfNot {
+                        src: dest,
+                        target: 0,
+                    }));
+                    checked[i] = true;
+                }
+            }
+            let left = match matched {
+                Some(matched) => Some(LeftJoin {
+                    matched,
+                    set: self.program.emit(Instruction::Integer {
+                        value: 1,
+                        dest: matched,
+                    }),
+                    cursors: std::iter::once(cursor).chain(*index_cursor).collect(),
+                }),
+                None => None,
+            };
+            for (i, term) in terms.iter().enumerate() {
+                if checked[i]
+                    || used.contains(&i)
+                    || term.sources & !available != 0
+                    || term.on.is_some()
+                {
+                    continue;
+                }
+                let dest = self.program.allocate_registers(1);
+                self.expr(&scope, term.expr, dest)?;
+                next.push(self.program.emit(Instruction::IfNot {
+                    src: dest,
+                    target: 0,
+                }));
+                checked[i] = true;
+            }
+            loops.push(OpenLoop {
+                top,
+                next,
+                end,
+                left,
+            });
+        }
+
+        let start = self.program.allocate_registers(columns.len());
+        for (i, (expr, _)) in columns.iter().enumerate() {
+            self.expr(&scope, expr, start + i)?;
+        }
+        row(self, start, columns.len())?;
+        for OpenLoop {
+            top,
+            next,
+            end,
+            left,
+        } in loops.into_iter().rev()
+        {
+            self.patch(next);
+            if let Some((top, cursor)) = top {
+                self.program.emit(Instruction::Next {
+                    cursor,
+                    target: top,
+                });
+            }
+            self.patch(end);
+            if let Some(LeftJoin {
+                matched,
+                set,
+                cursors,
+            }) = left
+            {
+                let found = self.program.emit(Instruction::If {
+                    src: matched,
+                    target: 0,
+                });
+                for cursor in cursors {
+                    self.program.emit(Instruction::NullRow { cursor });
+                }
+                self.program.emit(Instruction::Goto { target: set });
+                self.patch(vec![found]);
+            }
+        }
+        self.patch(done);
+        Ok(columns.into_iter().map(|(_, name)| name).collect())
+    }
+
+    // Works out a bit for every source of the scope the query reads, and whether it reads any
+    // source of the scope or the ones around it, in which case it has to be run again for every
+    // row it is used on. The query is compiled and thrown away to find out.
+    fn correlation(&self, scope: &Scope, select: &Select) -> Result<(u64, bool), StatementError> {
+        let mut scopes = Vec::new();
+        let mut outer = Some(scope);
+        while let Some(scope) = outer {
+            scopes.push((scope, scope.referenced.replace(0)));
+            outer = scope.outer;
+        }
+        let mut compiler = Compiler {
+            program: Program::new(),
+            schema: self.schema,
+            parent: 0,
+            ctes: self.ctes.clone(),
+            firing: self.firing.clone(),
+            deleting: self.deleting.clone(),
+        };
+        let compiled = compiler.select(select, Some(scope), &mut |_, _, _| Ok(()));
+        let sources = scope.referenced.get();
+        let mut correlated = false;
+        for (scope, before) in scopes {
+            correlated |= scope.referenced.get() != 0;
+            scope.referenced.set(before | scope.referenced.get());
+        }
+        compiled?;
+        Ok((sources, correlated))
+    }
+
+    // A subquery used as a value. One that doesn't read the rows around it is only run the
+    // first time it is needed, with what it found kept for every time after.
+    fn subquery(&mut self, scope: &Scope, expr: &Expr, dest: usize) -> Result<(), StatementError> {
+        let (select, kind) = match expr {
+            Expr::Subquery(select) | Expr::Exists(select) => (select, "SCALAR SUBQUERY"),
+            Expr::In { select, .. } => (select, "LIST SUBQUERY"),
+            _ => unreachable!(),
+        };
+        let correlated = self.correlation(scope, select)?.1;
+        let once = if correlated {
+            None
+        } else {
+            Some(self.program.emit(Instruction::Once { target: 0 }))
+        };
+        let id = once.unwrap_or_else(|| self.program.next_address());
+        self.program.plan.push(PlanStep {
+            id,
+            parent: self.parent,
+            detail: if correlated {
+                format!("CORRELATED {}", kind)
+            } else {
+                kind.to_string()
+            },
+        });
+        let parent = std::mem::replace(&mut self.parent, id);
+        let value = self.program.allocate_registers(1);
+        let mut done = Pending::new();
+        match expr {
+            // the first row's value, or NULL without one
+            Expr::Subquery(_) => {
+                self.program.emit(Instruction::Null { dest: value });
+                self.select(select, Some(scope), &mut |compiler, start, count| {
+                    if count != 1 {
+                        return Err(StatementError::SubqueryColumns(count));
+                    }
+                    compiler.program.emit(Instruction::Copy {
+                        src: start,
+                        dest: value,
+                    });
+                    done.push(compiler.program.emit(Instruction::Goto { target: 0 }));
+                    Ok(())
+                })?;
+            }
+            Expr::Exists(_) => {
+                self.program.emit(Instruction::Integer {
+                    value: 0,
+                    dest: value,
+                });
+                self.select(select, Some(scope), &mut |compiler, _, _| {
+                    compiler.program.emit(Instruction::Integer {
+                        value: 1,
+                        dest: value,
+                    });
+                    done.push(compiler.program.emit(Instruction::Goto { target: 0 }));
+                    Ok(())
+                })?;
+            }
+            // the values go into a hash table, with registers saying whether there were any
+            // and whether any of them were NULL
+            Expr::In { expr: operand, .. } => {
+                let hash = self.program.allocate_cursor();
+                self.program.emit(Instruction::OpenHash { cursor: hash });
+                let flags = self.program.allocate_registers(2);
+                let (any, nulls) = (flags, flags + 1);
+                self.program.emit(Instruction::Integer {
+                    value: 0,
+                    dest: any,
+                });
+                self.program.emit(Instruction::Integer {
+                    value: 0,
+                    dest: nulls,
+                });
+                self.select(select, Some(scope), &mut |compiler, start, count| {
+                    if count != 1 {
+                        return Err(StatementError::SubqueryColumns(count));
+                    }
+                    let program = &mut compiler.program;
+                    program.emit(Instruction::Integer {
+                        value: 1,
+                        dest: any,
+                    });
+                    let null = program.emit(Instruction::IsNull {
+                        src: start,
+                        target: 0,
+                    });
+                    program.emit(Instruction::HashInsert {
+                        cursor: hash,
+                        key: start,
+                        start,
+                        count,
+                    });
+                    let next = program.emit(Instruction::Goto { target: 0 });
+                    compiler.patch(vec![null]);
+                    compiler.program.emit(Instruction::Integer {
+                        value: 1,
+                        dest: nulls,
+                    });
+                    compiler.patch(vec![next]);
+                    Ok(())
+                })?;
+                self.parent = parent;
+                self.patch(once.into_iter().collect());
+                return self.in_list(scope, operand, hash, any, nulls, dest);
+            }
+            _ => unreachable!(),
+        }
+        self.patch(done);
+        self.parent = parent;
+        self.patch(once.into_iter().collect());
+        self.program.emit(Instruction::Copy { src: value, dest });
+        Ok(())
+    }
+
+    // Looks a value up in the hash table an IN subquery's values went into. Like a list of
+    // comparisons ORed together, it's NULL rather than false if it's NULL or if any value is.
+    fn in_list(
+        &mut self,
+        scope: &Scope,
+        operand: &Expr,
+        hash: usize,
+        any: usize,
+        nulls: usize,
+        dest: usize,
+    ) -> Result<(), StatementError> {
+        let key = self.program.allocate_registers(1);
+        self.expr(scope, operand, key)?;
+        let program = &mut self.program;
+        let null = program.emit(Instruction::IsNull {
+            src: key,
+            target: 0,
+        });
+        let missing = program.emit(Instruction::HashSeek {
+            cursor: hash,
+            key,
+            target: 0,
+        });
+        program.emit(Instruction::Integer { value: 1, dest });
+        let mut done = vec![program.emit(Instruction::Goto { target: 0 })];
+        self.patch(vec![missing]);
+        let program = &mut self.program;
+        program.emit(Instruction::Integer { value: 0, dest });
+        done.push(program.emit(Instruction::IfNot {
+            src: nulls,
+            target: 0,
+        }));
+        program.emit(Instruction::Null { dest });
+        done.push(program.emit(Instruction::Goto { target: 0 }));
+        self.patch(vec![null]);
+        // nothing is in an empty list, not even NULL
+        let program = &mut self.program;
+        program.emit(Instruction::Integer { value: 0, dest });
+        done.push(program.emit(Instruction::IfNot {
+            src: any,
+            target: 0,
+        }));
+        program.emit(Instruction::Null { dest });
+        self.patch(done);
+        Ok(())
+    }
+
+    fn insert(&mut self, insert: &Insert, outer: Option<&Scope>) -> Result<(), StatementError> {
+        let table = self.table(&insert.table)?;
+        // the table column each value goes into
+        let targets = match &insert.columns {
+            Some(columns) => columns
+                .iter()
+                .map(|name| {
+                    table
+                        .column(name)
+                        .ok_or_else(|| StatementError::NoSuchColumn(name.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => (0..table.columns.len()).collect(),
+        };
+        let indexes: Vec<&IndexDef> = self.schema.indexes_of(&table.name).collect();
+        // the users table's columns only take values of one type and length
+        let checked = table.name == TABLE_NAME;
+        let before = self.triggers(table, TriggerTiming::Before, TriggerEvent::Insert);
+        let after = self.triggers(table, TriggerTiming::After, TriggerEvent::Insert);
+
+        let (cursor, index_cursors) = self.open_write(table, &indexes);
+        let scope = Scope {
+            outer,
+            ..Scope::default()
+        };
+        for row in &insert.rows {
+            if row.len() != targets.len() {
+                return Err(StatementError::Sql);
+            }
+            let count = table.columns.len();
+            let start = self.program.allocate_registers(count);
+            for column in 0..count {
+                let dest = start + column;
+                match targets.iter().position(|target| *target == column) {
+                    Some(i) => {
+                        match &row[i] {
+                            Expr::Literal(value) if checked => check_column(column, value)?,
+                            Expr::Param(param) if checked => {
+                                self.program.checks.push((*param, column))
+                            }
+                            _ => {}
+                        }
+                        self.expr(&scope, &row[i], dest)?;
+                    }
+                    None => match &table.columns[column].default {
+                        Some(default) => self.expr(&Scope::default(), &default.value, dest)?,
+                        None => {
+                            self.program.emit(Instruction::Null { dest });
+                        }
+                    },
+                }
+            }
+            // the rowid isn't known before the row goes in, unless it is the primary key's value
+            if !before.is_empty() {
+                let rowid = self.program.allocate_registers(1);
+                self.program.emit(match table.primary_key {
+                    Some(column) => Instruction::Copy {
+                        src: start + column,
+                        dest: rowid,
+                    },
+                    None => Instruction::Null { dest: rowid },
+                });
+                self.fire(&before, table, None, Some((start, rowid)))?;
+            }
+            let rowid = if table.root.is_none() && indexes.is_empty() && after.is_empty() {
+                None
+            } else {
+                let dest = self.program.allocate_registers(1);
+                match table.primary_key {
+                    Some(column) => self.primary_key(table, cursor, start + column, dest),
+                    None => {
+                        self.program.emit(Instruction::NewRowid { cursor, dest });
+                    }
+                }
+                Some(dest)
+            };
+            // only the users table goes without a rowid, and it has no constraints or keys
+            if let Some(rowid) = rowid {
+                self.constraints(table, &indexes, &index_cursors, (start, rowid))?;
+                self.foreign_keys(table, None, Some((start, rowid)), &|_| true)?;
+            }
+            self.program.emit(Instruction::Insert {
+                cursor,
+                start,
+                count,
+                rowid,
+            });
+            if let Some(rowid) = rowid {
+                self.index_entries(&indexes, &index_cursors, (start, rowid), true);
+                self.referring_keys(table, None, Some((start, rowid)), &|_| true)?;
+                self.fire(&after, table, None, Some((start, rowid)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // opens the table and each of its indexes for writing, returning the cursors
+    fn open_write(&mut self, table: &TableDef, indexes: &[&IndexDef]) -> (usize, Vec<usize>) {
+        let cursor = self.program.allocate_cursor();
+        self.program.emit(Instruction::OpenWrite {
+            cursor,
+            tree: tree(table),
+        });
+        let mut index_cursors = Vec::new();
+        for index in indexes {
+            let index_cursor = self.program.allocate_cursor();
+            self.program.emit(Instruction::OpenWrite {
+                cursor: index_cursor,
+                tree: Tree::Index(index.root),
+            });
+            index_cursors.push(index_cursor);
+        }
+        (cursor, index_cursors)
+    }
+
+    // adds the row's entry to each of the indexes, or takes it out
+    fn index_entries(
+        &mut self,
+        indexes: &[&IndexDef],
+        index_cursors: &[usize],
+        (start, rowid): RowRegisters,
+        add: bool,
+    ) {
+        for (index, index_cursor) in indexes.iter().zip(index_cursors) {
+            let count = index.columns.len() + 1;
+            let key = self.program.allocate_registers(count);
+            for (i, column) in index.columns.iter().enumerate() {
+                self.program.emit(Instruction::Copy {
+                    src: start + column,
+                    dest: key + i,
+                });
+            }
+            self.program.emit(Instruction::Copy {
+                src: rowid,
+                dest: key + index.columns.len(),
+            });
+            let (cursor, start) = (*index_cursor, key);
+            self.program.emit(if add {
+                Instruction::IdxInsert {
+                    cursor,
+                    start,
+                    count,
+                }
+            } else {
+                Instruction::IdxDelete {
+                    cursor,
+                    start,
+                    count,
+                }
+            });
+        }
+    }
+
+    // Fails the statement unless the row in `row` can go into the table: no column declared NOT
+    // NULL is NULL, no CHECK is false, and no other row has the same values in a unique index.
+    fn constraints(
+        &mut self,
+        table: &TableDef,
+        indexes: &[&IndexDef],
+        index_cursors: &[usize],
+        (start, rowid): RowRegisters,
+    ) -> Result<(), StatementError> {
+        for (column, def) in table.columns.iter().enumerate() {
+            if def.not_null {
+                let program = &mut self.program;
+                let null = program.allocate_registers(1);
+                program.emit(Instruction::Unary {
+                    op: UnaryOp::NotNull,
+                    src: start + column,
+                    dest: null,
+                });
+                let ok = program.emit(Instruction::If {
+                    src: null,
+                    target: 0,
+                });
+                program.emit(constraint_failed("NOT NULL", None, table, &[column]));
+                self.patch(vec![ok]);
+            }
+        }
+        if table.columns.iter().any(|column| column.check.is_some()) {
+            // checks read the row's columns by name, the way a query reads the table
+            let scope = Scope {
+                sources: vec![self.pseudo(&table.name, table, (start, rowid))],
+                ..Scope::default()
+            };
+            for (column, def) in table.columns.iter().enumerate() {
+                if let Some(check) = &def.check {
+                    let dest = self.program.allocate_registers(1);
+                    self.expr(&scope, check, dest)?;
+                    // a check that is NULL passes
+                    let program = &mut self.program;
+                    program.emit(Instruction::Unary {
+                        op: UnaryOp::Not,
+                        src: dest,
+                        dest,
+                    });
+                    let ok = program.emit(Instruction::IfNot {
+                        src: dest,
+                        target: 0,
+                    });
+                    let name = def.check_name.as_deref();
+                    program.emit(constraint_failed("CHECK", name, table, &[column]));
+                    self.patch(vec![ok]);
+                }
+            }
+        }
+        for (index, index_cursor) in indexes.iter().zip(index_cursors) {
+            if index.unique {
+                let key = self.program.allocate_registers(index.columns.len());
+                for (i, column) in index.columns.iter().enumerate() {
+                    self.program.emit(Instruction::Copy {
+                        src: start + column,
+                        dest: key + i,
+                    });
+                }
+                self.unique(table, &index.columns, *index_cursor, key);
+            }
+        }
+        Ok(())
+    }
+
+    // fails the statement if the index has an entry with the values in the registers from `key`,
+    // unless one of them is NULL, since NULL is never the same as anything
+    fn unique(&mut self, table: &TableDef, columns: &[usize], cursor: usize, key: usize) {
+        let program = &mut self.program;
+        let count = columns.len();
+        let mut ok = Pending::new();
+        for i in 0..count {
+            ok.push(program.emit(Instruction::IsNull {
+                src: key + i,
+                target: 0,
+            }));
+        }
+        ok.push(program.emit(Instruction::SeekGE {
+            cursor,
+            start: key,
+            count,
+            target: 0,
+        }));
+        ok.push(program.emit(Instruction::IdxGT {
+            cursor,
+            start: key,
+            count,
+            target: 0,
+        }));
+        program.emit(constraint_failed("UNIQUE", None, table, columns));
+        self.patch(ok);
+    }
+
+    // The foreign keys on the table, for a row that is going (`old`) and a row that is coming
+    // (`new`). A row coming in that refers to nothing counts as a violation, and a row going
+    // that referred to nothing must have been counted, so it takes one away. Only the keys on
+    // columns that have `changed` are looked at.
+    fn foreign_keys(
+        &mut self,
+        table: &TableDef,
+        old: Option<RowRegisters>,
+        new: Option<RowRegisters>,
+        changed: &dyn Fn(usize) -> bool,
+    ) -> Result<(), StatementError> {
+        for (column, def) in table.columns.iter().enumerate() {
+            let key = match &def.references {
+                Some(key) if changed(column) => key,
+                _ => continue,
+            };
+            let (parent, parent_column) = self.parent_key(table, key)?;
+            let deferred = key.deferred;
+            for (name, row, amount) in [("old", old, -1), ("new", new, 1)] {
+                let row = match row {
+                    Some(row) => row,
+                    None => continue,
+                };
+                let mut done = Pending::new();
+                if amount < 0 {
+                    done.push(self.program.emit(Instruction::FkIfZero {
+                        deferred,
+                        target: 0,
+                    }));
+                }
+                done.push(self.program.emit(Instruction::IsNull {
+                    src: row.0 + column,
+                    target: 0,
+                }));
+                let scope = Scope {
+                    sources: vec![self.pseudo(name, table, row)],
+                    ..Scope::default()
+                };
+                let found = self.program.allocate_registers(1);
+                self.program.emit(Instruction::Integer {
+                    value: 0,
+                    dest: found,
+                });
+                let select = rows_with(parent, parent_column, name, &def.name);
+                self.simple_select(&select, Some(&scope), &mut |compiler, _, _| {
+                    compiler.program.emit(Instruction::Integer {
+                        value: 1,
+                        dest: found,
+                    });
+                    Ok(())
+                })?;
+                done.push(self.program.emit(Instruction::If {
+                    src: found,
+                    target: 0,
+                }));
+                self.program
+                    .emit(Instruction::FkCounter { deferred, amount });
+                self.patch(done);
+            }
+        }
+        Ok(())
+    }
+
+    // The foreign keys referring to the table, for a row whose key is going (`old`) or coming
+    // (`new`). Each row left referring to a key that goes counts as a violation, or fails the
+    // statement straight away if the foreign key restricts changes; a key that comes takes away
+    // the violations counted for rows referring to it. Then the foreign key's action changes
+    // the rows referring to a key that went.
+    fn referring_keys(
+        &mut self,
+        table: &TableDef,
+        old: Option<RowRegisters>,
+        new: Option<RowRegisters>,
+        changed: &dyn Fn(usize) -> bool,
+    ) -> Result<(), StatementError> {
+        for (child, column, key) in self.schema.foreign_keys_to(&table.name) {
+            let (_, parent_column) = self.parent_key(child, key)?;
+            if !changed(parent_column) {
+                continue;
+            }
+            let (deferred, parent_name) = (key.deferred, &table.columns[parent_column].name);
+            let mut same = Pending::new();
+            if let (Some(old), Some(new)) = (old, new) {
+                // a key set to the value it had hasn't changed
+                let program = &mut self.program;
+                let equal = program.allocate_registers(1);
+                program.emit(Instruction::Binary {
+                    op: BinaryOp::Eq,
+                    left: old.0 + parent_column,
+                    right: new.0 + parent_column,
+                    dest: equal,
+                });
+                same.push(program.emit(Instruction::If {
+                    src: equal,
+                    target: 0,
+                }));
+            }
+            let action = match new {
+                Some(_) => key.on_update,
+                None => key.on_delete,
+            };
+            if let Some(old) = old {
+                let scope = Scope {
+                    sources: vec![self.pseudo("old", table, old)],
+                    ..Scope::default()
+                };
+                let select = rows_with(child, column, "old", parent_name);
+                self.simple_select(&select, Some(&scope), &mut |compiler, _, _| {
+                    compiler.program.emit(match action {
+                        ForeignKeyAction::Restrict => Instruction::Constraint {
+                            message: FOREIGN_KEY_FAILED.into(),
+                        },
+                        _ => Instruction::FkCounter {
+                            deferred,
+                            amount: 1,
+                        },
+                    });
+                    Ok(())
+                })?;
+            }
+            if let Some(new) = new {
+                let none = self.program.emit(Instruction::FkIfZero {
+                    deferred,
+                    target: 0,
+                });
+                let scope = Scope {
+                    sources: vec![self.pseudo("new", table, new)],
+                    ..Scope::default()
+                };
+                let select = rows_with(child, column, "new", parent_name);
+                self.simple_select(&select, Some(&scope), &mut |compiler, _, _| {
+                    compiler.program.emit(Instruction::FkCounter {
+                        deferred,
+                        amount: -1,
+                    });
+                    Ok(())
+                })?;
+                self.patch(vec![none]);
+            }
+            if let Some(old) = old {
+                self.key_action(table, parent_column, (child, column), action, old, new)?;
+            }
+            self.patch(same);
+        }
+        Ok(())
+    }
+
+    // A foreign key's ON DELETE or ON UPDATE action, run on the rows referring to a key that
+    // went or changed the way a trigger on the table referred to would be. An action that is
+    // already being taken further out isn't compiled in again: a cascading delete adds the
+    // rows it would delete on to the end of the ones the delete further out is working
+    // through, however many steps the rows refer to each other in, while any other action
+    // leaves what it would have changed counted as a violation.
+    fn key_action(
+        &mut self,
+        table: &TableDef,
+        parent_column: usize,
+        (child, column): (&TableDef, usize),
+        action: ForeignKeyAction,
+        old: RowRegisters,
+        new: Option<RowRegisters>,
+    ) -> Result<(), StatementError> {
+        let parent_name = &table.columns[parent_column].name;
+        let name = &child.columns[column].name;
+        let filter = rows_with(child, column, "old", parent_name).filter;
+        let set = |value| {
+            Statement::Update(Update {
+                table: child.name.clone(),
+                assignments: vec![(name.clone(), value)],
+                filter: filter.clone(),
+            })
+        };
+        let body = match (action, new) {
+            (ForeignKeyAction::Cascade, None) => Statement::Delete(Delete {
+                table: child.name.clone(),
+                filter: filter.clone(),
+            }),
+            (ForeignKeyAction::Cascade, Some(_)) => set(Expr::Column {
+                table: Some("new".to_string()),
+                name: parent_name.clone(),
+            }),
+            (ForeignKeyAction::SetNull, _) => set(Expr::Literal(Value::Null)),
+            _ => return Ok(()),
+        };
+        let trigger = TriggerDef {
+            name: format!("foreign key {}.{}", child.name, name),
+            table: table.name.clone(),
+            timing: TriggerTiming::After,
+            event: match new {
+                Some(_) => TriggerEvent::Update,
+                None => TriggerEvent::Delete,
+            },
+            when: None,
+            body: vec![body],
+            sql: String::new(),
+        };
+        if self.firing.contains(&trigger.name) {
+            let deleting = self
+                .deleting
+                .iter()
+                .rev()
+                .find(|(name, _)| name == &child.name);
+            if let (Statement::Delete(_), Some(&(_, rowids))) = (&trigger.body[0], deleting) {
+                let scope = Scope {
+                    sources: vec![self.pseudo("old", table, old)],
+                    ..Scope::default()
+                };
+                self.add_rows_where(child, filter.as_ref(), Some(&scope), rowids)?;
+            }
+            return Ok(());
+        }
+        self.trigger(&trigger, table, Some(old), new)
+    }
+
+    // the table and column a foreign key on the table refers to
+    fn parent_key(
+        &self,
+        table: &TableDef,
+        key: &ForeignKey,
+    ) -> Result<(&'s TableDef, usize), StatementError> {
+        self.schema
+            .parent_key(key)
+            .ok_or_else(|| StatementError::ForeignKeyMismatch {
+                table: table.name.clone(),
+                parent: key.table.clone(),
+            })
+    }
+
+    // ends a statement that changes rows, failing it if it has left a row referring to nothing
+    // through a foreign key that isn't deferred
+    fn check_foreign_keys(&mut self) {
+        let counted = self.program.instructions.iter().any(|instruction| {
+            matches!(
+                instruction,
+                Instruction::FkCounter {
+                    deferred: false,
+                    ..
+                }
+            )
+        });
+        if counted {
+            self.program.emit(Instruction::FkCheck);
+        }
+    }
+
+    // opens a cursor on the row in `row` as its registers are now, to be read as a table with
+    // the name
+    fn pseudo<'a>(
+        &mut self,
+        name: &str,
+        table: &'a TableDef,
+        (start, rowid): RowRegisters,
+    ) -> Source<'a> {
+        let cursor = self.program.allocate_cursor();
+        self.program.emit(Instruction::OpenPseudo {
+            cursor,
+            start,
+            count: table.columns.len(),
+            rowid,
+        });
+        Source {
+            name: name.to_string(),
+            table,
+            cursor,
+            covering: None,
+            derived: None,
+        }
+    }
+
+    // The rowid of a row going into a table with an integer primary key is the key's value, as
+    // long as no other row has it; a NULL key gets a new rowid, as if there were no key.
+    fn primary_key(&mut self, table: &TableDef, cursor: usize, value: usize, dest: usize) {
+        let program = &mut self.program;
+        program.emit(Instruction::Copy { src: value, dest });
+        let null = program.emit(Instruction::IsNull {
+            src: dest,
+            target: 0,
+        });
+        program.emit(Instruction::MustBeInt { src: dest });
+        let free = program.emit(Instruction::SeekRowid {
+            cursor,
+            src: dest,
+            target: 0,
+        });
+        program.emit(constraint_failed(
+            "UNIQUE",
+            None,
+            table,
+            &[table.primary_key.unwrap()],
+        ));
+        self.patch(vec![null]);
+        self.program.emit(Instruction::NewRowid { cursor, dest });
+        self.patch(vec![free]);
+        self.program.emit(Instruction::Copy {
+            src: dest,
+            dest: value,
+        });
+    }
+
+    // Each row's new values are worked out from its old ones. A row whose primary key changes
+    // moves to its new rowid, as long as no other row has it.
+    fn update(&mut self, update: &Update, outer: Option<&Scope>) -> Result<(), StatementError> {
+        let table = self.table(&update.table)?;
+        let mut assignments = Vec::new();
+        for (name, expr) in &update.assignments {
+            let column = table
+                .column(name)
+                .ok_or_else(|| StatementError::NoSuchColumn(name.clone()))?;
+            assignments.push((column, expr));
+        }
+        let indexes: Vec<&IndexDef> = self.schema.indexes_of(&table.name).collect();
+        let checked = table.name == TABLE_NAME;
+        let before = self.triggers(table, TriggerTiming::Before, TriggerEvent::Update);
+        let after = self.triggers(table, TriggerTiming::After, TriggerEvent::Update);
+
+        let rowids = self.rows_where(table, update.filter.as_ref(), outer)?;
+        let (cursor, index_cursors) = self.open_write(table, &indexes);
+        let count = table.columns.len();
+        let old = self.program.allocate_registers(count + 1);
+        let new = self.program.allocate_registers(count + 1);
+        let (old, new) = ((old, old + count), (new, new + count));
+        let mut each = self.each_row(rowids, cursor, count, old);
+
+        let scope = Scope {
+            sources: vec![Source {
+                name: table.name.clone(),
+                table,
+                cursor,
+                covering: None,
+                derived: None,
+            }],
+            outer,
+            ..Scope::default()
+        };
+        for column in 0..count {
+            let dest = new.0 + column;
+            // the last value a column is set to is the one it gets
+            match assignments.iter().rev().find(|(c, _)| *c == column) {
+                Some((_, expr)) => {
+                    match expr {
+                        Expr::Literal(value) if checked => check_column(column, value)?,
+                        Expr::Param(param) if checked => self.program.checks.push((*param, column)),
+                        _ => {}
+                    }
+                    self.expr(&scope, expr, dest)?;
+                }
+                None => {
+                    self.program.emit(Instruction::Copy {
+                        src: old.0 + column,
+                        dest,
+                    });
+                }
+            }
+        }
+        let moved = table
+            .primary_key
+            .filter(|key| assignments.iter().any(|(column, _)| column == key));
+        let program = &mut self.program;
+        match moved {
+            Some(key) => {
+                program.emit(Instruction::Copy {
+                    src: new.0 + key,
+                    dest: new.1,
+                });
+                program.emit(Instruction::MustBeInt { src: new.1 });
+                program.emit(Instruction::Copy {
+                    src: new.1,
+                    dest: new.0 + key,
+                });
+            }
+            None => {
+                program.emit(Instruction::Copy {
+                    src: old.1,
+                    dest: new.1,
+                });
+            }
+        }
+        if !before.is_empty() {
+            self.fire(&before, table, Some(old), Some(new))?;
+            // the triggers might have deleted the row
+            each.next.push(self.program.emit(Instruction::SeekRowid {
+                cursor,
+                src: old.1,
+                target: 0,
+            }));
+        }
+        self.index_entries(&indexes, &index_cursors, old, false);
+        if let Some(key) = moved {
+            let program = &mut self.program;
+            let same = program.allocate_registers(1);
+            program.emit(Instruction::Binary {
+                op: BinaryOp::Eq,
+                left: new.1,
+                right: old.1,
+                dest: same,
+            });
+            let stays = program.emit(Instruction::If {
+                src: same,
+                target: 0,
+            });
+            let free = program.emit(Instruction::SeekRowid {
+                cursor,
+                src: new.1,
+                target: 0,
+            });
+            program.emit(constraint_failed("UNIQUE", None, table, &[key]));
+            self.patch(vec![free]);
+            let gone = self.program.emit(Instruction::SeekRowid {
+                cursor,
+                src: old.1,
+                target: 0,
+            });
+            self.program.emit(Instruction::Delete { cursor });
+            self.patch(vec![stays, gone]);
+        }
+        self.constraints(table, &indexes, &index_cursors, new)?;
+        let changed = |column| assignments.iter().any(|(c, _)| *c == column);
+        self.foreign_keys(table, Some(old), Some(new), &changed)?;
+        self.program.emit(Instruction::Insert {
+            cursor,
+            start: new.0,
+            count,
+            rowid: Some(new.1),
+        });
+        self.index_entries(&indexes, &index_cursors, new, true);
+        self.referring_keys(table, Some(old), Some(new), &changed)?;
+        self.fire(&after, table, Some(old), Some(new))?;
+        self.end_each_row(each);
+        Ok(())
+    }
+
+    fn delete(&mut self, delete: &Delete, outer: Option<&Scope>) -> Result<(), StatementError> {
+        let table = self.table(&delete.table)?;
+        let indexes: Vec<&IndexDef> = self.schema.indexes_of(&table.name).collect();
+        let before = self.triggers(table, TriggerTiming::Before, TriggerEvent::Delete);
+        let after = self.triggers(table, TriggerTiming::After, TriggerEvent::Delete);
+
+        let rowids = self.rows_where(table, delete.filter.as_ref(), outer)?;
+        let (cursor, index_cursors) = self.open_write(table, &indexes);
+        let count = table.columns.len();
+        let old = self.program.allocate_registers(count + 1);
+        let old = (old, old + count);
+        self.deleting.push((table.name.clone(), rowids));
+        let mut each = self.each_row(rowids, cursor, count, old);
+        if !before.is_empty() {
+            self.fire(&before, table, Some(old), None)?;
+            each.next.push(self.program.emit(Instruction::SeekRowid {
+                cursor,
+                src: old.1,
+                target: 0,
+            }));
+        }
+        self.index_entries(&indexes, &index_cursors, old, false);
+        self.foreign_keys(table, Some(old), None, &|_| true)?;
+        self.program.emit(Instruction::Delete { cursor });
+        self.referring_keys(table, Some(old), None, &|_| true)?;
+        self.fire(&after, table, Some(old), None)?;
+        self.end_each_row(each);
+        self.deleting.pop();
+        Ok(())
+    }
+
+    // The rowids of the rows an update or delete changes are gathered into a temporary tree
+    // before any of them is changed, so changing one can't change which others are found.
+    fn rows_where(
+        &mut self,
+        table: &TableDef,
+        filter: Option<&Expr>,
+        outer: Option<&Scope>,
+    ) -> Result<usize, StatementError> {
+        let rowids = self.program.allocate_cursor();
+        self.program
+            .emit(Instruction::OpenEphemeral { cursor: rowids });
+        self.add_rows_where(table, filter, outer, rowids)?;
+        Ok(rowids)
+    }
+
+    // adds the rowids of the rows the filter finds on to the end of the ones in `rowids`
+    fn add_rows_where(
+        &mut self,
+        table: &TableDef,
+        filter: Option<&Expr>,
+        outer: Option<&Scope>,
+        rowids: usize,
+    ) -> Result<(), StatementError> {
+        let select = Select {
+            with: None,
+            columns: vec![ResultColumn::Expr {
+                expr: Expr::Column {
+                    table: Some(table.name.clone()),
+                    name: "rowid".to_string(),
+                },
+                name: "rowid".to_string(),
+            }],
+            from: vec![TableRef {
+                name: table.name.clone(),
+                alias: None,
+                join: JoinKind::Inner,
+                on: None,
+                select: None,
+            }],
+            filter: filter.cloned(),
+            unions: Vec::new(),
+            limit: None,
+        };
+        self.simple_select(&select, outer, &mut |compiler, start, count| {
+            append(&mut compiler.program, rowids, start, count);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Starts a loop over the rows whose rowids `rows_where` gathered, moving the cursor to each
+    // one that is still there and reading its columns and rowid into the registers of `row`.
+    // The rowids are taken in the order they were added, by their place among them, so ones
+    // added on to the end while the loop runs are reached too.
+    fn each_row(
+        &mut self,
+        rowids: usize,
+        cursor: usize,
+        count: usize,
+        row: RowRegisters,
+    ) -> EachRow {
+        let program = &mut self.program;
+        let (place, one) = (program.allocate_registers(1), program.allocate_registers(1));
+        program.emit(Instruction::Integer {
+            value: 0,
+            dest: place,
+        });
+        program.emit(Instruction::Integer {
+            value: 1,
+            dest: one,
+        });
+        let top = program.emit(Instruction::Binary {
+            op: BinaryOp::Add,
+            left: place,
+            right: one,
+            dest: place,
+        });
+        let end = program.emit(Instruction::SeekRowid {
+            cursor: rowids,
+            src: place,
+            target: 0,
+        });
+        program.emit(Instruction::Column {
+            cursor: rowids,
+            column: 0,
+            dest: row.1,
+        });
+        let gone = program.emit(Instruction::SeekRowid {
+            cursor,
+            src: row.1,
+            target: 0,
+        });
+        for column in 0..count {
+            program.emit(Instruction::Column {
+                cursor,
+                column,
+                dest: row.0 + column,
+            });
+        }
+        EachRow {
+            top,
+            next: vec![gone],
+            end,
+        }
+    }
+
+    fn end_each_row(&mut self, each: EachRow) {
+        self.patch(each.next);
+        self.program.emit(Instruction::Goto { target: each.top });
+        self.patch(vec![each.end]);
+    }
+
+    // the triggers on the table that go off at `timing` on `event`, leaving out any that are
+    // already going off
+    fn triggers(
+        &self,
+        table: &TableDef,
+        timing: TriggerTiming,
+        event: TriggerEvent,
+    ) -> Vec<&'s TriggerDef> {
+        self.schema
+            .triggers_of(&table.name, timing, event)
+            .filter(|trigger| {
+                !self
+                    .firing
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&trigger.name))
+            })
+            .collect()
+    }
+
+    fn fire(
+        &mut self,
+        triggers: &[&TriggerDef],
+        table: &TableDef,
+        old: Option<RowRegisters>,
+        new: Option<RowRegisters>,
+    ) -> Result<(), StatementError> {
+        for trigger in triggers {
+            self.trigger(trigger, table, old, new)?;
+        }
+        Ok(())
+    }
+
+    // A trigger's statements are compiled in place, wherever the change that sets it off is.
+    // They read the row as it was and as it is going to be through `old` and `new`, which
+    // hold the values the registers have when the trigger goes off.
+    fn trigger(
+        &mut self,
+        trigger: &TriggerDef,
+        table: &TableDef,
+        old: Option<RowRegisters>,
+        new: Option<RowRegisters>,
+    ) -> Result<(), StatementError> {
+        let mut scope = Scope::default();
+        for (name, row) in [("old", old), ("new", new)] {
+            if let Some(row) = row {
+                let source = self.pseudo(name, table, row);
+                scope.sources.push(source);
+            }
+        }
+        self.firing.push(trigger.name.clone());
+        let compiled = self.trigger_body(trigger, &scope);
+        self.firing.pop();
+        compiled
+    }
+
+    fn trigger_body(&mut self, trigger: &TriggerDef, scope: &Scope) -> Result<(), StatementError> {
+        let mut skip = Pending::new();
+        if let Some(when) = &trigger.when {
+            let dest = self.program.allocate_registers(1);
+            self.expr(scope, when, dest)?;
+            skip.push(self.program.emit(Instruction::IfNot {
+                src: dest,
+                target: 0,
+            }));
+        }
+        for statement in &trigger.body {
+            match statement {
+                Statement::Insert(insert) => self.insert(insert, Some(scope))?,
+                Statement::Update(update) => self.update(update, Some(scope))?,
+                Statement::Delete(delete) => self.delete(delete, Some(scope))?,
+                // a query's rows go nowhere
+                Statement::Select(select) => {
+                    self.select(select, Some(scope), &mut |_, _, _| Ok(()))?;
+                }
+                _ => return Err(StatementError::Sql),
+            }
+        }
+        self.patch(skip);
+        Ok(())
+    }
+
+    fn create_table(&mut self, create: &CreateTable) -> Result<(), StatementError> {
+        if self.schema.has(&create.name) {
+            if create.if_not_exists {
+                return Ok(());
+            }
+            return Err(StatementError::AlreadyExists(create.name.clone()));
+        }
+        for (i, column) in create.columns.iter().enumerate() {
+            if create.columns[..i]
+                .iter()
+                .any(|before| before.name.eq_ignore_ascii_case(&column.name))
+            {
+                return Err(StatementError::DuplicateColumn(column.name.clone()));
+            }
+            if let Some(default) = &column.default {
+                if !constant(&default.value) {
+                    return Err(StatementError::NotConstant(column.name.clone()));
+                }
+            }
+        }
+        let table = TableDef::new(create, None);
+        Compiler::new(self.schema).check_table(&table)?;
+        for key in create
+            .columns
+            .iter()
+            .filter_map(|column| column.references.as_ref())
+        {
+            let mismatch = || StatementError::ForeignKeyMismatch {
+                table: create.name.clone(),
+                parent: key.table.clone(),
+            };
+            if !key.table.eq_ignore_ascii_case(&create.name) {
+                self.table(&key.table)?;
+                self.parent_key(&table, key)?;
+                continue;
+            }
+            // a table can refer to itself, through a key it is making now
+            let column = match &key.column {
+                Some(name) => table.column(name),
+                None => table.primary_key,
+            };
+            let column = column.ok_or_else(mismatch)?;
+            let spec = &create.columns[column];
+            if !(spec.unique || spec.primary_key) {
+                return Err(mismatch());
+            }
+        }
+
+        self.transaction(true);
+        let root = self.program.allocate_registers(1);
+        self.program.emit(Instruction::CreateBtree { dest: root });
+        self.schema_entry("table", &create.name, &create.name, root, &create.sql);
+        // a unique column, or a primary key that isn't the rowid, gets an index of its own
+        let unique = create.columns.iter().enumerate().filter(|(i, column)| {
+            column.unique || (column.primary_key && table.primary_key != Some(*i))
+        });
+        for (n, (_, column)) in unique.enumerate() {
+            let name = format!("autoindex_{}_{}", create.name, n + 1);
+            let sql = format!(
+                "create unique index \"{}\" on \"{}\" (\"{}\")",
+                name, create.name, column.name
+            );
+            let root = self.program.allocate_registers(1);
+            self.program.emit(Instruction::CreateBtree { dest: root });
+            self.schema_entry("index", &name, &create.name, root, &sql);
+        }
+        self.program.emit(Instruction::ChangeSchema);
+        Ok(())
+    }
+
+    // the checks have to make sense now, though they aren't run until a row goes in
+    fn check_table(&mut self, table: &TableDef) -> Result<(), StatementError> {
+        let count = table.columns.len();
+        let row = self.program.allocate_registers(count + 1);
+        self.constraints(table, &[], &[], (row, row + count))
+    }
+
+    // the query has to make sense now, though it isn't run until the view is read
+    fn check_view(
+        &mut self,
+        name: &str,
+        names: Option<&Vec<String>>,
+        select: &Select,
+    ) -> Result<(), StatementError> {
+        let columns = self.select(select, None, &mut |_, _, _| Ok(()))?;
+        derived_table(name, columns, names).map(|_| ())
+    }
+
+    // the statements have to make sense now, though they aren't run until the trigger goes off
+    fn check_trigger(
+        &mut self,
+        trigger: &TriggerDef,
+        table: &TableDef,
+    ) -> Result<(), StatementError> {
+        let count = table.columns.len();
+        let mut row = || {
+            let start = self.program.allocate_registers(count + 1);
+            (start, start + count)
+        };
+        let old = Some(row()).filter(|_| trigger.event != TriggerEvent::Insert);
+        let new = Some(row()).filter(|_| trigger.event != TriggerEvent::Delete);
+        self.trigger(trigger, table, old, new)
+    }
+
+    // Changes a table by rewriting the statements in the schema that mention it, which have to
+    // make sense together afterwards. The rows are only rewritten when a column's values have to
+    // go, or a new column's default isn't NULL.
+    fn alter_table(&mut self, alter: &AlterTable) -> Result<(), StatementError> {
+        let table = self.table(&alter.table)?;
+        // the users table wasn't made by a statement, so there is nothing to rewrite
+        if table.root.is_none() {
+            return Err(StatementError::CannotAlter(format!(
+                "table {} may not be altered",
+                table.name
+            )));
+        }
+        let create = match prepare_statement(&table.sql)?.0 {
+            Statement::CreateTable(create) => create,
+            _ => return Err(StatementError::Sql),
+        };
+        let columns: Vec<&str> = create.columns.iter().map(|c| c.sql.as_str()).collect();
+        let before = self.schema.entries();
+        let mut entries = before.clone();
+        let definition = table_entry(&entries, &table.name);
+        let own = |entry: &SchemaEntry| entry.table.eq_ignore_ascii_case(&table.name);
+        let mut rows = None;
+        match &alter.change {
+            TableChange::RenameTo(to) => {
+                if self.schema.has(to) {
+                    return Err(StatementError::AlreadyExists(to.clone()));
+                }
+                for entry in &mut entries {
+                    entry.sql = rename_table(&entry.sql, &table.name, to)?;
+                    if own(entry) {
+                        if entry.kind == "table" {
+                            entry.name = to.clone();
+                        }
+                        entry.table = to.clone();
+                    }
+                }
+            }
+            TableChange::RenameColumn { from, to } => {
+                let column = table
+                    .column(from)
+                    .ok_or_else(|| StatementError::NoSuchColumn(from.clone()))?;
+                if table.column(to).is_some_and(|other| other != column) {
+                    return Err(StatementError::DuplicateColumn(to.clone()));
+                }
+                // the table's own columns, the foreign keys referring to them and the views and
+                // triggers reading them have the new name
+                for entry in &mut entries {
+                    match prepare_statement(&entry.sql)?.0 {
+                        Statement::CreateTable(other) => {
+                            let mut changed = false;
+                            let mut columns = Vec::new();
+                            for column in &other.columns {
+                                let renamed =
+                                    rename_column(&column.sql, own(entry), &table.name, from, to)?;
+                                changed |= renamed != column.sql;
+                                columns.push(renamed);
+                            }
+                            if changed {
+                                entry.sql = table_sql(&entry.name, &columns);
+                            }
+                        }
+                        Statement::CreateView(_) | Statement::CreateTrigger(_) => {
+                            let others = |name: &str| {
+                                self.schema.table(name).is_some_and(|other| {
+                                    other.name != table.name && other.column(from).is_some()
+                                })
+                            };
+                            entry.sql =
+                                rename_column_in(&entry.sql, &table.name, from, to, &others)?;
+                        }
+                        Statement::CreateIndex(index) if own(entry) => {
+                            let columns: Vec<String> = index
+                                .columns
+                                .iter()
+                                .map(|c| match c.eq_ignore_ascii_case(from) {
+                                    true => to.clone(),
+                                    false => c.clone(),
+                                })
+                                .collect();
+                            entry.sql = index_sql(&index, &columns);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            TableChange::DropColumn(name) => {
+                let column = table
+                    .column(name)
+                    .ok_or_else(|| StatementError::NoSuchColumn(name.clone()))?;
+                let spec = &create.columns[column];
+                let cannot = |reason: &str| {
+                    Err(StatementError::CannotAlter(format!(
+                        "cannot drop {} column: \"{}\"",
+                        reason, spec.name
+                    )))
+                };
+                if spec.primary_key {
+                    return cannot("PRIMARY KEY");
+                }
+                if spec.unique {
+                    return cannot("UNIQUE");
+                }
+                if columns.len() == 1 {
+                    return cannot("the only");
+                }
+                let mut columns = columns.clone();
+                columns.remove(column);
+                entries[definition].sql = table_sql(&table.name, &columns);
+                rows = Some(RowChange::DropColumn(column));
+            }
+            TableChange::AddColumn(spec) => {
+                if table.column(&spec.name).is_some() {
+                    return Err(StatementError::DuplicateColumn(spec.name.clone()));
+                }
+                let default = spec.default.as_ref().map(|default| &default.value);
+                if default.is_some_and(|default| !constant(default)) {
+                    return Err(StatementError::NotConstant(spec.name.clone()));
+                }
+                let null = default.is_none_or(|default| *default == Expr::Literal(Value::Null));
+                let cannot = |reason: &str| {
+                    Err(StatementError::CannotAlter(format!(
+                        "cannot add {}",
+                        reason
+                    )))
+                };
+                if spec.primary_key {
+                    return cannot("a PRIMARY KEY column");
+                }
+                if spec.unique {
+                    return cannot("a UNIQUE column");
+                }
+                if spec.not_null && null {
+                    return cannot("a NOT NULL column with default value NULL");
+                }
+                if spec.references.is_some() && !null {
+                    return cannot("a REFERENCES column with non-NULL default value");
+                }
+                let mut columns = columns.clone();
+                columns.push(&spec.sql);
+                entries[definition].sql = table_sql(&table.name, &columns);
+                rows = default.filter(|_| !null).cloned().map(RowChange::AddColumn);
+            }
+        }
+        let schema = Schema::from_entries(self.schema.version, &entries)?;
+        check_schema(&schema)?;
+
+        self.transaction(true);
+        for (old, new) in before.iter().zip(&entries) {
+            if old != new {
+                self.replace_entry(old, new);
+            }
+        }
+        if let Some(change) = rows {
+            let altered = schema.table(&table.name).expect("altered table is gone");
+            let rowids = self.rows_where(table, None, None)?;
+            let (cursor, _) = self.open_write(table, &[]);
+            let count = table.columns.len();
+            let start = self.program.allocate_registers(count + 2);
+            let row = (start, start + count + 1);
+            let each = self.each_row(rowids, cursor, count, row);
+            match change {
+                RowChange::DropColumn(column) => {
+                    for i in column + 1..count {
+                        self.program.emit(Instruction::Copy {
+                            src: start + i,
+                            dest: start + i - 1,
+                        });
+                    }
+                }
+                // the new column's values are checked the way an insert's would be
+                RowChange::AddColumn(default) => {
+                    self.expr(&Scope::default(), &default, start + count)?;
+                    self.constraints(altered, &[], &[], row)?;
+                }
+            }
+            self.program.emit(Instruction::Insert {
+                cursor,
+                start,
+                count: altered.columns.len(),
+                rowid: Some(row.1),
+            });
+            self.end_each_row(each);
+        }
+        self.program.emit(Instruction::ChangeSchema);
+        Ok(())
+    }
+
+    // adds a row describing a new table or index, with its root page in a register, to the schema
+    fn schema_entry(&mut self, kind: &str, name: &str, table: &str, root: usize, sql: &str) {
+        let program = &mut self.program;
+        let schema_cursor = program.allocate_cursor();
+        program.emit(Instruction::OpenWrite {
+            cursor: schema_cursor,
+            tree: Tree::Schema,
+        });
+        let entry = program.allocate_registers(5);
+        program.load(&Value::Text(kind.into()), entry);
+        program.load(&Value::Text(name.into()), entry + 1);
+        program.load(&Value::Text(table.into()), entry + 2);
+        program.emit(Instruction::Copy {
+            src: root,
+            dest: entry + 3,
+        });
+        program.load(&Value::Text(sql.into()), entry + 4);
+        let rowid = program.allocate_registers(1);
+        program.emit(Instruction::NewRowid {
+            cursor: schema_cursor,
+            dest: rowid,
+        });
+        program.emit(Instruction::Insert {
+            cursor: schema_cursor,
+            start: entry,
+            count: 5,
+            rowid: Some(rowid),
+        });
+    }
+
+    fn create_view(&mut self, create: &CreateView) -> Result<(), StatementError> {
+        if self.schema.has(&create.name) {
+            if create.if_not_exists {
+                return Ok(());
+            }
+            return Err(StatementError::AlreadyExists(create.name.clone()));
+        }
+        Compiler::new(self.schema).check_view(
+            &create.name,
+            create.columns.as_ref(),
+            &create.select,
+        )?;
+
+        self.transaction(true);
+        let root = self.program.allocate_registers(1);
+        self.program.emit(Instruction::Integer {
+            value: 0,
+            dest: root,
+        });
+        self.schema_entry("view", &create.name, &create.name, root, &create.sql);
+        self.program.emit(Instruction::ChangeSchema);
+        Ok(())
+    }
+
+    fn drop_view(&mut self, name: &str, if_exists: bool) -> Result<(), StatementError> {
+        match self.schema.view(name) {
+            Some(view) => self.drop_entry("view", &view.name),
+            None if if_exists => Ok(()),
+            None => Err(StatementError::NoSuchView(name.to_string())),
+        }
+    }
+
+    fn create_trigger(&mut self, create: &CreateTrigger) -> Result<(), StatementError> {
+        if self.schema.trigger(&create.name).is_some() {
+            if create.if_not_exists {
+                return Ok(());
+            }
+            return Err(StatementError::AlreadyExists(create.name.clone()));
+        }
+        let table = self.table(&create.table)?;
+        let trigger = TriggerDef {
+            name: create.name.clone(),
+            table: table.name.clone(),
+            timing: create.timing,
+            event: create.event,
+            when: create.when.clone(),
+            body: create.body.clone(),
+            sql: create.sql.clone(),
+        };
+        Compiler::new(self.schema).check_trigger(&trigger, table)?;
+
+        self.transaction(true);
+        let root = self.program.allocate_registers(1);
+        self.program.emit(Instruction::Integer {
+            value: 0,
+            dest: root,
+        });
+        self.schema_entry("trigger", &create.name, &table.name, root, &create.sql);
+        self.program.emit(Instruction::ChangeSchema);
+        Ok(())
+    }
+
+    fn drop_trigger(&mut self, name: &str, if_exists: bool) -> Result<(), StatementError> {
+        match self.schema.trigger(name) {
+            Some(trigger) => self.drop_entry("trigger", &trigger.name),
+            None if if_exists => Ok(()),
+            None => Err(StatementError::NoSuchTrigger(name.to_string())),
+        }
+    }
+
+    // takes the row of the view or trigger with the name out of the schema
+    fn drop_entry(&mut self, kind: &str, name: &str) -> Result<(), StatementError> {
+        self.transaction(true);
+        let found = self.find_entry(kind, name);
+        self.program.emit(Instruction::Delete {
+            cursor: found.cursor,
+        });
+        self.end_find_entry(found);
+        self.program.emit(Instruction::ChangeSchema);
+        Ok(())
+    }
+
+    // writes the entry over the row of the schema that was `old`, keeping its rowid
+    fn replace_entry(&mut self, old: &SchemaEntry, new: &SchemaEntry) {
+        let found = self.find_entry(old.kind, &old.name);
+        let program = &mut self.program;
+        let entry = program.allocate_registers(6);
+        program.load(&Value::Text(new.kind.into()), entry);
+        program.load(&Value::Text(new.name.clone()), entry + 1);
+        program.load(&Value::Text(new.table.clone()), entry + 2);
+        program.load(&Value::Integer(new.root as i64), entry + 3);
+        program.load(&Value::Text(new.sql.clone()), entry + 4);
+        program.emit(Instruction::Rowid {
+            cursor: found.cursor,
+            dest: entry + 5,
+        });
+        program.emit(Instruction::Insert {
+            cursor: found.cursor,
+            start: entry,
+            count: 5,
+            rowid: Some(entry + 5),
+        });
+        self.end_find_entry(found);
+    }
+
+    // Starts a scan of the schema that stops at the row of the kind with the name. What comes
+    // after runs with the cursor on that row, and `end_find_entry` ends the scan.
+    fn find_entry(&mut self, kind: &str, name: &str) -> FoundEntry {
+        let program = &mut self.program;
+        let cursor = program.allocate_cursor();
+        program.emit(Instruction::OpenWrite {
+            cursor,
+            tree: Tree::Schema,
+        });
+        let registers = program.allocate_registers(3);
+        let (entry, wanted, found) = (registers, registers + 1, registers + 2);
+        let rewind = program.emit(Instruction::Rewind { cursor, target: 0 });
+        let top = program.next_address();
+        let mut next = Pending::new();
+        for (column, value) in [(0, kind), (1, name)] {
+            program.load(&Value::Text(value.to_string()), wanted);
+            program.emit(Instruction::Column {
+                cursor,
+                column,
+                dest: entry,
+            });
+            program.emit(Instruction::Binary {
+                op: BinaryOp::Eq,
+                left: entry,
+                right: wanted,
+                dest: found,
+            });
+            next.push(program.emit(Instruction::IfNot {
+                src: found,
+                target: 0,
+            }));
+        }
+        FoundEntry {
+            cursor,
+            top,
+            next,
+            end: vec![rewind],
+        }
+    }
+
+    fn end_find_entry(&mut self, mut found: FoundEntry) {
+        found
+            .end
+            .push(self.program.emit(Instruction::Goto { target: 0 }));
+        self.patch(found.next);
+        self.program.emit(Instruction::Next {
+            cursor: found.cursor,
+            target: found.top,
+        });
+        self.patch(found.end);
+    }
+
+    fn create_index(&mut self, create: &CreateIndex) -> Result<(), StatementError> {
+        let table = self.table(&create.table)?;
+        if self.schema.has(&create.name) {
+            if create.if_not_exists {
+                return Ok(());
+            }
+            return Err(StatementError::AlreadyExists(create.name.clone()));
+        }
+        let columns = create
+            .columns
+            .iter()
+            .map(|name| {
+                table
+                    .column(name)
+                    .ok_or_else(|| StatementError::NoSuchColumn(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.transaction(true);
+        let root = self.program.allocate_registers(1);
+        self.program.emit(Instruction::CreateBtree { dest: root });
+        self.schema_entry("index", &create.name, &table.name, root, &create.sql);
+
+        // every row already in the table gets its entry, as long as a unique index stays unique
+        let program = &mut self.program;
+        let cursor = program.allocate_cursor();
+        program.emit(Instruction::OpenRead {
+            cursor,
+            tree: tree(table),
+        });
+        let index_cursor = program.allocate_cursor();
+        program.emit(Instruction::OpenWrite {
+            cursor: index_cursor,
+            tree: Tree::NewIndex(root),
+        });
+        let key = program.allocate_registers(columns.len() + 1);
+        let rewind = program.emit(Instruction::Rewind { cursor, target: 0 });
+        let top = program.next_address();
+        for (i, column) in columns.iter().enumerate() {
+            program.emit(Instruction::Column {
+                cursor,
+                column: *column,
+                dest: key + i,
+            });
+        }
+        program.emit(Instruction::Rowid {
+            cursor,
+            dest: key + columns.len(),
+        });
+        if create.unique {
+            self.unique(table, &columns, index_cursor, key);
+        }
+        let program = &mut self.program;
+        program.emit(Instruction::IdxInsert {
+            cursor: index_cursor,
+            start: key,
+            count: columns.len() + 1,
+        });
+        program.emit(Instruction::Next {
+            cursor,
+            target: top,
+        });
+        let end = program.next_address();
+        program.set_target(rewind, end);
+        program.emit(Instruction::ChangeSchema);
+        Ok(())
+    }
+
+    fn analyze(&mut self, table: Option<&str>) -> Result<(), StatementError> {
+        let tables = match table {
+            Some(name) => vec![self.table(name)?],
+            None => self.schema.tables.iter().collect(),
+        };
+        self.transaction(true);
+        let program = &mut self.program;
+        let stats = program.allocate_cursor();
+        program.emit(Instruction::OpenWrite {
+            cursor: stats,
+            tree: Tree::Stats,
+        });
+        for table in tables {
+            let cursor = program.allocate_cursor();
+            program.emit(Instruction::OpenRead {
+                cursor,
+                tree: tree(table),
+            });
+            let sample = program.allocate_cursor();
+            program.emit(Instruction::OpenSample { cursor: sample });
+            let count = table.columns.len();
+            let start = program.allocate_registers(count);
+            let rewind = program.emit(Instruction::Rewind { cursor, target: 0 });
+            let top = program.next_address();
+            for column in 0..count {
+                program.emit(Instruction::Column {
+                    cursor,
+                    column,
+                    dest: start + column,
+                });
+            }
+            program.emit(Instruction::SampleRow {
+                cursor: sample,
+                start,
+                count,
+            });
+            program.emit(Instruction::Next {
+                cursor,
+                target: top,
+            });
+            let end = program.next_address();
+            program.set_target(rewind, end);
+            program.emit(Instruction::SaveStats {
+                cursor: sample,
+                stats,
+                table: table.name.clone(),
+                columns: table
+                    .columns
+                    .iter()
+                    .map(|column| column.name.clone())
+                    .collect(),
+                indexes: self
+                    .schema
+                    .indexes_of(&table.name)
+                    .map(|index| (index.name.clone(), index.columns.clone()))
+                    .collect(),
+            });
+        }
+        // statements are planned again with the new statistics
+        program.emit(Instruction::ChangeSchema);
+        Ok(())
+    }
+
+    // works out the value of an expression into the `dest` register
+    fn expr(&mut self, scope: &Scope, expr: &Expr, dest: usize) -> Result<(), StatementError> {
+        match expr {
+            Expr::Literal(value) => {
+                self.program.load(value, dest);
+            }
+            Expr::Param(param) => {
+                self.program.emit(Instruction::Variable {
+                    param: *param,
+                    dest,
+                });
+            }
+            Expr::Column { table, name } => {
+                let found = scope.resolve(table.as_deref(), name)?;
+                let source = &found.scope.sources[found.source];
+                let instruction = match (source.covering, found.column) {
+                    (Some((cursor, index)), Some(column)) => Instruction::Column {
+                        cursor,
+                        column: index.columns.iter().position(|c| *c == column).unwrap(),
+                        dest,
+                    },
+                    (Some((cursor, _)), None) => Instruction::Rowid { cursor, dest },
+                    (None, Some(column)) => Instruction::Column {
+                        cursor: source.cursor,
+                        column,
+                        dest,
+                    },
+                    (None, None) => Instruction::Rowid {
+                        cursor: source.cursor,
+                        dest,
+                    },
+                };
+                self.program.emit(instruction);
+            }
+            Expr::Unary { op, expr } => {
+                let src = self.program.allocate_registers(1);
+                self.expr(scope, expr, src)?;
+                self.program.emit(Instruction::Unary { op: *op, src, dest });
+            }
+            Expr::Binary { op, left, right } => {
+                let operands = self.program.allocate_registers(2);
+                self.expr(scope, left, operands)?;
+                self.expr(scope, right, operands + 1)?;
+                self.program.emit(Instruction::Binary {
+                    op: *op,
+                    left: operands,
+                    right: operands + 1,
+                    dest,
+                });
+            }
+            Expr::Function { name, args } => {
+                let name = name.to_ascii_lowercase();
+                let arity_ok = match &name[..] {
+                    "abs" | "length" | "lower" | "upper" | "typeof" => args.len() == 1,
+                    "ifnull" => args.len() == 2,
+                    "coalesce" => args.len() >= 2,
+                    _ => return Err(StatementError::NoSuchFunction(name)),
+                };
+                if !arity_ok {
+                    return Err(StatementError::Sql);
+                }
+                let start = self.program.allocate_registers(args.len());
+                for (i, arg) in args.iter().enumerate() {
+                    self.expr(scope, arg, start + i)?;
+                }
+                self.program.emit(Instruction::Function {
+                    name,
+                    start,
+                    count: args.len(),
+                    dest,
+                });
+            }
+            Expr::Subquery(_) | Expr::In { .. } | Expr::Exists(_) => {
+                self.subquery(scope, expr, dest)?;
+            }
+        }
+        Ok(())
+    }
+
+    // a bit for every source of the scope the expression reads, itself or through a subquery
+    fn sources_of(&self, scope: &Scope, expr: &Expr) -> Result<u64, StatementError> {
+        let mut columns = Vec::new();
+        let mut subqueries = Vec::new();
+        expr.walk(&mut |expr| match expr {
+            Expr::Column { table, name } => columns.push((table.as_deref(), name)),
+            Expr::Subquery(select) | Expr::In { select, .. } | Expr::Exists(select) => {
+                subqueries.push(select)
+            }
+            _ => {}
+        });
+        let mut sources = 0;
+        for (table, name) in columns {
+            let found = scope.resolve(table, name)?;
+            if found.depth == 0 {
+                sources |= 1 << found.source;
+            }
+        }
+        for select in subqueries {
+            sources |= self.correlation(scope, select)?.0;
+        }
+        Ok(sources)
+    }
+
+    fn term<'e>(&self, scope: &Scope, expr: &'e Expr) -> Result<Term<'e>, StatementError> {
+        let sources = self.sources_of(scope, expr)?;
+        let mut comparison = None;
+        if let Expr::Binary { op, left, right } = expr {
+            if let Some(reversed) = op.reversed() {
+                for (column, value, op) in [(left, right, *op), (right, left, reversed)] {
+                    if let Expr::Column { table, name } = &**column {
+                        let found = scope.resolve(table.as_deref(), name)?;
+                        // a column of the query around this one is as good as a constant
+                        if found.depth > 0 {
+                            continue;
+                        }
+                        let value_sources = self.sources_of(scope, value)?;
+                        if value_sources & (1 << found.source) == 0 {
+                            comparison = Some(Comparison {
+                                source: found.source,
+                                column: found.column,
+                                op,
+                                value,
+                                value_sources,
+                            });
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Term {
+            expr,
+            sources,
+            comparison,
+            on: None,
+        })
+    }
+}
+
+impl<'a> Scope<'a> {
+    // finds the scope, source and column a name refers to, looking outwards from this one
+    fn resolve(&self, table: Option<&str>, name: &str) -> Result<Resolved<'_, 'a>, StatementError> {
+        let mut scope = self;
+        let mut depth = 0;
+        loop {
+            if let Some((source, column)) = scope.find(table, name)? {
+                scope.referenced.set(scope.referenced.get() | 1 << source);
+                return Ok(Resolved {
+                    scope,
+                    depth,
+                    source,
+                    column,
+                });
+            }
+            scope = scope
+                .outer
+                .ok_or_else(|| StatementError::NoSuchColumn(qualified(table, name)))?;
+            depth += 1;
+        }
+    }
+
+    // the source and column a name refers to among this scope's own tables
+    fn find(
+        &self,
+        table: Option<&str>,
+        name: &str,
+    ) -> Result<Option<(usize, Option<usize>)>, StatementError> {
+        let mut found = None;
+        for (i, source) in self.sources.iter().enumerate() {
+            if table.is_some_and(|table| !table.eq_ignore_ascii_case(&source.name)) {
+                continue;
+            }
+            let column = match source.table.column(name) {
+                Some(column) if source.table.primary_key == Some(column) => None,
+                Some(column) => Some(column),
+                // the rows of a table made from a subquery have no rowid
+                None if name.eq_ignore_ascii_case("rowid") && source.derived.is_none() => None,
+                None => continue,
+            };
+            if found.is_some() {
+                return Err(StatementError::AmbiguousColumn(qualified(table, name)));
+            }
+            found = Some((i, column));
+        }
+        Ok(found)
+    }
+
+    // adds the columns the expression reads to what is read from each source
+    fn columns_read(
+        &self,
+        expr: &Expr,
+        read: &mut [Option<Vec<usize>>],
+    ) -> Result<(), StatementError> {
+        let mut columns = Vec::new();
+        let mut subquery = false;
+        expr.walk(&mut |expr| match expr {
+            Expr::Column { table, name } => columns.push((table.as_deref(), name)),
+            Expr::Subquery(_) | Expr::In { .. } | Expr::Exists(_) => subquery = true,
+            _ => {}
+        });
+        // a subquery could read any column of any of the tables
+        if subquery {
+            read.iter_mut().for_each(|read| *read = None);
+        }
+        for (table, name) in columns {
+            let found = self.resolve(table, name)?;
+            if let (0, Some(column)) = (found.depth, found.column) {
+                if let Some(read) = &mut read[found.source] {
+                    read.push(column);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn qualified(table: Option<&str>, name: &str) -> String {
+    match table {
+        Some(table) => format!("{}.{}", table, name),
+        None => name.to_string(),
+    }
+}
+
+// fails the statement, naming the constraint and the columns it is on
+// the error naming the columns, and the constraint too if CONSTRAINT gave it a name
+fn constraint_failed(
+    kind: &str,
+    name: Option<&str>,
+    table: &TableDef,
+    columns: &[usize],
+) -> Instruction {
+    let columns: Vec<String> = columns
+        .iter()
+        .map(|column| format!("{}.{}", table.name, table.columns[*column].name))
+        .collect();
+    let columns = columns.join(", ");
+    Instruction::Constraint {
+        message: match name {
+            Some(name) => format!("{} constraint failed: {} ({})", kind, name, columns),
+            None => format!("{} constraint failed: {}", kind, columns),
+        },
+    }
+}
+
+// `select 1 from table where table.column = row.value`, which finds the rows of the table with
+// the value a row in some other scope has
+fn rows_with(table: &TableDef, column: usize, row: &str, value: &str) -> Select {
+    Select {
+        with: None,
+        columns: vec![ResultColumn::Expr {
+            expr: Expr::Literal(Value::Integer(1)),
+            name: "1".to_string(),
+        }],
+        from: vec![TableRef {
+            name: table.name.clone(),
+            alias: None,
+            join: JoinKind::Inner,
+            on: None,
+            select: None,
+        }],
+        filter: Some(Expr::Binary {
+            op: BinaryOp::Eq,
+            left: Box::new(Expr::Column {
+                table: Some(table.name.clone()),
+                name: table.columns[column].name.clone(),
+            }),
+            right: Box::new(Expr::Column {
+                table: Some(row.to_string()),
+                name: value.to_string(),
+            }),
+        }),
+        unions: Vec::new(),
+        limit: None,
+    }
+}
+
+// whether the expression has the same value wherever it is worked out
+fn constant(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(_) => true,
+        Expr::Unary { expr, .. } => constant(expr),
+        Expr::Binary { left, right, .. } => constant(left) && constant(right),
+        Expr::Function { args, .. } => args.iter().all(constant),
+        _ => false,
+    }
+}
+
+// where a table's rows are kept
+fn tree(table: &TableDef) -> Tree {
+    match table.root {
+        Some(root) => Tree::Table(root),
+        None => Tree::Rows,
+    }
+}
+
+// adds the row in `count` registers from `start` to the end of a temporary tree
+fn append(program: &mut Program, cursor: usize, start: usize, count: usize) {
+    let rowid = program.allocate_registers(1);
+    program.emit(Instruction::NewRowid {
+        cursor,
+        dest: rowid,
+    });
+    program.emit(Instruction::Insert {
+        cursor,
+        start,
+        count,
+        rowid: Some(rowid),
+    });
+}
+
+// Counts a row against the ones a LIMIT has left, returning a jump to be pointed past the query
+// for when there are none. A negative limit never runs out.
+fn take_row(program: &mut Program, left: usize) -> usize {
+    let (constant, test) = (program.allocate_registers(1), program.allocate_registers(1));
+    program.emit(Instruction::Integer {
+        value: 0,
+        dest: constant,
+    });
+    program.emit(Instruction::Binary {
+        op: BinaryOp::Eq,
+        left,
+        right: constant,
+        dest: test,
+    });
+    let stop = program.emit(Instruction::If {
+        src: test,
+        target: 0,
+    });
+    program.emit(Instruction::Integer {
+        value: 1,
+        dest: constant,
+    });
+    program.emit(Instruction::Binary {
+        op: BinaryOp::Subtract,
+        left,
+        right: constant,
+        dest: left,
+    });
+    stop
+}
+
+// Adds the row to an index of the rows seen so far, unless it's already there. Returns a jump
+// to be pointed past whatever is done with rows that are new.
+fn distinct_row(program: &mut Program, seen: usize, start: usize, count: usize) -> usize {
+    let new = program.emit(Instruction::SeekGE {
+        cursor: seen,
+        start,
+        count,
+        target: 0,
+    });
+    let different = program.emit(Instruction::IdxGT {
+        cursor: seen,
+        start,
+        count,
+        target: 0,
+    });
+    let old = program.emit(Instruction::Goto { target: 0 });
+    let insert = program.emit(Instruction::IdxInsert {
+        cursor: seen,
+        start,
+        count,
+    });
+    program.set_target(new, insert);
+    program.set_target(different, insert);
+    old
+}
+
+// where the table is among the schema's entries
+fn table_entry(entries: &[SchemaEntry], name: &str) -> usize {
+    entries
+        .iter()
+        .position(|entry| entry.kind == "table" && entry.name.eq_ignore_ascii_case(name))
+        .expect("table has no entry")
+}
+
+// the statement that makes a table with columns defined the way they are written
+fn table_sql<S: AsRef<str>>(name: &str, columns: &[S]) -> String {
+    let columns: Vec<&str> = columns.iter().map(|column| column.as_ref()).collect();
+    format!("create table {} ({})", quote(name), columns.join(", "))
+}
+
+fn index_sql(index: &CreateIndex, columns: &[String]) -> String {
+    let columns: Vec<String> = columns.iter().map(|column| quote(column)).collect();
+    format!(
+        "create {}index {} on {} ({})",
+        if index.unique { "unique " } else { "" },
+        quote(&index.name),
+        quote(&index.table),
+        columns.join(", ")
+    )
+}
+
+// a table made from a query's rows, with the names given for its columns if there are any
+fn derived_table(
+    name: &str,
+    columns: Vec<String>,
+    names: Option<&Vec<String>>,
+) -> Result<TableDef, StatementError> {
+    let columns = match names {
+        Some(names) if names.len() != columns.len() => {
+            return Err(StatementError::TableColumns {
+                table: name.to_string(),
+                values: columns.len(),
+                columns: names.len(),
+            })
+        }
+        Some(names) => names.clone(),
+        None => columns,
+    };
+    Ok(TableDef {
+        name: name.to_string(),
+        columns: columns
+            .into_iter()
+            .map(|name| ColumnDef {
+                name,
+                data_type: String::new(),
+                not_null: false,
+                check: None,
+                check_name: None,
+                default: None,
+                references: None,
+                stats: None,
+            })
+            .collect(),
+        rows: DEFAULT_ROWS,
+        root: None,
+        primary_key: None,
+        sql: String::new(),
+    })
+}
+
+// returns every row the cursor reaches, with as many columns as the program says it produces
+fn scan(program: &mut Program, cursor: usize) {
+    let count = program.columns.len();
+    let start = program.allocate_registers(count);
+    let rewind = program.emit(Instruction::Rewind { cursor, target: 0 });
+    let top = program.next_address();
+    for column in 0..count {
+        program.emit(Instruction::Column {
+            cursor,
+            column,
+            dest: start + column,
+        });
+    }
+    program.emit(Instruction::ResultRow { start, count });
+    program.emit(Instruction::Next {
+        cursor,
+        target: top,
+    });
+    let end = program.next_address();
+    program.set_target(rewind, end);
+}
+
+// returns rows that are already known when the statement is compiled
+fn output(program: &mut Program, columns: &[&str], rows: Vec<Vec<Value>>) {
+    program.columns = columns.iter().map(|column| column.to_string()).collect();
+    let start = program.allocate_registers(columns.len());
+    for row in rows {
+        for (i, value) in row.iter().enumerate() {
+            program.load(value, start + i);
+        }
+        program.emit(Instruction::ResultRow {
+            start,
+            count: columns.len(),
+        });
+    }
+}
+
+// setting a pragma reports nothing, except for the ones that report what they were set to
+fn pragma_columns(name: &str, setting: bool) -> Vec<String> {
+    let name = name.to_ascii_lowercase();
+    let columns: &[&str] = match (&name[..], setting) {
+        ("table_info", _) => &["cid", "name", "type", "notnull", "dflt_value", "pk"],
+        ("journal_mode", _) => &["journal_mode"],
+        ("stats", _) => &[
+            "table",
+            "name",
+            "rows",
+            "distinct",
+            "null_fraction",
+            "histogram",
+        ],
+        (_, true) => &[],
+        (name, false) => return vec![name.to_string()],
+    };
+    columns.iter().map(|column| column.to_string()).collect()
+}
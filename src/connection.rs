@@ -0,0 +1,334 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::ast::Statement;
+use crate::compiler::compile;
+use crate::database::Database;
+use crate::error::Error;
+use crate::mapping::{FromRow, FromValue, RowError, ToParams};
+use crate::pager::PagerOptions;
+use crate::parser::{prepare_statement, StatementError};
+use crate::program::Program;
+use crate::value::Value;
+use crate::virtual_machine::{VMErr, VirtualMachine};
+
+// The way into the engine for anybody embedding it. A connection can be cloned and handed to
+// other threads; the clones all share the same open database.
+#[derive(Clone)]
+pub struct Connection {
+    database: Database,
+}
+
+// A statement parsed once and run as many times as needed, with new parameter values each time.
+// Parameters are numbered from 1.
+pub struct PreparedStatement<'c> {
+    connection: &'c Connection,
+    statement: Statement,
+    // shared with every run of the program, which can outlive the statement; compiled again
+    // whenever the schema it was compiled against changes
+    program: Mutex<Arc<Program>>,
+    // the name of each parameter, for those that have one
+    names: Vec<Option<String>>,
+    params: Vec<Option<Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    // shared by every row of a result
+    columns: Arc<[String]>,
+    values: Vec<Value>,
+}
+
+// Rows are produced as they are asked for; a query's snapshot of the database stays open
+// until its rows are dropped.
+pub struct Rows<'c> {
+    columns: Arc<[String]>,
+    // None for a statement that returns nothing
+    machine: Option<Box<VirtualMachine<'c>>>,
+}
+
+// rows turned into the caller's own type as they are read
+pub struct RowsAs<'c, T> {
+    rows: Rows<'c>,
+    row_type: PhantomData<T>,
+}
+
+// what running a statement produced; statements that don't return rows just succeed
+#[derive(Debug)]
+pub enum Outcome<'c> {
+    Rows(Rows<'c>),
+    Success,
+}
+
+impl Connection {
+    pub fn open<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Connection::open_with(path, PagerOptions::default())
+    }
+
+    pub fn open_with<P>(path: P, options: PagerOptions) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let database = Database::open_with(path, options).map_err(Error::Open)?;
+        Ok(Connection { database })
+    }
+
+    // parses and compiles a statement without running it
+    pub fn prepare(&self, sql: &str) -> Result<PreparedStatement<'_>, Error> {
+        let (statement, names) = prepare_statement(sql).map_err(Error::Statement)?;
+        Ok(PreparedStatement {
+            connection: self,
+            program: Mutex::new(self.compile(&statement, false)?),
+            params: vec![None; names.len()],
+            statement,
+            names,
+        })
+    }
+
+    // A statement that doesn't compile against the schema the connection knows about may
+    // be referring to something another connection has just made, so it gets another go with
+    // the latest schema.
+    fn compile(&self, statement: &Statement, reload: bool) -> Result<Arc<Program>, Error> {
+        let schema = if reload {
+            self.database.reload_schema()
+        } else {
+            self.database.schema()
+        }
+        .map_err(|e| Error::Execute(VMErr::Table(e)))?;
+        match compile(statement, &schema) {
+            Ok(program) => Ok(Arc::new(program)),
+            Err(e) if !reload => match self.compile(statement, true) {
+                Err(Error::Execute(_)) => Err(Error::Statement(e)),
+                outcome => outcome,
+            },
+            Err(e) => Err(Error::Statement(e)),
+        }
+    }
+
+    // a program that doesn't produce rows has finished by the time it returns
+    fn run(&self, program: &Arc<Program>, params: Vec<Value>) -> Result<Outcome<'_>, Error> {
+        let machine = self
+            .database
+            .execute(program.clone(), params)
+            .map_err(Error::Execute)?;
+        if program.changes_schema() {
+            self.database.forget_schema();
+        }
+        if program.columns.is_empty() {
+            return Ok(Outcome::Success);
+        }
+        Ok(Outcome::Rows(Rows {
+            columns: program.columns.clone().into(),
+            machine: Some(Box::new(machine)),
+        }))
+    }
+
+    // runs a statement for its effect, throwing away any rows it returns
+    pub fn execute(&self, sql: &str) -> Result<(), Error> {
+        self.prepare(sql)?.execute().map(drop)
+    }
+
+    pub fn execute_with<P>(&self, sql: &str, params: &P) -> Result<(), Error>
+    where
+        P: ToParams + ?Sized,
+    {
+        self.prepare(sql)?.bind_params(params)?.execute().map(drop)
+    }
+
+    pub fn query(&self, sql: &str) -> Result<Rows<'_>, Error> {
+        self.prepare(sql)?.query()
+    }
+
+    pub fn query_as<T>(&self, sql: &str) -> Result<RowsAs<'_, T>, Error>
+    where
+        T: FromRow,
+    {
+        self.query(sql).map(Rows::mapped)
+    }
+
+    // only closing the last connection to a database closes it, and only that can fail
+    pub fn close(self) -> Result<(), Error> {
+        self.database.close().map_err(Error::Close)
+    }
+}
+
+impl<'c> PreparedStatement<'c> {
+    pub fn statement(&self) -> &Statement {
+        &self.statement
+    }
+
+    pub fn parameter_count(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn bind<V>(&mut self, index: usize, value: V) -> Result<&mut Self, Error>
+    where
+        V: Into<Value>,
+    {
+        match index.checked_sub(1).and_then(|i| self.params.get_mut(i)) {
+            Some(param) => {
+                *param = Some(value.into());
+                Ok(self)
+            }
+            None => Err(Error::Statement(StatementError::UnknownParameter)),
+        }
+    }
+
+    // the name can be given with or without its leading colon
+    pub fn bind_named<V>(&mut self, name: &str, value: V) -> Result<&mut Self, Error>
+    where
+        V: Into<Value>,
+    {
+        let name = name.strip_prefix(':').unwrap_or(name);
+        match self
+            .names
+            .iter()
+            .position(|param| param.as_deref() == Some(name))
+        {
+            Some(index) => self.bind(index + 1, value),
+            None => Err(Error::Statement(StatementError::UnknownParameter)),
+        }
+    }
+
+    // a value with a name goes to the parameter of that name if there is one,
+    // anything else to the parameter at its position
+    pub fn bind_params<P>(&mut self, params: &P) -> Result<&mut Self, Error>
+    where
+        P: ToParams + ?Sized,
+    {
+        for (index, (name, value)) in params.to_params().into_iter().enumerate() {
+            match name {
+                Some(name) if self.names.iter().any(|n| n.as_deref() == Some(name)) => {
+                    self.bind_named(name, value)?
+                }
+                _ => self.bind(index + 1, value)?,
+            };
+        }
+        Ok(self)
+    }
+
+    pub fn clear_bindings(&mut self) {
+        self.params.iter_mut().for_each(|param| *param = None);
+    }
+
+    pub fn execute(&self) -> Result<Outcome<'c>, Error> {
+        let mut program = self.program.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let params = program.bind(&self.params).map_err(Error::Statement)?;
+            match self.connection.run(&program, params) {
+                // another statement changed the schema since this one was compiled
+                Err(Error::Execute(VMErr::SchemaChanged)) => {
+                    *program = self.connection.compile(&self.statement, true)?;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    // like execute, but a statement that returns nothing gives back no rows
+    pub fn query(&self) -> Result<Rows<'c>, Error> {
+        match self.execute()? {
+            Outcome::Rows(rows) => Ok(rows),
+            Outcome::Success => Ok(Rows {
+                columns: Vec::new().into(),
+                machine: None,
+            }),
+        }
+    }
+
+    pub fn query_as<T>(&self) -> Result<RowsAs<'c, T>, Error>
+    where
+        T: FromRow,
+    {
+        self.query().map(Rows::mapped)
+    }
+}
+
+impl Row {
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(index)
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn get_as<T>(&self, index: usize) -> Result<T, Error>
+    where
+        T: FromValue,
+    {
+        let column = || match self.columns.get(index) {
+            Some(name) => name.clone(),
+            None => index.to_string(),
+        };
+        match self.values.get(index) {
+            Some(value) => {
+                T::from_value(value).ok_or_else(|| Error::Row(RowError::WrongType(column())))
+            }
+            None => Err(Error::Row(RowError::NoSuchColumn(column()))),
+        }
+    }
+
+    pub fn get_named<T>(&self, name: &str) -> Result<T, Error>
+    where
+        T: FromValue,
+    {
+        match self.columns.iter().position(|column| column == name) {
+            Some(index) => self.get_as(index),
+            None => Err(Error::Row(RowError::NoSuchColumn(name.to_string()))),
+        }
+    }
+}
+
+impl<'c> Rows<'c> {
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn mapped<T>(self) -> RowsAs<'c, T>
+    where
+        T: FromRow,
+    {
+        RowsAs {
+            rows: self,
+            row_type: PhantomData,
+        }
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Result<Row, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let values = self.machine.as_mut()?.next()?.map_err(Error::Execute);
+        Some(values.map(|values| Row {
+            columns: self.columns.clone(),
+            values,
+        }))
+    }
+}
+
+impl<T: FromRow> Iterator for RowsAs<'_, T> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rows
+            .next()
+            .map(|row| row.and_then(|row| T::from_row(&row)))
+    }
+}
+
+impl fmt::Debug for Rows<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rows").finish_non_exhaustive()
+    }
+}
@@ -3,9 +3,51 @@ pub const USERNAME_SIZE: usize = std::mem::size_of::<[u8; 32]>();
 pub const ID_SIZE: usize = std::mem::size_of::<u32>();
 pub const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
 pub const MAX_PAGES: usize = 100;
-pub const PAGE_SIZE: usize = 4096;
-pub const ROWS_PER_PAGE: usize = PAGE_SIZE / ROW_SIZE;
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 65536;
 pub const ID_OFFSET: usize = 0;
 pub const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
 pub const EMAIL_OFFSET: usize = USERNAME_OFFSET + USERNAME_SIZE;
-pub const TABLE_MAX_ROWS: usize = ROWS_PER_PAGE * MAX_PAGES;
+// page 0 starts with the file header; rows are stored from page 1 onwards
+pub const HEADER_MAGIC: &[u8; 16] = b"db_tutorial v1\0\0";
+pub const PAGE_SIZE_OFFSET: usize = HEADER_MAGIC.len();
+pub const NUM_ROWS_OFFSET: usize = PAGE_SIZE_OFFSET + 4;
+pub const CHANGE_COUNTER_OFFSET: usize = NUM_ROWS_OFFSET + 4;
+pub const HEADER_SIZE: usize = CHANGE_COUNTER_OFFSET + 4;
+// files written before there was a header hold only rows, packed into pages of this size
+pub const HEADERLESS_PAGE_SIZE: usize = 4096;
+// the rest of the header belongs to the table layer: how many pages the file has in use,
+// where the schema is kept and how often it changed, the page each run of rows is on, then
+// where the statistics ANALYZE collects are kept and the first page on the freelist
+pub const PAGE_COUNT_OFFSET: usize = HEADER_SIZE;
+pub const SCHEMA_ROOT_OFFSET: usize = PAGE_COUNT_OFFSET + 4;
+pub const SCHEMA_VERSION_OFFSET: usize = SCHEMA_ROOT_OFFSET + 4;
+pub const ROW_PAGES_OFFSET: usize = SCHEMA_VERSION_OFFSET + 4;
+pub const STATS_ROOT_OFFSET: usize = ROW_PAGES_OFFSET + MAX_PAGES * 4;
+pub const FREELIST_OFFSET: usize = STATS_ROOT_OFFSET + 4;
+pub const MAX_FILE_PAGES: usize = 1 << 20;
+pub const JOURNAL_MAGIC: &[u8; 8] = b"dbtjrnl1";
+pub const JOURNAL_HEADER_SIZE: usize = JOURNAL_MAGIC.len() + 4 + 8;
+pub const DEFAULT_CACHE_SIZE: usize = 2000;
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+pub const BUSY_RETRY_INTERVAL_MS: u64 = 5;
+// the most parameters a statement can have, the same as in SQLite
+pub const MAX_PARAMETERS: usize = 32766;
+// How many expressions can be inside each other, and how many parentheses, subqueries and
+// operators the parser can be inside of while it reads them. SQLite allows expressions 1000 deep,
+// but compiling one that deep takes more stack than a thread gets by default in a debug build;
+// the parser's limit is the depth of SQLite's parser stack.
+pub const MAX_EXPR_DEPTH: usize = 500;
+pub const MAX_PARSER_DEPTH: usize = 100;
+// the most tables one query can read from, the same as in SQLite; which of them something
+// reads is kept in the bits of a u64
+pub const MAX_JOIN_TABLES: usize = 64;
+pub const TABLE_NAME: &str = "users";
+// name and declared type of each column in a row, in storage order
+pub const COLUMNS: [(&str, &str); 3] = [
+    ("id", "integer"),
+    ("username", "varchar(32)"),
+    ("email", "varchar(255)"),
+];
+pub const FOREIGN_KEY_FAILED: &str = "FOREIGN KEY constraint failed";
@@ -0,0 +1,70 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::pager::PagerOptions;
+use crate::program::Program;
+use crate::schema::Schema;
+use crate::table::{Table, TableError};
+use crate::value::Value;
+use crate::virtual_machine::{VMErr, VirtualMachine};
+
+// A handle on an open database that any number of threads can clone and use at once;
+// every read sees the database as it was when the read started, while writes take turns.
+#[derive(Clone)]
+pub struct Database {
+    table: Arc<Table>,
+    // loaded when a statement is first compiled, and again whenever it turns out to be out of date
+    schema: Arc<Mutex<Option<Arc<Schema>>>>,
+}
+
+impl Database {
+    pub fn open_with<P>(filename: P, options: PagerOptions) -> Result<Self, TableError>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(Database {
+            table: Arc::new(Table::open(filename, options)?),
+            schema: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn execute(
+        &self,
+        program: Arc<Program>,
+        params: Vec<Value>,
+    ) -> Result<VirtualMachine<'_>, VMErr> {
+        VirtualMachine::start(&self.table, program, params)
+    }
+
+    // the schema statements are compiled against, which may be behind the latest commit
+    pub fn schema(&self) -> Result<Arc<Schema>, TableError> {
+        let cached = self.cached_schema().clone();
+        match cached {
+            Some(schema) => Ok(schema),
+            None => self.reload_schema(),
+        }
+    }
+
+    pub fn reload_schema(&self) -> Result<Arc<Schema>, TableError> {
+        let schema = Arc::new(Schema::load(&self.table.read()?)?);
+        *self.cached_schema() = Some(schema.clone());
+        Ok(schema)
+    }
+
+    // the next statement compiled loads the schema again
+    pub fn forget_schema(&self) {
+        *self.cached_schema() = None;
+    }
+
+    fn cached_schema(&self) -> MutexGuard<'_, Option<Arc<Schema>>> {
+        self.schema.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // the database is only closed along with its last handle; until then there is nothing to report
+    pub fn close(self) -> Result<(), TableError> {
+        match Arc::try_unwrap(self.table) {
+            Ok(table) => table.close(),
+            Err(_) => Ok(()),
+        }
+    }
+}
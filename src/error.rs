@@ -0,0 +1,106 @@
+use std::fmt;
+
+use crate::mapping::RowError;
+use crate::parser::StatementError;
+use crate::table::TableError;
+use crate::virtual_machine::VMErr;
+
+// Everything that can go wrong using the engine. The message says what happened,
+// `source` leads to the error underneath it, and `code` sorts it into a stable category.
+#[derive(Debug)]
+pub enum Error {
+    Open(TableError),
+    Statement(StatementError),
+    Execute(VMErr),
+    Row(RowError),
+    Close(TableError),
+}
+
+// Stable error codes, numbered the same way as SQLite's primary result codes
+// so they mean something to anybody who has handled those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    // a statement that can't be run as written
+    Error = 1,
+    // the engine found itself in a state it should never be in
+    Internal = 2,
+    // another process holds the database
+    Busy = 5,
+    IoErr = 10,
+    // the file holds something that can't have been written by this engine
+    Corrupt = 11,
+    Full = 13,
+    // the schema changed after the statement was compiled
+    Schema = 17,
+    TooBig = 18,
+    // a row that breaks a rule the schema sets, like a primary key that is already taken
+    Constraint = 19,
+    // a value of the wrong type for where it is going
+    Mismatch = 20,
+    // the API was used the wrong way, like running a statement with parameters left unbound
+    Misuse = 21,
+    // a parameter or column index that doesn't exist
+    Range = 25,
+    NotADb = 26,
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Open(e) | Error::Close(e) => e.code(),
+            Error::Statement(e) => e.code(),
+            Error::Execute(e) => e.code(),
+            Error::Row(e) => e.code(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Open(_) => write!(f, "could not open database"),
+            Error::Close(_) => write!(f, "could not close database"),
+            Error::Statement(e) => e.fmt(f),
+            Error::Execute(e) => e.fmt(f),
+            Error::Row(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open(e) | Error::Close(e) => Some(e),
+            Error::Statement(e) => e.source(),
+            Error::Execute(e) => e.source(),
+            Error::Row(e) => e.source(),
+        }
+    }
+}
+
+impl ErrorCode {
+    pub fn as_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::Error => "error",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Busy => "busy",
+            ErrorCode::IoErr => "ioerr",
+            ErrorCode::Corrupt => "corrupt",
+            ErrorCode::Full => "full",
+            ErrorCode::Schema => "schema",
+            ErrorCode::TooBig => "toobig",
+            ErrorCode::Constraint => "constraint",
+            ErrorCode::Mismatch => "mismatch",
+            ErrorCode::Misuse => "misuse",
+            ErrorCode::Range => "range",
+            ErrorCode::NotADb => "notadb",
+        };
+        write!(f, "{}", name)
+    }
+}
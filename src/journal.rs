@@ -0,0 +1,135 @@
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::constants::*;
+use crate::pager::{validate_page_size, Synchronous};
+
+// A rollback journal holds the original content of every page a transaction changes,
+// so the database file can be put back the way it was if the transaction never commits.
+//
+// layout: magic, page size (u32), database length before the transaction (u64),
+// then one record per page: page number (u32) followed by the page itself
+pub struct Journal {
+    file: File,
+    path: PathBuf,
+    page_size: usize,
+}
+
+impl Journal {
+    pub fn path_for<P>(database_path: P) -> PathBuf
+    where
+        P: AsRef<Path>,
+    {
+        let mut path = database_path.as_ref().as_os_str().to_owned();
+        path.push("-journal");
+        PathBuf::from(path)
+    }
+
+    pub fn create(path: &Path, page_size: usize, file_length: u64) -> Result<Self, std::io::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut header = Vec::with_capacity(JOURNAL_HEADER_SIZE);
+        header.extend_from_slice(JOURNAL_MAGIC);
+        header.extend_from_slice(&(page_size as u32).to_be_bytes());
+        header.extend_from_slice(&file_length.to_be_bytes());
+        file.write_all(&header)?;
+        Ok(Journal {
+            file,
+            path: path.to_owned(),
+            page_size,
+        })
+    }
+
+    pub fn append(&mut self, page_num: u32, original: &[u8]) -> Result<(), std::io::Error> {
+        debug_assert_eq!(original.len(), self.page_size);
+        self.file.write_all(&page_num.to_be_bytes())?;
+        self.file.write_all(original)
+    }
+
+    // the journal has to be on disk before the first database page is overwritten
+    pub fn sync(&self, synchronous: Synchronous) -> Result<(), std::io::Error> {
+        match synchronous {
+            Synchronous::Off => Ok(()),
+            Synchronous::Normal => self.file.sync_data(),
+            Synchronous::Full => self.file.sync_all(),
+        }
+    }
+
+    // puts the original pages back into the database file and returns its original length
+    pub fn roll_back(&mut self, database: &mut File) -> Result<u64, std::io::Error> {
+        restore(&mut self.file, database)?
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "journal header is damaged"))
+    }
+
+    pub fn delete(self) -> Result<(), std::io::Error> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)
+    }
+}
+
+// A journal left behind by a process that died mid-transaction is "hot": the database file
+// may hold some of that transaction's pages, so it is rolled back before anything reads it.
+// Returns whether there was anything to roll back.
+pub fn roll_back_hot_journal(path: &Path, database: &mut File) -> Result<bool, std::io::Error> {
+    let mut journal = match File::open(path) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    // a journal without a complete header was still being written when the process died;
+    // the database file is only touched after the journal is complete, so it is untouched
+    let rolled_back = restore(&mut journal, database)?.is_some();
+    if rolled_back {
+        database.sync_all()?;
+    }
+    drop(journal);
+    std::fs::remove_file(path)?;
+    Ok(rolled_back)
+}
+
+fn restore(journal: &mut File, database: &mut File) -> Result<Option<u64>, std::io::Error> {
+    let mut header = [0u8; JOURNAL_HEADER_SIZE];
+    journal.seek(SeekFrom::Start(0))?;
+    if !read_fully(journal, &mut header)? || &header[..JOURNAL_MAGIC.len()] != JOURNAL_MAGIC {
+        return Ok(None);
+    }
+    let page_size_offset = JOURNAL_MAGIC.len();
+    let length_offset = page_size_offset + 4;
+    let page_size =
+        u32::from_be_bytes(header[page_size_offset..length_offset].try_into().unwrap()) as usize;
+    // a page size no database could have means the header itself is damaged
+    if validate_page_size(page_size).is_err() {
+        return Ok(None);
+    }
+    let file_length = u64::from_be_bytes(
+        header[length_offset..JOURNAL_HEADER_SIZE]
+            .try_into()
+            .unwrap(),
+    );
+
+    let mut page_num = [0u8; 4];
+    let mut page = vec![0u8; page_size];
+    // a record cut short by a crash was never synced, so the page it describes was never overwritten
+    while read_fully(journal, &mut page_num)? && read_fully(journal, &mut page)? {
+        let page_num = u32::from_be_bytes(page_num);
+        database.seek(SeekFrom::Start(page_num as u64 * page_size as u64))?;
+        database.write_all(&page)?;
+    }
+    database.set_len(file_length)?;
+    Ok(Some(file_length))
+}
+
+// like read_exact, but running out of file is reported as false rather than an error
+fn read_fully(file: &mut File, buffer: &mut [u8]) -> Result<bool, std::io::Error> {
+    match file.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,35 @@
+mod ast;
+mod btree;
+mod compiler;
+mod connection;
+mod constants;
+mod database;
+mod error;
+mod journal;
+mod mapping;
+mod pager;
+mod parser;
+mod planner;
+mod program;
+mod record;
+mod schema;
+mod stats;
+mod table;
+mod value;
+mod virtual_machine;
+
+pub use ast::{
+    AlterTable, BinaryOp, ColumnDefault, ColumnSpec, CommonTable, CreateIndex, CreateTable,
+    CreateTrigger, CreateView, Delete, Expr, ForeignKey, ForeignKeyAction, Insert, JoinKind,
+    ResultColumn, Select, Statement, TableChange, TableRef, TriggerEvent, TriggerTiming, UnaryOp,
+    Union, Update, With,
+};
+pub use connection::{Connection, Outcome, PreparedStatement, Row, Rows, RowsAs};
+pub use db_tutorial_derive::{FromRow, ToParams};
+pub use error::{Error, ErrorCode};
+pub use mapping::{FromRow, FromValue, RowError, ToParams, ToValue};
+pub use pager::{JournalMode, PagerError, PagerOptions, Synchronous};
+pub use parser::StatementError;
+pub use table::TableError;
+pub use value::Value;
+pub use virtual_machine::VMErr;
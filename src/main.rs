@@ -1,17 +1,8 @@
+use std::error::Error;
 use std::io::Write;
 use std::io::{stdin, stdout};
 
-use std::str::from_utf8;
-
-mod constants;
-mod pager;
-mod table;
-mod virtual_machine;
-
-use table::Table;
-use virtual_machine::{
-    prepare_statement, ResultRow, Statement, StatementError, VMErr, VMResult, VirtualMachine,
-};
+use db_tutorial::{Connection, Outcome, PagerOptions, Statement, Value};
 
 enum ReplAction<'a> {
     Exit,
@@ -19,19 +10,6 @@ enum ReplAction<'a> {
     Unsupported { message: String },
 }
 
-#[derive(Debug)]
-pub enum ReplResult {
-    Rows(Vec<ResultRow>),
-    Success,
-}
-
-#[derive(Debug)]
-enum ReplErr {
-    IOErr(std::io::Error),
-    Execute(VMErr),
-    Statement(StatementError),
-}
-
 fn main() {
     // parse command line args
     let args: Vec<String> = std::env::args().collect();
@@ -40,8 +18,18 @@ fn main() {
     // initialize any thing we need for the REPL
     let mut input_buffer = String::new();
 
-    let mut table = Table::new(database_file_name).expect("could not create table");
-    let mut virtual_machine = VirtualMachine { table: &mut table };
+    // a file written before there was a header is only given one when asked to
+    let options = PagerOptions {
+        upgrade_headerless: args.iter().skip(2).any(|arg| arg == "--upgrade"),
+        ..PagerOptions::default()
+    };
+    let connection = match Connection::open_with(database_file_name, options) {
+        Ok(connection) => connection,
+        Err(e) => {
+            println!("db message: {}", describe(&e));
+            std::process::exit(1);
+        }
+    };
 
     // Loop until "exit" input is provided
     loop {
@@ -52,52 +40,114 @@ fn main() {
                 ReplAction::Exit => break,
                 ReplAction::Statement { original_input } => {
                     println!("processing statement {:?}", original_input);
-                    match prepare_statement(original_input)
-                        .map_err(ReplErr::Statement)
-                        .and_then(|s| {
-                            match s {
-                                Statement::Insert { row: _ } => {
-                                    println!("executing insert statement");
-                                }
-                                Statement::Select => {
-                                    println!("executing select statement");
-                                }
+                    match connection.prepare(original_input).and_then(|s| {
+                        // pragma values are shown as they are, table values the way they'd be written
+                        let quote_text = match s.statement() {
+                            Statement::Insert(_) => {
+                                println!("executing insert statement");
+                                true
+                            }
+                            Statement::Update(_) => {
+                                println!("executing update statement");
+                                true
+                            }
+                            Statement::Delete(_) => {
+                                println!("executing delete statement");
+                                true
+                            }
+                            Statement::Select(_) => {
+                                println!("executing select statement");
+                                true
                             }
-                            virtual_machine
-                                .execute_statement(s)
-                                .map_err(ReplErr::Execute)
-                        }) {
-                        Ok(results) => match results {
-                            VMResult::Rows(rows) => {
-                                rows.iter().for_each(|r| {
-                                    println!(
-                                        "{:?}, {:?}, {:?}",
-                                        r.id,
-                                        from_utf8(&r.username).unwrap().trim_matches(char::from(0)),
-                                        from_utf8(&r.email).unwrap().trim_matches(char::from(0))
-                                    );
-                                });
+                            Statement::Pragma { .. } => {
+                                println!("executing pragma statement");
+                                false
                             }
-                            _ => println!("result {:?}", results),
-                        },
-                        Err(e) => println!("db message: {:?}", &e),
+                            Statement::Explain { .. } => {
+                                println!("executing explain statement");
+                                false
+                            }
+                            Statement::CreateTable(_)
+                            | Statement::CreateIndex(_)
+                            | Statement::CreateView(_)
+                            | Statement::CreateTrigger(_) => {
+                                println!("executing create statement");
+                                false
+                            }
+                            Statement::AlterTable(_) => {
+                                println!("executing alter statement");
+                                false
+                            }
+                            Statement::DropView { .. } | Statement::DropTrigger { .. } => {
+                                println!("executing drop statement");
+                                false
+                            }
+                            Statement::Analyze { .. } => {
+                                println!("executing analyze statement");
+                                false
+                            }
+                        };
+                        s.execute().map(|outcome| (outcome, quote_text))
+                    }) {
+                        Ok((Outcome::Rows(rows), quote_text)) => {
+                            // each row is printed as soon as it is read
+                            for row in rows {
+                                match row {
+                                    Ok(row) => {
+                                        let values: Vec<String> = row
+                                            .values()
+                                            .iter()
+                                            .map(|value| match value {
+                                                Value::Text(text) if quote_text => {
+                                                    format!("{:?}", text)
+                                                }
+                                                value => value.to_string(),
+                                            })
+                                            .collect();
+                                        println!("{}", values.join(", "));
+                                    }
+                                    Err(e) => {
+                                        println!("db message: {}", describe(&e));
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                        Ok((outcome, _)) => println!("result {:?}", outcome),
+                        Err(e) => println!("db message: {}", describe(&e)),
                     }
                 }
                 ReplAction::Unsupported { message } => println!("db message: {}", &message),
             },
             Err(e) => {
-                println!("db message: {:?}", &e)
+                println!("db message: {}", describe(&e))
             }
         }
     }
+
+    if let Err(e) = connection.close() {
+        println!("db message: {}", describe(&e));
+        std::process::exit(1);
+    }
+}
+
+// an error followed by each of the errors that caused it, like "disk I/O error: No space left on device"
+fn describe(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
 }
 
-fn read_user_input(input_buffer: &mut String) -> Result<&str, ReplErr> {
+fn read_user_input(input_buffer: &mut String) -> Result<&str, std::io::Error> {
     flush_stdout()
         .and_then(|_| stdin().read_line(input_buffer))
         .and_then(move |n| ensure_stdout_newline((n, input_buffer)))
         .map(|s| s.trim())
-        .map_err(ReplErr::IOErr)
 }
 
 fn flush_stdout() -> Result<(), std::io::Error> {
@@ -0,0 +1,157 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::connection::Row;
+use crate::error::{Error, ErrorCode};
+use crate::value::Value;
+
+// Turns a result row into one of the caller's own types. `#[derive(FromRow)]` fills in each
+// field from the column with the same name, or each field of a tuple struct from the column
+// at its position.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, Error>;
+}
+
+// a single column value turned into a Rust type; None means the value has the wrong type
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+// Supplies values for a statement's parameters, in order. Values can also carry a name, so
+// `#[derive(ToParams)]` structs can fill `:field` parameters no matter where they appear.
+pub trait ToParams {
+    fn to_params(&self) -> Vec<(Option<&'static str>, Value)>;
+}
+
+#[derive(Debug)]
+pub enum RowError {
+    NoSuchColumn(String),
+    WrongType(String),
+}
+
+impl RowError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RowError::NoSuchColumn(_) => ErrorCode::Range,
+            RowError::WrongType(_) => ErrorCode::Mismatch,
+        }
+    }
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowError::NoSuchColumn(column) => write!(f, "no such column: {}", column),
+            RowError::WrongType(column) => {
+                write!(f, "column {} holds a different type of value", column)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RowError {}
+
+impl FromRow for Row {
+    fn from_row(row: &Row) -> Result<Self, Error> {
+        Ok(row.clone())
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for u32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        i64::from_value(value).and_then(|i| u32::try_from(i).ok())
+    }
+}
+
+impl FromValue for i32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        i64::from_value(value).and_then(|i| i32::try_from(i).ok())
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Text(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+// NULL is None, and anything else has to be a T
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: Clone + Into<Value>> ToValue for T {
+    fn to_value(&self) -> Value {
+        self.clone().into()
+    }
+}
+
+impl ToValue for str {
+    fn to_value(&self) -> Value {
+        self.into()
+    }
+}
+
+impl<T: ToParams + ?Sized> ToParams for &T {
+    fn to_params(&self) -> Vec<(Option<&'static str>, Value)> {
+        (**self).to_params()
+    }
+}
+
+impl<V: ToValue> ToParams for [V] {
+    fn to_params(&self) -> Vec<(Option<&'static str>, Value)> {
+        self.iter().map(|value| (None, value.to_value())).collect()
+    }
+}
+
+impl<V: ToValue> ToParams for Vec<V> {
+    fn to_params(&self) -> Vec<(Option<&'static str>, Value)> {
+        self[..].to_params()
+    }
+}
+
+macro_rules! tuple_params {
+    ($($name:ident),+) => {
+        impl<$($name: ToValue),+> ToParams for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn to_params(&self) -> Vec<(Option<&'static str>, Value)> {
+                let ($($name,)+) = self;
+                vec![$((None, $name.to_value())),+]
+            }
+        }
+    };
+}
+
+tuple_params!(A);
+tuple_params!(A, B);
+tuple_params!(A, B, C);
+tuple_params!(A, B, C, D);
+tuple_params!(A, B, C, D, E);
+tuple_params!(A, B, C, D, E, F);
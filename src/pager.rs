@@ -1,141 +1,1254 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-
-use std::path::Path;
-
-use crate::constants::*;
-
-#[derive(Debug)]
-pub struct Page {
-    pub buffer: [u8; PAGE_SIZE],
-}
-
-pub struct Pager {
-    file: File,
-    pub file_length: u64,
-    pages: HashMap<u32, Page>,
-}
-
-#[derive(Debug)]
-pub enum PagerError {
-    File(std::io::Error),
-    PagesFull,
-}
-
-impl Pager {
-    pub fn new<P>(filename: P) -> Result<Self, PagerError>
-    where
-        P: AsRef<Path>,
-    {
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)
-            .and_then(|mut file| file.seek(SeekFrom::End(0)).map(|len| (file, len)))
-            .map(|(file, len)| Pager {
-                file,
-                pages: HashMap::new(),
-                file_length: len,
-            })
-            .map_err(PagerError::File)
-    }
-
-    pub fn get_page(&mut self, page_num: u32) -> Result<&mut Page, PagerError> {
-        if page_num > MAX_PAGES as u32 {
-            Err(PagerError::PagesFull)
-        } else {
-            match self.pages.entry(page_num) {
-                Entry::Occupied(o) => Ok(o.into_mut()),
-                Entry::Vacant(v) => {
-                    let mut page = Page {
-                        buffer: [0u8; PAGE_SIZE],
-                    };
-
-                    let total_num_pages_in_file_now = if self.file_length % PAGE_SIZE as u64 > 0 {
-                        // We might save a partial page at the end of the file
-                        (self.file_length / PAGE_SIZE as u64) + 1
-                    } else {
-                        self.file_length / PAGE_SIZE as u64
-                    };
-
-                    // if the page number requested is greater than the total num of pages
-                    // we have recorded in the file then there is nothing in the file for us to read
-                    // this will be true the first time we write to a fresh page and until we first write
-                    // to the file for that fresh page (bytes in the new page won't be counted until we write to file/disk)
-                    if page_num as u64 <= total_num_pages_in_file_now {
-                        self.file
-                            .seek(SeekFrom::Start((page_num as usize * PAGE_SIZE) as u64))
-                            .map_err(PagerError::File)?;
-                        self.file
-                            .read_exact(&mut page.buffer)
-                            .or_else(|e| match e.kind() {
-                                // This means that we could not fill the entire buffer which is fine since we can't (we know its not a full page)
-                                std::io::ErrorKind::UnexpectedEof => Ok(()),
-                                _ => Err(e),
-                            })
-                            .map_err(PagerError::File)?;
-                    }
-
-                    // return the page buffer whether its totally fresh or had been written to disk before
-                    Ok(v.insert(page))
-                }
-            }
-        }
-    }
-
-    // the table knows about rows, not the pager; so we expect that data as input
-    pub fn flush(
-        &mut self,
-        num_full_pages: usize,
-        num_additional_bytes: usize,
-    ) -> Result<(), PagerError> {
-        for page_num in 0..num_full_pages {
-            let page = match self.pages.get_mut(&(page_num as u32)) {
-                Some(p) => p,
-                None => {
-                    // during a flush, if there is no page in memory, then nothing about that page needs to be flushed
-                    // since the user could not possibly have changed it if it was never read into memory
-                    continue;
-                }
-            };
-            self.file
-                .seek(SeekFrom::Start((page_num as usize * PAGE_SIZE) as u64))
-                .map_err(PagerError::File)?;
-
-            self.file
-                .write_all(&page.buffer)
-                .map_err(PagerError::File)?;
-        }
-
-        let last_possible_partial_page = num_full_pages;
-        if num_additional_bytes > 0 {
-            match self.pages.get_mut(&(last_possible_partial_page as u32)) {
-                Some(page) => {
-                    self.file
-                        .seek(SeekFrom::Start(
-                            (last_possible_partial_page as usize * PAGE_SIZE) as u64,
-                        ))
-                        .map_err(PagerError::File)?;
-
-                    self.file
-                        .write_all(&page.buffer[..num_additional_bytes])
-                        .or_else(|e| match e.kind() {
-                            // I believe this means that
-                            std::io::ErrorKind::UnexpectedEof => Ok(()),
-                            _ => Err(e),
-                        })
-                        .map_err(PagerError::File)
-                }
-                None => {
-                    // if the page we are trying to flush isn't in memory then it doesn't need to be flushed
-                    Ok(())
-                }
-            }
-        } else {
-            Ok(())
-        }
-    }
-}
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::constants::*;
+use crate::error::ErrorCode;
+use crate::journal::{roll_back_hot_journal, Journal};
+use crate::table::check_text_column;
+
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub buffer: Box<[u8]>,
+}
+
+// The pager is shared by every thread using the database. Committed pages are handed out
+// behind an Arc, while a writer keeps copies of the pages it changes to itself until it commits.
+//
+// Every commit made through this pager starts a new version of the database. A reader keeps
+// seeing the version that was current when it began: when a commit overwrites pages that a
+// running reader may still need, their previous content is kept in memory until that reader ends.
+// Other processes can't commit at all while a reader runs, since its shared lock keeps them out;
+// they can read again as soon as the writer here is done.
+pub struct Pager {
+    journal_path: PathBuf,
+    state: Mutex<PagerState>,
+}
+
+struct PagerState {
+    file: File,
+    lock_path: PathBuf,
+    file_length: u64,
+    // fixed when the file is created and read back from its header afterwards
+    page_size: usize,
+    // bumped by every commit so other processes can tell their cached pages are stale
+    change_counter: u32,
+    pages: HashMap<u32, CachedPage>,
+    // the version the latest commit produced
+    version: u64,
+    // how many readers are running on each version
+    snapshots: BTreeMap<u64, usize>,
+    // the earlier contents of pages overwritten since the oldest running reader began, each with
+    // the version that replaced it, oldest first; a page is looked up here before the cache
+    old_versions: HashMap<u32, Vec<(u64, Arc<Page>)>>,
+    // readers on versions before this one can't be given their pages any more, because the file
+    // was changed under them by something that ignored their lock
+    lost_before: u64,
+    // ticks on every page access so eviction can find the least recently used page
+    clock: u64,
+    lock: Lock,
+    // transactions in this process relying on the lock; it is released when the last one ends
+    holders: usize,
+    synchronous: Synchronous,
+    journal_mode: JournalMode,
+    // the number of pages kept in memory before the least recently used ones are evicted
+    cache_size: usize,
+    // how long to keep retrying when another process holds a conflicting lock
+    busy_timeout: Duration,
+    // whether a commit has skipped syncing since the file was last synced
+    unsynced: bool,
+}
+
+// The lock file is held by a process while it takes an exclusive lock on the database, and is
+// removed before it is let go. A process that opened it just before it was removed finds, once
+// it holds it, that it isn't the file at the path any more, and opens the one that is.
+struct LockFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl LockFile {
+    fn path_for<P>(database_path: P) -> PathBuf
+    where
+        P: AsRef<Path>,
+    {
+        let mut path = database_path.as_ref().as_os_str().to_owned();
+        path.push("-lock");
+        PathBuf::from(path)
+    }
+
+    // None if another process is holding it
+    fn try_lock(path: &Path) -> Result<Option<Self>, PagerError> {
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(PagerError::File)?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => return Ok(None),
+                Err(TryLockError::Error(e)) => return Err(PagerError::File(e)),
+            }
+            if is_file_at(&file, path).map_err(PagerError::File)? {
+                return Ok(Some(LockFile {
+                    file,
+                    path: path.to_owned(),
+                }));
+            }
+        }
+    }
+
+    fn release(self) -> Result<(), PagerError> {
+        // an open file can't be removed everywhere; where it can't, it's left for next time
+        if cfg!(unix) {
+            std::fs::remove_file(&self.path).map_err(PagerError::File)?;
+        }
+        self.file.unlock().map_err(PagerError::File)
+    }
+}
+
+#[cfg(unix)]
+fn is_file_at(file: &File, path: &Path) -> Result<bool, std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+    let open = file.metadata()?;
+    match std::fs::metadata(path) {
+        Ok(named) => Ok(open.dev() == named.dev() && open.ino() == named.ino()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn is_file_at(_: &File, _: &Path) -> Result<bool, std::io::Error> {
+    Ok(true)
+}
+
+struct CachedPage {
+    page: Arc<Page>,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+pub enum PagerError {
+    File(std::io::Error),
+    PagesFull,
+    InvalidPageSize,
+    NotADatabase,
+    Locked,
+    NotEmpty,
+    SnapshotLost,
+}
+
+impl PagerError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            PagerError::File(_) => ErrorCode::IoErr,
+            PagerError::PagesFull => ErrorCode::Full,
+            PagerError::InvalidPageSize => ErrorCode::Range,
+            PagerError::NotADatabase => ErrorCode::NotADb,
+            PagerError::Locked => ErrorCode::Busy,
+            PagerError::NotEmpty => ErrorCode::Misuse,
+            PagerError::SnapshotLost => ErrorCode::Busy,
+        }
+    }
+}
+
+impl fmt::Display for PagerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PagerError::File(_) => write!(f, "disk I/O error"),
+            PagerError::PagesFull => write!(f, "database is full"),
+            PagerError::InvalidPageSize => write!(
+                f,
+                "page size must be a power of two between {} and {}",
+                MIN_PAGE_SIZE, MAX_PAGE_SIZE
+            ),
+            PagerError::NotADatabase => write!(f, "file is not a database"),
+            PagerError::Locked => write!(f, "database is locked"),
+            PagerError::NotEmpty => {
+                write!(f, "page size can't be changed once the database has data")
+            }
+            PagerError::SnapshotLost => write!(
+                f,
+                "the database was changed by another process while it was being read"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PagerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PagerError::File(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// the OS lock this process holds on the database file; locks only last while transactions run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lock {
+    None,
+    // any number of processes may read at the same time
+    Shared,
+    // a writer keeps every other process out until it has committed or rolled back
+    Exclusive,
+}
+
+// how hard the pager works to get committed pages onto stable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    // leave it to the OS; a power loss can lose writes that looked successful
+    Off,
+    // sync the file contents after every commit
+    Normal,
+    // sync the file contents and its metadata after every commit
+    Full,
+}
+
+#[derive(Debug)]
+pub struct InvalidSynchronous;
+
+impl FromStr for Synchronous {
+    type Err = InvalidSynchronous;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_ascii_lowercase()[..] {
+            "off" | "0" => Ok(Synchronous::Off),
+            "normal" | "1" => Ok(Synchronous::Normal),
+            "full" | "2" => Ok(Synchronous::Full),
+            _ => Err(InvalidSynchronous),
+        }
+    }
+}
+
+impl fmt::Display for InvalidSynchronous {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "synchronous must be off, normal or full")
+    }
+}
+
+impl std::error::Error for InvalidSynchronous {}
+
+impl fmt::Display for Synchronous {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Synchronous::Off => write!(f, "off"),
+            Synchronous::Normal => write!(f, "normal"),
+            Synchronous::Full => write!(f, "full"),
+        }
+    }
+}
+
+// whether the original content of changed pages is saved so a failed transaction can be undone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    // a crash in the middle of a commit can leave the file with only some of its pages
+    Off,
+    // keep a rollback journal next to the database and delete it once the transaction commits
+    Delete,
+}
+
+#[derive(Debug)]
+pub struct InvalidJournalMode;
+
+impl FromStr for JournalMode {
+    type Err = InvalidJournalMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_ascii_lowercase()[..] {
+            "off" => Ok(JournalMode::Off),
+            "delete" => Ok(JournalMode::Delete),
+            _ => Err(InvalidJournalMode),
+        }
+    }
+}
+
+impl fmt::Display for InvalidJournalMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "journal mode must be off or delete")
+    }
+}
+
+impl std::error::Error for InvalidJournalMode {}
+
+impl fmt::Display for JournalMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalMode::Off => write!(f, "off"),
+            JournalMode::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PagerOptions {
+    // only used when creating a new file; existing files keep the page size in their header
+    pub page_size: usize,
+    pub synchronous: Synchronous,
+    pub journal_mode: JournalMode,
+    pub cache_size: usize,
+    pub busy_timeout: Duration,
+    // whether a file written before there was a header is given one when it is opened; without
+    // this it is refused like any other file that isn't a database
+    pub upgrade_headerless: bool,
+}
+
+impl Default for PagerOptions {
+    fn default() -> Self {
+        PagerOptions {
+            page_size: DEFAULT_PAGE_SIZE,
+            synchronous: Synchronous::Full,
+            journal_mode: JournalMode::Delete,
+            cache_size: DEFAULT_CACHE_SIZE,
+            busy_timeout: Duration::from_millis(DEFAULT_BUSY_TIMEOUT_MS),
+            upgrade_headerless: false,
+        }
+    }
+}
+
+impl Page {
+    pub fn new(page_size: usize) -> Self {
+        Page {
+            buffer: vec![0u8; page_size].into_boxed_slice(),
+        }
+    }
+}
+
+// anything pages can be read through: a read transaction sees committed pages,
+// a write transaction sees its own changes on top of them
+pub trait PageRead {
+    fn page(&self, page_num: u32) -> Result<Arc<Page>, PagerError>;
+    fn page_size(&self) -> usize;
+}
+
+// pages that can be changed as well as read, new pages added to and pages given back
+pub trait PageWrite: PageRead {
+    fn page_mut(&mut self, page_num: u32) -> Result<&mut Page, PagerError>;
+    fn allocate(&mut self) -> Result<u32, PagerError>;
+    // the page isn't used by anything any more, so allocate can hand it out again
+    fn free(&mut self, page_num: u32) -> Result<(), PagerError>;
+}
+
+impl Pager {
+    pub fn new<P>(filename: P, options: PagerOptions) -> Result<Self, PagerError>
+    where
+        P: AsRef<Path>,
+    {
+        let journal_path = Journal::path_for(&filename);
+        let lock_path = LockFile::path_for(&filename);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(filename)
+            .map_err(PagerError::File)?;
+
+        let pager = Pager {
+            journal_path,
+            state: Mutex::new(PagerState {
+                file,
+                lock_path,
+                file_length: 0,
+                page_size: validate_page_size(options.page_size)?,
+                change_counter: 0,
+                pages: HashMap::new(),
+                version: 0,
+                snapshots: BTreeMap::new(),
+                old_versions: HashMap::new(),
+                lost_before: 0,
+                clock: 0,
+                lock: Lock::None,
+                holders: 0,
+                synchronous: options.synchronous,
+                journal_mode: options.journal_mode,
+                cache_size: options.cache_size,
+                busy_timeout: options.busy_timeout,
+                unsynced: false,
+            }),
+        };
+        // reading once validates the header and rolls back whatever a crashed writer left behind
+        match pager.begin_read() {
+            Err(PagerError::NotADatabase) if options.upgrade_headerless => pager.add_header()?,
+            read => drop(read?),
+        }
+        Ok(pager)
+    }
+
+    // Files written before there was a header are given one when the caller asks for it, as long
+    // as what they hold looks like rows and nothing else.
+    fn add_header(&self) -> Result<(), PagerError> {
+        let mut state = self.wait_for(Lock::Exclusive, PagerState::try_lock)?;
+        let added = state.add_header(&self.journal_path);
+        state.release()?;
+        drop(state);
+        added?;
+        self.begin_read().map(drop)
+    }
+
+    fn state(&self) -> MutexGuard<'_, PagerState> {
+        // the state is consistent between calls, so a panic elsewhere doesn't make it unusable
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn begin_read(&self) -> Result<ReadTransaction<'_>, PagerError> {
+        let mut state = self.acquire(Lock::Shared)?;
+        // nobody can be writing while we hold a shared lock, so a journal on disk is a hot one
+        if state.holders == 1 && self.journal_path.exists() {
+            state.release()?;
+            drop(state);
+            let mut state = self.acquire(Lock::Exclusive)?;
+            state.roll_back_hot_journal(&self.journal_path)?;
+            state.release()?;
+            drop(state);
+            return self.begin_read();
+        }
+        let snapshot = state.version;
+        *state.snapshots.entry(snapshot).or_insert(0) += 1;
+        Ok(ReadTransaction {
+            pager: self,
+            snapshot,
+        })
+    }
+
+    // callers make sure there is only ever one write transaction at a time
+    pub fn begin_write(&self) -> Result<WriteTransaction<'_>, PagerError> {
+        let mut state = self.acquire(Lock::Exclusive)?;
+        state.roll_back_hot_journal(&self.journal_path)?;
+        Ok(WriteTransaction {
+            pager: self,
+            dirty: HashMap::new(),
+            originals: HashMap::new(),
+            journal: None,
+            finished: false,
+        })
+    }
+
+    fn acquire(&self, lock: Lock) -> Result<MutexGuard<'_, PagerState>, PagerError> {
+        self.wait_for(lock, PagerState::try_acquire)
+    }
+
+    // keeps trying to take the lock until the busy timeout runs out, without blocking other threads
+    fn wait_for(
+        &self,
+        lock: Lock,
+        attempt: fn(&mut PagerState, Lock) -> Result<bool, PagerError>,
+    ) -> Result<MutexGuard<'_, PagerState>, PagerError> {
+        let deadline = Instant::now() + self.state().busy_timeout;
+        loop {
+            let mut state = self.state();
+            if attempt(&mut state, lock)? {
+                return Ok(state);
+            }
+            if Instant::now() >= deadline {
+                return Err(PagerError::Locked);
+            }
+            drop(state);
+            std::thread::sleep(Duration::from_millis(BUSY_RETRY_INTERVAL_MS));
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.state().page_size
+    }
+
+    pub fn temp_pages(&self) -> TempPages {
+        let page_size = self.page_size();
+        TempPages {
+            page_size,
+            // no tree is rooted at page 0, in here as in the file
+            pages: vec![Arc::new(Page::new(page_size))],
+            free: Vec::new(),
+        }
+    }
+
+    pub fn file_length(&self) -> u64 {
+        self.state().file_length
+    }
+
+    pub fn synchronous(&self) -> Synchronous {
+        self.state().synchronous
+    }
+
+    pub fn set_synchronous(&self, synchronous: Synchronous) {
+        self.state().synchronous = synchronous;
+    }
+
+    pub fn journal_mode(&self) -> JournalMode {
+        self.state().journal_mode
+    }
+
+    pub fn set_journal_mode(&self, journal_mode: JournalMode) {
+        self.state().journal_mode = journal_mode;
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.state().cache_size
+    }
+
+    pub fn set_cache_size(&self, cache_size: usize) {
+        self.state().cache_size = cache_size;
+    }
+
+    pub fn busy_timeout(&self) -> Duration {
+        self.state().busy_timeout
+    }
+
+    pub fn set_busy_timeout(&self, busy_timeout: Duration) {
+        self.state().busy_timeout = busy_timeout;
+    }
+
+    // makes sure everything committed so far is on stable storage, whatever `synchronous` says
+    pub fn sync_all(&self) -> Result<(), PagerError> {
+        let mut state = self.state();
+        if state.unsynced {
+            state.file.sync_all().map_err(PagerError::File)?;
+            state.unsynced = false;
+        }
+        Ok(())
+    }
+}
+
+impl PagerState {
+    fn try_acquire(&mut self, lock: Lock) -> Result<bool, PagerError> {
+        let held = self.lock;
+        if !self.try_lock(lock)? {
+            return Ok(false);
+        }
+        // other processes had a chance to commit while we held no lock (or gave it up)
+        if self.lock != held {
+            if let Err(e) = self.refresh() {
+                match lock {
+                    Lock::Exclusive => self.release_exclusive()?,
+                    _ => self.release()?,
+                }
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+
+    // takes the lock without looking at what the file holds
+    fn try_lock(&mut self, lock: Lock) -> Result<bool, PagerError> {
+        if self.lock != Lock::None && (lock == Lock::Shared || self.lock == lock) {
+            self.holders += 1;
+            return Ok(true);
+        }
+        let attempt = match lock {
+            Lock::None => Ok(()),
+            Lock::Shared => self.file.try_lock_shared(),
+            // whoever holds the lock file is the only process that can be taking an exclusive lock
+            Lock::Exclusive => match LockFile::try_lock(&self.lock_path)? {
+                Some(lock_file) => {
+                    let attempt = self.try_lock_exclusive();
+                    lock_file.release()?;
+                    attempt
+                }
+                None => Err(TryLockError::WouldBlock),
+            },
+        };
+        match attempt {
+            Ok(()) => {
+                self.lock = lock;
+                self.holders += 1;
+                Ok(true)
+            }
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(PagerError::File(e)),
+        }
+    }
+
+    // A shared lock can't be upgraded in place, so it is given up and taken back if the upgrade
+    // fails. Readers in this process go on relying on it in that short window: no other process
+    // can take an exclusive lock without the lock file, so the shared lock is there to take back.
+    fn try_lock_exclusive(&mut self) -> Result<(), TryLockError> {
+        if self.lock == Lock::Shared {
+            self.file.unlock().map_err(TryLockError::Error)?;
+        }
+        match self.file.try_lock() {
+            Err(TryLockError::WouldBlock) if self.lock == Lock::Shared => {
+                self.file
+                    .try_lock_shared()
+                    .map_err(|e| TryLockError::Error(e.into()))?;
+                Err(TryLockError::WouldBlock)
+            }
+            attempt => attempt,
+        }
+    }
+
+    fn release(&mut self) -> Result<(), PagerError> {
+        self.holders -= 1;
+        if self.holders == 0 {
+            self.lock = Lock::None;
+            self.file.unlock().map_err(PagerError::File)?;
+        }
+        Ok(())
+    }
+
+    // Once the writer is done, readers in this process may still rely on the lock, but they only
+    // need it shared, so other processes can read again. The lock file keeps every other process
+    // from taking an exclusive lock in the moment there is none; if one of them is holding it
+    // right now, the lock stays exclusive until the readers here are done.
+    fn release_exclusive(&mut self) -> Result<(), PagerError> {
+        self.release()?;
+        if self.lock != Lock::Exclusive {
+            return Ok(());
+        }
+        if let Some(lock_file) = LockFile::try_lock(&self.lock_path)? {
+            let downgraded = self
+                .file
+                .unlock()
+                .and_then(|_| self.file.try_lock_shared().map_err(Into::into));
+            lock_file.release()?;
+            downgraded.map_err(PagerError::File)?;
+            self.lock = Lock::Shared;
+        }
+        Ok(())
+    }
+
+    fn roll_back_hot_journal(&mut self, journal_path: &Path) -> Result<(), PagerError> {
+        if roll_back_hot_journal(journal_path, &mut self.file).map_err(PagerError::File)? {
+            self.pages.clear();
+            self.refresh()?;
+        }
+        Ok(())
+    }
+
+    // Moves the rows of a file without a header along a page, with a header in front of them
+    // recording how many there are. The rows are journaled first, so a crash part way through
+    // leaves the file as it was, to be given its header the next time it is opened.
+    fn add_header(&mut self, journal_path: &Path) -> Result<(), PagerError> {
+        roll_back_hot_journal(journal_path, &mut self.file).map_err(PagerError::File)?;
+        let mut rows = Vec::new();
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.read_to_end(&mut rows))
+            .map_err(PagerError::File)?;
+        if rows.starts_with(HEADER_MAGIC) {
+            // another process got here first
+            return Ok(());
+        }
+        let num_rows = headerless_rows(&rows).ok_or(PagerError::NotADatabase)?;
+
+        let page_size = HEADERLESS_PAGE_SIZE;
+        let mut journal = Journal::create(journal_path, page_size, rows.len() as u64)
+            .map_err(PagerError::File)?;
+        for (page_num, page) in rows.chunks(page_size).enumerate() {
+            let mut original = page.to_vec();
+            original.resize(page_size, 0);
+            journal
+                .append(page_num as u32, &original)
+                .map_err(PagerError::File)?;
+        }
+        journal.sync(Synchronous::Full).map_err(PagerError::File)?;
+
+        let mut header = Page::new(page_size);
+        init_header(&mut header, page_size);
+        header.buffer[NUM_ROWS_OFFSET..NUM_ROWS_OFFSET + 4]
+            .copy_from_slice(&num_rows.to_be_bytes());
+        rows.resize(rows.len().div_ceil(page_size) * page_size, 0);
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.write_all(&header.buffer))
+            .and_then(|_| self.file.write_all(&rows))
+            .and_then(|_| self.file.sync_all())
+            .map_err(PagerError::File)?;
+        journal.delete().map_err(PagerError::File)
+    }
+
+    // another process may have committed since we last held a lock; if so, our cache is stale
+    fn refresh(&mut self) -> Result<(), PagerError> {
+        let file_length = self.file.seek(SeekFrom::End(0)).map_err(PagerError::File)?;
+        let (page_size, change_counter) = if file_length == 0 {
+            (self.page_size, 0)
+        } else {
+            read_header(&mut self.file)?
+        };
+        if file_length != self.file_length || change_counter != self.change_counter {
+            // Nothing that respects the lock can get here while a reader runs. The pages those
+            // readers see aren't kept anywhere but the file, so all they can do now is fail.
+            if !self.snapshots.is_empty() {
+                self.lost_before = self.version + 1;
+                self.old_versions.clear();
+            }
+            self.version += 1;
+            self.pages.clear();
+            self.file_length = file_length;
+            self.page_size = page_size;
+            self.change_counter = change_counter;
+        }
+        Ok(())
+    }
+
+    // the page as it was when the given version was current
+    fn snapshot_page(&mut self, page_num: u32, snapshot: u64) -> Result<Arc<Page>, PagerError> {
+        if snapshot < self.lost_before {
+            return Err(PagerError::SnapshotLost);
+        }
+        let old_version = self.old_versions.get(&page_num).and_then(|versions| {
+            versions
+                .iter()
+                .find(|(replaced_by, _)| *replaced_by > snapshot)
+        });
+        match old_version {
+            Some((_, page)) => Ok(page.clone()),
+            None => self.committed_page(page_num),
+        }
+    }
+
+    // keeps the content a commit is about to overwrite for as long as a running reader can ask for it
+    fn keep_old_versions(&mut self, originals: HashMap<u32, Arc<Page>>, replaced_by: u64) {
+        if self.snapshots.is_empty() {
+            return;
+        }
+        for (page_num, page) in originals {
+            self.old_versions
+                .entry(page_num)
+                .or_default()
+                .push((replaced_by, page));
+        }
+    }
+
+    fn end_snapshot(&mut self, snapshot: u64) {
+        if let Some(readers) = self.snapshots.get_mut(&snapshot) {
+            *readers -= 1;
+            if *readers == 0 {
+                self.snapshots.remove(&snapshot);
+            }
+        }
+        // only readers on versions before the one that replaced a page can still ask for it
+        match self.snapshots.keys().next().copied() {
+            Some(oldest) => self.old_versions.retain(|_, versions| {
+                versions.retain(|(replaced_by, _)| *replaced_by > oldest);
+                !versions.is_empty()
+            }),
+            None => self.old_versions.clear(),
+        }
+    }
+
+    fn committed_page(&mut self, page_num: u32) -> Result<Arc<Page>, PagerError> {
+        if page_num as usize >= MAX_FILE_PAGES {
+            return Err(PagerError::PagesFull);
+        }
+
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(cached) = self.pages.get_mut(&page_num) {
+            cached.last_used = clock;
+            return Ok(cached.page.clone());
+        }
+
+        self.evict_pages();
+        let mut page = Page::new(self.page_size);
+        let total_num_pages_in_file_now = self.file_length / self.page_size as u64;
+
+        // if the page number requested is greater than the total num of pages
+        // we have recorded in the file then there is nothing in the file for us to read
+        // this will be true the first time we write to a fresh page and until we first write
+        // to the file for that fresh page (bytes in the new page won't be counted until we write to file/disk)
+        if (page_num as u64) < total_num_pages_in_file_now {
+            self.file
+                .seek(SeekFrom::Start(page_num as u64 * self.page_size as u64))
+                .map_err(PagerError::File)?;
+            self.file
+                .read_exact(&mut page.buffer)
+                .map_err(PagerError::File)?;
+        } else if page_num == 0 {
+            // an empty file gets its header with the first commit
+            init_header(&mut page, self.page_size);
+        }
+
+        let page = Arc::new(page);
+        self.pages.insert(
+            page_num,
+            CachedPage {
+                page: page.clone(),
+                last_used: clock,
+            },
+        );
+        Ok(page)
+    }
+
+    // make room for one more page by dropping the least recently used ones;
+    // anybody still reading an evicted page keeps their own reference to it
+    fn evict_pages(&mut self) {
+        while self.pages.len() >= self.cache_size {
+            let victim = self
+                .pages
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(page_num, _)| *page_num);
+            match victim {
+                Some(page_num) => {
+                    self.pages.remove(&page_num);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), PagerError> {
+        match self.synchronous {
+            Synchronous::Off => {
+                self.unsynced = true;
+                Ok(())
+            }
+            // the file length counts as data here, so the pages can always be read back
+            Synchronous::Normal => self.file.sync_data(),
+            Synchronous::Full => self.file.sync_all(),
+        }
+        .map_err(PagerError::File)
+    }
+}
+
+pub struct ReadTransaction<'p> {
+    pager: &'p Pager,
+    // the version this reader sees, whatever gets committed while it runs
+    snapshot: u64,
+}
+
+impl PageRead for ReadTransaction<'_> {
+    fn page(&self, page_num: u32) -> Result<Arc<Page>, PagerError> {
+        self.pager.state().snapshot_page(page_num, self.snapshot)
+    }
+
+    fn page_size(&self) -> usize {
+        self.pager.page_size()
+    }
+}
+
+impl Drop for ReadTransaction<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pager.state();
+        state.end_snapshot(self.snapshot);
+        if let Err(e) = state.release() {
+            eprintln!("db message: failed to release read lock: {:?}", e);
+        }
+    }
+}
+
+pub struct WriteTransaction<'p> {
+    pager: &'p Pager,
+    // the pages this transaction changed, invisible to everybody else until it commits
+    dirty: HashMap<u32, Arc<Page>>,
+    // what the changed pages looked like before, for readers that started before the commit
+    originals: HashMap<u32, Arc<Page>>,
+    // open once the transaction has changed a page that already exists in the file
+    journal: Option<Journal>,
+    finished: bool,
+}
+
+impl PageRead for WriteTransaction<'_> {
+    fn page(&self, page_num: u32) -> Result<Arc<Page>, PagerError> {
+        match self.dirty.get(&page_num) {
+            Some(page) => Ok(page.clone()),
+            None => self.pager.state().committed_page(page_num),
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        self.pager.page_size()
+    }
+}
+
+impl WriteTransaction<'_> {
+    pub fn page_mut(&mut self, page_num: u32) -> Result<&mut Page, PagerError> {
+        if !self.dirty.contains_key(&page_num) {
+            let original = self.pager.state().committed_page(page_num)?;
+            self.journal_page(page_num, &original)?;
+            self.originals.insert(page_num, original.clone());
+            self.dirty.insert(page_num, original);
+        }
+        // the first change copies the page, so readers holding the committed one are unaffected
+        Ok(Arc::make_mut(self.dirty.get_mut(&page_num).unwrap()))
+    }
+
+    // saves the original content of a page the first time this transaction is about to change it
+    fn journal_page(&mut self, page_num: u32, original: &Page) -> Result<(), PagerError> {
+        let state = self.pager.state();
+        let pages_in_file = state.file_length / state.page_size as u64;
+        // pages past the end of the file are undone by truncating it back to its old length
+        if state.journal_mode == JournalMode::Off || page_num as u64 >= pages_in_file {
+            return Ok(());
+        }
+
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => self.journal.insert(
+                Journal::create(&self.pager.journal_path, state.page_size, state.file_length)
+                    .map_err(PagerError::File)?,
+            ),
+        };
+        journal
+            .append(page_num, &original.buffer)
+            .map_err(PagerError::File)
+    }
+
+    // the page size can only change while nothing has been written to the file yet
+    pub fn set_page_size(&mut self, page_size: usize) -> Result<(), PagerError> {
+        let page_size = validate_page_size(page_size)?;
+        let mut state = self.pager.state();
+        if state.file_length > 0 || !self.dirty.is_empty() {
+            return Err(PagerError::NotEmpty);
+        }
+        state.page_size = page_size;
+        state.pages.clear();
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<(), PagerError> {
+        if self.dirty.is_empty() {
+            self.finished = true;
+            return self.pager.state().release_exclusive();
+        }
+
+        let change_counter = self.pager.state().change_counter.wrapping_add(1);
+        let header = self.page_mut(0)?;
+        header.buffer[CHANGE_COUNTER_OFFSET..CHANGE_COUNTER_OFFSET + 4]
+            .copy_from_slice(&change_counter.to_be_bytes());
+        self.finished = true;
+
+        let mut state = self.pager.state();
+        match self.write(&mut state) {
+            Ok(()) => {
+                state.change_counter = change_counter;
+                state.version += 1;
+                let version = state.version;
+                state.keep_old_versions(std::mem::take(&mut self.originals), version);
+                let clock = state.clock;
+                for (page_num, page) in self.dirty.drain() {
+                    state.pages.insert(
+                        page_num,
+                        CachedPage {
+                            page,
+                            last_used: clock,
+                        },
+                    );
+                }
+                state.release_exclusive()
+            }
+            // a commit that failed halfway still has to be undone
+            Err(e) => {
+                self.undo(&mut state)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn write(&mut self, state: &mut PagerState) -> Result<(), PagerError> {
+        if let Some(journal) = &self.journal {
+            journal.sync(state.synchronous).map_err(PagerError::File)?;
+        }
+
+        let mut page_nums: Vec<&u32> = self.dirty.keys().collect();
+        page_nums.sort();
+        for page_num in page_nums {
+            let offset = *page_num as u64 * state.page_size as u64;
+            state
+                .file
+                .seek(SeekFrom::Start(offset))
+                .map_err(PagerError::File)?;
+            state
+                .file
+                .write_all(&self.dirty[page_num].buffer)
+                .map_err(PagerError::File)?;
+            state.file_length = state.file_length.max(offset + state.page_size as u64);
+        }
+        state.sync()?;
+
+        // deleting the journal is what makes the transaction stick
+        match self.journal.take() {
+            Some(journal) => journal.delete().map_err(PagerError::File),
+            None => Ok(()),
+        }
+    }
+
+    // forgets every change this transaction made
+    pub fn rollback(mut self) -> Result<(), PagerError> {
+        self.finished = true;
+        let mut state = self.pager.state();
+        self.undo(&mut state)
+    }
+
+    fn undo(&mut self, state: &mut PagerState) -> Result<(), PagerError> {
+        self.dirty.clear();
+        self.originals.clear();
+        if let Some(mut journal) = self.journal.take() {
+            // a failed commit may have written some of the pages already
+            state.file_length = journal
+                .roll_back(&mut state.file)
+                .map_err(PagerError::File)?;
+            state.sync()?;
+            journal.delete().map_err(PagerError::File)?;
+            state.pages.clear();
+        }
+        state.release_exclusive()
+    }
+}
+
+impl Drop for WriteTransaction<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // best effort only; nobody is left to report a failure to
+        let mut state = self.pager.state();
+        if let Err(e) = self.undo(&mut state) {
+            eprintln!("db message: failed to roll back transaction: {:?}", e);
+        }
+    }
+}
+
+// Pages for the trees a statement keeps its working rows in while it runs. They are the same
+// size as the database's but live in memory only, out of reach of the file, the journal and
+// every transaction, and are gone once whoever asked for them drops them.
+pub struct TempPages {
+    page_size: usize,
+    pages: Vec<Arc<Page>>,
+    free: Vec<u32>,
+}
+
+impl PageRead for TempPages {
+    fn page(&self, page_num: u32) -> Result<Arc<Page>, PagerError> {
+        Ok(self.pages[page_num as usize].clone())
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+}
+
+impl PageWrite for TempPages {
+    fn page_mut(&mut self, page_num: u32) -> Result<&mut Page, PagerError> {
+        Ok(Arc::make_mut(&mut self.pages[page_num as usize]))
+    }
+
+    fn allocate(&mut self) -> Result<u32, PagerError> {
+        if let Some(page_num) = self.free.pop() {
+            return Ok(page_num);
+        }
+        self.pages.push(Arc::new(Page::new(self.page_size)));
+        Ok(self.pages.len() as u32 - 1)
+    }
+
+    fn free(&mut self, page_num: u32) -> Result<(), PagerError> {
+        self.free.push(page_num);
+        Ok(())
+    }
+}
+
+pub fn validate_page_size(page_size: usize) -> Result<usize, PagerError> {
+    if (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) && page_size.is_power_of_two() {
+        Ok(page_size)
+    } else {
+        Err(PagerError::InvalidPageSize)
+    }
+}
+
+fn init_header(page: &mut Page, page_size: usize) {
+    page.buffer[..HEADER_MAGIC.len()].copy_from_slice(HEADER_MAGIC);
+    page.buffer[PAGE_SIZE_OFFSET..PAGE_SIZE_OFFSET + 4]
+        .copy_from_slice(&(page_size as u32).to_be_bytes());
+}
+
+// The number of rows in a file without a header, if that's all it holds: every page but the
+// last is full of rows with nothing after them, the last holds a whole number of rows, and
+// every row's text columns hold what could have been typed into them, padded with NULs.
+fn headerless_rows(file: &[u8]) -> Option<u32> {
+    let rows_per_page = HEADERLESS_PAGE_SIZE / ROW_SIZE;
+    let pages: Vec<&[u8]> = file.chunks(HEADERLESS_PAGE_SIZE).collect();
+    let (last, full) = pages.split_last()?;
+    if pages.len() > MAX_PAGES
+        || full
+            .iter()
+            .any(|page| page[rows_per_page * ROW_SIZE..].iter().any(|b| *b != 0))
+    {
+        return None;
+    }
+    let last_rows = match last.len() {
+        HEADERLESS_PAGE_SIZE => last[rows_per_page * ROW_SIZE..]
+            .iter()
+            .all(|b| *b == 0)
+            .then_some(rows_per_page),
+        length if length.is_multiple_of(ROW_SIZE) => Some(length / ROW_SIZE),
+        _ => None,
+    }?;
+    let mut problems = Vec::new();
+    let rows = full
+        .iter()
+        .flat_map(|page| page.chunks(ROW_SIZE).take(rows_per_page))
+        .chain(last.chunks(ROW_SIZE).take(last_rows));
+    for (row_num, row) in (0..).zip(rows) {
+        check_text_column(
+            row_num,
+            "username",
+            &row[USERNAME_OFFSET..EMAIL_OFFSET],
+            &mut problems,
+        );
+        check_text_column(
+            row_num,
+            "email",
+            &row[EMAIL_OFFSET..ROW_SIZE],
+            &mut problems,
+        );
+        // the values were typed on one line and split on spaces, so none of them holds either
+        if row[USERNAME_OFFSET..]
+            .iter()
+            .any(|b| *b == b' ' || *b == b'\n')
+        {
+            return None;
+        }
+    }
+    problems
+        .is_empty()
+        .then_some((full.len() * rows_per_page + last_rows) as u32)
+}
+
+// the header lives at the start of page 0, so it can be read before the page size is known
+fn read_header(file: &mut File) -> Result<(usize, u32), PagerError> {
+    let mut header = [0u8; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_exact(&mut header))
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => PagerError::NotADatabase,
+            _ => PagerError::File(e),
+        })?;
+    if &header[..HEADER_MAGIC.len()] != HEADER_MAGIC {
+        return Err(PagerError::NotADatabase);
+    }
+    let page_size = u32::from_be_bytes(
+        header[PAGE_SIZE_OFFSET..PAGE_SIZE_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    );
+    let change_counter = u32::from_be_bytes(
+        header[CHANGE_COUNTER_OFFSET..CHANGE_COUNTER_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    );
+    validate_page_size(page_size as usize)
+        .map(|page_size| (page_size, change_counter))
+        .map_err(|_| PagerError::NotADatabase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ensure_clean_fs(test_file_name: &str) {
+        std::fs::remove_file(test_file_name)
+            .or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+            .expect("could not clean up database files before running tests");
+    }
+
+    fn clean_test(test_case: &str, test: fn(&Pager)) {
+        let test_file_name = format!("test-pager-for-{}.db", test_case);
+        ensure_clean_fs(&test_file_name);
+        let options = PagerOptions {
+            synchronous: Synchronous::Off,
+            ..PagerOptions::default()
+        };
+        test(&Pager::new(&test_file_name, options).unwrap());
+        ensure_clean_fs(&test_file_name);
+    }
+
+    fn first_byte(pages: &dyn PageRead, page_num: u32) -> u8 {
+        pages.page(page_num).unwrap().buffer[0]
+    }
+
+    #[test]
+    fn readers_on_other_threads_only_see_committed_pages() {
+        let test = |pager: &Pager| {
+            let mut write = pager.begin_write().unwrap();
+            write.page_mut(1).unwrap().buffer[0] = 1;
+            write.commit().unwrap();
+
+            let mut write = pager.begin_write().unwrap();
+            write.page_mut(1).unwrap().buffer[0] = 2;
+            // readers run side by side, and alongside the writer, without seeing its changes
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        let read = pager.begin_read().unwrap();
+                        assert_eq!(first_byte(&read, 1), 1);
+                    });
+                }
+            });
+            assert_eq!(first_byte(&write, 1), 2);
+            write.commit().unwrap();
+            assert_eq!(first_byte(&pager.begin_read().unwrap(), 1), 2);
+        };
+
+        clean_test("readers_on_other_threads_only_see_committed_pages", test);
+    }
+
+    #[test]
+    fn changes_that_are_not_committed_are_never_seen() {
+        let test = |pager: &Pager| {
+            let mut write = pager.begin_write().unwrap();
+            write.page_mut(1).unwrap().buffer[0] = 1;
+            write.commit().unwrap();
+
+            let mut write = pager.begin_write().unwrap();
+            write.page_mut(1).unwrap().buffer[0] = 2;
+            write.rollback().unwrap();
+            assert_eq!(first_byte(&pager.begin_read().unwrap(), 1), 1);
+
+            let mut write = pager.begin_write().unwrap();
+            write.page_mut(1).unwrap().buffer[0] = 3;
+            drop(write);
+            assert_eq!(first_byte(&pager.begin_read().unwrap(), 1), 1);
+        };
+
+        clean_test("changes_that_are_not_committed_are_never_seen", test);
+    }
+
+    #[test]
+    fn readers_keep_the_snapshot_they_started_with() {
+        let test = |pager: &Pager| {
+            let mut write = pager.begin_write().unwrap();
+            write.page_mut(1).unwrap().buffer[0] = 1;
+            write.page_mut(2).unwrap().buffer[0] = 1;
+            write.commit().unwrap();
+
+            let before = pager.begin_read().unwrap();
+            assert_eq!(first_byte(&before, 1), 1);
+            // the writer doesn't wait for the reader, which doesn't see what the writer did,
+            // not even on the pages it hadn't read yet
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    for value in 2..=3 {
+                        let mut write = pager.begin_write().unwrap();
+                        write.page_mut(1).unwrap().buffer[0] = value;
+                        write.page_mut(2).unwrap().buffer[0] = value;
+                        write.commit().unwrap();
+                    }
+                });
+            });
+            let after = pager.begin_read().unwrap();
+            assert_eq!((first_byte(&before, 1), first_byte(&before, 2)), (1, 1));
+            assert_eq!((first_byte(&after, 1), first_byte(&after, 2)), (3, 3));
+
+            // nothing holds on to the old pages once the readers that could see them are gone
+            drop(before);
+            assert!(pager.state().old_versions.is_empty());
+            drop(after);
+        };
+
+        clean_test("readers_keep_the_snapshot_they_started_with", test);
+    }
+}
@@ -0,0 +1,1567 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::ast::*;
+use crate::constants::*;
+use crate::error::ErrorCode;
+use crate::value::Value;
+use crate::virtual_machine::check_column;
+
+#[derive(Debug)]
+pub enum StatementError {
+    Sql,
+    TooLong,
+    InvalidId,
+    WrongType,
+    MissingParameter,
+    UnknownParameter,
+    TooManyParameters,
+    // an expression with more than MAX_EXPR_DEPTH expressions inside each other
+    TooDeep,
+    // more than MAX_PARSER_DEPTH parentheses, subqueries or operators inside each other
+    TooNested,
+    // a query reading from more than MAX_JOIN_TABLES tables
+    TooManyTables,
+    NoSuchTable(String),
+    NoSuchView(String),
+    NoSuchTrigger(String),
+    NoSuchColumn(String),
+    // a column name that more than one table in the query has
+    AmbiguousColumn(String),
+    NoSuchFunction(String),
+    AlreadyExists(String),
+    DuplicateColumn(String),
+    // a column's default value that reads something other than constants
+    NotConstant(String),
+    // a change ALTER TABLE can't make, and why
+    CannotAlter(String),
+    // a table, index, view or trigger that a change to the schema would leave making no sense
+    ErrorIn {
+        entry: String,
+        error: Box<StatementError>,
+    },
+    // a foreign key referring to a column that isn't its table's key
+    ForeignKeyMismatch {
+        table: String,
+        parent: String,
+    },
+    // a subquery used as a value that returns some other number of columns than one
+    SubqueryColumns(usize),
+    // the queries of a union return different numbers of columns
+    UnionColumns,
+    // a table in a WITH clause whose column names don't match its query's columns
+    TableColumns {
+        table: String,
+        values: usize,
+        columns: usize,
+    },
+    // a recursive table in a WITH clause with no rows to start from
+    CircularReference(String),
+}
+
+impl StatementError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            StatementError::Sql => ErrorCode::Error,
+            StatementError::TooLong => ErrorCode::TooBig,
+            StatementError::InvalidId => ErrorCode::Range,
+            StatementError::WrongType => ErrorCode::Mismatch,
+            StatementError::MissingParameter => ErrorCode::Misuse,
+            StatementError::UnknownParameter | StatementError::TooManyParameters => {
+                ErrorCode::Range
+            }
+            StatementError::NoSuchTable(_)
+            | StatementError::NoSuchView(_)
+            | StatementError::NoSuchTrigger(_)
+            | StatementError::NoSuchColumn(_)
+            | StatementError::AmbiguousColumn(_)
+            | StatementError::NoSuchFunction(_)
+            | StatementError::AlreadyExists(_)
+            | StatementError::DuplicateColumn(_)
+            | StatementError::NotConstant(_)
+            | StatementError::ForeignKeyMismatch { .. }
+            | StatementError::CannotAlter(_)
+            | StatementError::ErrorIn { .. }
+            | StatementError::SubqueryColumns(_)
+            | StatementError::UnionColumns
+            | StatementError::TableColumns { .. }
+            | StatementError::CircularReference(_)
+            | StatementError::TooDeep
+            | StatementError::TooNested
+            | StatementError::TooManyTables => ErrorCode::Error,
+        }
+    }
+}
+
+impl fmt::Display for StatementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatementError::Sql => write!(f, "syntax error"),
+            StatementError::TooLong => write!(f, "string is too long for its column"),
+            StatementError::InvalidId => {
+                write!(f, "id must be an integer between 0 and {}", u32::MAX)
+            }
+            StatementError::WrongType => write!(f, "value has the wrong type for its column"),
+            StatementError::MissingParameter => write!(f, "a parameter has no value bound to it"),
+            StatementError::UnknownParameter => write!(f, "no such parameter"),
+            StatementError::TooManyParameters => write!(
+                f,
+                "parameters must be numbered between ?1 and ?{}",
+                MAX_PARAMETERS
+            ),
+            StatementError::TooDeep => write!(
+                f,
+                "Expression tree is too large (maximum depth {})",
+                MAX_EXPR_DEPTH
+            ),
+            StatementError::TooNested => write!(f, "parser stack overflow"),
+            StatementError::TooManyTables => {
+                write!(f, "at most {} tables in a join", MAX_JOIN_TABLES)
+            }
+            StatementError::NoSuchTable(name) => write!(f, "no such table: {}", name),
+            StatementError::NoSuchView(name) => write!(f, "no such view: {}", name),
+            StatementError::NoSuchTrigger(name) => write!(f, "no such trigger: {}", name),
+            StatementError::NoSuchColumn(name) => write!(f, "no such column: {}", name),
+            StatementError::AmbiguousColumn(name) => write!(f, "ambiguous column name: {}", name),
+            StatementError::NoSuchFunction(name) => write!(f, "no such function: {}", name),
+            StatementError::AlreadyExists(name) => write!(f, "{} already exists", name),
+            StatementError::DuplicateColumn(name) => write!(f, "duplicate column name: {}", name),
+            StatementError::NotConstant(name) => {
+                write!(f, "default value of column [{}] is not constant", name)
+            }
+            StatementError::CannotAlter(reason) => write!(f, "{}", reason),
+            StatementError::ErrorIn { entry, error } => write!(f, "error in {}: {}", entry, error),
+            StatementError::ForeignKeyMismatch { table, parent } => write!(
+                f,
+                "foreign key mismatch - \"{}\" referencing \"{}\"",
+                table, parent
+            ),
+            StatementError::SubqueryColumns(count) => {
+                write!(f, "sub-select returns {} columns - expected 1", count)
+            }
+            StatementError::UnionColumns => write!(
+                f,
+                "SELECTs to the left and right of UNION do not have the same number of result columns"
+            ),
+            StatementError::TableColumns {
+                table,
+                values,
+                columns,
+            } => write!(
+                f,
+                "table {} has {} values for {} columns",
+                table, values, columns
+            ),
+            StatementError::CircularReference(name) => write!(f, "circular reference: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for StatementError {}
+
+// words that end an expression or a table name, so they can't be taken for an alias
+const RESERVED: &[&str] = &[
+    "from", "where", "on", "join", "inner", "left", "cross", "and", "or", "not", "as", "select",
+    "union", "limit",
+];
+
+// words that start a column constraint, and so end the column's type
+const COLUMN_CONSTRAINTS: &[&str] = &[
+    "constraint",
+    "primary",
+    "not",
+    "null",
+    "unique",
+    "check",
+    "default",
+    "references",
+];
+
+// returns the statement along with the name of each of its parameters, for those that have one
+pub fn prepare_statement(input: &str) -> Result<(Statement, Vec<Option<String>>), StatementError> {
+    let mut params = Vec::new();
+    let statement = parse(input, &mut params)?;
+    Ok((statement, params))
+}
+
+fn parse(input: &str, params: &mut Vec<Option<String>>) -> Result<Statement, StatementError> {
+    let input = input.trim();
+    let (first, rest) = first_word(input);
+    if first.eq_ignore_ascii_case("explain") {
+        let (second, after_second) = first_word(rest);
+        let (third, after_third) = first_word(after_second);
+        let (query_plan, explained) =
+            if second.eq_ignore_ascii_case("query") && third.eq_ignore_ascii_case("plan") {
+                (true, after_third)
+            } else {
+                (false, rest)
+            };
+        return Ok(Statement::Explain {
+            query_plan,
+            statement: Box::new(parse(explained, params)?),
+        });
+    }
+    // the first statements this database understood keep their own, simpler syntax
+    let simple = input.strip_suffix(';').unwrap_or(input).trim_end();
+    if first.eq_ignore_ascii_case("insert") && !first_word(rest).0.eq_ignore_ascii_case("into") {
+        return parse_legacy_insert(simple, params);
+    }
+    if first.eq_ignore_ascii_case("pragma") {
+        return parse_pragma(first_word(simple).1, params);
+    }
+    let mut parser = Parser {
+        source: input,
+        tokens: tokenize(input, params)?,
+        position: 0,
+        depth: 0,
+    };
+    let statement = parser.statement()?;
+    parser.accept_symbol(";");
+    if parser.position < parser.tokens.len() {
+        return Err(StatementError::Sql);
+    }
+    Ok(statement)
+}
+
+fn first_word(input: &str) -> (&str, &str) {
+    let input = input.trim_start();
+    match input.find(char::is_whitespace) {
+        Some(end) => (&input[..end], input[end..].trim_start()),
+        None => (input, ""),
+    }
+}
+
+// insert id username email, with the values separated by single spaces
+fn parse_legacy_insert(
+    input: &str,
+    params: &mut Vec<Option<String>>,
+) -> Result<Statement, StatementError> {
+    let mut parts = input.split(' ');
+    let id = parts.nth(1);
+    let username = parts.next();
+    let email = parts.next();
+    match (id, username, email) {
+        (Some(id), Some(username), Some(email)) => {
+            let id = match placeholder(id, params)? {
+                Some(param) => Expr::Param(param),
+                None => Expr::Literal(Value::Integer(
+                    id.parse::<u32>().map_err(|_| StatementError::InvalidId)? as i64,
+                )),
+            };
+            let mut text = |text: &str| {
+                placeholder(text, params).map(|param| match param {
+                    Some(param) => Expr::Param(param),
+                    None => Expr::Literal(Value::Text(text.to_string())),
+                })
+            };
+            let values = vec![id, text(username)?, text(email)?];
+            // values written into the statement are checked before it ever runs
+            for (column, value) in values.iter().enumerate() {
+                if let Expr::Literal(value) = value {
+                    check_column(column, value)?;
+                }
+            }
+            Ok(Statement::Insert(Insert {
+                table: TABLE_NAME.to_string(),
+                columns: None,
+                rows: vec![values],
+            }))
+        }
+        _ => Err(StatementError::Sql),
+    }
+}
+
+// pragma name | pragma name = value | pragma name(value), where the value is taken as it is
+fn parse_pragma(
+    pragma: &str,
+    params: &mut Vec<Option<String>>,
+) -> Result<Statement, StatementError> {
+    let pragma = pragma.trim();
+    let mut parts = match pragma.strip_suffix(')') {
+        Some(call) => call.splitn(2, '('),
+        None => pragma.splitn(2, '='),
+    };
+    let name = parts.next().map(str::trim).unwrap_or("");
+    let value = parts.next().map(str::trim);
+    match (name, value) {
+        ("", _) | (_, Some("")) => Err(StatementError::Sql),
+        (name, None) => Ok(Statement::Pragma {
+            name: name.to_string(),
+            value: None,
+        }),
+        (name, Some(value)) => Ok(Statement::Pragma {
+            name: name.to_string(),
+            value: Some(match placeholder(value, params)? {
+                Some(param) => Expr::Param(param),
+                None => Expr::Literal(Value::Text(value.to_string())),
+            }),
+        }),
+    }
+}
+
+// `?` takes the next parameter, `?N` the Nth one and `:name` the same parameter every time the
+// name is used; returns the index of the parameter, or None if the token is an ordinary value
+fn placeholder(
+    token: &str,
+    params: &mut Vec<Option<String>>,
+) -> Result<Option<usize>, StatementError> {
+    if (token == "?" || token.starts_with(':')) && params.len() >= MAX_PARAMETERS {
+        return Err(StatementError::TooManyParameters);
+    }
+    if token == "?" {
+        params.push(None);
+        Ok(Some(params.len() - 1))
+    } else if let Some(number) = token.strip_prefix('?') {
+        match number.parse::<usize>() {
+            Ok(number) if number > MAX_PARAMETERS => Err(StatementError::TooManyParameters),
+            Ok(number) if number > 0 => {
+                if number > params.len() {
+                    params.resize(number, None);
+                }
+                Ok(Some(number - 1))
+            }
+            _ => Err(StatementError::Sql),
+        }
+    } else if let Some(name) = token.strip_prefix(':') {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(StatementError::Sql);
+        }
+        match params
+            .iter()
+            .position(|param| param.as_deref() == Some(name))
+        {
+            Some(index) => Ok(Some(index)),
+            None => {
+                params.push(Some(name.to_string()));
+                Ok(Some(params.len() - 1))
+            }
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // a keyword or a name; which one depends on where it is
+    Word(String),
+    // a name in double quotes, which is never a keyword
+    Quoted(String),
+    Integer(i64),
+    Text(String),
+    Param(usize),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "||", "==", "!=", "<>", "<=", ">=", "(", ")", ",", ".", ";", "*", "+", "-", "/", "%", "=", "<",
+    ">",
+];
+
+fn tokenize(
+    input: &str,
+    params: &mut Vec<Option<String>>,
+) -> Result<Vec<(Token, Range<usize>)>, StatementError> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        let token = if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        } else if input[i..].starts_with("--") {
+            i = input[i..].find('\n').map_or(bytes.len(), |end| i + end);
+            continue;
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            Token::Word(input[start..i].to_string())
+        } else if c.is_ascii_digit() {
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            let number = input[start..i].parse().map_err(|_| StatementError::Sql)?;
+            Token::Integer(number)
+        } else if c == b'\'' || c == b'"' {
+            // the quote is written twice to put it inside the string
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match input[i..].find(c as char) {
+                    Some(end) => {
+                        text.push_str(&input[i..i + end]);
+                        i += end + 1;
+                        if bytes.get(i) == Some(&c) {
+                            text.push(c as char);
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    None => return Err(StatementError::Sql),
+                }
+            }
+            if c == b'\'' {
+                Token::Text(text)
+            } else {
+                Token::Quoted(text)
+            }
+        } else if c == b'?' || c == b':' {
+            i += 1;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            let param = placeholder(&input[start..i], params)?;
+            Token::Param(param.ok_or(StatementError::Sql)?)
+        } else {
+            match SYMBOLS
+                .iter()
+                .find(|symbol| input[i..].starts_with(**symbol))
+            {
+                Some(symbol) => {
+                    i += symbol.len();
+                    Token::Symbol(symbol)
+                }
+                None => return Err(StatementError::Sql),
+            }
+        };
+        tokens.push((token, start..i));
+    }
+    Ok(tokens)
+}
+
+struct Parser<'s> {
+    source: &'s str,
+    tokens: Vec<(Token, Range<usize>)>,
+    position: usize,
+    // how many parentheses, subqueries and operators the parser is inside of
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek_keyword(keyword);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), StatementError> {
+        if self.accept_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(StatementError::Sql)
+        }
+    }
+
+    fn accept_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(found)) if *found == symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), StatementError> {
+        if self.accept_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(StatementError::Sql)
+        }
+    }
+
+    fn name(&mut self) -> Result<String, StatementError> {
+        match self.peek() {
+            Some(Token::Word(word)) if !is_reserved(word) => {
+                let word = word.clone();
+                self.position += 1;
+                Ok(word)
+            }
+            Some(Token::Quoted(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(StatementError::Sql),
+        }
+    }
+
+    // `as name`, or just the name
+    fn alias(&mut self) -> Result<Option<String>, StatementError> {
+        if self.accept_keyword("as") {
+            return self.name().map(Some);
+        }
+        match self.peek() {
+            Some(Token::Word(word)) if !is_reserved(word) => self.name().map(Some),
+            Some(Token::Quoted(_)) => self.name().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // the statement as it was written from the token at `start` up to the current one
+    fn text_since(&self, start: usize) -> String {
+        let from = self.tokens[start].1.start;
+        let to = self.tokens[self.position - 1].1.end;
+        self.source[from..to].to_string()
+    }
+
+    fn statement(&mut self) -> Result<Statement, StatementError> {
+        if self.peek_select() {
+            self.select().map(Statement::Select)
+        } else if self.accept_keyword("insert") {
+            self.insert().map(Statement::Insert)
+        } else if self.accept_keyword("update") {
+            self.update().map(Statement::Update)
+        } else if self.accept_keyword("delete") {
+            self.delete().map(Statement::Delete)
+        } else if self.accept_keyword("create") {
+            if self.accept_keyword("table") {
+                self.create_table().map(Statement::CreateTable)
+            } else if self.accept_keyword("index") {
+                self.create_index(false).map(Statement::CreateIndex)
+            } else if self.accept_keyword("unique") {
+                self.expect_keyword("index")?;
+                self.create_index(true).map(Statement::CreateIndex)
+            } else if self.accept_keyword("view") {
+                self.create_view().map(Statement::CreateView)
+            } else if self.accept_keyword("trigger") {
+                self.create_trigger().map(Statement::CreateTrigger)
+            } else {
+                Err(StatementError::Sql)
+            }
+        } else if self.accept_keyword("drop") {
+            let view = if self.accept_keyword("view") {
+                true
+            } else {
+                self.expect_keyword("trigger")?;
+                false
+            };
+            let if_exists = if self.accept_keyword("if") {
+                self.expect_keyword("exists")?;
+                true
+            } else {
+                false
+            };
+            let name = self.name()?;
+            Ok(if view {
+                Statement::DropView { name, if_exists }
+            } else {
+                Statement::DropTrigger { name, if_exists }
+            })
+        } else if self.accept_keyword("alter") {
+            self.expect_keyword("table")?;
+            self.alter_table().map(Statement::AlterTable)
+        } else if self.accept_keyword("analyze") {
+            let table = match self.peek() {
+                Some(_) => Some(self.name()?),
+                None => None,
+            };
+            Ok(Statement::Analyze { table })
+        } else {
+            Err(StatementError::Sql)
+        }
+    }
+
+    fn peek_select(&self) -> bool {
+        self.peek_keyword("select") || self.peek_keyword("with")
+    }
+
+    fn select(&mut self) -> Result<Select, StatementError> {
+        let with = if self.accept_keyword("with") {
+            Some(self.with()?)
+        } else {
+            None
+        };
+        let mut select = self.simple_select()?;
+        while self.accept_keyword("union") {
+            let all = self.accept_keyword("all");
+            let union = self.simple_select()?;
+            select.unions.push(Union { all, select: union });
+        }
+        if self.accept_keyword("limit") {
+            select.limit = Some(self.expr()?);
+        }
+        select.with = with;
+        Ok(select)
+    }
+
+    fn with(&mut self) -> Result<With, StatementError> {
+        let recursive = self.accept_keyword("recursive");
+        let mut tables = Vec::new();
+        loop {
+            let name = self.name()?;
+            let columns = if self.accept_symbol("(") {
+                Some(self.names()?)
+            } else {
+                None
+            };
+            self.expect_keyword("as")?;
+            self.expect_symbol("(")?;
+            let select = self.nested(Self::select)?;
+            self.expect_symbol(")")?;
+            tables.push(CommonTable {
+                name,
+                columns,
+                select,
+            });
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        Ok(With { recursive, tables })
+    }
+
+    fn simple_select(&mut self) -> Result<Select, StatementError> {
+        self.expect_keyword("select")?;
+        // on its own, select reads every row of the users table
+        if self.peek().is_none() {
+            return Ok(Select {
+                with: None,
+                columns: vec![ResultColumn::All(None)],
+                from: vec![TableRef {
+                    name: TABLE_NAME.to_string(),
+                    alias: None,
+                    join: JoinKind::Inner,
+                    on: None,
+                    select: None,
+                }],
+                filter: None,
+                unions: Vec::new(),
+                limit: None,
+            });
+        }
+        let mut columns = Vec::new();
+        loop {
+            columns.push(self.result_column()?);
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        let mut from = Vec::new();
+        if self.accept_keyword("from") {
+            let mut join = JoinKind::Inner;
+            loop {
+                let (name, select) = if self.accept_symbol("(") {
+                    let select = self.nested(Self::select)?;
+                    self.expect_symbol(")")?;
+                    ("(subquery)".to_string(), Some(Box::new(select)))
+                } else {
+                    (self.name()?, None)
+                };
+                let alias = self.alias()?;
+                let on = if !from.is_empty() && self.accept_keyword("on") {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                from.push(TableRef {
+                    name,
+                    alias,
+                    join,
+                    on,
+                    select,
+                });
+                join = if self.accept_symbol(",") {
+                    JoinKind::Inner
+                } else if self.accept_keyword("left") {
+                    self.accept_keyword("outer");
+                    self.expect_keyword("join")?;
+                    JoinKind::Left
+                } else if self.accept_keyword("join") {
+                    JoinKind::Inner
+                } else if self.accept_keyword("inner") || self.accept_keyword("cross") {
+                    self.expect_keyword("join")?;
+                    JoinKind::Inner
+                } else {
+                    break;
+                };
+            }
+        }
+        let filter = self.filter()?;
+        Ok(Select {
+            with: None,
+            columns,
+            from,
+            filter,
+            unions: Vec::new(),
+            limit: None,
+        })
+    }
+
+    fn result_column(&mut self) -> Result<ResultColumn, StatementError> {
+        if self.accept_symbol("*") {
+            return Ok(ResultColumn::All(None));
+        }
+        // t.*
+        if let (Some(Token::Word(_)) | Some(Token::Quoted(_)), Some((Token::Symbol("."), _))) =
+            (self.peek(), self.tokens.get(self.position + 1))
+        {
+            if let Some((Token::Symbol("*"), _)) = self.tokens.get(self.position + 2) {
+                let table = self.name()?;
+                self.position += 2;
+                return Ok(ResultColumn::All(Some(table)));
+            }
+        }
+        let start = self.position;
+        let expr = self.expr()?;
+        let name = match self.alias()? {
+            Some(alias) => alias,
+            None => match &expr {
+                Expr::Column { name, .. } => name.clone(),
+                _ => self.text_since(start),
+            },
+        };
+        Ok(ResultColumn::Expr { expr, name })
+    }
+
+    // insert into name [(columns)] values (values), ...
+    fn insert(&mut self) -> Result<Insert, StatementError> {
+        self.expect_keyword("into")?;
+        let table = self.name()?;
+        let columns = if self.accept_symbol("(") {
+            Some(self.names()?)
+        } else {
+            None
+        };
+        self.expect_keyword("values")?;
+        let mut rows = Vec::new();
+        loop {
+            self.expect_symbol("(")?;
+            let mut row = Vec::new();
+            loop {
+                row.push(self.expr()?);
+                if !self.accept_symbol(",") {
+                    break;
+                }
+            }
+            self.expect_symbol(")")?;
+            rows.push(row);
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        Ok(Insert {
+            table,
+            columns,
+            rows,
+        })
+    }
+
+    // update name set column = value, ... [where condition]
+    fn update(&mut self) -> Result<Update, StatementError> {
+        let table = self.name()?;
+        self.expect_keyword("set")?;
+        let mut assignments = Vec::new();
+        loop {
+            let column = self.name()?;
+            self.expect_symbol("=")?;
+            assignments.push((column, self.expr()?));
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        Ok(Update {
+            table,
+            assignments,
+            filter: self.filter()?,
+        })
+    }
+
+    // delete from name [where condition]
+    fn delete(&mut self) -> Result<Delete, StatementError> {
+        self.expect_keyword("from")?;
+        let table = self.name()?;
+        Ok(Delete {
+            table,
+            filter: self.filter()?,
+        })
+    }
+
+    fn filter(&mut self) -> Result<Option<Expr>, StatementError> {
+        if self.accept_keyword("where") {
+            self.expr().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // a list of names up to its closing parenthesis
+    fn names(&mut self) -> Result<Vec<String>, StatementError> {
+        let mut names = Vec::new();
+        loop {
+            names.push(self.name()?);
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+        Ok(names)
+    }
+
+    fn create_table(&mut self) -> Result<CreateTable, StatementError> {
+        let if_not_exists = self.if_not_exists()?;
+        let name = self.name()?;
+        self.expect_symbol("(")?;
+        let mut columns = Vec::new();
+        loop {
+            columns.push(self.column_spec()?);
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+        Ok(CreateTable {
+            name,
+            columns,
+            if_not_exists,
+            sql: self.text_since(0),
+        })
+    }
+
+    // the table, then one change to it: a column added, renamed or dropped, or a new name
+    fn alter_table(&mut self) -> Result<AlterTable, StatementError> {
+        let table = self.name()?;
+        let change = if self.accept_keyword("add") {
+            self.accept_keyword("column");
+            TableChange::AddColumn(Box::new(self.column_spec()?))
+        } else if self.accept_keyword("rename") {
+            if self.accept_keyword("to") {
+                TableChange::RenameTo(self.name()?)
+            } else {
+                self.accept_keyword("column");
+                let from = self.name()?;
+                self.expect_keyword("to")?;
+                TableChange::RenameColumn {
+                    from,
+                    to: self.name()?,
+                }
+            }
+        } else {
+            self.expect_keyword("drop")?;
+            self.accept_keyword("column");
+            TableChange::DropColumn(self.name()?)
+        };
+        Ok(AlterTable { table, change })
+    }
+
+    // a column's name, then its type and constraints, if it has any
+    fn column_spec(&mut self) -> Result<ColumnSpec, StatementError> {
+        let start = self.position;
+        let name = self.name()?;
+        let mut data_type = Vec::new();
+        while let Some(Token::Word(word)) = self.peek() {
+            if COLUMN_CONSTRAINTS
+                .iter()
+                .any(|constraint| word.eq_ignore_ascii_case(constraint))
+            {
+                break;
+            }
+            data_type.push(word.clone());
+            self.position += 1;
+        }
+        // a size, like varchar(32), is kept as part of the type
+        if !data_type.is_empty() && self.accept_symbol("(") {
+            let start = self.position - 1;
+            while !self.accept_symbol(")") {
+                match self.peek() {
+                    Some(Token::Integer(_)) | Some(Token::Symbol(",")) => self.position += 1,
+                    _ => return Err(StatementError::Sql),
+                }
+            }
+            let size = self.text_since(start);
+            data_type.last_mut().unwrap().push_str(&size);
+        }
+        let mut column = ColumnSpec {
+            name,
+            sql: String::new(),
+            data_type: data_type.join(" "),
+            primary_key: false,
+            not_null: false,
+            unique: false,
+            check: None,
+            check_name: None,
+            default: None,
+            references: None,
+        };
+        loop {
+            // any constraint can be given a name, though only a CHECK's errors use it
+            let name = match self.accept_keyword("constraint") {
+                true => Some(self.name()?),
+                false => None,
+            };
+            if self.accept_keyword("primary") {
+                self.expect_keyword("key")?;
+                column.primary_key = true;
+            } else if self.accept_keyword("not") {
+                self.expect_keyword("null")?;
+                column.not_null = true;
+            } else if self.accept_keyword("null") {
+                // says what is so anyway
+            } else if self.accept_keyword("unique") {
+                column.unique = true;
+            } else if self.accept_keyword("check") {
+                self.expect_symbol("(")?;
+                column.check = Some(self.expr()?);
+                column.check_name = name;
+                self.expect_symbol(")")?;
+            } else if self.accept_keyword("default") {
+                column.default = Some(self.default_value()?);
+            } else if self.accept_keyword("references") {
+                column.references = Some(self.foreign_key()?);
+            } else if name.is_some() {
+                return Err(StatementError::Sql);
+            } else {
+                break;
+            }
+        }
+        column.sql = self.text_since(start);
+        Ok(column)
+    }
+
+    // `references table (column)`, then what happens when the row referred to changes, and
+    // whether the key is checked at commit
+    fn foreign_key(&mut self) -> Result<ForeignKey, StatementError> {
+        let mut key = ForeignKey {
+            table: self.name()?,
+            column: None,
+            on_delete: ForeignKeyAction::NoAction,
+            on_update: ForeignKeyAction::NoAction,
+            deferred: false,
+        };
+        if self.accept_symbol("(") {
+            key.column = Some(self.name()?);
+            self.expect_symbol(")")?;
+        }
+        while self.accept_keyword("on") {
+            let delete = self.accept_keyword("delete");
+            if !delete {
+                self.expect_keyword("update")?;
+            }
+            let action = if self.accept_keyword("set") {
+                self.expect_keyword("null")?;
+                ForeignKeyAction::SetNull
+            } else if self.accept_keyword("cascade") {
+                ForeignKeyAction::Cascade
+            } else if self.accept_keyword("restrict") {
+                ForeignKeyAction::Restrict
+            } else {
+                self.expect_keyword("no")?;
+                self.expect_keyword("action")?;
+                ForeignKeyAction::NoAction
+            };
+            if delete {
+                key.on_delete = action;
+            } else {
+                key.on_update = action;
+            }
+        }
+        let start = self.position;
+        let not = self.accept_keyword("not");
+        if self.accept_keyword("deferrable") {
+            if self.accept_keyword("initially") {
+                key.deferred = self.accept_keyword("deferred");
+                if !key.deferred {
+                    self.expect_keyword("immediate")?;
+                }
+            }
+            key.deferred &= !not;
+        } else {
+            // the NOT starts the column's next constraint
+            self.position = start;
+        }
+        Ok(key)
+    }
+
+    // a literal, which can be negative, or an expression in parentheses
+    fn default_value(&mut self) -> Result<ColumnDefault, StatementError> {
+        let start = self.position;
+        let value = if self.accept_symbol("(") {
+            let value = self.expr()?;
+            self.expect_symbol(")")?;
+            value
+        } else {
+            self.unary()?
+        };
+        Ok(ColumnDefault {
+            value,
+            sql: self.text_since(start),
+        })
+    }
+
+    fn create_index(&mut self, unique: bool) -> Result<CreateIndex, StatementError> {
+        let if_not_exists = self.if_not_exists()?;
+        let name = self.name()?;
+        self.expect_keyword("on")?;
+        let table = self.name()?;
+        self.expect_symbol("(")?;
+        let columns = self.names()?;
+        Ok(CreateIndex {
+            name,
+            table,
+            columns,
+            unique,
+            if_not_exists,
+            sql: self.text_since(0),
+        })
+    }
+
+    fn create_view(&mut self) -> Result<CreateView, StatementError> {
+        let if_not_exists = self.if_not_exists()?;
+        let name = self.name()?;
+        let columns = if self.accept_symbol("(") {
+            Some(self.names()?)
+        } else {
+            None
+        };
+        self.expect_keyword("as")?;
+        let select = self.select()?;
+        Ok(CreateView {
+            name,
+            columns,
+            select,
+            if_not_exists,
+            sql: self.text_since(0),
+        })
+    }
+
+    // create trigger name [before | after] insert | update | delete on table [for each row]
+    // [when condition] begin statement; ... end
+    fn create_trigger(&mut self) -> Result<CreateTrigger, StatementError> {
+        let if_not_exists = self.if_not_exists()?;
+        let name = self.name()?;
+        let timing = if self.accept_keyword("after") {
+            TriggerTiming::After
+        } else {
+            self.accept_keyword("before");
+            TriggerTiming::Before
+        };
+        let event = if self.accept_keyword("insert") {
+            TriggerEvent::Insert
+        } else if self.accept_keyword("update") {
+            TriggerEvent::Update
+        } else {
+            self.expect_keyword("delete")?;
+            TriggerEvent::Delete
+        };
+        self.expect_keyword("on")?;
+        let table = self.name()?;
+        if self.accept_keyword("for") {
+            self.expect_keyword("each")?;
+            self.expect_keyword("row")?;
+        }
+        let when = if self.accept_keyword("when") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+        self.expect_keyword("begin")?;
+        let mut body = Vec::new();
+        while !self.accept_keyword("end") {
+            let statement = self.statement()?;
+            match statement {
+                Statement::Select(_)
+                | Statement::Insert(_)
+                | Statement::Update(_)
+                | Statement::Delete(_) => body.push(statement),
+                _ => return Err(StatementError::Sql),
+            }
+            self.expect_symbol(";")?;
+        }
+        if body.is_empty() {
+            return Err(StatementError::Sql);
+        }
+        Ok(CreateTrigger {
+            name,
+            timing,
+            event,
+            table,
+            when,
+            body,
+            if_not_exists,
+            sql: self.text_since(0),
+        })
+    }
+
+    fn if_not_exists(&mut self) -> Result<bool, StatementError> {
+        if self.accept_keyword("if") {
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    // parses something inside whatever is being parsed, which takes the parser further down
+    // the stack
+    fn nested<T>(
+        &mut self,
+        parse: fn(&mut Self) -> Result<T, StatementError>,
+    ) -> Result<T, StatementError> {
+        if self.depth >= MAX_PARSER_DEPTH {
+            return Err(StatementError::TooNested);
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn expr(&mut self) -> Result<Expr, StatementError> {
+        self.nested(Self::or)
+    }
+
+    fn or(&mut self) -> Result<Expr, StatementError> {
+        let mut left = self.and()?;
+        while self.accept_keyword("or") {
+            left = limit_depth(Expr::binary(BinaryOp::Or, left, self.and()?))?;
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, StatementError> {
+        let mut left = self.not()?;
+        while self.accept_keyword("and") {
+            left = limit_depth(Expr::binary(BinaryOp::And, left, self.not()?))?;
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, StatementError> {
+        if self.accept_keyword("not") {
+            return Ok(Expr::unary(UnaryOp::Not, self.nested(Self::not)?));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, StatementError> {
+        let mut left = self.additive()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("=")) | Some(Token::Symbol("==")) => Some(BinaryOp::Eq),
+                Some(Token::Symbol("!=")) | Some(Token::Symbol("<>")) => Some(BinaryOp::Ne),
+                Some(Token::Symbol("<")) => Some(BinaryOp::Lt),
+                Some(Token::Symbol("<=")) => Some(BinaryOp::Le),
+                Some(Token::Symbol(">")) => Some(BinaryOp::Gt),
+                Some(Token::Symbol(">=")) => Some(BinaryOp::Ge),
+                _ => None,
+            };
+            if let Some(op) = op {
+                self.position += 1;
+                left = limit_depth(Expr::binary(op, left, self.additive()?))?;
+                continue;
+            }
+            if self.accept_keyword("is") {
+                let op = if self.accept_keyword("not") {
+                    UnaryOp::NotNull
+                } else {
+                    UnaryOp::IsNull
+                };
+                self.expect_keyword("null")?;
+                left = limit_depth(Expr::unary(op, left))?;
+                continue;
+            }
+            let negated = match self.tokens.get(self.position + 1) {
+                Some((Token::Word(next), _))
+                    if self.peek_keyword("not")
+                        && ["between", "in", "like"]
+                            .iter()
+                            .any(|keyword| next.eq_ignore_ascii_case(keyword)) =>
+                {
+                    self.position += 1;
+                    true
+                }
+                _ => false,
+            };
+            let expr = if self.accept_keyword("between") {
+                let low = self.additive()?;
+                self.expect_keyword("and")?;
+                let high = self.additive()?;
+                Expr::binary(
+                    BinaryOp::And,
+                    Expr::binary(BinaryOp::Ge, left.clone(), low),
+                    Expr::binary(BinaryOp::Le, left, high),
+                )
+            } else if self.accept_keyword("in") {
+                self.expect_symbol("(")?;
+                if self.peek_select() {
+                    let select = self.select()?;
+                    self.expect_symbol(")")?;
+                    Expr::In {
+                        expr: Box::new(left),
+                        select: Box::new(select),
+                    }
+                } else {
+                    self.in_list(left)?
+                }
+            } else if self.accept_keyword("like") {
+                Expr::binary(BinaryOp::Like, left, self.additive()?)
+            } else {
+                return Ok(left);
+            };
+            left = limit_depth(if negated {
+                Expr::unary(UnaryOp::Not, expr)
+            } else {
+                expr
+            })?;
+        }
+    }
+
+    // `x in (a, b, c)` is the same as `x = a or x = b or x = c`, with the ors paired up so that
+    // a long list doesn't make a deep expression
+    fn in_list(&mut self, left: Expr) -> Result<Expr, StatementError> {
+        let mut items = Vec::new();
+        loop {
+            items.push(Expr::binary(BinaryOp::Eq, left.clone(), self.expr()?));
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+        while items.len() > 1 {
+            let mut items_left = items.into_iter();
+            items = Vec::new();
+            while let Some(item) = items_left.next() {
+                items.push(match items_left.next() {
+                    Some(next) => Expr::binary(BinaryOp::Or, item, next),
+                    None => item,
+                });
+            }
+        }
+        limit_depth(items.pop().unwrap())
+    }
+
+    fn additive(&mut self) -> Result<Expr, StatementError> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = if self.accept_symbol("+") {
+                BinaryOp::Add
+            } else if self.accept_symbol("-") {
+                BinaryOp::Subtract
+            } else {
+                return Ok(left);
+            };
+            left = limit_depth(Expr::binary(op, left, self.multiplicative()?))?;
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, StatementError> {
+        let mut left = self.concat()?;
+        loop {
+            let op = if self.accept_symbol("*") {
+                BinaryOp::Multiply
+            } else if self.accept_symbol("/") {
+                BinaryOp::Divide
+            } else if self.accept_symbol("%") {
+                BinaryOp::Remainder
+            } else {
+                return Ok(left);
+            };
+            left = limit_depth(Expr::binary(op, left, self.concat()?))?;
+        }
+    }
+
+    fn concat(&mut self) -> Result<Expr, StatementError> {
+        let mut left = self.unary()?;
+        while self.accept_symbol("||") {
+            left = limit_depth(Expr::binary(BinaryOp::Concat, left, self.unary()?))?;
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, StatementError> {
+        if self.accept_symbol("-") {
+            return Ok(match self.nested(Self::unary)? {
+                Expr::Literal(Value::Integer(i)) => Expr::Literal(Value::Integer(-i)),
+                expr => Expr::unary(UnaryOp::Negate, expr),
+            });
+        }
+        if self.accept_symbol("+") {
+            return self.nested(Self::unary);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, StatementError> {
+        let token = self.peek().cloned().ok_or(StatementError::Sql)?;
+        match token {
+            Token::Integer(i) => {
+                self.position += 1;
+                Ok(Expr::Literal(Value::Integer(i)))
+            }
+            Token::Text(text) => {
+                self.position += 1;
+                Ok(Expr::Literal(Value::Text(text)))
+            }
+            Token::Param(param) => {
+                self.position += 1;
+                Ok(Expr::Param(param))
+            }
+            Token::Symbol("(") => {
+                self.position += 1;
+                let expr = if self.peek_select() {
+                    Expr::Subquery(Box::new(self.select()?))
+                } else {
+                    self.expr()?
+                };
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Token::Word(word)
+                if word.eq_ignore_ascii_case("exists")
+                    && matches!(
+                        self.tokens.get(self.position + 1),
+                        Some((Token::Symbol("("), _))
+                    ) =>
+            {
+                self.position += 2;
+                let select = self.select()?;
+                self.expect_symbol(")")?;
+                Ok(Expr::Exists(Box::new(select)))
+            }
+            Token::Word(word) if word.eq_ignore_ascii_case("null") => {
+                self.position += 1;
+                Ok(Expr::Literal(Value::Null))
+            }
+            Token::Word(_) | Token::Quoted(_) => {
+                let name = self.name()?;
+                if self.accept_symbol("(") {
+                    let mut args = Vec::new();
+                    if !self.accept_symbol(")") {
+                        loop {
+                            args.push(self.expr()?);
+                            if !self.accept_symbol(",") {
+                                break;
+                            }
+                        }
+                        self.expect_symbol(")")?;
+                    }
+                    return Ok(Expr::Function { name, args });
+                }
+                if self.accept_symbol(".") {
+                    let column = self.name()?;
+                    return Ok(Expr::Column {
+                        table: Some(name),
+                        name: column,
+                    });
+                }
+                Ok(Expr::Column { table: None, name })
+            }
+            _ => Err(StatementError::Sql),
+        }
+    }
+}
+
+// An expression built up one operator at a time in a loop doesn't take the parser any deeper,
+// but it still has to be walked by everything after the parser, so it can't get deeper either.
+fn limit_depth(expr: Expr) -> Result<Expr, StatementError> {
+    if expr.deeper_than(MAX_EXPR_DEPTH) {
+        return Err(StatementError::TooDeep);
+    }
+    Ok(expr)
+}
+
+// a name written so it reads as one wherever it goes, even if it's a keyword
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// words that come before a table's name
+const TABLE_PLACES: &[&str] = &[
+    "table",
+    "exists",
+    "on",
+    "references",
+    "into",
+    "update",
+    "from",
+    "join",
+];
+
+// The statement that made a table, index, view or trigger with a table's name changed
+// wherever a table's name can go: after words like `from` and `references`, and before a dot.
+// Anything this misses is an error when the statement is checked against the new name.
+pub fn rename_table(sql: &str, from: &str, to: &str) -> Result<String, StatementError> {
+    let tokens = tokenize(sql, &mut Vec::new())?;
+    let mut renamed = Vec::new();
+    for (i, (token, range)) in tokens.iter().enumerate() {
+        let after = tokens.get(i + 1).map(|(token, _)| token);
+        let place = table_place(&tokens, i) || after == Some(&Token::Symbol("."));
+        if place && is_name(token, from) {
+            renamed.push(range.clone());
+        }
+    }
+    Ok(replace(sql, renamed, to))
+}
+
+// One column's definition from a CREATE TABLE statement with a column of `table` renamed: its
+// own name and the names in its CHECK if it's a column of that table (`own`), and the column
+// its foreign key refers to if the key refers to that table.
+pub fn rename_column(
+    column: &str,
+    own: bool,
+    table: &str,
+    from: &str,
+    to: &str,
+) -> Result<String, StatementError> {
+    let tokens = tokenize(column, &mut Vec::new())?;
+    let token = |i: usize| tokens.get(i).map(|(token, _)| token);
+    let mut renamed = Vec::new();
+    if own && token(0).is_some_and(|name| is_name(name, from)) {
+        renamed.push(tokens[0].1.clone());
+    }
+    // the type isn't a name, even if it reads like one
+    let mut i = 1;
+    while matches!(token(i), Some(Token::Word(word)) if !COLUMN_CONSTRAINTS
+        .iter()
+        .any(|constraint| word.eq_ignore_ascii_case(constraint)))
+    {
+        i += 1;
+    }
+    if i > 1 && token(i) == Some(&Token::Symbol("(")) {
+        while token(i).is_some_and(|token| *token != Token::Symbol(")")) {
+            i += 1;
+        }
+    }
+    while let Some(current) = token(i) {
+        let call = token(i + 1) == Some(&Token::Symbol("("));
+        if is_name(current, "references") {
+            let parent = token(i + 1).is_some_and(|parent| is_name(parent, table));
+            i += 2;
+            if token(i) == Some(&Token::Symbol("(")) {
+                if parent && token(i + 1).is_some_and(|name| is_name(name, from)) {
+                    renamed.push(tokens[i + 1].1.clone());
+                }
+                i += 3;
+            }
+            continue;
+        }
+        if own && !call && is_name(current, from) {
+            renamed.push(tokens[i].1.clone());
+        }
+        i += 1;
+    }
+    Ok(replace(column, renamed, to))
+}
+
+// A view's or trigger's statement with a column of `table` renamed wherever it reads it: after
+// the table's name or one of its aliases and a dot, after `new.` or `old.` in a trigger on the
+// table, and on its own in a statement that reads the table and no other table that `others`
+// says has a column by that name. Anything this misses is an error when the statement is
+// checked against the new name.
+pub fn rename_column_in(
+    sql: &str,
+    table: &str,
+    from: &str,
+    to: &str,
+    others: &dyn Fn(&str) -> bool,
+) -> Result<String, StatementError> {
+    let tokens = tokenize(sql, &mut Vec::new())?;
+    let token = |i: usize| tokens.get(i).map(|(token, _)| token);
+    let word = |i: usize, word: &str| token(i).is_some_and(|token| is_name(token, word));
+    // the view's or trigger's own name, and the names of a view's columns, are left alone
+    let trigger = word(1, "trigger");
+    let start = if trigger {
+        if word(2, "if") {
+            6
+        } else {
+            3
+        }
+    } else {
+        (0..tokens.len())
+            .find(|i| word(*i, "as"))
+            .map_or(0, |i| i + 1)
+    };
+    let on_table = trigger
+        && (start..tokens.len())
+            .find(|i| word(*i, "on"))
+            .is_some_and(|i| word(i + 1, table));
+
+    let mut reads = false;
+    let mut other = false;
+    let mut aliases = Vec::new();
+    for i in (start..tokens.len()).filter(|i| table_place(&tokens, *i)) {
+        let alias = if word(i + 1, "as") { i + 2 } else { i + 1 };
+        let alias = match token(alias) {
+            Some(Token::Word(alias)) if !is_reserved(alias) => Some(alias),
+            Some(Token::Quoted(alias)) => Some(alias),
+            _ => None,
+        };
+        match &tokens[i].0 {
+            name if is_name(name, table) => {
+                reads = true;
+                aliases.extend(alias);
+            }
+            Token::Word(name) | Token::Quoted(name) => other |= others(name),
+            _ => {}
+        }
+    }
+
+    let mut renamed = Vec::new();
+    for i in start..tokens.len() {
+        let before = i.checked_sub(1).and_then(token);
+        let after = token(i + 1);
+        if !is_name(&tokens[i].0, from)
+            || table_place(&tokens, i)
+            || before.is_some_and(|before| is_name(before, "as"))
+            || matches!(after, Some(Token::Symbol(".")) | Some(Token::Symbol("(")))
+        {
+            continue;
+        }
+        let rename = if before == Some(&Token::Symbol(".")) {
+            match i.checked_sub(2).and_then(token) {
+                Some(qualifier) => {
+                    is_name(qualifier, table)
+                        || aliases.iter().any(|alias| is_name(qualifier, alias))
+                        || (on_table && (is_name(qualifier, "new") || is_name(qualifier, "old")))
+                }
+                None => false,
+            }
+        } else {
+            reads && !other
+        };
+        if rename {
+            renamed.push(tokens[i].1.clone());
+        }
+    }
+    Ok(replace(sql, renamed, to))
+}
+
+// whether the token comes after a word like `from` or `references`, where a table's name goes
+fn table_place(tokens: &[(Token, Range<usize>)], i: usize) -> bool {
+    let before = i.checked_sub(1).map(|i| &tokens[i].0);
+    matches!(before, Some(Token::Word(word)) if TABLE_PLACES
+        .iter()
+        .any(|place| word.eq_ignore_ascii_case(place)))
+}
+
+fn is_name(token: &Token, name: &str) -> bool {
+    matches!(token, Token::Word(word) | Token::Quoted(word) if word.eq_ignore_ascii_case(name))
+}
+
+// the text with each of the ranges, which come in order, replaced by the name
+fn replace(text: &str, ranges: Vec<Range<usize>>, name: &str) -> String {
+    let mut text = text.to_string();
+    for range in ranges.into_iter().rev() {
+        text.replace_range(range, &quote(name));
+    }
+    text
+}
+
+fn is_reserved(word: &str) -> bool {
+    RESERVED
+        .iter()
+        .any(|reserved| word.eq_ignore_ascii_case(reserved))
+}
@@ -0,0 +1,449 @@
+use crate::ast::{BinaryOp, Expr};
+use crate::schema::{IndexDef, TableDef};
+use crate::stats::ColumnStats;
+
+// The planner decides how a query gets at each of its tables and in which order it goes
+// through them. Every way of reading a table is costed by the number of rows it is expected to
+// touch, and for a join the cost of each table is multiplied by the number of rows the tables
+// before it produce, so the cheapest order is the one that filters out the most rows early.
+//
+// Once ANALYZE has run, the number of rows a table has and how its values are spread out say
+// how many rows a condition keeps. Without statistics, an equality on an index is taken to find
+// 10 rows and every bound on a range to keep a quarter of them.
+//
+// A table joined on an equality no index can help with can be read once into a hash table
+// instead of once for every row before it. Building the hash table costs a scan, so it only
+// pays off for joins with many rows on the outside.
+const EQUALITY_ROWS: f64 = 10.0;
+const RANGE_SELECTIVITY: f64 = 0.25;
+// how much of a table a condition that can't use an index is taken to keep
+const FILTER_SELECTIVITY: f64 = 0.25;
+// every possible order is tried for joins of up to this many tables, and the written order kept
+// for bigger ones
+const MAX_REORDERED_TABLES: usize = 6;
+
+// a table the query reads, as far as the planner is concerned
+pub struct Source<'s> {
+    pub table: &'s TableDef,
+    pub indexes: Vec<&'s IndexDef>,
+    // the columns the query reads from the table; None means the whole row
+    pub columns: Option<Vec<usize>>,
+    // LEFT JOINed, so it has a row of NULLs for every row before it that nothing matches
+    pub left: bool,
+    // a bit for every source that has to be read around this one
+    pub must_follow: u64,
+}
+
+// A condition from the WHERE clause, or a join's ON clause, that has to be true for a row to
+// be returned.
+pub struct Term<'e> {
+    pub expr: &'e Expr,
+    // a bit for every source the condition reads
+    pub sources: u64,
+    // `column op value`, when the condition compares a column with something else
+    pub comparison: Option<Comparison<'e>>,
+    // the source whose LEFT JOIN this is the ON condition of; it decides whether that source
+    // has a match rather than whether a row is returned, so it is only checked there
+    pub on: Option<usize>,
+}
+
+pub struct Comparison<'e> {
+    pub source: usize,
+    // None for the rowid
+    pub column: Option<usize>,
+    pub op: BinaryOp,
+    pub value: &'e Expr,
+    pub value_sources: u64,
+}
+
+#[derive(Debug)]
+pub struct Plan<'s, 'e> {
+    // outermost first
+    pub loops: Vec<Loop<'s, 'e>>,
+}
+
+#[derive(Debug)]
+pub struct Loop<'s, 'e> {
+    pub source: usize,
+    pub access: Access<'s, 'e>,
+    // the terms the access takes care of, which don't need checking again
+    pub used: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub enum Access<'s, 'e> {
+    Scan,
+    Rowid(&'e Expr),
+    // entries whose first columns equal `eq`, with the next column within the bounds, if any;
+    // a covering index has every column the query needs, so the table itself is never read
+    Index {
+        index: &'s IndexDef,
+        eq: Vec<&'e Expr>,
+        lower: Option<Bound<'e>>,
+        upper: Option<Bound<'e>>,
+        covering: bool,
+    },
+    // the rows whose column equals the value, from a hash table built before the query starts
+    Hash {
+        column: usize,
+        value: &'e Expr,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bound<'e> {
+    pub value: &'e Expr,
+    pub inclusive: bool,
+}
+
+// one way of reading a table, with what it costs and how many rows it produces
+struct Candidate<'s, 'e> {
+    access: Access<'s, 'e>,
+    used: Vec<usize>,
+    // paid once, however many times the table is read
+    setup: f64,
+    cost: f64,
+    rows: f64,
+}
+
+pub fn plan<'s, 'e>(sources: &[Source<'s>], terms: &[Term<'e>]) -> Plan<'s, 'e> {
+    let orders: Vec<Vec<usize>> = if sources.len() <= MAX_REORDERED_TABLES {
+        permutations(sources.len())
+    } else {
+        vec![(0..sources.len()).collect()]
+    };
+    let mut best: Option<(f64, Vec<Candidate<'s, 'e>>, Vec<usize>)> = None;
+    for order in orders {
+        let mut cost = 0.0;
+        let mut rows = 1.0;
+        let mut available = 0u64;
+        let mut chosen = Vec::new();
+        for &source in &order {
+            // a LEFT JOIN stays after the tables it joins on to
+            if sources[source].must_follow & !available != 0 {
+                break;
+            }
+            let candidate = best_access(sources, terms, source, available, rows);
+            cost += candidate.setup + rows * candidate.cost;
+            rows *= candidate.rows;
+            available |= 1 << source;
+            chosen.push(candidate);
+        }
+        if chosen.len() < order.len() {
+            continue;
+        }
+        // ties go to the order that comes first, which starts with the order the query was written in
+        if best
+            .as_ref()
+            .is_none_or(|(best_cost, ..)| cost < *best_cost)
+        {
+            best = Some((cost, chosen, order));
+        }
+    }
+    let (_, chosen, order) = best.unwrap_or_default();
+    Plan {
+        loops: chosen
+            .into_iter()
+            .zip(order)
+            .map(|(candidate, source)| Loop {
+                source,
+                access: candidate.access,
+                used: candidate.used,
+            })
+            .collect(),
+    }
+}
+
+// every order of n items, starting with 0, 1, 2, ...
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![Vec::new()];
+    }
+    let mut orders = Vec::new();
+    for first in 0..n {
+        for rest in permutations(n - 1) {
+            let mut order = vec![first];
+            order.extend(rest.into_iter().map(|i| if i >= first { i + 1 } else { i }));
+            orders.push(order);
+        }
+    }
+    orders
+}
+
+// the cheapest way to read a source once the sources in `available` are being read around it,
+// `outer` times over
+fn best_access<'s, 'e>(
+    sources: &[Source<'s>],
+    terms: &[Term<'e>],
+    source: usize,
+    available: u64,
+    outer: f64,
+) -> Candidate<'s, 'e> {
+    let table_rows = (sources[source].table.rows as f64).max(1.0);
+    // finding a row by its rowid takes a walk down a tree
+    let lookup = table_rows.log2().max(1.0);
+
+    // the comparisons on this source that can be worked out before it is read
+    let usable: Vec<(usize, &Comparison)> = terms
+        .iter()
+        .enumerate()
+        .filter_map(|(i, term)| match &term.comparison {
+            // the WHERE clause can't narrow down what a LEFT JOIN reads, only what it returns
+            Some(comparison)
+                if comparison.source == source
+                    && comparison.value_sources & !available == 0
+                    && comparison.value_sources & (1 << source) == 0
+                    && term.on.is_none_or(|on| on == source)
+                    && (!sources[source].left || term.on == Some(source)) =>
+            {
+                Some((i, comparison))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut candidates = vec![Candidate {
+        access: Access::Scan,
+        used: Vec::new(),
+        setup: 0.0,
+        cost: table_rows,
+        rows: table_rows,
+    }];
+
+    if let Some((i, comparison)) = usable
+        .iter()
+        .find(|(_, comparison)| comparison.column.is_none() && comparison.op == BinaryOp::Eq)
+    {
+        candidates.push(Candidate {
+            access: Access::Rowid(comparison.value),
+            used: vec![*i],
+            setup: 0.0,
+            cost: lookup,
+            rows: 1.0,
+        });
+    }
+
+    for index in &sources[source].indexes {
+        let covering = match &sources[source].columns {
+            Some(columns) => columns.iter().all(|column| index.columns.contains(column)),
+            None => false,
+        };
+        let mut used = Vec::new();
+        let mut eq = Vec::new();
+        for column in &index.columns {
+            match usable.iter().find(|(_, comparison)| {
+                comparison.column == Some(*column) && comparison.op == BinaryOp::Eq
+            }) {
+                Some((i, comparison)) => {
+                    used.push(*i);
+                    eq.push(comparison.value);
+                }
+                None => break,
+            }
+        }
+        let mut lower = None;
+        let mut upper = None;
+        let mut ranges = Vec::new();
+        if let Some(column) = index.columns.get(eq.len()) {
+            for (i, comparison) in &usable {
+                if comparison.column != Some(*column) {
+                    continue;
+                }
+                let bound = |inclusive| {
+                    Some(Bound {
+                        value: comparison.value,
+                        inclusive,
+                    })
+                };
+                match comparison.op {
+                    BinaryOp::Gt | BinaryOp::Ge if lower.is_none() => {
+                        lower = bound(comparison.op == BinaryOp::Ge);
+                    }
+                    BinaryOp::Lt | BinaryOp::Le if upper.is_none() => {
+                        upper = bound(comparison.op == BinaryOp::Le);
+                    }
+                    _ => continue,
+                }
+                used.push(*i);
+                ranges.push(*comparison);
+            }
+        }
+        if eq.is_empty() && lower.is_none() && upper.is_none() && !covering {
+            continue;
+        }
+        let table = sources[source].table;
+        let mut rows = table_rows;
+        if !eq.is_empty() {
+            let key_stats: Option<Vec<&ColumnStats>> = index.columns[..eq.len()]
+                .iter()
+                .map(|column| table.columns[*column].stats.as_ref())
+                .collect();
+            rows = match (index.distinct.get(eq.len() - 1), key_stats) {
+                // a unique index has at most one entry with every column's value given
+                _ if index.unique && eq.len() == index.columns.len() => rows.min(1.0),
+                (Some(distinct), _) => rows / (*distinct).max(1) as f64,
+                (None, Some(key_stats)) => key_stats.iter().fold(rows, |rows, s| rows * s.equal()),
+                (None, None) => rows.min(EQUALITY_ROWS),
+            };
+        }
+        if !ranges.is_empty() {
+            rows *= selectivity(table, &ranges)
+                .unwrap_or_else(|| RANGE_SELECTIVITY.powi(ranges.len() as i32));
+        }
+        // an index holds less than its table, so reading all of it is still a little cheaper
+        let entries = if covering { rows * 0.5 } else { rows };
+        let seek = if eq.is_empty() && lower.is_none() && upper.is_none() {
+            0.0
+        } else {
+            lookup
+        };
+        let cost = seek + entries + if covering { 0.0 } else { rows * lookup };
+        candidates.push(Candidate {
+            access: Access::Index {
+                index,
+                eq,
+                lower,
+                upper,
+                covering,
+            },
+            used,
+            setup: 0.0,
+            cost,
+            rows,
+        });
+    }
+
+    // only a join condition is worth a hash table; a constant would be looked up the same way
+    // every time
+    for (i, comparison) in &usable {
+        let column = match comparison.column {
+            Some(column) if comparison.op == BinaryOp::Eq && comparison.value_sources != 0 => {
+                column
+            }
+            _ => continue,
+        };
+        let rows = match &sources[source].table.columns[column].stats {
+            Some(stats) => table_rows * stats.equal(),
+            None => table_rows.min(EQUALITY_ROWS),
+        };
+        candidates.push(Candidate {
+            access: Access::Hash {
+                column,
+                value: comparison.value,
+            },
+            used: vec![*i],
+            setup: table_rows,
+            cost: 1.0 + rows,
+            rows,
+        });
+    }
+
+    let total = |candidate: &Candidate| candidate.setup + outer * candidate.cost;
+    let mut best = candidates
+        .into_iter()
+        .reduce(|best, candidate| {
+            if total(&candidate) < total(&best) {
+                candidate
+            } else {
+                best
+            }
+        })
+        .unwrap();
+    // the other conditions that can be checked as each row is read cut down the rows that are
+    // left for the tables after this one
+    for (i, term) in terms.iter().enumerate() {
+        if best.used.contains(&i)
+            || term.sources & (1 << source) == 0
+            || term.sources & !(available | 1 << source) != 0
+        {
+            continue;
+        }
+        let comparison = usable
+            .iter()
+            .find(|(usable, _)| *usable == i)
+            .map(|(_, comparison)| *comparison);
+        let kept =
+            comparison.and_then(|comparison| selectivity(sources[source].table, &[comparison]));
+        best.rows *= kept.unwrap_or(FILTER_SELECTIVITY);
+    }
+    best.rows = best.rows.max(1.0);
+    best
+}
+
+// The fraction of a table's rows that pass comparisons between one of its columns and
+// constants, if there are statistics on the column to go by.
+fn selectivity(table: &TableDef, comparisons: &[&Comparison]) -> Option<f64> {
+    let stats = table.columns[comparisons[0].column?].stats.as_ref()?;
+    let values = comparisons
+        .iter()
+        .map(|comparison| match (comparison.op, comparison.value) {
+            (BinaryOp::Eq | BinaryOp::Ne, Expr::Literal(value))
+            | (BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge, Expr::Literal(value)) => {
+                Some((comparison.op, value))
+            }
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match values[..] {
+        [(BinaryOp::Eq, _)] => Some(stats.equal()),
+        [(BinaryOp::Ne, _)] => Some(1.0 - stats.equal()),
+        _ => stats.compare(|bound| {
+            values.iter().all(|(op, value)| match op {
+                BinaryOp::Lt => bound < value,
+                BinaryOp::Le => bound <= value,
+                BinaryOp::Gt => bound > value,
+                _ => bound >= value,
+            })
+        }),
+    }
+}
+
+impl Access<'_, '_> {
+    // how the table is read, as EXPLAIN QUERY PLAN describes it
+    pub fn describe(&self, table: &TableDef, name: &str) -> String {
+        let name = if name.eq_ignore_ascii_case(&table.name) {
+            table.name.clone()
+        } else {
+            format!("{} AS {}", table.name, name)
+        };
+        match self {
+            Access::Scan => format!("SCAN {}", name),
+            Access::Rowid(_) => format!("SEARCH {} USING INTEGER PRIMARY KEY (rowid=?)", name),
+            Access::Hash { column, .. } => format!(
+                "SEARCH {} USING HASH TABLE ({}=?)",
+                name, table.columns[*column].name
+            ),
+            Access::Index {
+                index,
+                eq,
+                lower,
+                upper,
+                covering,
+            } => {
+                let kind = if *covering { "COVERING INDEX" } else { "INDEX" };
+                let column = |i: usize| &table.columns[index.columns[i]].name;
+                let mut constraints: Vec<String> =
+                    (0..eq.len()).map(|i| format!("{}=?", column(i))).collect();
+                if let Some(lower) = lower {
+                    let op = if lower.inclusive { ">=" } else { ">" };
+                    constraints.push(format!("{}{}?", column(eq.len()), op));
+                }
+                if let Some(upper) = upper {
+                    let op = if upper.inclusive { "<=" } else { "<" };
+                    constraints.push(format!("{}{}?", column(eq.len()), op));
+                }
+                if constraints.is_empty() {
+                    format!("SCAN {} USING {} {}", name, kind, index.name)
+                } else {
+                    format!(
+                        "SEARCH {} USING {} {} ({})",
+                        name,
+                        kind,
+                        index.name,
+                        constraints.join(" AND ")
+                    )
+                }
+            }
+        }
+    }
+}
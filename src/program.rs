@@ -0,0 +1,1014 @@
+use std::fmt;
+
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::parser::StatementError;
+use crate::value::Value;
+use crate::virtual_machine::check_column;
+
+// A statement compiled into instructions for the virtual machine. Instructions work on numbered
+// registers, each holding a single value, and on numbered cursors, each pointing into a source of
+// rows; jumps name the address of the instruction to go to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    // the name of each column in the rows the program produces; empty if it produces none
+    pub columns: Vec<String>,
+    pub registers: usize,
+    pub cursors: usize,
+    // how the program gets at each table it reads, for EXPLAIN QUERY PLAN
+    pub plan: Vec<PlanStep>,
+    // parameters that go straight into a column of the users table, with the column, so their
+    // values can be checked before the program runs
+    pub checks: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanStep {
+    // the address of the instruction that opens the table
+    pub id: usize,
+    // the step this one runs inside of, or 0 if it isn't inside another
+    pub parent: usize,
+    pub detail: String,
+}
+
+// what a cursor goes through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tree {
+    // the fixed-size rows of the users table
+    Rows,
+    // the rows of any other table, by its root page
+    Table(u32),
+    // the table describing every other table and index
+    Schema,
+    // the table of statistics ANALYZE collects
+    Stats,
+    // an index, by its root page
+    Index(u32),
+    // an index the program created itself, with its root page in a register
+    NewIndex(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    // starts a transaction that lasts until the program halts, as long as the schema is still
+    // the version the program was compiled against
+    Transaction {
+        write: bool,
+        schema_version: u32,
+    },
+    OpenRead {
+        cursor: usize,
+        tree: Tree,
+    },
+    OpenWrite {
+        cursor: usize,
+        tree: Tree,
+    },
+    // moves to the first row, or jumps if there isn't one
+    Rewind {
+        cursor: usize,
+        target: usize,
+    },
+    Column {
+        cursor: usize,
+        column: usize,
+        dest: usize,
+    },
+    // hands the values in `count` registers from `start` back to the caller as a row
+    ResultRow {
+        start: usize,
+        count: usize,
+    },
+    // moves to the next row and jumps back to handle it, unless there are no more rows
+    Next {
+        cursor: usize,
+        target: usize,
+    },
+    Integer {
+        value: i64,
+        dest: usize,
+    },
+    String {
+        value: String,
+        dest: usize,
+    },
+    Null {
+        dest: usize,
+    },
+    // copies the value bound to a parameter
+    Variable {
+        param: usize,
+        dest: usize,
+    },
+    Goto {
+        target: usize,
+    },
+    // jumps if the value is true
+    If {
+        src: usize,
+        target: usize,
+    },
+    // jumps if the value is false or NULL
+    IfNot {
+        src: usize,
+        target: usize,
+    },
+    IsNull {
+        src: usize,
+        target: usize,
+    },
+    Copy {
+        src: usize,
+        dest: usize,
+    },
+    // turns the value into an integer, failing if it isn't one
+    MustBeInt {
+        src: usize,
+    },
+    // adds to the number of rows left referring to nothing through a foreign key, which has
+    // to be back to zero when the statement ends, or for a deferred key when it commits
+    FkCounter {
+        deferred: bool,
+        amount: i64,
+    },
+    // jumps if no rows are left referring to nothing
+    FkIfZero {
+        deferred: bool,
+        target: usize,
+    },
+    // fails the statement if it has left a row referring to nothing through a key that isn't
+    // deferred
+    FkCheck,
+    // fails the statement, undoing everything it did
+    Constraint {
+        message: String,
+    },
+    Unary {
+        op: UnaryOp,
+        src: usize,
+        dest: usize,
+    },
+    Binary {
+        op: BinaryOp,
+        left: usize,
+        right: usize,
+        dest: usize,
+    },
+    Function {
+        name: String,
+        start: usize,
+        count: usize,
+        dest: usize,
+    },
+    // until the cursor is moved again, every column it reads is NULL and it has no next row,
+    // for a LEFT JOIN with nothing to join
+    NullRow {
+        cursor: usize,
+    },
+    // the rowid of the row the cursor is on; for an index, the rowid the entry points at
+    Rowid {
+        cursor: usize,
+        dest: usize,
+    },
+    // moves to the row with the rowid in `src`, or jumps if there isn't one
+    SeekRowid {
+        cursor: usize,
+        src: usize,
+        target: usize,
+    },
+    // moves an index cursor to the first entry starting with values at least (or greater
+    // than) the ones in `count` registers from `start`, or jumps if there isn't one
+    SeekGE {
+        cursor: usize,
+        start: usize,
+        count: usize,
+        target: usize,
+    },
+    SeekGT {
+        cursor: usize,
+        start: usize,
+        count: usize,
+        target: usize,
+    },
+    // jumps if the entry's first `count` values are greater than (or at least) the registers'
+    IdxGT {
+        cursor: usize,
+        start: usize,
+        count: usize,
+        target: usize,
+    },
+    IdxGE {
+        cursor: usize,
+        start: usize,
+        count: usize,
+        target: usize,
+    },
+    // the rowid a row inserted through the cursor will get
+    NewRowid {
+        cursor: usize,
+        dest: usize,
+    },
+    // adds a row made of the values in `count` registers from `start`, or replaces the row with
+    // the same rowid; a new row in the users table goes on the end, where NewRowid said it would
+    Insert {
+        cursor: usize,
+        start: usize,
+        count: usize,
+        rowid: Option<usize>,
+    },
+    // removes the entry the cursor is on from its tree, or the row it is on from the users table
+    Delete {
+        cursor: usize,
+    },
+    // adds an index entry made of the values in `count` registers from `start`
+    IdxInsert {
+        cursor: usize,
+        start: usize,
+        count: usize,
+    },
+    // removes the index entry made of the values in `count` registers from `start`, if it's there
+    IdxDelete {
+        cursor: usize,
+        start: usize,
+        count: usize,
+    },
+    // starts an empty hash table in the cursor, which reads rows the way a table does
+    OpenHash {
+        cursor: usize,
+    },
+    // adds the rowid and columns in `count` registers from `start` to the hash table, under the
+    // value in `key`; rows with a NULL key are left out, since they never match anything
+    HashInsert {
+        cursor: usize,
+        key: usize,
+        start: usize,
+        count: usize,
+    },
+    // moves the cursor to the first row under the value in `key`, or jumps if there isn't one;
+    // Next goes through the rest of them
+    HashSeek {
+        cursor: usize,
+        key: usize,
+        target: usize,
+    },
+    // falls through the first time the program gets here and jumps every time after, for work
+    // that only needs doing once
+    Once {
+        target: usize,
+    },
+    // opens the cursor on a new, empty tree in the program's temporary pages, which lasts until
+    // the program ends
+    OpenEphemeral {
+        cursor: usize,
+    },
+    // opens the cursor on the temporary tree another cursor is on
+    OpenDup {
+        cursor: usize,
+        from: usize,
+    },
+    // opens the cursor on a single row made of the values in `count` registers from `start` as
+    // they are now, with the rowid in `rowid`
+    OpenPseudo {
+        cursor: usize,
+        start: usize,
+        count: usize,
+        rowid: usize,
+    },
+    // makes a new, empty tree and puts its root page in a register
+    CreateBtree {
+        dest: usize,
+    },
+    // bumps the schema version, so statements compiled against the old schema get compiled again
+    ChangeSchema,
+    // starts a sample of rows in the cursor, for working out statistics from
+    OpenSample {
+        cursor: usize,
+    },
+    // adds the row in `count` registers from `start` to the sample
+    SampleRow {
+        cursor: usize,
+        start: usize,
+        count: usize,
+    },
+    // works out the statistics for a table and its indexes from the sample, and writes them
+    // through the `stats` cursor; each index is given by its name and the columns it covers
+    SaveStats {
+        cursor: usize,
+        stats: usize,
+        table: String,
+        columns: Vec<String>,
+        indexes: Vec<(String, Vec<usize>)>,
+    },
+    // runs a pragma, setting it to the value in the `value` register if there is one, and opens
+    // the cursor over the rows it reports
+    Pragma {
+        cursor: usize,
+        name: String,
+        value: Option<usize>,
+    },
+    // ends the program, committing its transaction, unless a deferred foreign key has been left
+    // referring to nothing
+    Halt,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Program {
+            instructions: Vec::new(),
+            columns: Vec::new(),
+            registers: 0,
+            cursors: 0,
+            plan: Vec::new(),
+            checks: Vec::new(),
+        }
+    }
+
+    // adds an instruction and returns its address
+    pub fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    // the address the next instruction will get
+    pub fn next_address(&self) -> usize {
+        self.instructions.len()
+    }
+
+    // points the jump at `address` somewhere else, for jumps emitted before their target was known
+    pub fn set_target(&mut self, address: usize, to: usize) {
+        match &mut self.instructions[address] {
+            Instruction::Rewind { target, .. }
+            | Instruction::Next { target, .. }
+            | Instruction::Goto { target }
+            | Instruction::If { target, .. }
+            | Instruction::IfNot { target, .. }
+            | Instruction::IsNull { target, .. }
+            | Instruction::SeekRowid { target, .. }
+            | Instruction::SeekGE { target, .. }
+            | Instruction::SeekGT { target, .. }
+            | Instruction::IdxGT { target, .. }
+            | Instruction::IdxGE { target, .. }
+            | Instruction::HashSeek { target, .. }
+            | Instruction::FkIfZero { target, .. }
+            | Instruction::Once { target } => *target = to,
+            instruction => panic!("{:?} at {} is not a jump", instruction, address),
+        }
+    }
+
+    // returns the first of `count` registers in a row
+    pub fn allocate_registers(&mut self, count: usize) -> usize {
+        self.registers += count;
+        self.registers - count
+    }
+
+    pub fn allocate_cursor(&mut self) -> usize {
+        self.cursors += 1;
+        self.cursors - 1
+    }
+
+    // loads a constant into a register
+    pub fn load(&mut self, value: &Value, dest: usize) -> usize {
+        match value {
+            Value::Null => self.emit(Instruction::Null { dest }),
+            Value::Integer(value) => self.emit(Instruction::Integer {
+                value: *value,
+                dest,
+            }),
+            Value::Text(value) => self.emit(Instruction::String {
+                value: value.clone(),
+                dest,
+            }),
+        }
+    }
+
+    // Returns the value of every parameter, in order, once each has been checked against where
+    // it is going; a parameter the program uses but that has no value is an error.
+    pub fn bind(&self, params: &[Option<Value>]) -> Result<Vec<Value>, StatementError> {
+        for (index, param) in params.iter().enumerate() {
+            match param {
+                Some(value) => {
+                    for (_, column) in self.checks.iter().filter(|(param, _)| *param == index) {
+                        check_column(*column, value)?;
+                    }
+                }
+                None if self.uses(index) => return Err(StatementError::MissingParameter),
+                None => {}
+            }
+        }
+        // parameters the program never uses don't need a value
+        Ok(params
+            .iter()
+            .map(|param| param.clone().unwrap_or(Value::Null))
+            .collect())
+    }
+
+    pub fn changes_schema(&self) -> bool {
+        self.instructions.contains(&Instruction::ChangeSchema)
+    }
+
+    fn uses(&self, param: usize) -> bool {
+        self.instructions.iter().any(
+            |instruction| matches!(instruction, Instruction::Variable { param: p, .. } if *p == param),
+        )
+    }
+}
+
+impl Instruction {
+    // the instruction as a row of EXPLAIN output: its name, up to three numeric operands,
+    // a fourth operand of any other kind, and what it does in words
+    pub fn explain(&self) -> [Value; 6] {
+        let (p1, p2, p3, p4, comment) = match self {
+            Instruction::Transaction {
+                write,
+                schema_version,
+            } => (
+                0,
+                *write as i64,
+                *schema_version as i64,
+                Value::Null,
+                format!(
+                    "begin a {} transaction",
+                    if *write { "write" } else { "read" }
+                ),
+            ),
+            Instruction::OpenRead { cursor, tree } => (
+                *cursor as i64,
+                tree.root(),
+                0,
+                Value::Null,
+                format!("open cursor {} to read {}", cursor, tree),
+            ),
+            Instruction::OpenWrite { cursor, tree } => (
+                *cursor as i64,
+                tree.root(),
+                0,
+                Value::Null,
+                format!("open cursor {} to write {}", cursor, tree),
+            ),
+            Instruction::Rewind { cursor, target } => (
+                *cursor as i64,
+                *target as i64,
+                0,
+                Value::Null,
+                format!("go to {} if cursor {} has no rows", target, cursor),
+            ),
+            Instruction::Column {
+                cursor,
+                column,
+                dest,
+            } => (
+                *cursor as i64,
+                *column as i64,
+                *dest as i64,
+                Value::Null,
+                format!("r[{}] = column {} of cursor {}", dest, column, cursor),
+            ),
+            Instruction::ResultRow { start, count } => (
+                *start as i64,
+                *count as i64,
+                0,
+                Value::Null,
+                format!("output r[{}..{}]", start, start + count),
+            ),
+            Instruction::Next { cursor, target } => (
+                *cursor as i64,
+                *target as i64,
+                0,
+                Value::Null,
+                format!("go to {} unless cursor {} has no more rows", target, cursor),
+            ),
+            Instruction::Integer { value, dest } => (
+                *value,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = {}", dest, value),
+            ),
+            Instruction::String { value, dest } => (
+                0,
+                *dest as i64,
+                0,
+                Value::Text(value.clone()),
+                format!("r[{}] = {:?}", dest, value),
+            ),
+            Instruction::Null { dest } => (
+                0,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = NULL", dest),
+            ),
+            // parameters are numbered from 1 everywhere the caller sees them
+            Instruction::Variable { param, dest } => (
+                *param as i64 + 1,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = parameter {}", dest, param + 1),
+            ),
+            Instruction::Goto { target } => (
+                0,
+                *target as i64,
+                0,
+                Value::Null,
+                format!("go to {}", target),
+            ),
+            Instruction::If { src, target } => (
+                *src as i64,
+                *target as i64,
+                0,
+                Value::Null,
+                format!("go to {} if r[{}] is true", target, src),
+            ),
+            Instruction::IfNot { src, target } => (
+                *src as i64,
+                *target as i64,
+                0,
+                Value::Null,
+                format!("go to {} unless r[{}] is true", target, src),
+            ),
+            Instruction::IsNull { src, target } => (
+                *src as i64,
+                *target as i64,
+                0,
+                Value::Null,
+                format!("go to {} if r[{}] is NULL", target, src),
+            ),
+            Instruction::Copy { src, dest } => (
+                *src as i64,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = r[{}]", dest, src),
+            ),
+            Instruction::MustBeInt { src } => (
+                *src as i64,
+                0,
+                0,
+                Value::Null,
+                format!("fail unless r[{}] is an integer", src),
+            ),
+            Instruction::FkCounter { deferred, amount } => (
+                *deferred as i64,
+                *amount,
+                0,
+                Value::Null,
+                format!(
+                    "add {} to the {} foreign key violations",
+                    amount,
+                    violations(*deferred)
+                ),
+            ),
+            Instruction::FkIfZero { deferred, target } => (
+                *deferred as i64,
+                *target as i64,
+                0,
+                Value::Null,
+                format!(
+                    "go to {} if there are no {} foreign key violations",
+                    target,
+                    violations(*deferred)
+                ),
+            ),
+            Instruction::FkCheck => (
+                0,
+                0,
+                0,
+                Value::Null,
+                "fail if there are immediate foreign key violations".into(),
+            ),
+            Instruction::Constraint { message } => (
+                0,
+                0,
+                0,
+                Value::Text(message.clone()),
+                format!("fail with {:?}", message),
+            ),
+            Instruction::Unary { op, src, dest } => (
+                *src as i64,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = {} r[{}]", dest, op.name(), src),
+            ),
+            Instruction::Binary {
+                op,
+                left,
+                right,
+                dest,
+            } => (
+                *left as i64,
+                *right as i64,
+                *dest as i64,
+                Value::Text(op.symbol().into()),
+                format!("r[{}] = r[{}] {} r[{}]", dest, left, op.symbol(), right),
+            ),
+            Instruction::Function {
+                name,
+                start,
+                count,
+                dest,
+            } => (
+                *start as i64,
+                *count as i64,
+                *dest as i64,
+                Value::Text(name.clone()),
+                format!("r[{}] = {}(r[{}..{}])", dest, name, start, start + count),
+            ),
+            Instruction::NullRow { cursor } => (
+                *cursor as i64,
+                0,
+                0,
+                Value::Null,
+                format!("make cursor {} read a row of NULLs", cursor),
+            ),
+            Instruction::Rowid { cursor, dest } => (
+                *cursor as i64,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = rowid of cursor {}", dest, cursor),
+            ),
+            Instruction::SeekRowid {
+                cursor,
+                src,
+                target,
+            } => (
+                *cursor as i64,
+                *target as i64,
+                *src as i64,
+                Value::Null,
+                format!(
+                    "move cursor {} to rowid r[{}], or go to {} if there is none",
+                    cursor, src, target
+                ),
+            ),
+            Instruction::SeekGE {
+                cursor,
+                start,
+                count,
+                target,
+            }
+            | Instruction::SeekGT {
+                cursor,
+                start,
+                count,
+                target,
+            } => (
+                *cursor as i64,
+                *target as i64,
+                *start as i64,
+                Value::Integer(*count as i64),
+                format!(
+                    "move cursor {} to the first key {} r[{}..{}], or go to {}",
+                    cursor,
+                    if matches!(self, Instruction::SeekGE { .. }) {
+                        ">="
+                    } else {
+                        ">"
+                    },
+                    start,
+                    start + count,
+                    target
+                ),
+            ),
+            Instruction::IdxGT {
+                cursor,
+                start,
+                count,
+                target,
+            }
+            | Instruction::IdxGE {
+                cursor,
+                start,
+                count,
+                target,
+            } => (
+                *cursor as i64,
+                *target as i64,
+                *start as i64,
+                Value::Integer(*count as i64),
+                format!(
+                    "go to {} if the key of cursor {} {} r[{}..{}]",
+                    target,
+                    cursor,
+                    if matches!(self, Instruction::IdxGT { .. }) {
+                        ">"
+                    } else {
+                        ">="
+                    },
+                    start,
+                    start + count
+                ),
+            ),
+            Instruction::NewRowid { cursor, dest } => (
+                *cursor as i64,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = a new rowid for cursor {}", dest, cursor),
+            ),
+            Instruction::Insert {
+                cursor,
+                start,
+                count,
+                rowid,
+            } => (
+                *cursor as i64,
+                *start as i64,
+                *count as i64,
+                rowid.map_or(Value::Null, |rowid| Value::Integer(rowid as i64)),
+                format!(
+                    "insert r[{}..{}] through cursor {}",
+                    start,
+                    start + count,
+                    cursor
+                ),
+            ),
+            Instruction::Delete { cursor } => (
+                *cursor as i64,
+                0,
+                0,
+                Value::Null,
+                format!("delete the entry cursor {} is on", cursor),
+            ),
+            Instruction::IdxInsert {
+                cursor,
+                start,
+                count,
+            } => (
+                *cursor as i64,
+                *start as i64,
+                *count as i64,
+                Value::Null,
+                format!(
+                    "insert key r[{}..{}] through cursor {}",
+                    start,
+                    start + count,
+                    cursor
+                ),
+            ),
+            Instruction::IdxDelete {
+                cursor,
+                start,
+                count,
+            } => (
+                *cursor as i64,
+                *start as i64,
+                *count as i64,
+                Value::Null,
+                format!(
+                    "delete key r[{}..{}] through cursor {}",
+                    start,
+                    start + count,
+                    cursor
+                ),
+            ),
+            Instruction::OpenHash { cursor } => (
+                *cursor as i64,
+                0,
+                0,
+                Value::Null,
+                format!("start a hash table in cursor {}", cursor),
+            ),
+            Instruction::HashInsert {
+                cursor,
+                key,
+                start,
+                count,
+            } => (
+                *cursor as i64,
+                *key as i64,
+                *start as i64,
+                Value::Integer(*count as i64),
+                format!(
+                    "add r[{}..{}] to the hash table in cursor {} under r[{}]",
+                    start,
+                    start + count,
+                    cursor,
+                    key
+                ),
+            ),
+            Instruction::HashSeek {
+                cursor,
+                key,
+                target,
+            } => (
+                *cursor as i64,
+                *target as i64,
+                *key as i64,
+                Value::Null,
+                format!(
+                    "move cursor {} to the rows under r[{}], or go to {} if there are none",
+                    cursor, key, target
+                ),
+            ),
+            Instruction::Once { target } => (
+                0,
+                *target as i64,
+                0,
+                Value::Null,
+                format!("go to {} if this has run before", target),
+            ),
+            Instruction::OpenEphemeral { cursor } => (
+                *cursor as i64,
+                0,
+                0,
+                Value::Null,
+                format!("open cursor {} on a new temporary tree", cursor),
+            ),
+            Instruction::OpenDup { cursor, from } => (
+                *cursor as i64,
+                *from as i64,
+                0,
+                Value::Null,
+                format!("open cursor {} on the tree of cursor {}", cursor, from),
+            ),
+            Instruction::OpenPseudo {
+                cursor,
+                start,
+                count,
+                rowid,
+            } => (
+                *cursor as i64,
+                *start as i64,
+                *count as i64,
+                Value::Integer(*rowid as i64),
+                format!(
+                    "open cursor {} on the row in r[{}..{}] with rowid r[{}]",
+                    cursor,
+                    start,
+                    start + count,
+                    rowid
+                ),
+            ),
+            Instruction::CreateBtree { dest } => (
+                0,
+                *dest as i64,
+                0,
+                Value::Null,
+                format!("r[{}] = root page of a new tree", dest),
+            ),
+            Instruction::ChangeSchema => (0, 0, 0, Value::Null, "bump the schema version".into()),
+            Instruction::OpenSample { cursor } => (
+                *cursor as i64,
+                0,
+                0,
+                Value::Null,
+                format!("start a sample in cursor {}", cursor),
+            ),
+            Instruction::SampleRow {
+                cursor,
+                start,
+                count,
+            } => (
+                *cursor as i64,
+                *start as i64,
+                *count as i64,
+                Value::Null,
+                format!(
+                    "add r[{}..{}] to the sample in cursor {}",
+                    start,
+                    start + count,
+                    cursor
+                ),
+            ),
+            Instruction::SaveStats {
+                cursor,
+                stats,
+                table,
+                ..
+            } => (
+                *cursor as i64,
+                *stats as i64,
+                0,
+                Value::Text(table.clone()),
+                format!(
+                    "write statistics on {} from the sample in cursor {} through cursor {}",
+                    table, cursor, stats
+                ),
+            ),
+            Instruction::Pragma {
+                cursor,
+                name,
+                value,
+            } => (
+                *cursor as i64,
+                value.map_or(-1, |value| value as i64),
+                0,
+                Value::Text(name.clone()),
+                match value {
+                    Some(value) => format!("set {} to r[{}]", name, value),
+                    None => format!("read {}", name),
+                },
+            ),
+            Instruction::Halt => (0, 0, 0, Value::Null, "end the program".into()),
+        };
+        [
+            Value::Text(self.opcode().into()),
+            Value::Integer(p1),
+            Value::Integer(p2),
+            Value::Integer(p3),
+            p4,
+            Value::Text(comment),
+        ]
+    }
+
+    pub fn opcode(&self) -> &'static str {
+        match self {
+            Instruction::Transaction { .. } => "Transaction",
+            Instruction::OpenRead { .. } => "OpenRead",
+            Instruction::OpenWrite { .. } => "OpenWrite",
+            Instruction::Rewind { .. } => "Rewind",
+            Instruction::Column { .. } => "Column",
+            Instruction::ResultRow { .. } => "ResultRow",
+            Instruction::Next { .. } => "Next",
+            Instruction::Integer { .. } => "Integer",
+            Instruction::String { .. } => "String",
+            Instruction::Null { .. } => "Null",
+            Instruction::Variable { .. } => "Variable",
+            Instruction::Goto { .. } => "Goto",
+            Instruction::If { .. } => "If",
+            Instruction::IfNot { .. } => "IfNot",
+            Instruction::IsNull { .. } => "IsNull",
+            Instruction::Copy { .. } => "Copy",
+            Instruction::MustBeInt { .. } => "MustBeInt",
+            Instruction::FkCounter { .. } => "FkCounter",
+            Instruction::FkIfZero { .. } => "FkIfZero",
+            Instruction::FkCheck => "FkCheck",
+            Instruction::Constraint { .. } => "Constraint",
+            Instruction::Unary { .. } => "Unary",
+            Instruction::Binary { .. } => "Binary",
+            Instruction::Function { .. } => "Function",
+            Instruction::NullRow { .. } => "NullRow",
+            Instruction::Rowid { .. } => "Rowid",
+            Instruction::SeekRowid { .. } => "SeekRowid",
+            Instruction::SeekGE { .. } => "SeekGE",
+            Instruction::SeekGT { .. } => "SeekGT",
+            Instruction::IdxGT { .. } => "IdxGT",
+            Instruction::IdxGE { .. } => "IdxGE",
+            Instruction::NewRowid { .. } => "NewRowid",
+            Instruction::Insert { .. } => "Insert",
+            Instruction::Delete { .. } => "Delete",
+            Instruction::IdxInsert { .. } => "IdxInsert",
+            Instruction::IdxDelete { .. } => "IdxDelete",
+            Instruction::OpenHash { .. } => "OpenHash",
+            Instruction::HashInsert { .. } => "HashInsert",
+            Instruction::HashSeek { .. } => "HashSeek",
+            Instruction::Once { .. } => "Once",
+            Instruction::OpenEphemeral { .. } => "OpenEphemeral",
+            Instruction::OpenDup { .. } => "OpenDup",
+            Instruction::OpenPseudo { .. } => "OpenPseudo",
+            Instruction::CreateBtree { .. } => "CreateBtree",
+            Instruction::ChangeSchema => "ChangeSchema",
+            Instruction::OpenSample { .. } => "OpenSample",
+            Instruction::SampleRow { .. } => "SampleRow",
+            Instruction::SaveStats { .. } => "SaveStats",
+            Instruction::Pragma { .. } => "Pragma",
+            Instruction::Halt => "Halt",
+        }
+    }
+}
+
+impl Tree {
+    // the root page, or for a tree the program creates, the register holding it
+    fn root(&self) -> i64 {
+        match self {
+            Tree::Rows | Tree::Schema | Tree::Stats => 0,
+            Tree::Table(root) | Tree::Index(root) => *root as i64,
+            Tree::NewIndex(register) => *register as i64,
+        }
+    }
+}
+
+impl fmt::Display for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tree::Rows | Tree::Table(_) => write!(f, "the table"),
+            Tree::Schema => write!(f, "the schema"),
+            Tree::Stats => write!(f, "the statistics"),
+            Tree::Index(_) => write!(f, "the index"),
+            Tree::NewIndex(register) => write!(f, "the new index in r[{}]", register),
+        }
+    }
+}
+
+impl UnaryOp {
+    fn name(self) -> &'static str {
+        match self {
+            UnaryOp::Not => "NOT",
+            UnaryOp::Negate => "-",
+            UnaryOp::IsNull => "ISNULL",
+            UnaryOp::NotNull => "NOTNULL",
+        }
+    }
+}
+
+// which of the counts of foreign key violations an instruction works on
+fn violations(deferred: bool) -> &'static str {
+    if deferred {
+        "deferred"
+    } else {
+        "immediate"
+    }
+}
@@ -0,0 +1,80 @@
+use std::convert::TryInto;
+
+use crate::value::Value;
+
+// Values are stored so that comparing two encoded records byte by byte orders them the same way
+// as comparing their values one by one: NULL first, then integers, then text. Every value says
+// where it ends, so a record that starts with the encoding of another one sorts right after it.
+const NULL: u8 = 0;
+const INTEGER: u8 = 1;
+const TEXT: u8 = 2;
+
+pub fn encode(values: &[Value]) -> Vec<u8> {
+    let mut record = Vec::new();
+    for value in values {
+        encode_value(value, &mut record);
+    }
+    record
+}
+
+pub fn encode_value(value: &Value, record: &mut Vec<u8>) {
+    match value {
+        Value::Null => record.push(NULL),
+        Value::Integer(i) => {
+            record.push(INTEGER);
+            // flipping the sign bit puts negative numbers before positive ones
+            record.extend_from_slice(&((*i as u64) ^ (1 << 63)).to_be_bytes());
+        }
+        Value::Text(text) => {
+            record.push(TEXT);
+            // a NUL in the text is followed by 0xff, so the 0x00 0x00 terminator can't be mistaken
+            for byte in text.bytes() {
+                record.push(byte);
+                if byte == 0 {
+                    record.push(0xff);
+                }
+            }
+            record.extend_from_slice(&[0, 0]);
+        }
+    }
+}
+
+pub fn decode(mut record: &[u8]) -> Vec<Value> {
+    let mut values = Vec::new();
+    while !record.is_empty() {
+        let (value, rest) = decode_value(record);
+        values.push(value);
+        record = rest;
+    }
+    values
+}
+
+// the value at the start of the record, and the rest of the record after it
+fn decode_value(record: &[u8]) -> (Value, &[u8]) {
+    match record[0] {
+        NULL => (Value::Null, &record[1..]),
+        INTEGER => {
+            let bits = u64::from_be_bytes(record[1..9].try_into().unwrap());
+            (Value::Integer((bits ^ (1 << 63)) as i64), &record[9..])
+        }
+        _ => {
+            let mut bytes = Vec::new();
+            let mut i = 1;
+            loop {
+                match (record[i], record[i + 1]) {
+                    (0, 0) => break,
+                    (0, _) => {
+                        bytes.push(0);
+                        i += 2;
+                    }
+                    (byte, _) => {
+                        bytes.push(byte);
+                        i += 1;
+                    }
+                }
+            }
+            let text = String::from_utf8_lossy(&bytes).into_owned();
+            (Value::Text(text), &record[i + 2..])
+        }
+    }
+}
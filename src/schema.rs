@@ -0,0 +1,429 @@
+use crate::ast::{
+    ColumnDefault, CreateTable, Expr, ForeignKey, Select, Statement, TriggerEvent, TriggerTiming,
+};
+use crate::btree::BTreeCursor;
+use crate::constants::*;
+use crate::parser::{prepare_statement, StatementError};
+use crate::record;
+use crate::stats::ColumnStats;
+use crate::table::{header_value, TableError, TableView};
+use crate::value::Value;
+
+// Every table, index, view and trigger in the database. The users table is built in; everything
+// else is described by a row of the schema table, which holds the statement that created it:
+//
+// type ("table", "index", "view" or "trigger"), name, the table it belongs to, its root page (0
+// for a view or a trigger, which have no tree), and the statement's SQL
+
+// how many rows a table is taken to have until ANALYZE has counted them
+pub const DEFAULT_ROWS: u64 = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct Schema {
+    // bumped by every change to the schema, so statements compiled against an older one can tell
+    pub version: u32,
+    pub tables: Vec<TableDef>,
+    pub indexes: Vec<IndexDef>,
+    pub views: Vec<ViewDef>,
+    pub triggers: Vec<TriggerDef>,
+}
+
+// a row of the schema table
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaEntry {
+    pub kind: &'static str,
+    pub name: String,
+    pub table: String,
+    pub root: u32,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableDef {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    // how many rows the table is thought to have, for planning queries
+    pub rows: u64,
+    // the tree the table's rows are kept in, keyed by rowid; None for the users table, whose
+    // rows have a fixed size and live in pages of their own
+    pub root: Option<u32>,
+    // an integer primary key column, which holds the rowid
+    pub primary_key: Option<usize>,
+    // the statement that made it, or nothing for the users table
+    pub sql: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: String,
+    pub not_null: bool,
+    pub check: Option<Expr>,
+    pub check_name: Option<String>,
+    pub default: Option<ColumnDefault>,
+    pub references: Option<ForeignKey>,
+    // what ANALYZE found out about the column's values, if it has been run
+    pub stats: Option<ColumnStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ViewDef {
+    pub name: String,
+    pub columns: Option<Vec<String>>,
+    pub select: Select,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TriggerDef {
+    pub name: String,
+    pub table: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    pub when: Option<Expr>,
+    pub body: Vec<Statement>,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexDef {
+    pub name: String,
+    pub table: String,
+    // the position of each indexed column in its table; entries are keyed by these columns'
+    // values followed by the rowid
+    pub columns: Vec<usize>,
+    pub root: u32,
+    pub unique: bool,
+    // how many different values the first column, the first two columns and so on take, once
+    // ANALYZE has been run
+    pub distinct: Vec<u64>,
+    pub sql: String,
+}
+
+impl Schema {
+    pub fn load(view: &dyn TableView) -> Result<Self, TableError> {
+        let mut schema = Schema::empty(header_value(view.pages(), SCHEMA_VERSION_OFFSET)?);
+        let corrupt = || TableError::Corrupt("schema entry doesn't make sense".into());
+        for (_, entry) in entries(view, SCHEMA_ROOT_OFFSET)? {
+            match entry.as_slice() {
+                [Value::Text(_), Value::Text(_), Value::Text(_), Value::Integer(root), Value::Text(sql)] => {
+                    schema.add(*root as u32, sql).map_err(|_| corrupt())?
+                }
+                _ => return Err(corrupt()),
+            }
+        }
+        for (key, stats) in entries(view, STATS_ROOT_OFFSET)? {
+            schema.add_stats(&key, &stats);
+        }
+        Ok(schema)
+    }
+
+    // The schema the entries describe, for checking a change to the schema before it's made.
+    // An entry that doesn't make sense is an error that names it.
+    pub fn from_entries(version: u32, entries: &[SchemaEntry]) -> Result<Self, StatementError> {
+        let mut schema = Schema::empty(version);
+        for entry in entries {
+            schema
+                .add(entry.root, &entry.sql)
+                .map_err(|error| StatementError::ErrorIn {
+                    entry: format!("{} {}", entry.kind, entry.name),
+                    error: Box::new(error),
+                })?;
+        }
+        Ok(schema)
+    }
+
+    // just the users table
+    fn empty(version: u32) -> Self {
+        Schema {
+            version,
+            tables: vec![TableDef {
+                name: TABLE_NAME.to_string(),
+                columns: COLUMNS
+                    .iter()
+                    .map(|(name, data_type)| ColumnDef {
+                        name: name.to_string(),
+                        data_type: data_type.to_string(),
+                        not_null: false,
+                        check: None,
+                        check_name: None,
+                        default: None,
+                        references: None,
+                        stats: None,
+                    })
+                    .collect(),
+                rows: DEFAULT_ROWS,
+                root: None,
+                primary_key: None,
+                sql: String::new(),
+            }],
+            indexes: Vec::new(),
+            views: Vec::new(),
+            triggers: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, root: u32, sql: &str) -> Result<(), StatementError> {
+        match prepare_statement(sql)?.0 {
+            Statement::CreateTable(table) => {
+                self.tables.push(TableDef::new(&table, Some(root)));
+                Ok(())
+            }
+            Statement::CreateIndex(index) => {
+                let table = self
+                    .table(&index.table)
+                    .ok_or_else(|| StatementError::NoSuchTable(index.table.clone()))?;
+                let columns = index
+                    .columns
+                    .iter()
+                    .map(|column| {
+                        table
+                            .column(column)
+                            .ok_or_else(|| StatementError::NoSuchColumn(column.clone()))
+                    })
+                    .collect::<Result<_, _>>()?;
+                self.indexes.push(IndexDef {
+                    name: index.name,
+                    table: table.name.clone(),
+                    columns,
+                    root,
+                    unique: index.unique,
+                    distinct: Vec::new(),
+                    sql: index.sql,
+                });
+                Ok(())
+            }
+            Statement::CreateView(view) => {
+                self.views.push(ViewDef {
+                    name: view.name,
+                    columns: view.columns,
+                    select: view.select,
+                    sql: view.sql,
+                });
+                Ok(())
+            }
+            Statement::CreateTrigger(trigger) => {
+                let table = self
+                    .table(&trigger.table)
+                    .ok_or_else(|| StatementError::NoSuchTable(trigger.table.clone()))?;
+                self.triggers.push(TriggerDef {
+                    name: trigger.name,
+                    table: table.name.clone(),
+                    timing: trigger.timing,
+                    event: trigger.event,
+                    when: trigger.when,
+                    body: trigger.body,
+                    sql: trigger.sql,
+                });
+                Ok(())
+            }
+            _ => Err(StatementError::Sql),
+        }
+    }
+
+    // every table, index, view and trigger the way the schema table has them, tables first
+    pub fn entries(&self) -> Vec<SchemaEntry> {
+        let entry = |kind, name: &str, table: &str, root, sql: &str| SchemaEntry {
+            kind,
+            name: name.to_string(),
+            table: table.to_string(),
+            root,
+            sql: sql.to_string(),
+        };
+        let tables = self.tables.iter().filter_map(|table| {
+            let root = table.root?;
+            Some(entry("table", &table.name, &table.name, root, &table.sql))
+        });
+        let indexes = self
+            .indexes
+            .iter()
+            .map(|index| entry("index", &index.name, &index.table, index.root, &index.sql));
+        let views = self
+            .views
+            .iter()
+            .map(|view| entry("view", &view.name, &view.name, 0, &view.sql));
+        let triggers = self
+            .triggers
+            .iter()
+            .map(|trigger| entry("trigger", &trigger.name, &trigger.table, 0, &trigger.sql));
+        tables.chain(indexes).chain(views).chain(triggers).collect()
+    }
+
+    // The statistics table is keyed by the table's name alone for the number of rows it has,
+    // and by the table's name, "column" or "index", and its name for the rest. Statistics on
+    // things that have gone since are left alone.
+    fn add_stats(&mut self, key: &[Value], stats: &[Value]) {
+        match (key, stats) {
+            ([Value::Text(table)], [Value::Integer(rows)]) => {
+                if let Some(table) = self.table_mut(table) {
+                    table.rows = *rows as u64;
+                }
+            }
+            ([Value::Text(table), Value::Text(kind), Value::Text(name)], _) if kind == "column" => {
+                let column = self
+                    .table_mut(table)
+                    .and_then(|table| table.columns.iter_mut().find(|c| c.name == *name));
+                if let Some(column) = column {
+                    column.stats = ColumnStats::from_record(stats);
+                }
+            }
+            ([Value::Text(_), Value::Text(kind), Value::Text(name)], _) if kind == "index" => {
+                if let Some(index) = self.indexes.iter_mut().find(|i| i.name == *name) {
+                    index.distinct = stats
+                        .iter()
+                        .map(|distinct| match distinct {
+                            Value::Integer(distinct) => *distinct as u64,
+                            _ => 0,
+                        })
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn table_mut(&mut self, name: &str) -> Option<&mut TableDef> {
+        self.tables
+            .iter_mut()
+            .find(|table| table.name.eq_ignore_ascii_case(name))
+    }
+
+    // names are looked up without regard to case, the way SQL has it
+    pub fn table(&self, name: &str) -> Option<&TableDef> {
+        self.tables
+            .iter()
+            .find(|table| table.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn index(&self, name: &str) -> Option<&IndexDef> {
+        self.indexes
+            .iter()
+            .find(|index| index.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn view(&self, name: &str) -> Option<&ViewDef> {
+        self.views
+            .iter()
+            .find(|view| view.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn trigger(&self, name: &str) -> Option<&TriggerDef> {
+        self.triggers
+            .iter()
+            .find(|trigger| trigger.name.eq_ignore_ascii_case(name))
+    }
+
+    // the triggers on the table that go off at `timing` on `event`, in the order they were made
+    pub fn triggers_of<'s>(
+        &'s self,
+        table: &str,
+        timing: TriggerTiming,
+        event: TriggerEvent,
+    ) -> impl Iterator<Item = &'s TriggerDef> {
+        let table = table.to_string();
+        self.triggers.iter().filter(move |trigger| {
+            trigger.table.eq_ignore_ascii_case(&table)
+                && trigger.timing == timing
+                && trigger.event == event
+        })
+    }
+
+    // whether a table, index or view has the name already
+    pub fn has(&self, name: &str) -> bool {
+        self.table(name).is_some() || self.index(name).is_some() || self.view(name).is_some()
+    }
+
+    pub fn indexes_of<'s>(&'s self, table: &'s str) -> impl Iterator<Item = &'s IndexDef> {
+        self.indexes
+            .iter()
+            .filter(move |index| index.table.eq_ignore_ascii_case(table))
+    }
+
+    // The table and column a foreign key refers to, as long as the column is the table's key:
+    // its integer primary key, or the one column of a unique index.
+    pub fn parent_key(&self, key: &ForeignKey) -> Option<(&TableDef, usize)> {
+        let table = self.table(&key.table)?;
+        let column = match &key.column {
+            Some(name) => table.column(name)?,
+            None => table.primary_key?,
+        };
+        let unique = table.primary_key == Some(column)
+            || self
+                .indexes_of(&table.name)
+                .any(|index| index.unique && index.columns == [column]);
+        Some((table, column)).filter(|_| unique)
+    }
+
+    // every column of every table with a foreign key referring to the table
+    pub fn foreign_keys_to(&self, table: &str) -> Vec<(&TableDef, usize, &ForeignKey)> {
+        let mut keys = Vec::new();
+        for child in &self.tables {
+            for (column, def) in child.columns.iter().enumerate() {
+                match &def.references {
+                    Some(key) if key.table.eq_ignore_ascii_case(table) => {
+                        keys.push((child, column, key))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        keys
+    }
+}
+
+impl TableDef {
+    pub fn new(create: &CreateTable, root: Option<u32>) -> Self {
+        TableDef {
+            name: create.name.clone(),
+            columns: create
+                .columns
+                .iter()
+                .map(|column| ColumnDef {
+                    name: column.name.clone(),
+                    data_type: column.data_type.clone(),
+                    not_null: column.not_null,
+                    check: column.check.clone(),
+                    check_name: column.check_name.clone(),
+                    default: column.default.clone(),
+                    references: column.references.clone(),
+                    stats: None,
+                })
+                .collect(),
+            rows: DEFAULT_ROWS,
+            root,
+            primary_key: create.columns.iter().position(|column| {
+                column.primary_key && column.data_type.eq_ignore_ascii_case("integer")
+            }),
+            sql: create.sql.clone(),
+        }
+    }
+
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+    }
+}
+
+// a key and its payload, decoded
+type Entry = (Vec<Value>, Vec<Value>);
+
+// every key and payload in a tree whose root page is in the header, if it has been made yet
+fn entries(view: &dyn TableView, root_offset: usize) -> Result<Vec<Entry>, TableError> {
+    let pages = view.pages();
+    let root = header_value(pages, root_offset)?;
+    let mut entries = Vec::new();
+    if root == 0 {
+        return Ok(entries);
+    }
+    let mut cursor = BTreeCursor::new(root);
+    cursor.first(pages)?;
+    while !cursor.eof {
+        entries.push((
+            record::decode(cursor.key()),
+            record::decode(cursor.payload()),
+        ));
+        cursor.next(pages)?;
+    }
+    Ok(entries)
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use crate::value::Value;
+
+// ANALYZE reads every row of a table, but only works its statistics out from a sample of them,
+// spread evenly through the table.
+const SAMPLE_ROWS: usize = 1000;
+// each bucket of a column's histogram holds about as many of the sampled values as the others
+const HISTOGRAM_BUCKETS: usize = 10;
+// Text in a histogram is cut short to this many characters, so a column's statistics stay small
+// however long its values are. A shortened bound sorts no later than the value it came from,
+// which is close enough for guessing how many rows a comparison matches.
+const BOUND_CHARS: usize = 32;
+
+// the rows of a table as ANALYZE goes through them
+#[derive(Debug, Clone)]
+pub struct Sample {
+    rows: u64,
+    // every `stride`th row is kept; it doubles each time the sample fills up, and every other
+    // row kept so far is let go
+    stride: u64,
+    kept: Vec<Vec<Value>>,
+}
+
+// What is known about the values in one column of a table. They are stored in the statistics
+// table keyed by the table's name, "column" and the column's name, as its distinct count, the
+// number of rows sampled and how many of those were NULL, followed by the histogram.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub distinct: u64,
+    pub null_fraction: f64,
+    // the largest value in each bucket, smallest first and with text cut short
+    pub histogram: Vec<Value>,
+}
+
+impl Sample {
+    pub fn new() -> Self {
+        Sample {
+            rows: 0,
+            stride: 1,
+            kept: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: &[Value]) {
+        if self.rows.is_multiple_of(self.stride) {
+            self.kept.push(row.to_vec());
+            if self.kept.len() > SAMPLE_ROWS {
+                let mut position = 0;
+                self.kept.retain(|_| {
+                    position += 1;
+                    position % 2 == 1
+                });
+                self.stride *= 2;
+            }
+        }
+        self.rows += 1;
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn sampled(&self) -> u64 {
+        self.kept.len() as u64
+    }
+
+    pub fn column(&self, column: usize) -> ColumnStats {
+        let values: Vec<&Value> = self.kept.iter().map(|row| &row[column]).collect();
+        let mut present: Vec<&Value> = values
+            .iter()
+            .copied()
+            .filter(|value| **value != Value::Null)
+            .collect();
+        present.sort();
+        let histogram = if present.is_empty() {
+            Vec::new()
+        } else {
+            (1..=HISTOGRAM_BUCKETS)
+                .map(|bucket| {
+                    match present[(bucket * present.len()).div_ceil(HISTOGRAM_BUCKETS) - 1] {
+                        Value::Text(text) => Value::Text(text.chars().take(BOUND_CHARS).collect()),
+                        value => value.clone(),
+                    }
+                })
+                .collect()
+        };
+        ColumnStats {
+            distinct: self.distinct(&[column]),
+            null_fraction: match values.len() {
+                0 => 0.0,
+                sampled => (sampled - present.len()) as f64 / sampled as f64,
+            },
+            histogram,
+        }
+    }
+
+    // Estimates how many different combinations of values the columns take in the whole
+    // table from how often each turns up in the sample, the way Haas and Stokes do: values seen
+    // only once suggest there are more the sample missed.
+    pub fn distinct(&self, columns: &[usize]) -> u64 {
+        let mut counts: HashMap<Vec<&Value>, u64> = HashMap::new();
+        for row in &self.kept {
+            *counts
+                .entry(columns.iter().map(|column| &row[*column]).collect())
+                .or_default() += 1;
+        }
+        let sampled = self.kept.len() as f64;
+        let seen = counts.len() as f64;
+        let once = counts.values().filter(|count| **count == 1).count() as f64;
+        if sampled == 0.0 {
+            return 0;
+        }
+        let estimate = sampled * seen / (sampled - once + once * sampled / self.rows as f64);
+        estimate.round() as u64
+    }
+}
+
+impl ColumnStats {
+    pub fn to_record(&self, sampled: u64) -> Vec<Value> {
+        let nulls = (self.null_fraction * sampled as f64).round() as i64;
+        let mut record = vec![
+            Value::Integer(self.distinct as i64),
+            Value::Integer(sampled as i64),
+            Value::Integer(nulls),
+        ];
+        record.extend(self.histogram.iter().cloned());
+        record
+    }
+
+    pub fn from_record(record: &[Value]) -> Option<Self> {
+        match record {
+            [Value::Integer(distinct), Value::Integer(sampled), Value::Integer(nulls), histogram @ ..] => {
+                Some(ColumnStats {
+                    distinct: *distinct as u64,
+                    null_fraction: match sampled {
+                        0 => 0.0,
+                        sampled => *nulls as f64 / *sampled as f64,
+                    },
+                    histogram: histogram.to_vec(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // the fraction of rows equal to any one value
+    pub fn equal(&self) -> f64 {
+        (1.0 - self.null_fraction) / self.distinct.max(1) as f64
+    }
+
+    // the fraction of rows with a value `matches` accepts, going by how many of the buckets'
+    // largest values it accepts; the bucket where it stops accepting them counts as half in
+    pub fn compare(&self, matches: impl Fn(&Value) -> bool) -> Option<f64> {
+        if self.histogram.is_empty() {
+            return None;
+        }
+        let buckets = self.histogram.len() as f64;
+        let matching = self.histogram.iter().filter(|bound| matches(bound)).count() as f64;
+        Some((1.0 - self.null_fraction) * ((matching + 0.5) / buckets).min(1.0))
+    }
+}
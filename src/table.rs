@@ -1,86 +1,576 @@
+use std::convert::TryInto;
+use std::fmt;
 use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
-use crate::pager::{Pager, PagerError};
+use crate::btree;
+use crate::pager::{
+    JournalMode, Page, PageRead, PageWrite, Pager, PagerError, PagerOptions, ReadTransaction,
+    Synchronous, TempPages, WriteTransaction,
+};
 
 use crate::constants::*;
+use crate::error::ErrorCode;
 
+// A table can be shared between threads: readers each see the table as it was when they started
+// and never wait for a writer, while writers take turns.
 pub struct Table {
-    pub num_rows: u32,
     pager: Pager,
+    writer: Mutex<()>,
+    closed: bool,
 }
 
 #[derive(Debug)]
 pub enum TableError {
     Pager(PagerError),
+    NotEmpty,
+    // the key of an entry has to fit in a quarter of a page, though its payload doesn't
+    TooBig,
+    // something read from the file makes no sense, like a schema entry that doesn't parse
+    Corrupt(String),
+}
+
+impl TableError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            TableError::Pager(e) => e.code(),
+            TableError::NotEmpty => ErrorCode::Misuse,
+            TableError::TooBig => ErrorCode::TooBig,
+            TableError::Corrupt(_) => ErrorCode::Corrupt,
+        }
+    }
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableError::Pager(e) => e.fmt(f),
+            TableError::NotEmpty => write!(f, "page size can't be changed once the table has rows"),
+            TableError::TooBig => write!(f, "key is too big to fit in a page"),
+            TableError::Corrupt(what) => write!(f, "database disk image is malformed: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for TableError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TableError::Pager(e) => e.source(),
+            TableError::NotEmpty | TableError::TooBig | TableError::Corrupt(_) => None,
+        }
+    }
 }
 
 impl Table {
-    pub fn new<P>(filename: P) -> Result<Self, TableError>
+    pub fn open<P>(filename: P, options: PagerOptions) -> Result<Self, TableError>
     where
         P: AsRef<Path>,
     {
-        Pager::new(filename)
-            .map_err(TableError::Pager)
-            .map(|pager| {
-                let num_rows: u32 = (pager.file_length / ROW_SIZE as u64) as u32;
-                Table { num_rows, pager }
-            })
+        let pager = Pager::new(filename, options).map_err(TableError::Pager)?;
+        Ok(Table {
+            pager,
+            writer: Mutex::new(()),
+            closed: false,
+        })
     }
 
-    pub fn start(&mut self) -> Cursor {
-        let end_of_table = self.num_rows == 0;
-        Cursor {
-            table: self,
-            row_num: 0,
-            end_of_table,
+    pub fn read(&self) -> Result<TableRead<'_>, TableError> {
+        let txn = self.pager.begin_read().map_err(TableError::Pager)?;
+        let num_rows = read_num_rows(&txn)?;
+        Ok(TableRead { txn, num_rows })
+    }
+
+    // the table stays consistent between statements, so a panic elsewhere doesn't make it unusable
+    pub fn write(&self) -> Result<TableWrite<'_>, TableError> {
+        let turn = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let txn = self.pager.begin_write().map_err(TableError::Pager)?;
+        let num_rows = read_num_rows(&txn)?;
+        Ok(TableWrite {
+            txn,
+            _turn: turn,
+            num_rows,
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.pager.page_size()
+    }
+
+    pub fn temp_pages(&self) -> TempPages {
+        self.pager.temp_pages()
+    }
+
+    pub fn set_page_size(&self, page_size: usize) -> Result<(), TableError> {
+        let mut table = self.write()?;
+        if table.num_rows > 0 {
+            return Err(TableError::NotEmpty);
         }
+        table.txn.set_page_size(page_size).map_err(|e| match e {
+            PagerError::NotEmpty => TableError::NotEmpty,
+            e => TableError::Pager(e),
+        })?;
+        table.commit()
     }
 
-    pub fn end(&mut self) -> Cursor {
-        let row_num = self.num_rows;
-        Cursor {
-            table: self,
-            end_of_table: true,
-            row_num,
+    pub fn cache_size(&self) -> usize {
+        self.pager.cache_size()
+    }
+
+    pub fn set_cache_size(&self, cache_size: usize) {
+        self.pager.set_cache_size(cache_size);
+    }
+
+    pub fn synchronous(&self) -> Synchronous {
+        self.pager.synchronous()
+    }
+
+    pub fn set_synchronous(&self, synchronous: Synchronous) {
+        self.pager.set_synchronous(synchronous);
+    }
+
+    pub fn busy_timeout(&self) -> Duration {
+        self.pager.busy_timeout()
+    }
+
+    pub fn set_busy_timeout(&self, busy_timeout: Duration) {
+        self.pager.set_busy_timeout(busy_timeout);
+    }
+
+    pub fn journal_mode(&self) -> JournalMode {
+        self.pager.journal_mode()
+    }
+
+    pub fn set_journal_mode(&self, journal_mode: JournalMode) {
+        self.pager.set_journal_mode(journal_mode);
+    }
+
+    // Walks every row, tree and page of the file and describes anything that doesn't look right.
+    // The trees come from the schema, which is read from the same snapshot as everything else.
+    pub fn integrity_check<F>(&self, trees: F) -> Result<Vec<String>, TableError>
+    where
+        F: FnOnce(&TableRead<'_>) -> Result<Vec<CheckedTree>, TableError>,
+    {
+        let table = self.read()?;
+        let file_length = self.pager.file_length();
+        let mut problems = Vec::new();
+
+        if table.num_rows as usize > table.max_rows() {
+            problems.push(format!(
+                "table has {} rows but can hold at most {}",
+                table.num_rows,
+                table.max_rows()
+            ));
         }
+
+        let page_size = table.txn.page_size() as u64;
+        if !file_length.is_multiple_of(page_size) {
+            problems.push(format!(
+                "file is {} bytes long which is not a whole number of pages",
+                file_length
+            ));
+        }
+
+        let mut used = vec![false; table.page_count()?];
+        used[0] = true;
+        let rows_end = table.rows_per_page() * ROW_SIZE;
+        let deleted = table.deleted_offset();
+        for index in 0..table.row_pages() {
+            let page_num = table.row_page(index)?;
+            match used.get_mut(page_num as usize) {
+                Some(false) => used[page_num as usize] = true,
+                _ => problems.push(format!("page {} can't hold rows", page_num)),
+            }
+            let page = table.txn.page(page_num).map_err(TableError::Pager)?;
+            if page.buffer[rows_end..deleted].iter().any(|b| *b != 0) {
+                problems.push(format!(
+                    "page {} has data after its last row slot",
+                    page_num
+                ));
+            }
+        }
+
+        let mut rows = 0;
+        let mut cursor = Cursor::new(&table);
+        cursor.skip_deleted(&table)?;
+        while !cursor.end_of_table {
+            rows += 1;
+            let row_num = cursor.row_num;
+            let row = cursor.value(&table)?;
+            check_text_column(
+                row_num,
+                "username",
+                &row[USERNAME_OFFSET..EMAIL_OFFSET],
+                &mut problems,
+            );
+            check_text_column(
+                row_num,
+                "email",
+                &row[EMAIL_OFFSET..ROW_SIZE],
+                &mut problems,
+            );
+            cursor.advance();
+            cursor.skip_deleted(&table)?;
+        }
+
+        for offset in [SCHEMA_ROOT_OFFSET, STATS_ROOT_OFFSET] {
+            let root = header_value(&table.txn, offset)?;
+            if root != 0 {
+                btree::check(&table.txn, root, &mut used, &mut problems)?;
+            }
+        }
+        let trees = trees(&table)?;
+        let mut entries = Vec::new();
+        for tree in &trees {
+            entries.push(match tree.root {
+                Some(root) => btree::check(&table.txn, root, &mut used, &mut problems)?,
+                None => rows,
+            });
+        }
+        for (tree, count) in trees.iter().zip(&entries) {
+            if let Some(table) = tree.table {
+                if *count != entries[table] {
+                    problems.push(format!(
+                        "index {} has {} entries but table {} has {} rows",
+                        tree.name, count, trees[table].name, entries[table]
+                    ));
+                }
+            }
+        }
+        // the pages given back are on the freelist, and every other one should still belong to
+        // something
+        let mut page_num = header_value(&table.txn, FREELIST_OFFSET)?;
+        while page_num != 0 && btree::mark_used(page_num, &mut used, &mut problems) {
+            page_num = next_free_page(&table.txn, page_num)?;
+        }
+        for (page_num, used) in used.iter().enumerate() {
+            if !used {
+                problems.push(format!("page {} is never used", page_num));
+            }
+        }
+
+        Ok(problems)
+    }
+
+    // every transaction has committed or rolled back by now, so all that is left is making sure
+    // the file is on disk; closing is the only way to find out whether that worked
+    pub fn close(mut self) -> Result<(), TableError> {
+        self.closed = true;
+        self.pager.sync_all().map_err(TableError::Pager)
     }
 }
 
 impl Drop for Table {
     fn drop(&mut self) {
-        let total_num_rows = self.num_rows as usize;
-        let num_full_pages = total_num_rows / ROWS_PER_PAGE;
-        let num_additional_rows = total_num_rows % ROWS_PER_PAGE;
-        let num_additional_bytes = num_additional_rows * ROW_SIZE;
-        self.pager
-            .flush(num_full_pages, num_additional_bytes)
-            .expect("dropping table failed to flush pages to disk");
+        if self.closed {
+            return;
+        }
+        // best effort only; panicking here would abort the process and lose the pages anyway
+        if let Err(e) = self.pager.sync_all() {
+            eprintln!(
+                "db message: failed to sync table while dropping it: {:?}",
+                e
+            );
+        }
+    }
+}
+
+// the row count is only trustworthy inside a transaction, since other processes can change it
+fn read_num_rows(pages: &dyn PageRead) -> Result<u32, TableError> {
+    header_value(pages, NUM_ROWS_OFFSET)
+}
+
+// every page on the freelist starts with the one after it
+fn next_free_page(pages: &dyn PageRead, page_num: u32) -> Result<u32, TableError> {
+    let page = pages.page(page_num).map_err(TableError::Pager)?;
+    Ok(u32::from_be_bytes(page.buffer[..4].try_into().unwrap()))
+}
+
+pub fn header_value(pages: &dyn PageRead, offset: usize) -> Result<u32, TableError> {
+    let header = pages.page(0).map_err(TableError::Pager)?;
+    Ok(u32::from_be_bytes(
+        header.buffer[offset..offset + 4].try_into().unwrap(),
+    ))
+}
+
+// A tree for integrity_check to walk, with the position among the trees of the table it
+// indexes if it's an index, whose entries have to match that table's rows.
+pub struct CheckedTree {
+    pub name: String,
+    // the users table keeps its rows in pages of their own, outside any tree
+    pub root: Option<u32>,
+    pub table: Option<usize>,
+}
+
+// what reading the table looks like from inside either kind of transaction
+pub trait TableView {
+    fn pages(&self) -> &dyn PageRead;
+    fn num_rows(&self) -> u32;
+
+    fn rows_per_page(&self) -> usize {
+        self.pages().page_size() / ROW_SIZE
+    }
+
+    fn max_rows(&self) -> usize {
+        self.rows_per_page() * MAX_PAGES
+    }
+
+    // A deleted row keeps its slot, so the rows after it keep their rowids. Each page has a bit
+    // for every slot, set once its row is deleted, in the bytes after the last slot that rows
+    // leave spare; there are enough of them at every page size.
+    fn deleted_offset(&self) -> usize {
+        self.pages().page_size() - self.rows_per_page().div_ceil(8)
+    }
+
+    // the number of pages holding rows
+    fn row_pages(&self) -> usize {
+        (self.num_rows() as usize).div_ceil(self.rows_per_page())
+    }
+
+    // Rows fill pages from front to back, but the pages themselves can be anywhere in the file;
+    // files written before anything else was kept in them have their rows from page 1 onwards.
+    fn row_page(&self, index: usize) -> Result<u32, TableError> {
+        match header_value(self.pages(), ROW_PAGES_OFFSET + index * 4)? {
+            0 => Ok(1 + index as u32),
+            page_num => Ok(page_num),
+        }
+    }
+
+    // counts the header page as well as the pages holding rows and everything else
+    fn page_count(&self) -> Result<usize, TableError> {
+        match header_value(self.pages(), PAGE_COUNT_OFFSET)? {
+            0 => Ok(1 + self.row_pages()),
+            page_count => Ok(page_count as usize),
+        }
+    }
+
+    // Only overflow pages are ever given back. A leaf that DELETE empties stays in its tree and
+    // is filled again by later inserts; integrity_check reports any page that no longer belongs
+    // to anything, the freelist included.
+    fn freelist_count(&self) -> Result<usize, TableError> {
+        let page_count = self.page_count()?;
+        let mut count = 0;
+        let mut page_num = header_value(self.pages(), FREELIST_OFFSET)?;
+        // a freelist that runs in circles can't have more pages than the file
+        while page_num != 0 && count < page_count {
+            count += 1;
+            page_num = next_free_page(self.pages(), page_num)?;
+        }
+        Ok(count)
+    }
+}
+
+pub struct TableRead<'t> {
+    txn: ReadTransaction<'t>,
+    num_rows: u32,
+}
+
+impl TableView for TableRead<'_> {
+    fn pages(&self) -> &dyn PageRead {
+        &self.txn
+    }
+
+    fn num_rows(&self) -> u32 {
+        self.num_rows
     }
 }
 
-pub struct Cursor<'table> {
-    table: &'table mut Table,
-    row_num: u32,
+pub struct TableWrite<'t> {
+    // declared before the guard so the transaction ends before the next writer gets its turn
+    txn: WriteTransaction<'t>,
+    _turn: MutexGuard<'t, ()>,
+    pub num_rows: u32,
+}
+
+impl TableView for TableWrite<'_> {
+    fn pages(&self) -> &dyn PageRead {
+        &self.txn
+    }
+
+    fn num_rows(&self) -> u32 {
+        self.num_rows
+    }
+}
+
+impl PageRead for TableWrite<'_> {
+    fn page(&self, page_num: u32) -> Result<Arc<Page>, PagerError> {
+        self.txn.page(page_num)
+    }
+
+    fn page_size(&self) -> usize {
+        self.txn.page_size()
+    }
+}
+
+impl PageWrite for TableWrite<'_> {
+    fn page_mut(&mut self, page_num: u32) -> Result<&mut Page, PagerError> {
+        self.txn.page_mut(page_num)
+    }
+
+    // pages on the freelist are handed out again before the file grows
+    fn allocate(&mut self) -> Result<u32, PagerError> {
+        let pager_error = |e| match e {
+            TableError::Pager(e) => e,
+            _ => PagerError::PagesFull,
+        };
+        let free = header_value(self.pages(), FREELIST_OFFSET).map_err(pager_error)?;
+        if free != 0 {
+            let next = next_free_page(self.pages(), free).map_err(pager_error)?;
+            self.set_header_value(FREELIST_OFFSET, next)?;
+            self.txn.page_mut(free)?.buffer.fill(0);
+            return Ok(free);
+        }
+        let page_num = self.page_count().map_err(pager_error)?;
+        if page_num >= MAX_FILE_PAGES {
+            return Err(PagerError::PagesFull);
+        }
+        self.set_header_value(PAGE_COUNT_OFFSET, page_num as u32 + 1)?;
+        Ok(page_num as u32)
+    }
+
+    fn free(&mut self, page_num: u32) -> Result<(), PagerError> {
+        let next = self.txn.page(0)?.buffer[FREELIST_OFFSET..FREELIST_OFFSET + 4].to_vec();
+        let page = self.txn.page_mut(page_num)?;
+        page.buffer.fill(0);
+        page.buffer[..4].copy_from_slice(&next);
+        self.set_header_value(FREELIST_OFFSET, page_num)
+    }
+}
+
+impl TableWrite<'_> {
+    // the page for a row past the last one is found when the first row on it is written
+    pub fn row_mut(&mut self, row_num: u32) -> Result<&mut [u8], TableError> {
+        let rows_per_page = self.rows_per_page() as u32;
+        let index = (row_num / rows_per_page) as usize;
+        let page_num = if index < self.row_pages() {
+            self.row_page(index)?
+        } else {
+            let page_num = self.allocate().map_err(TableError::Pager)?;
+            self.set_header_value(ROW_PAGES_OFFSET + index * 4, page_num)
+                .map_err(TableError::Pager)?;
+            page_num
+        };
+        let byte_offset = (row_num % rows_per_page) as usize * ROW_SIZE;
+        let page = self.txn.page_mut(page_num).map_err(TableError::Pager)?;
+        Ok(&mut page.buffer[byte_offset..byte_offset + ROW_SIZE])
+    }
+
+    // blanks the row and marks its slot as deleted
+    pub fn delete_row(&mut self, row_num: u32) -> Result<(), TableError> {
+        self.row_mut(row_num)?.fill(0);
+        let rows_per_page = self.rows_per_page() as u32;
+        let page_num = self.row_page((row_num / rows_per_page) as usize)?;
+        let slot = (row_num % rows_per_page) as usize;
+        let offset = self.deleted_offset() + slot / 8;
+        let page = self.txn.page_mut(page_num).map_err(TableError::Pager)?;
+        page.buffer[offset] |= 1 << (slot % 8);
+        Ok(())
+    }
+
+    pub fn set_header_value(&mut self, offset: usize, value: u32) -> Result<(), PagerError> {
+        let header = self.txn.page_mut(0)?;
+        header.buffer[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<(), TableError> {
+        let num_rows = self.num_rows.to_be_bytes();
+        let header = self.txn.page(0).map_err(TableError::Pager)?;
+        // leave the header page alone unless it changed, so it isn't journaled for nothing
+        if header.buffer[NUM_ROWS_OFFSET..NUM_ROWS_OFFSET + 4] != num_rows {
+            let header = self.txn.page_mut(0).map_err(TableError::Pager)?;
+            header.buffer[NUM_ROWS_OFFSET..NUM_ROWS_OFFSET + 4].copy_from_slice(&num_rows);
+        }
+        self.txn.commit().map_err(TableError::Pager)
+    }
+
+    pub fn rollback(self) -> Result<(), TableError> {
+        self.txn.rollback().map_err(TableError::Pager)
+    }
+}
+
+// A position in the rows of the table. The cursor doesn't hold on to the transaction it reads
+// through, which is handed in every time instead, so the same transaction can also be written to.
+pub struct Cursor {
+    num_rows: u32,
+    rows_per_page: u32,
+    deleted_offset: usize,
+    pub row_num: u32,
+    // the page the cursor is on, kept so reading its rows doesn't go back to the pager every time
+    page: Option<(u32, Arc<Page>)>,
     pub end_of_table: bool,
 }
 
-impl Cursor<'_> {
-    pub fn value(&mut self) -> Result<&mut [u8], TableError> {
-        let page_num = self.row_num / ROWS_PER_PAGE as u32;
-        let page = self
-            .table
-            .pager
-            .get_page(page_num)
-            .map_err(TableError::Pager)?;
-        let row_offset = self.row_num % ROWS_PER_PAGE as u32;
-        let byte_offset = row_offset * ROW_SIZE as u32;
-        Ok(&mut page.buffer[byte_offset as usize..byte_offset as usize + ROW_SIZE])
+impl Cursor {
+    // the cursor only sees the rows that were there when it was made
+    pub fn new(view: &dyn TableView) -> Self {
+        let num_rows = view.num_rows();
+        Cursor {
+            num_rows,
+            rows_per_page: view.rows_per_page() as u32,
+            deleted_offset: view.deleted_offset(),
+            row_num: 0,
+            page: None,
+            end_of_table: num_rows == 0,
+        }
     }
 
-    pub fn advance(&mut self) {
-        self.row_num += 1;
-        if self.row_num >= self.table.num_rows {
-            self.end_of_table = true;
+    pub fn value(&mut self, view: &dyn TableView) -> Result<&[u8], TableError> {
+        let byte_offset = (self.row_num % self.rows_per_page) as usize * ROW_SIZE;
+        Ok(&self.page(view)?.buffer[byte_offset..byte_offset + ROW_SIZE])
+    }
+
+    fn page(&mut self, view: &dyn TableView) -> Result<&Page, TableError> {
+        let index = self.row_num / self.rows_per_page;
+        if !matches!(&self.page, Some((current, _)) if *current == index) {
+            let page_num = view.row_page(index as usize)?;
+            let page = view.pages().page(page_num).map_err(TableError::Pager)?;
+            self.page = Some((index, page));
         }
+        Ok(&self.page.as_ref().unwrap().1)
+    }
+
+    pub fn deleted(&mut self, view: &dyn TableView) -> Result<bool, TableError> {
+        let slot = (self.row_num % self.rows_per_page) as usize;
+        let offset = self.deleted_offset + slot / 8;
+        Ok(self.page(view)?.buffer[offset] & (1 << (slot % 8)) != 0)
+    }
+
+    // moves on past any deleted rows, to the next one that is still there
+    pub fn skip_deleted(&mut self, view: &dyn TableView) -> Result<(), TableError> {
+        while !self.end_of_table && self.deleted(view)? {
+            self.advance();
+        }
+        Ok(())
+    }
+
+    pub fn rewind(&mut self) {
+        self.seek(0);
+    }
+
+    pub fn seek(&mut self, row_num: u32) {
+        self.row_num = row_num;
+        self.end_of_table = row_num >= self.num_rows;
+    }
+
+    pub fn advance(&mut self) {
+        self.seek(self.row_num + 1);
+    }
+}
+
+// text columns are padded with NULs, so anything after the first NUL means the row was mangled
+pub fn check_text_column(row_num: u32, name: &str, bytes: &[u8], problems: &mut Vec<String>) {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    if bytes[len..].iter().any(|b| *b != 0) {
+        problems.push(format!(
+            "row {} has garbage after the end of {}",
+            row_num, name
+        ));
+    }
+    if std::str::from_utf8(&bytes[..len]).is_err() {
+        problems.push(format!(
+            "row {} has a {} that is not valid utf-8",
+            row_num, name
+        ));
     }
 }
@@ -1,10 +1,26 @@
 use std::array::TryFromSliceError;
-use std::convert::TryInto;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
 use std::io::Write;
-use std::iter::repeat;
+use std::iter::repeat_n;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::btree::{self, BTreeCursor};
 use crate::constants::*;
-use crate::table::{Table, TableError};
+use crate::error::ErrorCode;
+use crate::pager::{PageRead, PageWrite, TempPages};
+use crate::parser::StatementError;
+use crate::program::{Instruction, Program, Tree};
+use crate::record;
+use crate::schema::Schema;
+use crate::stats::Sample;
+use crate::table::{
+    header_value, CheckedTree, Cursor, Table, TableError, TableRead, TableView, TableWrite,
+};
+use crate::value::Value;
 
 #[derive(Debug)]
 pub struct Row<'a> {
@@ -13,41 +29,61 @@ pub struct Row<'a> {
     email: &'a [u8],
 }
 
-#[derive(Debug)]
-pub struct ResultRow {
-    pub id: u32,
-    pub username: Vec<u8>,
-    pub email: Vec<u8>,
-}
-
-pub enum Statement<'a> {
-    Insert { row: Row<'a> },
-    Select,
-}
-
-#[derive(Debug)]
-pub enum StatementError {
-    Sql,
-    TooLong,
-    InvalidId,
-}
-
-pub struct VirtualMachine<'a> {
-    pub table: &'a mut Table,
-}
-
 #[derive(Debug)]
 pub enum VMErr {
     TableFull,
     RowRead(TryFromSliceError),
     Write(std::io::Error),
     Table(TableError),
+    UnknownPragma,
+    InvalidPragmaValue,
+    Mismatch,
+    // the program was compiled against a schema that has changed since
+    SchemaChanged,
+    Constraint(String),
 }
 
-#[derive(Debug)]
-pub enum VMResult {
-    Rows(Vec<ResultRow>),
-    Success,
+impl VMErr {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            VMErr::TableFull => ErrorCode::Full,
+            // a row that doesn't fit in the buffer it was read into or written from is our own bug
+            VMErr::RowRead(_) | VMErr::Write(_) => ErrorCode::Internal,
+            VMErr::Table(e) => e.code(),
+            VMErr::UnknownPragma => ErrorCode::Error,
+            VMErr::InvalidPragmaValue => ErrorCode::Mismatch,
+            VMErr::Mismatch => ErrorCode::Mismatch,
+            VMErr::SchemaChanged => ErrorCode::Schema,
+            VMErr::Constraint(_) => ErrorCode::Constraint,
+        }
+    }
+}
+
+impl fmt::Display for VMErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VMErr::TableFull => write!(f, "table is full"),
+            VMErr::RowRead(_) => write!(f, "could not read row"),
+            VMErr::Write(_) => write!(f, "could not write row"),
+            VMErr::Table(e) => e.fmt(f),
+            VMErr::UnknownPragma => write!(f, "unknown pragma"),
+            VMErr::InvalidPragmaValue => write!(f, "invalid value for pragma"),
+            VMErr::Mismatch => write!(f, "datatype mismatch"),
+            VMErr::SchemaChanged => write!(f, "database schema has changed"),
+            VMErr::Constraint(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for VMErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VMErr::RowRead(e) => Some(e),
+            VMErr::Write(e) => Some(e),
+            VMErr::Table(e) => e.source(),
+            _ => None,
+        }
+    }
 }
 
 fn serialize_row(row: &Row) -> [u8; ROW_SIZE] {
@@ -76,11 +112,7 @@ fn serialize_row(row: &Row) -> [u8; ROW_SIZE] {
 
     if USERNAME_SIZE - num_un_bytes > 0 {
         (&mut buf[USERNAME_OFFSET + num_un_bytes..USERNAME_OFFSET + USERNAME_SIZE])
-            .write_all(
-                &repeat(0u8)
-                    .take(USERNAME_SIZE - num_un_bytes)
-                    .collect::<Vec<u8>>(),
-            )
+            .write_all(&repeat_n(0u8, USERNAME_SIZE - num_un_bytes).collect::<Vec<u8>>())
             .unwrap();
     }
 
@@ -90,18 +122,14 @@ fn serialize_row(row: &Row) -> [u8; ROW_SIZE] {
 
     if EMAIL_SIZE - num_email_bytes > 0 {
         (&mut buf[EMAIL_OFFSET + num_email_bytes..ROW_SIZE])
-            .write_all(
-                &repeat(0u8)
-                    .take(EMAIL_SIZE - num_email_bytes)
-                    .collect::<Vec<u8>>(),
-            )
+            .write_all(&repeat_n(0u8, EMAIL_SIZE - num_email_bytes).collect::<Vec<u8>>())
             .unwrap();
     }
 
     buf
 }
 
-fn deserialize_row(buf: &[u8; ROW_SIZE]) -> Row {
+fn deserialize_row(buf: &[u8; ROW_SIZE]) -> Row<'_> {
     let id = u32::from_be_bytes(buf[..USERNAME_OFFSET].try_into().unwrap());
     let username = &buf[USERNAME_OFFSET..EMAIL_OFFSET];
     let email = &buf[EMAIL_OFFSET..ROW_SIZE];
@@ -113,76 +141,1198 @@ fn deserialize_row(buf: &[u8; ROW_SIZE]) -> Row {
     }
 }
 
-pub fn prepare_statement(original_input: &str) -> Result<Statement, StatementError> {
-    if original_input.starts_with("insert") {
-        let mut parts = original_input.split(' ');
-        let id = parts.nth(1);
-        let username = parts.next();
-        let email = parts.next();
-        match (id, username, email) {
-            (Some(id), Some(username), Some(email)) => {
-                let id = id.parse().map_err(|_| StatementError::InvalidId)?;
+// makes sure a value fits the column of the users table it is going into
+pub fn check_column(column: usize, value: &Value) -> Result<(), StatementError> {
+    match (column, value) {
+        (0, Value::Integer(id)) if u32::try_from(*id).is_ok() => Ok(()),
+        (0, _) => Err(StatementError::InvalidId),
+        (1, Value::Text(username)) if username.len() > USERNAME_SIZE => {
+            Err(StatementError::TooLong)
+        }
+        (2, Value::Text(email)) if email.len() > EMAIL_SIZE => Err(StatementError::TooLong),
+        (_, Value::Text(_)) => Ok(()),
+        (_, Value::Integer(_)) | (_, Value::Null) => Err(StatementError::WrongType),
+    }
+}
 
-                let username = username.as_bytes();
-                let email = email.as_bytes();
+// A program being run. It runs until it produces a row or halts, so the caller can go
+// through the rows at its own pace; rows are read from the table as it was when the program
+// started, and a transaction the program started ends when it halts or is dropped.
+pub struct VirtualMachine<'t> {
+    table: &'t Table,
+    program: Arc<Program>,
+    params: Vec<Value>,
+    pc: usize,
+    registers: Vec<Value>,
+    cursors: Vec<Option<VMCursor>>,
+    // cursors NullRow has set reading NULLs, until they are moved again
+    null_rows: Vec<bool>,
+    // the Once instructions that have been run, by address
+    once: Vec<bool>,
+    read: Option<TableRead<'t>>,
+    write: Option<TableWrite<'t>>,
+    // the pages of the temporary trees the program has made, once it has made one
+    temp: Option<TempPages>,
+    halted: bool,
+    // rows left referring to nothing through foreign keys that are checked when the statement
+    // ends, and through those checked when it commits
+    violations: i64,
+    deferred_violations: i64,
+    // the first row is produced as soon as the program starts, so errors show up straight away
+    first: Option<Vec<Value>>,
+}
 
-                if username.len() > USERNAME_SIZE || email.len() > EMAIL_SIZE {
-                    Err(StatementError::TooLong)
-                } else {
-                    Ok(Statement::Insert {
-                        row: Row {
-                            id,
-                            username,
-                            email,
+enum VMCursor {
+    // the users table, with its rows in the order they were inserted
+    Rows(Cursor),
+    Tree {
+        root: u32,
+        cursor: BTreeCursor,
+        index: bool,
+        // in the program's temporary pages rather than the database's
+        temp: bool,
+    },
+    // rows the machine made up itself, like the ones a pragma reports
+    Values {
+        rows: Vec<Vec<Value>>,
+        row: usize,
+    },
+    // the row a trigger reads as it was or will be, with its rowid
+    Pseudo {
+        row: Vec<Value>,
+        rowid: Value,
+    },
+    // rows being gathered for ANALYZE
+    Sample(Sample),
+    // a table's rows, each as its rowid followed by its columns, by the value of one column;
+    // it is on the `row`th row under `key`
+    Hash {
+        rows: HashMap<Value, Vec<Vec<Value>>>,
+        key: Value,
+        row: usize,
+    },
+}
+
+impl<'t> VirtualMachine<'t> {
+    pub fn start(
+        table: &'t Table,
+        program: Arc<Program>,
+        params: Vec<Value>,
+    ) -> Result<Self, VMErr> {
+        let mut vm = VirtualMachine {
+            table,
+            params,
+            pc: 0,
+            registers: vec![Value::Null; program.registers],
+            cursors: (0..program.cursors).map(|_| None).collect(),
+            null_rows: vec![false; program.cursors],
+            once: vec![false; program.instructions.len()],
+            read: None,
+            write: None,
+            temp: None,
+            halted: false,
+            violations: 0,
+            deferred_violations: 0,
+            first: None,
+            program,
+        };
+        vm.first = vm.step()?;
+        Ok(vm)
+    }
+
+    // runs until the next row is ready, returning None once the program has halted
+    pub fn step(&mut self) -> Result<Option<Vec<Value>>, VMErr> {
+        if self.halted {
+            return Ok(None);
+        }
+        match self.run() {
+            Ok(row) => Ok(row),
+            Err(e) => {
+                self.halted = true;
+                self.cursors.clear();
+                self.temp = None;
+                self.read = None;
+                if let Some(table) = self.write.take() {
+                    table.rollback().map_err(VMErr::Table)?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn run(&mut self) -> Result<Option<Vec<Value>>, VMErr> {
+        let program = self.program.clone();
+        loop {
+            let instruction = &program.instructions[self.pc];
+            self.pc += 1;
+            match instruction {
+                Instruction::Transaction {
+                    write,
+                    schema_version,
+                } => {
+                    let version = if *write {
+                        let table = self.write.insert(self.table.write().map_err(VMErr::Table)?);
+                        header_value(table.pages(), SCHEMA_VERSION_OFFSET)
+                    } else {
+                        let table = self.read.insert(self.table.read().map_err(VMErr::Table)?);
+                        header_value(table.pages(), SCHEMA_VERSION_OFFSET)
+                    };
+                    if version.map_err(VMErr::Table)? != *schema_version {
+                        return Err(VMErr::SchemaChanged);
+                    }
+                }
+                Instruction::OpenRead { cursor, tree }
+                | Instruction::OpenWrite { cursor, tree } => {
+                    let write = matches!(instruction, Instruction::OpenWrite { .. });
+                    self.cursors[*cursor] = Some(self.open(*tree, write)?);
+                }
+                Instruction::Rewind { cursor, target } => {
+                    if !self.rewind(*cursor)? {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::Column {
+                    cursor,
+                    column,
+                    dest,
+                } => {
+                    self.registers[*dest] = if self.null_rows[*cursor] {
+                        Value::Null
+                    } else {
+                        self.column(*cursor, *column)?
+                    };
+                }
+                Instruction::ResultRow { start, count } => {
+                    return Ok(Some(self.registers[*start..start + count].to_vec()));
+                }
+                Instruction::Next { cursor, target } => {
+                    if self.advance(*cursor)? {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::Integer { value, dest } => {
+                    self.registers[*dest] = Value::Integer(*value)
+                }
+                Instruction::String { value, dest } => {
+                    self.registers[*dest] = Value::Text(value.clone())
+                }
+                Instruction::Null { dest } => self.registers[*dest] = Value::Null,
+                Instruction::Variable { param, dest } => {
+                    self.registers[*dest] = self.params[*param].clone()
+                }
+                Instruction::Goto { target } => self.pc = *target,
+                Instruction::If { src, target } => {
+                    if truth(&self.registers[*src]) == Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::IfNot { src, target } => {
+                    if truth(&self.registers[*src]) != Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::IsNull { src, target } => {
+                    if self.registers[*src] == Value::Null {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::Copy { src, dest } => {
+                    self.registers[*dest] = self.registers[*src].clone();
+                }
+                Instruction::MustBeInt { src } => {
+                    let value = match &self.registers[*src] {
+                        Value::Integer(i) => Some(*i),
+                        Value::Text(text) => text.parse().ok(),
+                        Value::Null => None,
+                    };
+                    self.registers[*src] = Value::Integer(value.ok_or(VMErr::Mismatch)?);
+                }
+                Instruction::FkCounter { deferred, amount } => {
+                    if *deferred {
+                        self.deferred_violations += amount;
+                    } else {
+                        self.violations += amount;
+                    }
+                }
+                Instruction::FkIfZero { deferred, target } => {
+                    let violations = if *deferred {
+                        self.deferred_violations
+                    } else {
+                        self.violations
+                    };
+                    if violations == 0 {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::FkCheck => {
+                    if self.violations != 0 {
+                        return Err(VMErr::Constraint(FOREIGN_KEY_FAILED.into()));
+                    }
+                }
+                Instruction::Constraint { message } => {
+                    return Err(VMErr::Constraint(message.clone()));
+                }
+                Instruction::Unary { op, src, dest } => {
+                    self.registers[*dest] = unary(*op, &self.registers[*src]);
+                }
+                Instruction::Binary {
+                    op,
+                    left,
+                    right,
+                    dest,
+                } => {
+                    self.registers[*dest] =
+                        binary(*op, &self.registers[*left], &self.registers[*right]);
+                }
+                Instruction::Function {
+                    name,
+                    start,
+                    count,
+                    dest,
+                } => {
+                    self.registers[*dest] = function(name, &self.registers[*start..start + count]);
+                }
+                Instruction::NullRow { cursor } => self.null_rows[*cursor] = true,
+                Instruction::Rowid { cursor, dest } => {
+                    let rowid = match self.cursors[*cursor].as_ref().expect(OPEN) {
+                        _ if self.null_rows[*cursor] => Value::Null,
+                        VMCursor::Rows(cursor) => Value::Integer(cursor.row_num as i64 + 1),
+                        VMCursor::Tree { cursor, index, .. } => {
+                            let key = record::decode(cursor.key());
+                            let rowid = if *index { key.last() } else { key.first() };
+                            rowid.cloned().unwrap_or(Value::Null)
+                        }
+                        VMCursor::Values { row, .. } => Value::Integer(*row as i64 + 1),
+                        VMCursor::Pseudo { rowid, .. } => rowid.clone(),
+                        VMCursor::Hash { rows, key, row } => rows[key][*row][0].clone(),
+                        VMCursor::Sample(_) => panic!("program read rows from a sample"),
+                    };
+                    self.registers[*dest] = rowid;
+                }
+                Instruction::SeekRowid {
+                    cursor,
+                    src,
+                    target,
+                } => {
+                    self.null_rows[*cursor] = false;
+                    let rowid = match self.registers[*src] {
+                        Value::Integer(rowid) => Some(rowid),
+                        _ => None,
+                    };
+                    let found = match (self.cursors[*cursor].as_mut().expect(OPEN), rowid) {
+                        // the cursor starts again, so it sees rows written since it was opened
+                        (VMCursor::Rows(cursor), Some(rowid)) => match u32::try_from(rowid - 1) {
+                            Ok(row_num) => {
+                                let view = view(&self.read, &self.write);
+                                *cursor = Cursor::new(view);
+                                cursor.seek(row_num);
+                                !cursor.end_of_table
+                                    && !cursor.deleted(view).map_err(VMErr::Table)?
+                            }
+                            Err(_) => false,
                         },
+                        (VMCursor::Tree { cursor, temp, .. }, Some(rowid)) => {
+                            let pages = pages(*temp, &self.temp, &self.read, &self.write);
+                            let key = record::encode(&[Value::Integer(rowid)]);
+                            cursor.seek(pages, &key).map_err(VMErr::Table)?;
+                            !cursor.eof && cursor.key() == &key[..]
+                        }
+                        _ => false,
+                    };
+                    if !found {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::SeekGE {
+                    cursor,
+                    start,
+                    count,
+                    target,
+                }
+                | Instruction::SeekGT {
+                    cursor,
+                    start,
+                    count,
+                    target,
+                } => {
+                    let mut key = record::encode(&self.registers[*start..start + count]);
+                    // no key goes on from a prefix with this byte, so it sorts after them all
+                    if matches!(instruction, Instruction::SeekGT { .. }) {
+                        key.push(0xff);
+                    }
+                    self.null_rows[*cursor] = false;
+                    let (cursor, temp) = match self.cursors[*cursor].as_mut().expect(OPEN) {
+                        VMCursor::Tree { cursor, temp, .. } => (cursor, *temp),
+                        _ => panic!("program seeked a cursor that isn't on an index"),
+                    };
+                    let pages = pages(temp, &self.temp, &self.read, &self.write);
+                    cursor.seek(pages, &key).map_err(VMErr::Table)?;
+                    if cursor.eof {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::IdxGT {
+                    cursor,
+                    start,
+                    count,
+                    target,
+                }
+                | Instruction::IdxGE {
+                    cursor,
+                    start,
+                    count,
+                    target,
+                } => {
+                    let key = match self.cursors[*cursor].as_ref().expect(OPEN) {
+                        VMCursor::Tree { cursor, .. } => record::decode(cursor.key()),
+                        _ => panic!("program compared a cursor that isn't on an index"),
+                    };
+                    let values = &self.registers[*start..start + count];
+                    let prefix = &key[..(*count).min(key.len())];
+                    let jump = if matches!(instruction, Instruction::IdxGT { .. }) {
+                        prefix > values
+                    } else {
+                        prefix >= values
+                    };
+                    if jump {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::NewRowid { cursor, dest } => {
+                    let rowid = match self.cursors[*cursor].as_ref().expect(OPEN) {
+                        VMCursor::Rows(_) => view(&self.read, &self.write).num_rows() as i64 + 1,
+                        VMCursor::Tree { root, temp, .. } => {
+                            let pages = pages(*temp, &self.temp, &self.read, &self.write);
+                            let last = btree::last_key(pages, *root).map_err(VMErr::Table)?;
+                            match last.as_deref().map(record::decode).as_deref() {
+                                Some([Value::Integer(rowid), ..]) => match rowid.checked_add(1) {
+                                    Some(rowid) => rowid,
+                                    // the largest rowid there is has been used, so an unused
+                                    // one has to be looked for among the ones below it
+                                    None => unused_rowid(pages, *root)
+                                        .map_err(VMErr::Table)?
+                                        .ok_or(VMErr::TableFull)?,
+                                },
+                                _ => 1,
+                            }
+                        }
+                        VMCursor::Values { .. }
+                        | VMCursor::Pseudo { .. }
+                        | VMCursor::Sample(_)
+                        | VMCursor::Hash { .. } => panic!("program inserted into made up rows"),
+                    };
+                    self.registers[*dest] = Value::Integer(rowid);
+                }
+                Instruction::Insert {
+                    cursor,
+                    start,
+                    count,
+                    rowid,
+                } => {
+                    let registers = &self.registers;
+                    let values = &registers[*start..start + count];
+                    let table = self.write.as_mut();
+                    match self.cursors[*cursor].as_ref().expect(OPEN) {
+                        VMCursor::Rows(_) => {
+                            let table = table.expect(WRITE);
+                            // a row that is already there is written over in place
+                            let row_num = match rowid.map(|rowid| &registers[rowid]) {
+                                Some(Value::Integer(rowid))
+                                    if (1..=table.num_rows as i64).contains(rowid) =>
+                                {
+                                    Some(*rowid as u32 - 1)
+                                }
+                                _ => None,
+                            };
+                            insert(table, values, row_num)?
+                        }
+                        VMCursor::Tree { root, temp, .. } => {
+                            let rowid = &registers[rowid.expect("row inserted without a rowid")];
+                            btree::insert(
+                                pages_mut(*temp, &mut self.temp, table),
+                                *root,
+                                &record::encode(std::slice::from_ref(rowid)),
+                                &record::encode(values),
+                            )
+                            .map_err(VMErr::Table)?;
+                        }
+                        VMCursor::Values { .. }
+                        | VMCursor::Pseudo { .. }
+                        | VMCursor::Sample(_)
+                        | VMCursor::Hash { .. } => panic!("program inserted into made up rows"),
+                    }
+                }
+                Instruction::IdxInsert {
+                    cursor,
+                    start,
+                    count,
+                } => {
+                    let (root, temp) = match self.cursors[*cursor].as_ref().expect(OPEN) {
+                        VMCursor::Tree { root, temp, .. } => (*root, *temp),
+                        _ => panic!("program inserted a key into a cursor that isn't on an index"),
+                    };
+                    let key = record::encode(&self.registers[*start..start + count]);
+                    let pages = pages_mut(temp, &mut self.temp, self.write.as_mut());
+                    btree::insert(pages, root, &key, &[]).map_err(VMErr::Table)?;
+                }
+                Instruction::Delete { cursor } => {
+                    let (root, key, temp) = match self.cursors[*cursor].as_ref().expect(OPEN) {
+                        VMCursor::Rows(cursor) => {
+                            let table = self.write.as_mut().expect(WRITE);
+                            table.delete_row(cursor.row_num).map_err(VMErr::Table)?;
+                            continue;
+                        }
+                        VMCursor::Tree {
+                            root, cursor, temp, ..
+                        } => (*root, cursor.key().to_vec(), *temp),
+                        _ => panic!("program deleted from a cursor that isn't on a tree"),
+                    };
+                    let pages = pages_mut(temp, &mut self.temp, self.write.as_mut());
+                    btree::delete(pages, root, &key).map_err(VMErr::Table)?;
+                }
+                Instruction::IdxDelete {
+                    cursor,
+                    start,
+                    count,
+                } => {
+                    let (root, temp) = match self.cursors[*cursor].as_ref().expect(OPEN) {
+                        VMCursor::Tree { root, temp, .. } => (*root, *temp),
+                        _ => panic!("program deleted a key from a cursor that isn't on an index"),
+                    };
+                    let key = record::encode(&self.registers[*start..start + count]);
+                    let pages = pages_mut(temp, &mut self.temp, self.write.as_mut());
+                    btree::delete(pages, root, &key).map_err(VMErr::Table)?;
+                }
+                Instruction::OpenHash { cursor } => {
+                    self.cursors[*cursor] = Some(VMCursor::Hash {
+                        rows: HashMap::new(),
+                        key: Value::Null,
+                        row: 0,
+                    });
+                }
+                Instruction::HashInsert {
+                    cursor,
+                    key,
+                    start,
+                    count,
+                } => match self.cursors[*cursor].as_mut().expect(OPEN) {
+                    VMCursor::Hash { rows, .. } => {
+                        if self.registers[*key] != Value::Null {
+                            rows.entry(self.registers[*key].clone())
+                                .or_default()
+                                .push(self.registers[*start..start + count].to_vec());
+                        }
+                    }
+                    _ => panic!("program added a row to a cursor that isn't a hash table"),
+                },
+                Instruction::HashSeek {
+                    cursor,
+                    key,
+                    target,
+                } => {
+                    self.null_rows[*cursor] = false;
+                    match self.cursors[*cursor].as_mut().expect(OPEN) {
+                        VMCursor::Hash { rows, key: at, row } => {
+                            if rows.contains_key(&self.registers[*key]) {
+                                *at = self.registers[*key].clone();
+                                *row = 0;
+                            } else {
+                                self.pc = *target;
+                            }
+                        }
+                        _ => panic!("program looked up a cursor that isn't a hash table"),
+                    }
+                }
+                Instruction::Once { target } => {
+                    let address = self.pc - 1;
+                    if self.once[address] {
+                        self.pc = *target;
+                    }
+                    self.once[address] = true;
+                }
+                Instruction::OpenEphemeral { cursor } => {
+                    let table = self.table;
+                    let pages = self.temp.get_or_insert_with(|| table.temp_pages());
+                    let root = btree::create(pages).map_err(VMErr::Table)?;
+                    self.cursors[*cursor] = Some(temp_tree(root));
+                }
+                Instruction::OpenDup { cursor, from } => {
+                    let root = match self.cursors[*from].as_ref().expect(OPEN) {
+                        VMCursor::Tree {
+                            root, temp: true, ..
+                        } => *root,
+                        _ => panic!("program copied a cursor that isn't on a temporary tree"),
+                    };
+                    self.cursors[*cursor] = Some(temp_tree(root));
+                }
+                Instruction::OpenPseudo {
+                    cursor,
+                    start,
+                    count,
+                    rowid,
+                } => {
+                    self.null_rows[*cursor] = false;
+                    self.cursors[*cursor] = Some(VMCursor::Pseudo {
+                        row: self.registers[*start..start + count].to_vec(),
+                        rowid: self.registers[*rowid].clone(),
+                    });
+                }
+                Instruction::CreateBtree { dest } => {
+                    let table = self
+                        .write
+                        .as_mut()
+                        .expect("program created a tree outside a write transaction");
+                    let root = btree::create(table).map_err(VMErr::Table)?;
+                    self.registers[*dest] = Value::Integer(root as i64);
+                }
+                Instruction::ChangeSchema => {
+                    let table = self
+                        .write
+                        .as_mut()
+                        .expect("program changed the schema outside a write transaction");
+                    let version =
+                        header_value(table.pages(), SCHEMA_VERSION_OFFSET).map_err(VMErr::Table)?;
+                    table
+                        .set_header_value(SCHEMA_VERSION_OFFSET, version.wrapping_add(1))
+                        .map_err(|e| VMErr::Table(TableError::Pager(e)))?;
+                }
+                Instruction::OpenSample { cursor } => {
+                    self.cursors[*cursor] = Some(VMCursor::Sample(Sample::new()));
+                }
+                Instruction::SampleRow {
+                    cursor,
+                    start,
+                    count,
+                } => match self.cursors[*cursor].as_mut().expect(OPEN) {
+                    VMCursor::Sample(sample) => sample.push(&self.registers[*start..start + count]),
+                    _ => panic!("program sampled a row into a cursor that isn't a sample"),
+                },
+                Instruction::SaveStats {
+                    cursor,
+                    stats,
+                    table,
+                    columns,
+                    indexes,
+                } => {
+                    let sample = match self.cursors[*cursor].take().expect(OPEN) {
+                        VMCursor::Sample(sample) => sample,
+                        _ => panic!("program saved statistics from a cursor that isn't a sample"),
+                    };
+                    self.save_stats(&sample, *stats, table, columns, indexes)?;
+                }
+                Instruction::Pragma {
+                    cursor,
+                    name,
+                    value,
+                } => {
+                    let value = value.map(|value| self.registers[value].to_string());
+                    let rows = self.execute_pragma(name, value.as_deref())?;
+                    self.cursors[*cursor] = Some(VMCursor::Values {
+                        rows: rows
+                            .into_iter()
+                            .map(|row| row.into_iter().map(Value::Text).collect())
+                            .collect(),
+                        row: 0,
+                    });
+                }
+                Instruction::Halt => {
+                    // a transaction lasts as long as its statement, so it commits right after the
+                    // statement's own check
+                    if self.deferred_violations != 0 {
+                        return Err(VMErr::Constraint(FOREIGN_KEY_FAILED.into()));
+                    }
+                    self.halted = true;
+                    self.cursors.clear();
+                    self.temp = None;
+                    self.read = None;
+                    if let Some(table) = self.write.take() {
+                        table.commit().map_err(VMErr::Table)?;
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    // moves the cursor to its first row, returning whether it has one
+    fn rewind(&mut self, cursor: usize) -> Result<bool, VMErr> {
+        self.null_rows[cursor] = false;
+        Ok(match self.cursors[cursor].as_mut().expect(OPEN) {
+            VMCursor::Rows(cursor) => {
+                cursor.rewind();
+                let view = view(&self.read, &self.write);
+                cursor.skip_deleted(view).map_err(VMErr::Table)?;
+                !cursor.end_of_table
+            }
+            VMCursor::Tree { cursor, temp, .. } => {
+                cursor
+                    .first(pages(*temp, &self.temp, &self.read, &self.write))
+                    .map_err(VMErr::Table)?;
+                !cursor.eof
+            }
+            VMCursor::Values { rows, row } => {
+                *row = 0;
+                !rows.is_empty()
+            }
+            VMCursor::Pseudo { .. } | VMCursor::Sample(_) | VMCursor::Hash { .. } => {
+                panic!("program rewound a cursor it can only look rows up in")
+            }
+        })
+    }
+
+    fn column(&mut self, cursor: usize, column: usize) -> Result<Value, VMErr> {
+        Ok(match self.cursors[cursor].as_mut().expect(OPEN) {
+            VMCursor::Rows(cursor) => {
+                let row_buffer: &[u8; ROW_SIZE] = cursor
+                    .value(view(&self.read, &self.write))
+                    .map_err(VMErr::Table)?
+                    .try_into()
+                    .map_err(VMErr::RowRead)?;
+                column_value(&deserialize_row(row_buffer), column)
+            }
+            VMCursor::Tree { cursor, index, .. } => {
+                let record = if *index {
+                    cursor.key()
+                } else {
+                    cursor.payload()
+                };
+                record::decode(record)
+                    .into_iter()
+                    .nth(column)
+                    .unwrap_or(Value::Null)
+            }
+            VMCursor::Values { rows, row } => rows[*row][column].clone(),
+            VMCursor::Pseudo { row, .. } => row.get(column).cloned().unwrap_or(Value::Null),
+            // the rowid comes first
+            VMCursor::Hash { rows, key, row } => rows[key][*row][column + 1].clone(),
+            VMCursor::Sample(_) => panic!("program read rows from a sample"),
+        })
+    }
+
+    // moves the cursor on, returning whether there was another row to move on to
+    fn advance(&mut self, cursor: usize) -> Result<bool, VMErr> {
+        if self.null_rows[cursor] {
+            return Ok(false);
+        }
+        Ok(match self.cursors[cursor].as_mut().expect(OPEN) {
+            VMCursor::Rows(cursor) => {
+                cursor.advance();
+                let view = view(&self.read, &self.write);
+                cursor.skip_deleted(view).map_err(VMErr::Table)?;
+                !cursor.end_of_table
+            }
+            VMCursor::Tree { cursor, temp, .. } => {
+                cursor
+                    .next(pages(*temp, &self.temp, &self.read, &self.write))
+                    .map_err(VMErr::Table)?;
+                !cursor.eof
+            }
+            VMCursor::Values { rows, row } => {
+                *row += 1;
+                *row < rows.len()
+            }
+            VMCursor::Hash { rows, key, row } => {
+                *row += 1;
+                *row < rows[key].len()
+            }
+            VMCursor::Pseudo { .. } => false,
+            VMCursor::Sample(_) => panic!("program read rows from a sample"),
+        })
+    }
+
+    fn open(&mut self, tree: Tree, write: bool) -> Result<VMCursor, VMErr> {
+        let root = match tree {
+            Tree::Rows => {
+                let view = view(&self.read, &self.write);
+                return Ok(VMCursor::Rows(Cursor::new(view)));
+            }
+            Tree::Schema => match self.header_tree(SCHEMA_ROOT_OFFSET, write)? {
+                Some(root) => root,
+                None => {
+                    return Ok(VMCursor::Values {
+                        rows: Vec::new(),
+                        row: 0,
                     })
                 }
+            },
+            Tree::Stats => match self.header_tree(STATS_ROOT_OFFSET, write)? {
+                Some(root) => root,
+                None => {
+                    return Ok(VMCursor::Values {
+                        rows: Vec::new(),
+                        row: 0,
+                    })
+                }
+            },
+            Tree::Table(root) | Tree::Index(root) => root,
+            Tree::NewIndex(register) => match self.registers[register] {
+                Value::Integer(root) => root as u32,
+                _ => panic!("r[{}] doesn't hold a root page", register),
+            },
+        };
+        Ok(VMCursor::Tree {
+            root,
+            cursor: BTreeCursor::new(root),
+            index: !matches!(tree, Tree::Table(_) | Tree::Schema | Tree::Stats),
+            temp: false,
+        })
+    }
+
+    // The root page of a tree the header keeps track of. These trees are only made once
+    // something goes in them, so until then there is nothing to read.
+    fn header_tree(&mut self, offset: usize, write: bool) -> Result<Option<u32>, VMErr> {
+        let view = view(&self.read, &self.write);
+        let root = header_value(view.pages(), offset).map_err(VMErr::Table)?;
+        match (root, self.write.as_mut()) {
+            (0, Some(table)) if write => {
+                let root = btree::create(table).map_err(VMErr::Table)?;
+                table
+                    .set_header_value(offset, root)
+                    .map_err(|e| VMErr::Table(TableError::Pager(e)))?;
+                Ok(Some(root))
             }
-            _ => Err(StatementError::Sql),
+            (0, _) => Ok(None),
+            (root, _) => Ok(Some(root)),
         }
-    } else if original_input.starts_with("select") {
-        Ok(Statement::Select)
-    } else {
-        Err(StatementError::Sql)
     }
-}
 
-impl VirtualMachine<'_> {
-    pub fn execute_statement<'a>(
-        &'a mut self,
-        statement: Statement<'a>,
-    ) -> Result<VMResult, VMErr> {
-        match statement {
-            Statement::Insert { row } => {
-                if self.table.num_rows == TABLE_MAX_ROWS as u32 {
-                    Err(VMErr::TableFull)
+    fn save_stats(
+        &mut self,
+        sample: &Sample,
+        stats: usize,
+        table_name: &str,
+        columns: &[String],
+        indexes: &[(String, Vec<usize>)],
+    ) -> Result<(), VMErr> {
+        let root = match self.cursors[stats].as_ref().expect(OPEN) {
+            VMCursor::Tree { root, .. } => *root,
+            _ => panic!("program wrote statistics through a cursor that isn't on a tree"),
+        };
+        let table = self
+            .write
+            .as_mut()
+            .expect("program wrote statistics outside a write transaction");
+        let name = || Value::Text(table_name.to_string());
+        let mut entries = vec![(vec![name()], vec![Value::Integer(sample.rows() as i64)])];
+        for (column, column_name) in columns.iter().enumerate() {
+            entries.push((
+                vec![
+                    name(),
+                    Value::Text("column".into()),
+                    Value::Text(column_name.clone()),
+                ],
+                sample.column(column).to_record(sample.sampled()),
+            ));
+        }
+        for (index_name, index_columns) in indexes {
+            entries.push((
+                vec![
+                    name(),
+                    Value::Text("index".into()),
+                    Value::Text(index_name.clone()),
+                ],
+                (1..=index_columns.len())
+                    .map(|prefix| Value::Integer(sample.distinct(&index_columns[..prefix]) as i64))
+                    .collect(),
+            ));
+        }
+        for (key, stats) in entries {
+            btree::insert(table, root, &record::encode(&key), &record::encode(&stats))
+                .map_err(VMErr::Table)?;
+        }
+        Ok(())
+    }
+
+    // each pragma reports its rows already rendered as text
+    fn execute_pragma(&self, name: &str, value: Option<&str>) -> Result<Vec<Vec<String>>, VMErr> {
+        let name = name.to_ascii_lowercase();
+        let single = |value: String| Ok(vec![vec![value]]);
+        match (&name[..], value) {
+            ("page_size", None) => single(self.table.page_size().to_string()),
+            ("page_size", Some(value)) => {
+                let page_size = value.parse().map_err(|_| VMErr::InvalidPragmaValue)?;
+                self.table.set_page_size(page_size).map_err(VMErr::Table)?;
+                Ok(Vec::new())
+            }
+            ("cache_size", None) => single(self.table.cache_size().to_string()),
+            ("cache_size", Some(value)) => match value.parse::<usize>() {
+                Ok(cache_size) if cache_size > 0 => {
+                    self.table.set_cache_size(cache_size);
+                    Ok(Vec::new())
+                }
+                _ => Err(VMErr::InvalidPragmaValue),
+            },
+            ("synchronous", None) => single(self.table.synchronous().to_string()),
+            ("synchronous", Some(value)) => {
+                let synchronous = value.parse().map_err(|_| VMErr::InvalidPragmaValue)?;
+                self.table.set_synchronous(synchronous);
+                Ok(Vec::new())
+            }
+            ("busy_timeout", None) => single(self.table.busy_timeout().as_millis().to_string()),
+            ("busy_timeout", Some(value)) => {
+                let millis = value.parse().map_err(|_| VMErr::InvalidPragmaValue)?;
+                self.table.set_busy_timeout(Duration::from_millis(millis));
+                Ok(Vec::new())
+            }
+            ("journal_mode", None) => single(self.table.journal_mode().to_string()),
+            ("journal_mode", Some(value)) => {
+                let journal_mode = value.parse().map_err(|_| VMErr::InvalidPragmaValue)?;
+                self.table.set_journal_mode(journal_mode);
+                single(journal_mode.to_string())
+            }
+            ("integrity_check", None) => {
+                let problems = self
+                    .table
+                    .integrity_check(|table| {
+                        let schema = Schema::load(table)?;
+                        let position =
+                            |name: &str| schema.tables.iter().position(|table| table.name == name);
+                        let tables = schema.tables.iter().map(|table| CheckedTree {
+                            name: table.name.clone(),
+                            root: table.root,
+                            table: None,
+                        });
+                        let indexes = schema.indexes.iter().map(|index| CheckedTree {
+                            name: index.name.clone(),
+                            root: Some(index.root),
+                            table: position(&index.table),
+                        });
+                        Ok(tables.chain(indexes).collect())
+                    })
+                    .map_err(VMErr::Table)?;
+                if problems.is_empty() {
+                    single("ok".into())
                 } else {
-                    let mut cursor = self.table.end();
-                    let mut row_buffer = cursor.value().map_err(VMErr::Table)?;
-                    let bytes = serialize_row(&row);
-                    row_buffer.write_all(&bytes).map_err(VMErr::Write)?;
-                    self.table.num_rows += 1;
-                    Ok(VMResult::Success)
+                    Ok(problems.into_iter().map(|p| vec![p]).collect())
                 }
             }
-            Statement::Select => {
+            ("page_count", None) => {
+                let table = self.table.read().map_err(VMErr::Table)?;
+                single(table.page_count().map_err(VMErr::Table)?.to_string())
+            }
+            ("freelist_count", None) => {
+                let table = self.table.read().map_err(VMErr::Table)?;
+                single(table.freelist_count().map_err(VMErr::Table)?.to_string())
+            }
+            ("table_info", Some(name)) => {
+                let schema = Schema::load(&self.table.read().map_err(VMErr::Table)?)
+                    .map_err(VMErr::Table)?;
+                let table = schema.table(name).ok_or(VMErr::InvalidPragmaValue)?;
+                Ok(table
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(cid, column)| {
+                        let pk = table.primary_key == Some(cid);
+                        vec![
+                            cid.to_string(),
+                            column.name.clone(),
+                            column.data_type.clone(),
+                            (column.not_null as u8).to_string(),
+                            column
+                                .default
+                                .as_ref()
+                                .map_or_else(String::new, |default| default.sql.clone()),
+                            (pk as u8).to_string(),
+                        ]
+                    })
+                    .collect())
+            }
+            ("table_info", None) => Err(VMErr::InvalidPragmaValue),
+            // what ANALYZE found out, one row for each column and each index
+            ("stats", None) => {
+                let schema = Schema::load(&self.table.read().map_err(VMErr::Table)?)
+                    .map_err(VMErr::Table)?;
                 let mut rows = Vec::new();
-                let mut cursor = self.table.start();
-
-                while !cursor.end_of_table {
-                    let row_buffer = cursor.value().map_err(VMErr::Table)?;
-                    let sized_row_buffer = (&*row_buffer).try_into().map_err(VMErr::RowRead)?;
-                    let row = deserialize_row(sized_row_buffer);
-                    rows.push(ResultRow {
-                        id: row.id,
-                        username: row.username.to_owned(),
-                        email: row.email.to_owned(),
-                    });
-                    cursor.advance();
+                for table in &schema.tables {
+                    for column in &table.columns {
+                        if let Some(stats) = &column.stats {
+                            let histogram: Vec<String> =
+                                stats.histogram.iter().map(Value::to_string).collect();
+                            rows.push(vec![
+                                table.name.clone(),
+                                column.name.clone(),
+                                table.rows.to_string(),
+                                stats.distinct.to_string(),
+                                format!("{:.2}", stats.null_fraction),
+                                histogram.join(" "),
+                            ]);
+                        }
+                    }
+                    for index in schema.indexes_of(&table.name) {
+                        if !index.distinct.is_empty() {
+                            let distinct: Vec<String> =
+                                index.distinct.iter().map(u64::to_string).collect();
+                            rows.push(vec![
+                                table.name.clone(),
+                                index.name.clone(),
+                                table.rows.to_string(),
+                                distinct.join(" "),
+                                "".into(),
+                                "".into(),
+                            ]);
+                        }
+                    }
+                }
+                Ok(rows)
+            }
+            _ => Err(VMErr::UnknownPragma),
+        }
+    }
+}
+
+impl Iterator for VirtualMachine<'_> {
+    type Item = Result<Vec<Value>, VMErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.first.take() {
+            Some(row) => Some(Ok(row)),
+            None => self.step().transpose(),
+        }
+    }
+}
+
+// a cursor is always opened before the program uses it
+const OPEN: &str = "program used a cursor before opening it";
+const WRITE: &str = "program wrote to the database outside a write transaction";
+
+// the table the program is working on, as of its transaction
+fn view<'v>(read: &'v Option<TableRead>, write: &'v Option<TableWrite>) -> &'v dyn TableView {
+    match (write, read) {
+        (Some(write), _) => write,
+        (None, Some(read)) => read,
+        (None, None) => panic!("program read the table outside a transaction"),
+    }
+}
+
+// the pages a tree is in: the program's temporary ones, or the database's
+fn pages<'v>(
+    temp: bool,
+    temp_pages: &'v Option<TempPages>,
+    read: &'v Option<TableRead>,
+    write: &'v Option<TableWrite>,
+) -> &'v dyn PageRead {
+    match temp_pages {
+        Some(temp_pages) if temp => temp_pages,
+        _ => view(read, write).pages(),
+    }
+}
+
+fn pages_mut<'v>(
+    temp: bool,
+    temp_pages: &'v mut Option<TempPages>,
+    write: Option<&'v mut TableWrite>,
+) -> &'v mut dyn PageWrite {
+    match temp_pages {
+        Some(temp_pages) if temp => temp_pages,
+        _ => write.expect(WRITE),
+    }
+}
+
+// a cursor on a tree the program made for itself
+// the smallest positive rowid the tree doesn't have yet, if there is one
+fn unused_rowid(pages: &dyn PageRead, root: u32) -> Result<Option<i64>, TableError> {
+    let mut cursor = BTreeCursor::new(root);
+    cursor.first(pages)?;
+    let mut unused = 1;
+    while !cursor.eof {
+        if let [Value::Integer(rowid)] = record::decode(cursor.key()).as_slice() {
+            if *rowid > unused {
+                return Ok(Some(unused));
+            }
+            if *rowid == unused {
+                match unused.checked_add(1) {
+                    Some(next) => unused = next,
+                    None => return Ok(None),
                 }
+            }
+        }
+        cursor.next(pages)?;
+    }
+    Ok(Some(unused))
+}
+
+fn temp_tree(root: u32) -> VMCursor {
+    VMCursor::Tree {
+        root,
+        cursor: BTreeCursor::new(root),
+        index: false,
+        temp: true,
+    }
+}
+
+// NULL is neither true nor false
+fn truth(value: &Value) -> Option<bool> {
+    match value {
+        Value::Null => None,
+        Value::Integer(i) => Some(*i != 0),
+        Value::Text(text) => Some(text.trim().parse::<i64>().is_ok_and(|i| i != 0)),
+    }
+}
+
+fn boolean(value: Option<bool>) -> Value {
+    match value {
+        Some(value) => Value::Integer(value as i64),
+        None => Value::Null,
+    }
+}
 
-                Ok(VMResult::Rows(rows))
+// text used in arithmetic counts as the number it spells, or 0
+fn number(value: &Value) -> Option<i64> {
+    match value {
+        Value::Null => None,
+        Value::Integer(i) => Some(*i),
+        Value::Text(text) => Some(text.trim().parse().unwrap_or(0)),
+    }
+}
+
+fn unary(op: UnaryOp, value: &Value) -> Value {
+    match op {
+        UnaryOp::Not => boolean(truth(value).map(|value| !value)),
+        UnaryOp::Negate => match number(value) {
+            Some(i) => Value::Integer(i.wrapping_neg()),
+            None => Value::Null,
+        },
+        UnaryOp::IsNull => Value::Integer((*value == Value::Null) as i64),
+        UnaryOp::NotNull => Value::Integer((*value != Value::Null) as i64),
+    }
+}
+
+fn binary(op: BinaryOp, left: &Value, right: &Value) -> Value {
+    match op {
+        // NULL and false is false, NULL or true is true
+        BinaryOp::And => boolean(match (truth(left), truth(right)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        }),
+        BinaryOp::Or => boolean(match (truth(left), truth(right)) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        }),
+        _ if *left == Value::Null || *right == Value::Null => Value::Null,
+        BinaryOp::Eq => boolean(Some(left == right)),
+        BinaryOp::Ne => boolean(Some(left != right)),
+        BinaryOp::Lt => boolean(Some(left < right)),
+        BinaryOp::Le => boolean(Some(left <= right)),
+        BinaryOp::Gt => boolean(Some(left > right)),
+        BinaryOp::Ge => boolean(Some(left >= right)),
+        BinaryOp::Like => boolean(Some(like(
+            left.to_string().as_bytes(),
+            right.to_string().as_bytes(),
+        ))),
+        BinaryOp::Concat => Value::Text(format!("{}{}", left, right)),
+        BinaryOp::Add
+        | BinaryOp::Subtract
+        | BinaryOp::Multiply
+        | BinaryOp::Divide
+        | BinaryOp::Remainder => {
+            let (left, right) = (number(left).unwrap_or(0), number(right).unwrap_or(0));
+            let result = match op {
+                BinaryOp::Add => left.checked_add(right),
+                BinaryOp::Subtract => left.checked_sub(right),
+                BinaryOp::Multiply => left.checked_mul(right),
+                BinaryOp::Divide => left.checked_div(right),
+                _ => left.checked_rem(right),
+            };
+            // dividing by zero gives NULL rather than an error
+            result.map(Value::Integer).unwrap_or(Value::Null)
+        }
+    }
+}
+
+// % matches any run of characters and _ any one, ignoring ASCII case
+fn like(text: &[u8], pattern: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'%', rest)) => (0..=text.len()).any(|skip| like(&text[skip..], rest)),
+        Some((&c, rest)) => match text.split_first() {
+            Some((&t, text)) => (c == b'_' || c.eq_ignore_ascii_case(&t)) && like(text, rest),
+            None => false,
+        },
+    }
+}
+
+// the compiler has already checked the functions exist and get the right number of arguments
+fn function(name: &str, args: &[Value]) -> Value {
+    match &name.to_ascii_lowercase()[..] {
+        "abs" => match number(&args[0]) {
+            Some(i) => Value::Integer(i.wrapping_abs()),
+            None => Value::Null,
+        },
+        "length" => match &args[0] {
+            Value::Null => Value::Null,
+            value => Value::Integer(value.to_string().chars().count() as i64),
+        },
+        "lower" => match &args[0] {
+            Value::Null => Value::Null,
+            value => Value::Text(value.to_string().to_lowercase()),
+        },
+        "upper" => match &args[0] {
+            Value::Null => Value::Null,
+            value => Value::Text(value.to_string().to_uppercase()),
+        },
+        "typeof" => Value::Text(
+            match &args[0] {
+                Value::Null => "null",
+                Value::Integer(_) => "integer",
+                Value::Text(_) => "text",
             }
+            .into(),
+        ),
+        // ifnull is coalesce with two arguments
+        _ => args
+            .iter()
+            .find(|value| **value != Value::Null)
+            .cloned()
+            .unwrap_or(Value::Null),
+    }
+}
+
+fn column_value(row: &Row, column: usize) -> Value {
+    match column {
+        0 => Value::Integer(row.id as i64),
+        1 => Value::Text(text_column(row.username)),
+        _ => Value::Text(text_column(row.email)),
+    }
+}
+
+// text columns are stored padded with NULs
+fn text_column(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches(char::from(0))
+        .to_string()
+}
+
+// writes the row over the one at `row_num`, or on the end of the table without one
+fn insert(table: &mut TableWrite, values: &[Value], row_num: Option<u32>) -> Result<(), VMErr> {
+    let mut row = Row {
+        id: 0,
+        username: &[],
+        email: &[],
+    };
+    for (column, value) in values.iter().enumerate() {
+        check_column(column, value).map_err(|_| VMErr::Mismatch)?;
+        match value {
+            Value::Integer(id) => row.id = *id as u32,
+            Value::Text(text) if column == 1 => row.username = text.as_bytes(),
+            Value::Text(text) => row.email = text.as_bytes(),
+            Value::Null => return Err(VMErr::Mismatch),
+        }
+    }
+    let bytes = serialize_row(&row);
+    match row_num {
+        Some(row_num) => {
+            let mut row_buffer = table.row_mut(row_num).map_err(VMErr::Table)?;
+            row_buffer.write_all(&bytes).map_err(VMErr::Write)
+        }
+        None if table.num_rows as usize == table.max_rows() => Err(VMErr::TableFull),
+        None => {
+            let mut row_buffer = table.row_mut(table.num_rows).map_err(VMErr::Table)?;
+            row_buffer.write_all(&bytes).map_err(VMErr::Write)?;
+            table.num_rows += 1;
+            Ok(())
         }
     }
 }
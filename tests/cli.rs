@@ -1,7 +1,16 @@
-use std::io::{ErrorKind, Write};
+// these tests were written before clippy grew the lints below; they are kept as written
+#![allow(
+    clippy::let_and_return,
+    clippy::manual_repeat_n,
+    clippy::manual_str_repeat,
+    clippy::needless_borrow
+)]
+
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::iter::repeat;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
 
 fn run_script(commands: Vec<String>, test_file_name: &str) -> Vec<String> {
     let mut child = Command::new("cargo")
@@ -34,6 +43,52 @@ fn run_script(commands: Vec<String>, test_file_name: &str) -> Vec<String> {
     stringified.split("\n").map(String::from).collect()
 }
 
+// a REPL kept running between commands, for tests that interleave it with other processes
+struct Repl {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Repl {
+    fn spawn(test_file_name: &str) -> Self {
+        let mut child = Command::new("cargo")
+            .arg("run")
+            .arg(test_file_name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Failed to spawn child process");
+        let stdin = child.stdin.take().expect("failed to get stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("failed to get stdout"));
+        Repl {
+            child,
+            stdin,
+            stdout,
+        }
+    }
+
+    fn run(&mut self, command: &str, num_lines: usize) -> Vec<String> {
+        self.stdin
+            .write_all(&[command.as_bytes(), b"\n"].concat())
+            .expect("Failed to write to stdin");
+        (0..num_lines)
+            .map(|_| {
+                let mut line = String::new();
+                self.stdout
+                    .read_line(&mut line)
+                    .expect("Failed to read stdout");
+                line.trim_end_matches('\n').to_string()
+            })
+            .collect()
+    }
+
+    fn exit(mut self) {
+        self.run(".exit", 0);
+        self.child.wait().expect("Failed to wait for child process");
+    }
+}
+
 fn ensure_clean_fs<P>(test_file_name: P)
 where
     P: AsRef<Path>,
@@ -97,7 +152,7 @@ fn prints_error_message_when_table_is_full() {
 
         let output = run_script(cmds, &test_file_name);
         let relevant_output = output.get(output.len() - 2).unwrap();
-        assert_eq!(relevant_output, "db message: Execute(TableFull)",);
+        assert_eq!(relevant_output, "db message: table is full",);
     };
 
     clean_test(test_case, test)();
@@ -148,7 +203,7 @@ fn prints_error_messages_if_strings_are_too_long() {
             output,
                 vec![
                     "db > processing statement \"insert 1 aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\"",
-                    "db message: Statement(TooLong)",
+                    "db message: string is too long for its column",
                     "db > "
                 ]
         );
@@ -173,7 +228,7 @@ fn prints_error_messages_if_id_is_negative() {
             output,
             vec![
                 "db > processing statement \"insert -1 a a\"",
-                "db message: Statement(InvalidId)",
+                "db message: id must be an integer between 0 and 4294967295",
                 "db > "
             ]
         );
@@ -215,3 +270,385 @@ fn keeps_data_after_closing_connection() {
     };
     clean_test(test_case, test)();
 }
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reports_error_when_commit_fails_to_write() {
+    // every write to /dev/full fails with ENOSPC, so the commit after the insert cannot succeed
+    let output = run_script(
+        vec!["insert 1 user1 person1@example.com".into(), ".exit".into()],
+        "/dev/full",
+    );
+    let relevant_output = output.get(output.len() - 2).unwrap();
+    assert!(
+        relevant_output.starts_with("db message: disk I/O error: No space left on device"),
+        "unexpected output {:?}",
+        output
+    );
+}
+
+#[test]
+fn pragma_reads_and_changes_synchronous() {
+    let test_case = "pragma_reads_and_changes_synchronous";
+    let test = |test_file_name: &str| {
+        let output = run_script(
+            vec![
+                "pragma synchronous".into(),
+                "pragma synchronous = normal".into(),
+                "pragma synchronous".into(),
+                "pragma synchronous = sometimes".into(),
+                ".exit".into(),
+            ],
+            test_file_name,
+        );
+        assert_eq!(
+            output,
+            vec![
+                "db > processing statement \"pragma synchronous\"",
+                "executing pragma statement",
+                "full",
+                "db > processing statement \"pragma synchronous = normal\"",
+                "executing pragma statement",
+                "result Success",
+                "db > processing statement \"pragma synchronous\"",
+                "executing pragma statement",
+                "normal",
+                "db > processing statement \"pragma synchronous = sometimes\"",
+                "executing pragma statement",
+                "db message: invalid value for pragma",
+                "db > "
+            ]
+        );
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn pragmas_describe_the_database() {
+    let test_case = "pragmas_describe_the_database";
+    let test = |test_file_name: &str| {
+        let mut cmds: Vec<String> = (1..=15)
+            .map(|i| format!("insert {} user{} person{}@example.com", i, i, i))
+            .collect();
+        cmds.extend(vec![
+            "pragma page_size".into(),
+            "pragma page_count".into(),
+            "pragma freelist_count".into(),
+            "pragma cache_size".into(),
+            "pragma journal_mode".into(),
+            "pragma integrity_check".into(),
+            "pragma table_info(users)".into(),
+            "pragma table_info(teams)".into(),
+            "pragma user_version".into(),
+            ".exit".into(),
+        ]);
+        let output = run_script(cmds, test_file_name);
+        let relevant_output: Vec<&str> = output
+            .iter()
+            .filter(|line| !line.starts_with("db > ") && !line.starts_with("executing"))
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            relevant_output[relevant_output.len() - 11..],
+            [
+                "4096",
+                "3",
+                "0",
+                "2000",
+                "delete",
+                "ok",
+                "0, id, integer, 0, , 0",
+                "1, username, varchar(32), 0, , 0",
+                "2, email, varchar(255), 0, , 0",
+                "db message: invalid value for pragma",
+                "db message: unknown pragma",
+            ]
+        );
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn explain_shows_the_program_and_the_plan() {
+    let test_case = "explain_shows_the_program_and_the_plan";
+    let test = |test_file_name: &str| {
+        let output = run_script(
+            vec![
+                "explain select".into(),
+                "explain query plan select".into(),
+                "explain query plan insert 1 user1 person1@example.com".into(),
+                "explain update users".into(),
+                "select".into(),
+                ".exit".into(),
+            ],
+            test_file_name,
+        );
+        assert_eq!(
+            output,
+            vec![
+                "db > processing statement \"explain select\"",
+                "executing explain statement",
+                "0, Transaction, 0, 0, 0, NULL, begin a read transaction",
+                "1, OpenRead, 0, 0, 0, NULL, open cursor 0 to read the table",
+                "2, Rewind, 0, 8, 0, NULL, go to 8 if cursor 0 has no rows",
+                "3, Column, 0, 0, 0, NULL, r[0] = column 0 of cursor 0",
+                "4, Column, 0, 1, 1, NULL, r[1] = column 1 of cursor 0",
+                "5, Column, 0, 2, 2, NULL, r[2] = column 2 of cursor 0",
+                "6, ResultRow, 0, 3, 0, NULL, output r[0..3]",
+                "7, Next, 0, 3, 0, NULL, go to 3 unless cursor 0 has no more rows",
+                "8, Halt, 0, 0, 0, NULL, end the program",
+                "db > processing statement \"explain query plan select\"",
+                "executing explain statement",
+                "1, 0, 0, SCAN users",
+                "db > processing statement \"explain query plan insert 1 user1 person1@example.com\"",
+                "executing explain statement",
+                "db > processing statement \"explain update users\"",
+                "db message: syntax error",
+                "db > processing statement \"select\"",
+                "executing select statement",
+                "db > ",
+            ]
+        );
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn small_cache_still_reads_every_page() {
+    let test_case = "small_cache_still_reads_every_page";
+    let test = |test_file_name: &str| {
+        let mut cmds: Vec<String> = (1..=30)
+            .map(|i| format!("insert {} user{} person{}@example.com", i, i, i))
+            .collect();
+        cmds.push(".exit".into());
+        run_script(cmds, test_file_name);
+
+        let output = run_script(
+            vec![
+                "pragma cache_size = 1".into(),
+                "select".into(),
+                "pragma integrity_check".into(),
+                ".exit".into(),
+            ],
+            test_file_name,
+        );
+        let rows: Vec<&String> = output
+            .iter()
+            .filter(|line| line.contains("@example.com"))
+            .collect();
+        assert_eq!(rows.len(), 30);
+        assert_eq!(rows[29], "30, \"user30\", \"person30@example.com\"");
+        assert_eq!(output[output.len() - 2], "ok");
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn page_size_is_chosen_when_the_database_is_created() {
+    let test_case = "page_size_is_chosen_when_the_database_is_created";
+    let test = |test_file_name: &str| {
+        let output = run_script(
+            vec![
+                "pragma page_size = 1000".into(),
+                "pragma page_size = 512".into(),
+                "insert 1 user1 person1@example.com".into(),
+                "insert 2 user2 person2@example.com".into(),
+                "pragma page_size = 1024".into(),
+                ".exit".into(),
+            ],
+            test_file_name,
+        );
+        let messages: Vec<&String> = output
+            .iter()
+            .filter(|line| line.starts_with("db message"))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "db message: page size must be a power of two between 512 and 65536",
+                "db message: page size can't be changed once the table has rows",
+            ]
+        );
+
+        let output = run_script(
+            vec![
+                "pragma page_size".into(),
+                "pragma page_count".into(),
+                "select".into(),
+                ".exit".into(),
+            ],
+            test_file_name,
+        );
+        assert_eq!(
+            output,
+            vec![
+                "db > processing statement \"pragma page_size\"",
+                "executing pragma statement",
+                "512",
+                "db > processing statement \"pragma page_count\"",
+                "executing pragma statement",
+                "3",
+                "db > processing statement \"select\"",
+                "executing select statement",
+                "1, \"user1\", \"person1@example.com\"",
+                "2, \"user2\", \"person2@example.com\"",
+                "db > "
+            ]
+        );
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn hot_journal_is_rolled_back_on_startup() {
+    let test_case = "hot_journal_is_rolled_back_on_startup";
+    let test = |test_file_name: &str| {
+        let journal_file_name = format!("{}-journal", test_file_name);
+        ensure_clean_fs(&journal_file_name);
+        run_script(
+            vec!["insert 1 user1 person1@example.com".into(), ".exit".into()],
+            test_file_name,
+        );
+
+        // pretend a process journaled both pages, then died while committing a second row
+        let original = std::fs::read(test_file_name).unwrap();
+        assert_eq!(original.len(), 2 * 4096);
+        let mut journal = b"dbtjrnl1".to_vec();
+        journal.extend_from_slice(&4096u32.to_be_bytes());
+        journal.extend_from_slice(&(original.len() as u64).to_be_bytes());
+        for (page_num, page) in original.chunks(4096).enumerate() {
+            journal.extend_from_slice(&(page_num as u32).to_be_bytes());
+            journal.extend_from_slice(page);
+        }
+        std::fs::write(&journal_file_name, journal).unwrap();
+        let mut damaged = original.clone();
+        damaged[4096..].iter_mut().for_each(|b| *b = 0xff);
+        damaged.extend_from_slice(&[0xffu8; 4096]);
+        std::fs::write(test_file_name, damaged).unwrap();
+
+        let output = run_script(
+            vec![
+                "select".into(),
+                "pragma journal_mode".into(),
+                ".exit".into(),
+            ],
+            test_file_name,
+        );
+        assert_eq!(
+            output,
+            vec![
+                "db > processing statement \"select\"",
+                "executing select statement",
+                "1, \"user1\", \"person1@example.com\"",
+                "db > processing statement \"pragma journal_mode\"",
+                "executing pragma statement",
+                "delete",
+                "db > "
+            ]
+        );
+        assert_eq!(std::fs::read(test_file_name).unwrap(), original);
+        assert!(!Path::new(&journal_file_name).exists());
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn journal_with_an_impossible_page_size_is_ignored() {
+    let test_case = "journal_with_an_impossible_page_size_is_ignored";
+    let test = |test_file_name: &str| {
+        let journal_file_name = format!("{}-journal", test_file_name);
+        ensure_clean_fs(&journal_file_name);
+        run_script(
+            vec!["insert 1 user1 person1@example.com".into(), ".exit".into()],
+            test_file_name,
+        );
+        let original = std::fs::read(test_file_name).unwrap();
+
+        // a damaged header can't say how long the records after it are, so none of them are used
+        for page_size in [0, 100, u32::MAX] {
+            let mut journal = b"dbtjrnl1".to_vec();
+            journal.extend_from_slice(&page_size.to_be_bytes());
+            journal.extend_from_slice(&0u64.to_be_bytes());
+            journal.extend_from_slice(&[0u8; 64]);
+            std::fs::write(&journal_file_name, journal).unwrap();
+
+            let output = run_script(vec!["select".into(), ".exit".into()], test_file_name);
+            assert_eq!(
+                output,
+                vec![
+                    "db > processing statement \"select\"",
+                    "executing select statement",
+                    "1, \"user1\", \"person1@example.com\"",
+                    "db > "
+                ]
+            );
+            assert_eq!(std::fs::read(test_file_name).unwrap(), original);
+            assert!(!Path::new(&journal_file_name).exists());
+        }
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn processes_see_each_others_commits() {
+    let test_case = "processes_see_each_others_commits";
+    let test = |test_file_name: &str| {
+        let mut repl = Repl::spawn(test_file_name);
+        assert_eq!(
+            repl.run("insert 1 user1 person1@example.com", 3)[2],
+            "result Success"
+        );
+
+        run_script(
+            vec!["insert 2 user2 person2@example.com".into(), ".exit".into()],
+            test_file_name,
+        );
+
+        assert_eq!(
+            repl.run("select", 4)[2..],
+            [
+                "1, \"user1\", \"person1@example.com\"",
+                "2, \"user2\", \"person2@example.com\"",
+            ]
+        );
+        repl.exit();
+    };
+
+    clean_test(test_case, test)();
+}
+
+#[test]
+fn reports_locked_database_after_busy_timeout() {
+    let test_case = "reports_locked_database_after_busy_timeout";
+    let test = |test_file_name: &str| {
+        let mut repl = Repl::spawn(test_file_name);
+        assert_eq!(
+            repl.run("pragma busy_timeout = 200", 3)[2],
+            "result Success"
+        );
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(test_file_name)
+            .unwrap();
+        file.lock().unwrap();
+        assert_eq!(repl.run("select", 3)[2], "db message: database is locked");
+
+        file.unlock().unwrap();
+        assert_eq!(
+            repl.run("insert 1 user1 person1@example.com", 3)[2],
+            "result Success"
+        );
+        repl.exit();
+    };
+
+    clean_test(test_case, test)();
+}
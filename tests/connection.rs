@@ -0,0 +1,2621 @@
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use db_tutorial::{
+    Connection, Error, ErrorCode, FromRow, PagerOptions, Row, RowError, StatementError, ToParams,
+    VMErr, Value,
+};
+
+fn ensure_clean_fs<P>(test_file_name: P)
+where
+    P: AsRef<Path>,
+{
+    std::fs::remove_file(test_file_name)
+        .or_else(|e| match e.kind() {
+            ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })
+        .expect("could not clean up database files before running tests");
+}
+
+fn clean_test(test_case: &str, test: fn(&str)) -> impl Fn() {
+    let test_file_name = format!("test-connection-for-{}.db", test_case);
+    move || {
+        ensure_clean_fs(&test_file_name);
+        test(&test_file_name);
+        ensure_clean_fs(&test_file_name);
+    }
+}
+
+fn user(id: i64) -> Vec<Value> {
+    vec![
+        Value::Integer(id),
+        Value::Text(format!("user{}", id)),
+        Value::Text(format!("person{}@example.com", id)),
+    ]
+}
+
+fn insert_user(connection: &Connection, id: i64) {
+    connection
+        .execute(&format!(
+            "insert {} user{} person{}@example.com",
+            id, id, id
+        ))
+        .unwrap();
+}
+
+fn select_all(connection: &Connection) -> Vec<Row> {
+    connection
+        .query("select")
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn queries_return_typed_values() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        insert_user(&connection, 1);
+        insert_user(&connection, 2);
+        let rows = select_all(&connection);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].values(), &user(1)[..]);
+        assert_eq!(rows[1].get(1), Some(&Value::Text("user2".into())));
+        assert_eq!(rows[1].get(3), None);
+        connection.close().unwrap();
+
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(connection.query("select").unwrap().count(), 2);
+        let rows: Vec<Row> = connection
+            .query("pragma page_count")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows[0].values(), [Value::Text("2".into())]);
+        assert_eq!(
+            connection
+                .query("pragma synchronous = off")
+                .unwrap()
+                .count(),
+            0
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("queries_return_typed_values", test)();
+}
+
+#[test]
+fn reports_typed_errors() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        assert!(matches!(
+            connection.execute("update users"),
+            Err(Error::Statement(StatementError::Sql))
+        ));
+        assert!(matches!(
+            connection.execute("insert -1 user1 person1@example.com"),
+            Err(Error::Statement(StatementError::InvalidId))
+        ));
+        assert!(matches!(
+            connection.query("pragma no_such_setting"),
+            Err(Error::Execute(VMErr::UnknownPragma))
+        ));
+        assert!(matches!(
+            connection.query("pragmasynchronous"),
+            Err(Error::Statement(StatementError::Sql))
+        ));
+        for sql in [
+            "PRAGMA synchronous",
+            "pragma synchronous;",
+            "Pragma page_size ;",
+        ] {
+            assert_eq!(connection.query(sql).unwrap().count(), 1, "{}", sql);
+        }
+        connection
+            .execute("INSERT 1 user1 person1@example.com;")
+            .unwrap();
+        assert_eq!(select_all(&connection)[0].values(), &user(1)[..]);
+
+        connection.execute("pragma synchronous = off").unwrap();
+        for id in 2..=1400 {
+            insert_user(&connection, id);
+        }
+        assert!(matches!(
+            connection.execute("insert 1401 user1401 person1401@example.com"),
+            Err(Error::Execute(VMErr::TableFull))
+        ));
+        assert_eq!(connection.query("select").unwrap().count(), 1400);
+        connection.close().unwrap();
+    };
+
+    clean_test("reports_typed_errors", test)();
+}
+
+#[test]
+fn errors_have_messages_and_codes() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+
+        let error = connection.execute("update users").unwrap_err();
+        assert_eq!(error.to_string(), "syntax error");
+        assert_eq!(error.code(), ErrorCode::Error);
+        let error = connection
+            .execute(&format!("insert 1 {} person1@example.com", "a".repeat(33)))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "string is too long for its column");
+        assert_eq!(error.code(), ErrorCode::TooBig);
+        assert_eq!(error.code().as_i32(), 18);
+        let error = connection.query("pragma page_size = 1000").unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Range);
+
+        let row = connection.query("pragma page_count").unwrap().next();
+        let error = row.unwrap().unwrap().get_as::<i64>(0).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "column page_count holds a different type of value"
+        );
+        assert_eq!(error.code(), ErrorCode::Mismatch);
+        connection.close().unwrap();
+
+        // errors work with `?` in functions returning any error
+        let count = || -> Result<usize, Box<dyn std::error::Error>> {
+            let connection = Connection::open(test_file_name)?;
+            let count = connection.query("select")?.count();
+            connection.close()?;
+            Ok(count)
+        };
+        assert_eq!(count().unwrap(), 0);
+
+        // the underlying OS error is kept as the source
+        let error = Connection::open(std::env::temp_dir()).err().unwrap();
+        assert_eq!(error.to_string(), "could not open database");
+        assert_eq!(error.code(), ErrorCode::IoErr);
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(source.to_string(), "disk I/O error");
+        assert!(source.source().unwrap().is::<std::io::Error>());
+    };
+
+    clean_test("errors_have_messages_and_codes", test)();
+}
+
+#[test]
+fn expressions_nested_too_deeply_are_refused() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        let nested = |depth: usize| format!("select {}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(texts(&connection, &nested(99)), [["1"]]);
+        for sql in [
+            nested(100),
+            nested(100_000),
+            format!("select {}1", "- ".repeat(100_000)),
+            format!("select {}1", "not ".repeat(100_000)),
+            format!(
+                "select * from {}users{}",
+                "(select * from ".repeat(100_000),
+                ")".repeat(100_000)
+            ),
+        ] {
+            let error = connection.query(&sql).err().unwrap();
+            assert!(
+                matches!(error, Error::Statement(StatementError::TooNested)),
+                "{:?}",
+                error
+            );
+            assert_eq!(error.to_string(), "parser stack overflow");
+        }
+
+        // operators one after another nest their operands without nesting the parser
+        let sum = |terms: usize| format!("select 1{}", " + 1".repeat(terms - 1));
+        assert_eq!(texts(&connection, &sum(500)), [["500"]]);
+        for sql in [
+            sum(501),
+            sum(100_000),
+            format!("select 1{}", " is null".repeat(100_000)),
+        ] {
+            let error = connection.query(&sql).err().unwrap();
+            assert!(
+                matches!(error, Error::Statement(StatementError::TooDeep)),
+                "{:?}",
+                error
+            );
+            assert_eq!(error.code(), ErrorCode::Error);
+            assert_eq!(
+                error.to_string(),
+                "Expression tree is too large (maximum depth 500)"
+            );
+        }
+
+        // a long IN list doesn't make a deep expression
+        let list: Vec<String> = (1..=100_000).map(|i| i.to_string()).collect();
+        assert_eq!(
+            texts(
+                &connection,
+                &format!(
+                    "select 99999 in ({}), 0 in ({})",
+                    list.join(", "),
+                    list.join(", ")
+                )
+            ),
+            [["1", "0"]]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("expressions_nested_too_deeply_are_refused", test)();
+}
+
+#[test]
+fn explain_returns_rows_without_running_the_statement() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        let explain = connection.prepare("explain insert ? :name b").unwrap();
+        assert_eq!(explain.parameter_count(), 2);
+        let rows: Vec<Row> = explain.query().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            rows[0].columns(),
+            ["addr", "opcode", "p1", "p2", "p3", "p4", "comment"]
+        );
+        let opcodes: Vec<String> = rows
+            .iter()
+            .map(|row| row.get_named("opcode").unwrap())
+            .collect();
+        assert_eq!(
+            opcodes,
+            [
+                "Transaction",
+                "OpenWrite",
+                "Variable",
+                "Variable",
+                "String",
+                "Insert",
+                "Halt"
+            ]
+        );
+        assert_eq!(rows[4].get(5), Some(&Value::Text("b".into())));
+
+        let plan: Vec<Row> = connection
+            .query("explain query plan select")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].get(3), Some(&Value::Text("SCAN users".into())));
+        assert_eq!(select_all(&connection).len(), 0);
+        connection.close().unwrap();
+    };
+
+    clean_test("explain_returns_rows_without_running_the_statement", test)();
+}
+
+#[test]
+fn connections_are_shared_between_threads() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection.execute("pragma synchronous = off").unwrap();
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let connection = connection.clone();
+                std::thread::spawn(move || {
+                    for id in 0..25 {
+                        insert_user(&connection, writer * 25 + id + 1);
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let connection = connection.clone();
+                std::thread::spawn(move || {
+                    let mut seen = 0;
+                    while seen < 100 {
+                        let rows = select_all(&connection);
+                        // rows only ever get added, and never show up half written
+                        assert!(rows.len() >= seen);
+                        for row in &rows {
+                            match row.get(0) {
+                                Some(Value::Integer(id)) => {
+                                    assert_eq!(row.values(), &user(*id)[..])
+                                }
+                                value => panic!("unexpected id {:?}", value),
+                            }
+                        }
+                        seen = rows.len();
+                    }
+                })
+            })
+            .collect();
+
+        for thread in writers.into_iter().chain(readers) {
+            thread.join().unwrap();
+        }
+        let mut ids: Vec<Value> = select_all(&connection)
+            .iter()
+            .filter_map(|row| row.get(0).cloned())
+            .collect();
+        ids.sort_by_key(|id| match id {
+            Value::Integer(id) => *id,
+            _ => 0,
+        });
+        assert_eq!(ids, (1..=100).map(Value::Integer).collect::<Vec<_>>());
+        connection.close().unwrap();
+    };
+
+    clean_test("connections_are_shared_between_threads", test)();
+}
+
+#[test]
+fn rows_are_read_from_the_snapshot_the_query_started_with() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection.execute("pragma synchronous = off").unwrap();
+        for id in 1..=20 {
+            insert_user(&connection, id);
+        }
+
+        let mut rows = connection.query("select").unwrap();
+        assert_eq!(rows.next().unwrap().unwrap().values(), &user(1)[..]);
+
+        // the writer doesn't wait for the open query, which doesn't see what the writer did
+        let writer = connection.clone();
+        std::thread::spawn(move || {
+            for id in 21..=40 {
+                insert_user(&writer, id);
+            }
+        })
+        .join()
+        .unwrap();
+
+        let rest: Vec<Row> = rows.collect::<Result<_, _>>().unwrap();
+        assert_eq!(rest.len(), 19);
+        assert_eq!(rest[18].values(), &user(20)[..]);
+        assert_eq!(select_all(&connection).len(), 40);
+
+        // another connection's commit, like another process's, waits until the query is done
+        let other = Connection::open(test_file_name).unwrap();
+        let mut rows = connection.query("select").unwrap();
+        assert_eq!(rows.next().unwrap().unwrap().values(), &user(1)[..]);
+        let writer = std::thread::spawn(move || {
+            insert_user(&other, 41);
+            other
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!writer.is_finished());
+        let rest: Vec<Row> = rows.collect::<Result<_, _>>().unwrap();
+        assert_eq!(rest.len(), 39);
+        assert_eq!(rest[38].values(), &user(40)[..]);
+        writer.join().unwrap().close().unwrap();
+        assert_eq!(select_all(&connection).len(), 41);
+        connection.close().unwrap();
+    };
+
+    clean_test(
+        "rows_are_read_from_the_snapshot_the_query_started_with",
+        test,
+    )();
+}
+
+#[test]
+fn prepared_statements_bind_parameters() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+
+        let mut insert = connection.prepare("insert ? ? ?").unwrap();
+        assert_eq!(insert.parameter_count(), 3);
+        for id in 1..=3 {
+            insert
+                .bind(1, id)
+                .unwrap()
+                .bind(2, format!("user{}", id))
+                .unwrap()
+                .bind(3, format!("person{}@example.com", id))
+                .unwrap();
+            insert.execute().unwrap();
+        }
+
+        let mut insert = connection.prepare("insert ?2 :name ?1").unwrap();
+        assert_eq!(insert.parameter_count(), 3);
+        // bound values are never parsed, so they can hold anything the column allows
+        insert
+            .bind(1, "not parsed; select")
+            .unwrap()
+            .bind(2, 4)
+            .unwrap()
+            .bind_named(":name", "user 4")
+            .unwrap();
+        insert.execute().unwrap();
+
+        let rows = select_all(&connection);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[2].values(), &user(3)[..]);
+        assert_eq!(
+            rows[3].values(),
+            [
+                Value::Integer(4),
+                Value::Text("user 4".into()),
+                Value::Text("not parsed; select".into()),
+            ]
+        );
+
+        let mut pragma = connection.prepare("pragma cache_size = :pages").unwrap();
+        pragma.bind_named("pages", 10).unwrap();
+        pragma.execute().unwrap();
+        let rows: Vec<Row> = connection
+            .query("pragma cache_size")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows[0].values(), [Value::Text("10".into())]);
+        connection.close().unwrap();
+    };
+
+    clean_test("prepared_statements_bind_parameters", test)();
+}
+
+#[test]
+fn prepared_statements_check_their_parameters() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        let mut insert = connection.prepare("insert ? :username ?").unwrap();
+
+        assert!(matches!(
+            insert.bind(4, 1),
+            Err(Error::Statement(StatementError::UnknownParameter))
+        ));
+        assert!(matches!(
+            insert.bind_named("email", "person1@example.com"),
+            Err(Error::Statement(StatementError::UnknownParameter))
+        ));
+
+        insert.bind(1, 1).unwrap().bind(2, "user1").unwrap();
+        assert!(matches!(
+            insert.execute(),
+            Err(Error::Statement(StatementError::MissingParameter))
+        ));
+
+        insert.bind(3, 42).unwrap();
+        assert!(matches!(
+            insert.execute(),
+            Err(Error::Statement(StatementError::WrongType))
+        ));
+
+        insert
+            .bind(1, -1)
+            .unwrap()
+            .bind(3, "person1@example.com")
+            .unwrap();
+        assert!(matches!(
+            insert.execute(),
+            Err(Error::Statement(StatementError::InvalidId))
+        ));
+
+        insert.bind(1, 1).unwrap().bind(2, "a".repeat(33)).unwrap();
+        assert!(matches!(
+            insert.execute(),
+            Err(Error::Statement(StatementError::TooLong))
+        ));
+
+        insert.clear_bindings();
+        assert!(matches!(
+            insert.execute(),
+            Err(Error::Statement(StatementError::MissingParameter))
+        ));
+        assert!(matches!(
+            connection.prepare("insert ?0 user1 person1@example.com"),
+            Err(Error::Statement(StatementError::Sql))
+        ));
+
+        // parameter numbers are capped, so no statement can ask for more room than that
+        let select = connection.prepare("select ?32766").unwrap();
+        assert_eq!(select.parameter_count(), 32766);
+        for sql in [
+            "select ?32767".to_string(),
+            "select ?999999999999".to_string(),
+            format!("select ?32766, {}", ["?"; 10].join(", ")),
+            format!("select {}", vec!["?"; 32767].join(", ")),
+        ] {
+            let error = connection.prepare(&sql).err().unwrap();
+            assert!(matches!(
+                error,
+                Error::Statement(StatementError::TooManyParameters)
+            ));
+            assert_eq!(error.code(), ErrorCode::Range);
+        }
+        assert_eq!(select_all(&connection).len(), 0);
+        connection.close().unwrap();
+    };
+
+    clean_test("prepared_statements_check_their_parameters", test)();
+}
+
+#[derive(Debug, PartialEq, FromRow, ToParams)]
+struct User {
+    id: u32,
+    username: String,
+    email: String,
+}
+
+// fields in a different order from the columns, so only names can line them up
+#[derive(Debug, PartialEq, FromRow, ToParams)]
+struct Contact {
+    email: String,
+    id: i64,
+}
+
+#[derive(Debug, PartialEq, FromRow)]
+struct Pair(i64, String);
+
+#[derive(Debug, PartialEq, FromRow, ToParams)]
+struct Note {
+    id: i64,
+    body: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct Age {
+    #[allow(dead_code)]
+    age: i64,
+}
+
+#[test]
+fn rows_map_to_and_from_structs() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        let user1 = User {
+            id: 1,
+            username: "user1".into(),
+            email: "person1@example.com".into(),
+        };
+        connection.execute_with("insert ? ? ?", &user1).unwrap();
+        connection
+            .execute_with("insert ? ? ?", &(2, "user2", "person2@example.com"))
+            .unwrap();
+        let contact = Contact {
+            email: "person3@example.com".into(),
+            id: 3,
+        };
+        connection
+            .execute_with("insert :id user3 :email", &contact)
+            .unwrap();
+
+        let users: Vec<User> = connection
+            .query_as::<User>("select")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(users.len(), 3);
+        assert_eq!(users[0], user1);
+        assert_eq!(users[1].username, "user2");
+
+        let contacts: Vec<Contact> = connection
+            .query_as("select")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(contacts[2], contact);
+
+        let pair = connection.query_as::<Pair>("select").unwrap().next();
+        assert_eq!(pair.unwrap().unwrap(), Pair(1, "user1".into()));
+
+        assert!(matches!(
+            connection.query_as::<Age>("select").unwrap().next(),
+            Some(Err(Error::Row(RowError::NoSuchColumn(column)))) if column == "age"
+        ));
+        let row = connection.query("select").unwrap().next().unwrap().unwrap();
+        assert_eq!(row.columns(), ["id", "username", "email"]);
+        assert!(matches!(
+            row.get_as::<i64>(1),
+            Err(Error::Row(RowError::WrongType(column))) if column == "username"
+        ));
+        assert!(matches!(User::from_row(&row), Ok(User { id: 1, .. })));
+        assert_eq!(user1.to_params()[0], (Some("id"), Value::Integer(1)));
+
+        // a column that can be NULL maps to an Option
+        connection
+            .execute("create table notes (id integer primary key, body text)")
+            .unwrap();
+        let notes = [
+            Note {
+                id: 1,
+                body: Some("first".into()),
+            },
+            Note { id: 2, body: None },
+        ];
+        for note in &notes {
+            connection
+                .execute_with("insert into notes values (:id, :body)", note)
+                .unwrap();
+        }
+        connection
+            .execute_with("insert into notes values (?, ?)", &(3, None::<&str>))
+            .unwrap();
+        let read: Vec<Note> = connection
+            .query_as("select id, body from notes where id < 3")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, notes);
+        let row = connection
+            .query("select body from notes where id = 3")
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.get_as::<Option<String>>(0).unwrap(), None);
+        let row = connection
+            .query("select body from notes where id = 1")
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            row.get_as::<Option<i64>>(0),
+            Err(Error::Row(RowError::WrongType(column))) if column == "body"
+        ));
+        connection.close().unwrap();
+    };
+
+    clean_test("rows_map_to_and_from_structs", test)();
+}
+
+#[test]
+fn statements_run_as_programs_on_the_virtual_machine() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection.execute("pragma synchronous = off").unwrap();
+
+        // on an empty table the rewind jumps straight to the halt
+        let rows = connection.query("select").unwrap();
+        assert_eq!(rows.columns(), ["id", "username", "email"]);
+        assert_eq!(rows.count(), 0);
+
+        // each insert loads its values into registers and appends them as a row at once
+        let mut insert = connection.prepare("insert ? ? ?").unwrap();
+        for id in 1..=30 {
+            insert
+                .bind(1, id)
+                .unwrap()
+                .bind(2, format!("user{}", id))
+                .unwrap()
+                .bind(3, format!("person{}@example.com", id))
+                .unwrap();
+            insert.execute().unwrap();
+        }
+
+        // the next jumps back for every row, across pages, and on to the halt after the last one
+        let rows = select_all(&connection);
+        assert_eq!(rows.len(), 30);
+        for (id, row) in (1..).zip(&rows) {
+            assert_eq!(row.values(), &user(id)[..]);
+        }
+
+        // a pragma's program only reaches a row when it reports one
+        assert_eq!(
+            connection.query("pragma cache_size = 10").unwrap().count(),
+            0
+        );
+        let rows: Vec<Row> = connection
+            .query("pragma cache_size")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows[0].values(), [Value::Text("10".into())]);
+
+        // the halt committed every insert
+        connection.close().unwrap();
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(select_all(&connection).len(), 30);
+        connection.close().unwrap();
+    };
+
+    clean_test("statements_run_as_programs_on_the_virtual_machine", test)();
+}
+
+fn plan(connection: &Connection, sql: &str) -> Vec<String> {
+    connection
+        .query(&format!("explain query plan {}", sql))
+        .unwrap()
+        .map(|row| row.unwrap().get_as(3).unwrap())
+        .collect()
+}
+
+#[test]
+fn queries_use_the_cheapest_way_to_their_rows() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        for id in 1..=30 {
+            insert_user(&connection, id);
+        }
+        connection
+            .execute("create index users_username on users (username)")
+            .unwrap();
+        assert!(matches!(
+            connection.execute("create index users_username on users (email)"),
+            Err(Error::Statement(StatementError::AlreadyExists(name))) if name == "users_username"
+        ));
+        connection
+            .execute("create index if not exists users_username on users (email)")
+            .unwrap();
+
+        let rows: Vec<Row> = connection
+            .query("select id, email from users where username = 'user7'")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].values(), [user(7)[0].clone(), user(7)[2].clone()]);
+        assert_eq!(
+            plan(
+                &connection,
+                "select id, email from users where username = 'user7'"
+            ),
+            ["SEARCH users USING INDEX users_username (username=?)"]
+        );
+
+        let usernames: Vec<String> = connection
+            .query("select username from users where username > 'user27' and username < 'user4'")
+            .unwrap()
+            .map(|row| row.unwrap().get_as(0).unwrap())
+            .collect();
+        assert_eq!(usernames, ["user28", "user29", "user3", "user30"]);
+        assert_eq!(
+            plan(
+                &connection,
+                "select username from users where username > 'user27' and username < 'user4'"
+            ),
+            ["SEARCH users USING COVERING INDEX users_username (username>? AND username<?)"]
+        );
+        assert_eq!(
+            plan(&connection, "select username from users"),
+            ["SCAN users USING COVERING INDEX users_username"]
+        );
+        assert_eq!(
+            plan(&connection, "select * from users where rowid = 3"),
+            ["SEARCH users USING INTEGER PRIMARY KEY (rowid=?)"]
+        );
+        assert_eq!(
+            plan(&connection, "select * from users where email like '%1%'"),
+            ["SCAN users"]
+        );
+        let count = connection
+            .query("select * from users where id % 10 = 0 and email like 'person%'")
+            .unwrap()
+            .count();
+        assert_eq!(count, 3);
+
+        // the table that narrows things down the most goes first
+        let sql = "select a.id, b.id from users a, users b \
+                   where a.id = b.id and b.username = 'user12'";
+        assert_eq!(
+            plan(&connection, sql),
+            [
+                "SEARCH users AS b USING INDEX users_username (username=?)",
+                "SEARCH users AS a USING HASH TABLE (id=?)"
+            ]
+        );
+        let rows: Vec<Row> = connection
+            .query(sql)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].values(), [Value::Integer(12), Value::Integer(12)]);
+        connection.close().unwrap();
+
+        // the index is there for the next connection too, and kept up to date
+        let connection = Connection::open(test_file_name).unwrap();
+        insert_user(&connection, 31);
+        let ids: Vec<i64> = connection
+            .query("select id from users where username = 'user31'")
+            .unwrap()
+            .map(|row| row.unwrap().get_as(0).unwrap())
+            .collect();
+        assert_eq!(ids, [31]);
+        assert_eq!(
+            connection
+                .query("pragma integrity_check")
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+                .get(0),
+            Some(&Value::Text("ok".into()))
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("queries_use_the_cheapest_way_to_their_rows", test)();
+}
+
+#[test]
+fn statements_see_indexes_made_by_other_connections() {
+    let test = |test_file_name: &str| {
+        let first = Connection::open(test_file_name).unwrap();
+        let second = Connection::open(test_file_name).unwrap();
+        insert_user(&first, 1);
+        let select = second
+            .prepare("select id from users where username = 'user1'")
+            .unwrap();
+        first
+            .execute("create index users_username on users (username)")
+            .unwrap();
+        let rows: Vec<Row> = select.query().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows[0].values(), [Value::Integer(1)]);
+        assert_eq!(
+            plan(&second, "select id from users where username = 'user1'"),
+            ["SEARCH users USING INDEX users_username (username=?)"]
+        );
+        first.close().unwrap();
+        second.close().unwrap();
+    };
+
+    clean_test("statements_see_indexes_made_by_other_connections", test)();
+}
+
+#[test]
+fn analyze_gives_the_planner_statistics() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        for id in 1..=200 {
+            connection
+                .execute(&format!("insert {} user{} everyone@example.com", id, id))
+                .unwrap();
+        }
+        connection
+            .execute("create index users_username on users (username)")
+            .unwrap();
+        connection
+            .execute("create index users_email on users (email, username)")
+            .unwrap();
+        let by_email = "select * from users where email = 'everyone@example.com'";
+        let by_username = "select * from users where username < 'user100'";
+        assert_eq!(
+            plan(&connection, by_email),
+            ["SEARCH users USING INDEX users_email (email=?)"]
+        );
+        assert_eq!(plan(&connection, by_username), ["SCAN users"]);
+        assert_eq!(connection.query("pragma stats").unwrap().count(), 0);
+
+        connection.execute("analyze").unwrap();
+        // every row has the same email, so the index doesn't narrow anything down
+        assert_eq!(plan(&connection, by_email), ["SCAN users"]);
+        // and only a few usernames sort after this one
+        assert_eq!(
+            plan(&connection, by_username),
+            ["SEARCH users USING INDEX users_username (username<?)"]
+        );
+        assert_eq!(
+            plan(&connection, "select * from users where username > 'user1'"),
+            ["SCAN users"]
+        );
+        let count = connection.query(by_username).unwrap().count();
+        assert_eq!(count, 2);
+
+        let stats: Vec<Vec<String>> = connection
+            .query("pragma stats")
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                (0..6).map(|i| row.get_as(i).unwrap()).collect()
+            })
+            .collect();
+        assert_eq!(stats.len(), 5);
+        assert_eq!(stats[0][..5], ["users", "id", "200", "200", "0.00"]);
+        assert_eq!(stats[0][5].split(' ').count(), 10);
+        assert!(stats[0][5].ends_with(" 200"));
+        assert_eq!(stats[2][..5], ["users", "email", "200", "1", "0.00"]);
+        assert_eq!(stats[2][5].split(' ').count(), 10);
+        assert_eq!(stats[3][..4], ["users", "users_username", "200", "200"]);
+        assert_eq!(stats[4][..4], ["users", "users_email", "200", "1 200"]);
+        connection.close().unwrap();
+
+        // statistics are kept with the database, and collected again for just one table
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(plan(&connection, by_email), ["SCAN users"]);
+        for id in 201..=400 {
+            insert_user(&connection, id);
+        }
+        connection.execute("analyze users").unwrap();
+        let stats: Vec<Vec<String>> = connection
+            .query("pragma stats")
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                (0..4).map(|i| row.get_as(i).unwrap()).collect()
+            })
+            .collect();
+        assert_eq!(stats[2], ["users", "email", "400", "201"]);
+        assert!(matches!(
+            connection.execute("analyze teams"),
+            Err(Error::Statement(StatementError::NoSuchTable(name))) if name == "teams"
+        ));
+        connection.close().unwrap();
+    };
+
+    clean_test("analyze_gives_the_planner_statistics", test)();
+}
+
+#[test]
+fn analyze_keeps_only_the_start_of_long_text() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection.execute("pragma synchronous = off").unwrap();
+        connection
+            .execute("create table notes (id integer primary key, body text)")
+            .unwrap();
+        for id in 1..=50 {
+            connection
+                .execute(&format!(
+                    "insert {} user{} {}{}@example.com",
+                    id,
+                    id,
+                    "e".repeat(230),
+                    id
+                ))
+                .unwrap();
+            connection
+                .execute(&format!(
+                    "insert into notes values ({}, '{}{}')",
+                    id,
+                    "n".repeat(5000),
+                    id
+                ))
+                .unwrap();
+        }
+        connection.execute("analyze").unwrap();
+
+        let stats = texts(&connection, "pragma stats");
+        for (column, start) in [("email", "e".repeat(32)), ("body", "n".repeat(32))] {
+            let row = stats.iter().find(|row| row[1] == column).unwrap();
+            let histogram: Vec<&str> = row[5].split(' ').collect();
+            assert_eq!(histogram, [start.as_str(); 10]);
+        }
+        let body = format!("{}49", "n".repeat(5000));
+        assert_eq!(
+            texts(
+                &connection,
+                &format!("select id from notes where body >= '{}'", body)
+            ),
+            [["49"], ["5"], ["50"], ["6"], ["7"], ["8"], ["9"]]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("analyze_keeps_only_the_start_of_long_text", test)();
+}
+
+// users 1 to 5, three teams and who is on which; user 4 is on none, and user 5 is on a team
+// that doesn't exist
+fn create_teams(connection: &Connection) {
+    for id in 1..=5 {
+        insert_user(connection, id);
+    }
+    connection
+        .execute("create table teams (id integer primary key, name varchar(20))")
+        .unwrap();
+    connection
+        .execute("create table memberships (user_id integer, team_id integer, role text)")
+        .unwrap();
+    connection
+        .execute("insert into teams (name) values ('red'), ('blue'), ('green')")
+        .unwrap();
+    connection
+        .execute(
+            "insert into memberships values (1, 1, 'lead'), (2, 1, 'member'), (2, 2, 'lead'), \
+             (3, 2, 'member'), (5, 9, 'member')",
+        )
+        .unwrap();
+}
+
+// every row as text, sorted, since the planner picks the order rows come in
+fn texts(connection: &Connection, sql: &str) -> Vec<Vec<String>> {
+    let mut rows: Vec<Vec<String>> = connection
+        .query(sql)
+        .unwrap()
+        .map(|row| row.unwrap().values().iter().map(Value::to_string).collect())
+        .collect();
+    rows.sort();
+    rows
+}
+
+#[test]
+fn tables_join_inner_and_left() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        create_teams(&connection);
+
+        assert_eq!(
+            texts(&connection, "select id, name from teams"),
+            [["1", "red"], ["2", "blue"], ["3", "green"]]
+        );
+        let error = connection
+            .execute("insert into teams values (2, 'gold')")
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Constraint);
+        assert_eq!(error.to_string(), "UNIQUE constraint failed: teams.id");
+        connection
+            .execute("insert into teams values (7, 'gold')")
+            .unwrap();
+
+        assert_eq!(
+            texts(
+                &connection,
+                "select u.username, t.name from users u \
+                 join memberships m on m.user_id = u.id \
+                 inner join teams t on t.id = m.team_id \
+                 where t.name = 'red'"
+            ),
+            [["user1", "red"], ["user2", "red"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select t.name from teams t, memberships m \
+                 where m.team_id = t.id and m.user_id = 3"
+            ),
+            [["blue"]]
+        );
+        // users with no team, or no team that exists, still come back once each
+        assert_eq!(
+            texts(
+                &connection,
+                "select u.username, t.name from users u \
+                 left join memberships m on m.user_id = u.id \
+                 left outer join teams t on t.id = m.team_id"
+            ),
+            [
+                ["user1", "red"],
+                ["user2", "blue"],
+                ["user2", "red"],
+                ["user3", "blue"],
+                ["user4", "NULL"],
+                ["user5", "NULL"],
+            ]
+        );
+        // ON decides what matches, WHERE what is returned
+        assert_eq!(
+            texts(
+                &connection,
+                "select u.username, m.role from users u \
+                 left join memberships m on m.user_id = u.id and m.role = 'lead'"
+            ),
+            [
+                ["user1", "lead"],
+                ["user2", "lead"],
+                ["user3", "NULL"],
+                ["user4", "NULL"],
+                ["user5", "NULL"],
+            ]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select u.username from users u \
+                 left join memberships m on m.user_id = u.id where m.role is null"
+            ),
+            [["user4"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select t.name, m.role from teams t \
+                 left join memberships m on m.team_id = t.id where t.id > 2"
+            ),
+            [["gold", "NULL"], ["green", "NULL"]]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("tables_join_inner_and_left", test)();
+}
+
+#[test]
+fn joins_use_nested_loops_indexes_or_hash_tables() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        create_teams(&connection);
+
+        // nothing helps with a join that isn't on an equality
+        assert_eq!(
+            plan(
+                &connection,
+                "select * from users u join memberships m on m.user_id < u.id"
+            ),
+            ["SCAN users AS u", "SCAN memberships AS m"]
+        );
+        // every team is found by its primary key
+        assert_eq!(
+            plan(
+                &connection,
+                "select * from memberships m join teams t on t.id = m.team_id"
+            ),
+            [
+                "SCAN memberships AS m",
+                "SEARCH teams AS t USING INTEGER PRIMARY KEY (rowid=?)"
+            ]
+        );
+        // the table reads as many rows as the one it's joined to, so it goes into a hash table
+        let join = "select u.username, m.role from users u join memberships m on m.user_id = u.id";
+        assert_eq!(
+            plan(&connection, join),
+            [
+                "SCAN users AS u",
+                "SEARCH memberships AS m USING HASH TABLE (user_id=?)"
+            ]
+        );
+        assert_eq!(texts(&connection, join).len(), 5);
+
+        connection
+            .execute("create index memberships_user on memberships (user_id)")
+            .unwrap();
+        let one = "select m.role from users u join memberships m on m.user_id = u.id \
+                   where u.rowid = 2";
+        assert_eq!(
+            plan(&connection, one),
+            [
+                "SEARCH users AS u USING INTEGER PRIMARY KEY (rowid=?)",
+                "SEARCH memberships AS m USING INDEX memberships_user (user_id=?)"
+            ]
+        );
+        assert_eq!(texts(&connection, one), [["lead"], ["member"]]);
+        // a LEFT JOIN is always read inside the table it joins on to
+        assert_eq!(
+            plan(
+                &connection,
+                "select * from memberships m left join users u on u.rowid = m.user_id \
+                 where u.rowid = 2"
+            ),
+            [
+                "SCAN memberships AS m",
+                "SEARCH users AS u USING INTEGER PRIMARY KEY (rowid=?)"
+            ]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("joins_use_nested_loops_indexes_or_hash_tables", test)();
+}
+
+#[test]
+fn joins_read_at_most_64_tables() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection
+            .execute("create table t (a integer primary key)")
+            .unwrap();
+        connection.execute("insert into t values (1)").unwrap();
+        let tables = |count: usize| {
+            let tables: Vec<String> = (1..=count).map(|i| format!("t t{}", i)).collect();
+            tables.join(", ")
+        };
+        assert_eq!(
+            texts(&connection, &format!("select t64.a from {}", tables(64))),
+            [["1"]]
+        );
+        // a view is one table to the query reading it, but its own query can't read more either
+        connection
+            .execute(&format!(
+                "create view wide as select t1.a from {}",
+                tables(64)
+            ))
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select w1.a from wide w1, wide w2, t"),
+            [["1"]]
+        );
+        for sql in [
+            format!("select * from {}", tables(65)),
+            format!(
+                "select * from t where a in (select t1.a from {})",
+                tables(65)
+            ),
+            format!("create view wider as select t1.a from {}", tables(65)),
+        ] {
+            let error = connection.execute(&sql).unwrap_err();
+            assert!(
+                matches!(error, Error::Statement(StatementError::TooManyTables)),
+                "{:?}",
+                error
+            );
+            assert_eq!(error.to_string(), "at most 64 tables in a join");
+        }
+        connection.close().unwrap();
+    };
+
+    clean_test("joins_read_at_most_64_tables", test)();
+}
+
+#[test]
+fn subqueries_give_values_lists_and_tables() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        create_teams(&connection);
+        connection
+            .execute("create table admins (user_id integer)")
+            .unwrap();
+        connection
+            .execute("insert into admins values (2), (4)")
+            .unwrap();
+
+        assert_eq!(
+            texts(
+                &connection,
+                "select username from users where id in (select user_id from admins)"
+            ),
+            [["user2"], ["user4"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select username from users where id not in (select user_id from admins)"
+            ),
+            [["user1"], ["user3"], ["user5"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select username, (select name from teams where id = 2) from users where id = 1"
+            ),
+            [["user1", "blue"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select username, (select role from memberships m \
+                 where m.user_id = users.id and m.team_id = 1) from users"
+            ),
+            [
+                ["user1", "lead"],
+                ["user2", "member"],
+                ["user3", "NULL"],
+                ["user4", "NULL"],
+                ["user5", "NULL"],
+            ]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select name from teams t \
+                 where exists (select * from memberships m where m.team_id = t.id)"
+            ),
+            [["blue"], ["red"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select name from teams t \
+                 where not exists (select * from memberships m where m.team_id = t.id)"
+            ),
+            [["green"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select t.name, leads.user_id from \
+                 (select team_id, user_id from memberships where role = 'lead') as leads \
+                 join teams t on t.id = leads.team_id"
+            ),
+            [["blue", "2"], ["red", "1"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select username from users u where u.id in \
+                 (select m.user_id from (select user_id from memberships) m \
+                  where m.user_id > u.id - 1 and m.user_id < 3)"
+            ),
+            [["user1"], ["user2"]]
+        );
+
+        // once the list has a NULL in it, NOT IN can't be sure of anything outside the list
+        connection
+            .execute("insert into admins (user_id) values (null)")
+            .unwrap();
+        assert_eq!(
+            texts(
+                &connection,
+                "select username from users where id in (select user_id from admins)"
+            ),
+            [["user2"], ["user4"]]
+        );
+        assert!(texts(
+            &connection,
+            "select username from users where id not in (select user_id from admins)"
+        )
+        .is_empty());
+        assert_eq!(
+            texts(
+                &connection,
+                "select null in (select user_id from admins where user_id > 9), \
+                 (select user_id from admins where user_id > 9)"
+            ),
+            [["0", "NULL"]]
+        );
+
+        let error = connection
+            .query("select (select id, username from users)")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "sub-select returns 2 columns - expected 1"
+        );
+        let error = connection
+            .query("select rowid from (select id from users)")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no such column: rowid");
+        connection.close().unwrap();
+    };
+
+    clean_test("subqueries_give_values_lists_and_tables", test)();
+}
+
+#[test]
+fn subqueries_run_once_unless_they_read_the_row_around_them() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        create_teams(&connection);
+
+        let once = "select name from teams where id = \
+                    (select team_id from memberships where user_id = 3)";
+        assert_eq!(
+            plan(&connection, once),
+            [
+                "SEARCH teams USING INTEGER PRIMARY KEY (rowid=?)",
+                "SCALAR SUBQUERY",
+                "SCAN memberships"
+            ]
+        );
+        assert_eq!(texts(&connection, once), [["blue"]]);
+        assert_eq!(
+            plan(
+                &connection,
+                "select name from teams t \
+                 where exists (select * from memberships m where m.team_id = t.id)"
+            ),
+            [
+                "SCAN teams AS t",
+                "CORRELATED SCALAR SUBQUERY",
+                "SCAN memberships AS m"
+            ]
+        );
+        assert_eq!(
+            plan(
+                &connection,
+                "select username from users where id in (select user_id from memberships)"
+            ),
+            ["SCAN users", "LIST SUBQUERY", "SCAN memberships"]
+        );
+        assert_eq!(
+            plan(
+                &connection,
+                "select t.name from (select team_id from memberships) as m \
+                 join teams t on t.id = m.team_id"
+            ),
+            [
+                "MATERIALIZE m",
+                "SCAN memberships",
+                "SCAN m",
+                "SEARCH teams AS t USING INTEGER PRIMARY KEY (rowid=?)"
+            ]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test(
+        "subqueries_run_once_unless_they_read_the_row_around_them",
+        test,
+    )();
+}
+
+#[test]
+fn with_queries_define_tables_that_can_recurse() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection
+            .execute("create table staff (id integer primary key, name text, boss integer)")
+            .unwrap();
+        connection
+            .execute(
+                "insert into staff values (1, 'ada', null), (2, 'bob', 1), (3, 'cy', 2), \
+                 (4, 'di', 2), (5, 'ed', 1), (6, 'flo', 4)",
+            )
+            .unwrap();
+
+        assert_eq!(
+            texts(
+                &connection,
+                "with bosses as (select boss from staff union select boss from staff) \
+                 select name from staff where id in (select boss from bosses)"
+            ),
+            [["ada"], ["bob"], ["di"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "with a as (select id from staff where id < 4), b(n) as (select id from a) \
+                 select n from b where n > 1"
+            ),
+            [["2"], ["3"]]
+        );
+        let counting = "with recursive counter(n) as \
+                        (select 1 union all select n + 1 from counter where n < 5) \
+                        select n from counter";
+        assert_eq!(
+            texts(&connection, counting),
+            [["1"], ["2"], ["3"], ["4"], ["5"]]
+        );
+        // rows UNION has seen already aren't run again, so this ends
+        assert_eq!(
+            texts(
+                &connection,
+                "with recursive cycle(n) as (select 1 union select n % 3 + 1 from cycle) \
+                 select n from cycle"
+            ),
+            [["1"], ["2"], ["3"]]
+        );
+        // a LIMIT stops a table that would go on forever once it has that many rows
+        assert_eq!(
+            texts(
+                &connection,
+                "with recursive c(n) as (select 1 union all select n + 1 from c limit 4) \
+                 select n from c"
+            ),
+            [["1"], ["2"], ["3"], ["4"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "with recursive c(n) as (select 1 union all select n + 1 from c limit 50) \
+                 select n from c where n > 48"
+            ),
+            [["49"], ["50"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "with recursive c(n) as (select 1 union all select n + 1 from c limit 0) \
+                 select n from c"
+            ),
+            Vec::<Vec<String>>::new()
+        );
+        assert_eq!(
+            texts(&connection, "select name from staff limit 2"),
+            [["ada"], ["bob"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select id from staff where id < 3 union all select id from staff limit 1 + 2"
+            ),
+            [["1"], ["1"], ["2"]]
+        );
+        assert_eq!(texts(&connection, "select id from staff limit -1").len(), 6);
+        assert_eq!(
+            texts(
+                &connection,
+                "with recursive chart(id, name, depth) as \
+                 (select id, name, 0 from staff where boss is null \
+                  union all \
+                  select s.id, s.name, chart.depth + 1 from staff s join chart on s.boss = chart.id) \
+                 select name, depth from chart"
+            ),
+            [
+                ["ada", "0"],
+                ["bob", "1"],
+                ["cy", "2"],
+                ["di", "2"],
+                ["ed", "1"],
+                ["flo", "3"]
+            ]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select name from staff where id in \
+                 (with recursive up(id) as (select boss from staff where name = 'flo' \
+                  union all select boss from staff join up on staff.id = up.id) \
+                  select id from up)"
+            ),
+            [["ada"], ["bob"], ["di"]]
+        );
+        assert_eq!(
+            plan(&connection, counting),
+            ["MATERIALIZE counter", "SCAN counter", "SCAN counter"]
+        );
+
+        for (sql, message) in [
+            (
+                "with t(a, b) as (select 1) select * from t",
+                "table t has 1 values for 2 columns",
+            ),
+            (
+                "with recursive t(n) as (select n from t) select * from t",
+                "circular reference: t",
+            ),
+            (
+                "select 1, 2 union select 3",
+                "SELECTs to the left and right of UNION do not have the same number of result \
+                 columns",
+            ),
+        ] {
+            assert_eq!(connection.query(sql).unwrap_err().to_string(), message);
+        }
+        connection.close().unwrap();
+    };
+
+    clean_test("with_queries_define_tables_that_can_recurse", test)();
+}
+
+#[test]
+fn views_are_queries_read_like_tables() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        create_teams(&connection);
+
+        connection
+            .execute(
+                "create view leads (username, team) as \
+                 select u.username, t.name from users u \
+                 join memberships m on m.user_id = u.id join teams t on t.id = m.team_id \
+                 where m.role = 'lead'",
+            )
+            .unwrap();
+        connection
+            .execute("create view red_leads as select username from leads where team = 'red'")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select * from leads"),
+            [["user1", "red"], ["user2", "blue"]]
+        );
+        assert_eq!(texts(&connection, "select * from red_leads"), [["user1"]]);
+        // the view is run again every time, so it sees rows added since it was made
+        connection
+            .execute("insert into memberships values (4, 3, 'lead')")
+            .unwrap();
+        assert_eq!(
+            texts(
+                &connection,
+                "select l.team, u.email from leads l join users u on u.username = l.username \
+                 where l.team <> 'red'"
+            ),
+            [
+                ["blue", "person2@example.com"],
+                ["green", "person4@example.com"]
+            ]
+        );
+        assert_eq!(
+            plan(&connection, "select username from red_leads"),
+            [
+                "MATERIALIZE red_leads",
+                "MATERIALIZE leads",
+                "SCAN users AS u",
+                "SEARCH memberships AS m USING HASH TABLE (user_id=?)",
+                "SEARCH teams AS t USING INTEGER PRIMARY KEY (rowid=?)",
+                "SCAN leads",
+                "SCAN red_leads"
+            ]
+        );
+
+        let error = connection
+            .execute("create view leads as select 1")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "leads already exists");
+        connection
+            .execute("create view if not exists leads as select 1")
+            .unwrap();
+        let error = connection
+            .execute("create view broken as select nothing from users")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no such column: nothing");
+        let error = connection
+            .execute("create view pair (a, b) as select 1")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "table pair has 1 values for 2 columns");
+
+        connection.execute("drop view red_leads").unwrap();
+        let error = connection.query("select * from red_leads").unwrap_err();
+        assert_eq!(error.to_string(), "no such table: red_leads");
+        let error = connection.execute("drop view red_leads").unwrap_err();
+        assert_eq!(error.to_string(), "no such view: red_leads");
+        connection.execute("drop view if exists red_leads").unwrap();
+        connection.close().unwrap();
+
+        // views are kept in the schema along with the tables they read
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(texts(&connection, "select team from leads").len(), 3);
+        connection.close().unwrap();
+    };
+
+    clean_test("views_are_queries_read_like_tables", test)();
+}
+
+#[test]
+fn update_and_delete_change_the_rows_they_find() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        create_teams(&connection);
+        connection
+            .execute("create index users_by_name on users (username)")
+            .unwrap();
+        connection
+            .execute("create index teams_by_name on teams (name)")
+            .unwrap();
+
+        connection
+            .execute("update users set username = 'ann', email = username || '@example.org' where id = 1")
+            .unwrap();
+        connection
+            .execute_with("update users set email = ? where id > 3", &("gone",))
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select id, username, email from users"),
+            [
+                ["1", "ann", "user1@example.org"],
+                ["2", "user2", "person2@example.com"],
+                ["3", "user3", "person3@example.com"],
+                ["4", "user4", "gone"],
+                ["5", "user5", "gone"]
+            ]
+        );
+        // rows deleted from the users table leave the others where they were
+        connection
+            .execute("delete from users where email = 'gone' or username = 'user2'")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select rowid, username from users"),
+            [["1", "ann"], ["3", "user3"]]
+        );
+        assert_eq!(
+            texts(&connection, "select id from users where username = 'user3'"),
+            [["3"]]
+        );
+        assert!(texts(&connection, "select id from users where username = 'user1'").is_empty());
+        insert_user(&connection, 6);
+        assert_eq!(
+            texts(&connection, "select rowid from users where id = 6"),
+            [["6"]]
+        );
+
+        // every row is found before any is changed, so a row that moves isn't changed twice
+        connection
+            .execute("update teams set id = id + 10, name = upper(name)")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select id, name from teams"),
+            [["11", "RED"], ["12", "BLUE"], ["13", "GREEN"]]
+        );
+        assert_eq!(
+            texts(&connection, "select id from teams where name = 'BLUE'"),
+            [["12"]]
+        );
+        let error = connection
+            .execute("update teams set id = 13 where name = 'RED'")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "UNIQUE constraint failed: teams.id");
+        connection
+            .execute("delete from memberships where team_id not in (select id - 10 from teams)")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select user_id, team_id from memberships"),
+            [["1", "1"], ["2", "1"], ["2", "2"], ["3", "2"]]
+        );
+
+        let error = connection
+            .execute("update users set nickname = 'x'")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no such column: nickname");
+        // the users table's columns are checked the way they are on insert
+        let error = connection
+            .execute("update users set id = 'one' where id = 1")
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Range);
+        connection.close().unwrap();
+
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(
+            texts(&connection, "select username from users"),
+            [["ann"], ["user3"], ["user6"]]
+        );
+        assert_eq!(texts(&connection, "pragma integrity_check"), [["ok"]]);
+        connection.close().unwrap();
+    };
+
+    clean_test("update_and_delete_change_the_rows_they_find", test)();
+}
+
+#[test]
+fn triggers_run_statements_for_every_row_changed() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        insert_user(&connection, 1);
+        connection
+            .execute(
+                "create table audit (id integer primary key, action text, user_id integer, \
+                 before text, after text)",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "create trigger users_insert after insert on users begin \
+                 insert into audit (action, user_id, after) values ('insert', new.id, new.email); \
+                 end",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "create trigger users_update after update on users for each row \
+                 when old.email <> new.email begin \
+                 insert into audit (action, user_id, before, after) \
+                 values ('update', new.id, old.email, new.email); \
+                 end",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "create trigger users_delete before delete on users begin \
+                 insert into audit (action, user_id, before) values ('delete', old.id, old.email); \
+                 end",
+            )
+            .unwrap();
+
+        insert_user(&connection, 2);
+        connection
+            .execute("insert into users values (3, 'user3', 'person3@example.com')")
+            .unwrap();
+        connection
+            .execute("update users set email = 'new@example.com' where id <= 2")
+            .unwrap();
+        // the WHEN clause leaves out rows whose email stays the same
+        connection
+            .execute("update users set username = 'renamed' where id = 3")
+            .unwrap();
+        connection
+            .execute("delete from users where id = 1")
+            .unwrap();
+        assert_eq!(
+            texts(
+                &connection,
+                "select id, action, user_id, before, after from audit"
+            ),
+            [
+                ["1", "insert", "2", "NULL", "person2@example.com"],
+                ["2", "insert", "3", "NULL", "person3@example.com"],
+                ["3", "update", "1", "person1@example.com", "new@example.com"],
+                ["4", "update", "2", "person2@example.com", "new@example.com"],
+                ["5", "delete", "1", "new@example.com", "NULL"]
+            ]
+        );
+
+        // a trigger can change other rows of its own table, but doesn't set itself off again
+        connection
+            .execute("create table counts (name text primary key, total integer)")
+            .unwrap();
+        connection
+            .execute("insert into counts values ('audit', 5)")
+            .unwrap();
+        connection
+            .execute(
+                "create trigger count_audit after insert on audit begin \
+                 update counts set total = total + 1 where name = 'audit'; \
+                 insert into audit (action) values ('counted'); \
+                 end",
+            )
+            .unwrap();
+        connection.execute("delete from users").unwrap();
+        assert_eq!(texts(&connection, "select total from counts"), [["7"]]);
+        assert_eq!(
+            texts(&connection, "select action from audit where id > 5"),
+            [["counted"], ["counted"], ["delete"], ["delete"]]
+        );
+
+        let error = connection
+            .execute("create trigger users_insert after insert on users begin select 1; end")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "users_insert already exists");
+        let error = connection
+            .execute(
+                "create trigger bad after insert on users begin \
+                 insert into audit (action) values (old.username); end",
+            )
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no such column: old.username");
+        let error = connection
+            .execute("create trigger bad after insert on nothing begin select 1; end")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no such table: nothing");
+        connection.close().unwrap();
+
+        // triggers are kept in the schema, and go once they are dropped
+        let connection = Connection::open(test_file_name).unwrap();
+        insert_user(&connection, 4);
+        assert_eq!(texts(&connection, "select total from counts"), [["8"]]);
+        connection.execute("drop trigger count_audit").unwrap();
+        connection.execute("drop trigger users_insert").unwrap();
+        insert_user(&connection, 5);
+        assert_eq!(texts(&connection, "select total from counts"), [["8"]]);
+        let error = connection.execute("drop trigger users_insert").unwrap_err();
+        assert_eq!(error.to_string(), "no such trigger: users_insert");
+        connection
+            .execute("drop trigger if exists users_insert")
+            .unwrap();
+        connection.close().unwrap();
+    };
+
+    clean_test("triggers_run_statements_for_every_row_changed", test)();
+}
+
+#[test]
+fn constraints_are_checked_on_insert_and_update() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection
+            .execute(
+                "create table accounts (id integer primary key, \
+                 name text not null unique, \
+                 balance integer default 0 check (balance >= 0), \
+                 kind text default ('basic' || '') null, \
+                 code text constraint short check (code <> 'toolong'))",
+            )
+            .unwrap();
+        connection
+            .execute("insert into accounts (name) values ('ann')")
+            .unwrap();
+        connection
+            .execute("insert into accounts (name, balance, kind) values ('bob', 10, 'gold')")
+            .unwrap();
+        // NULL passes a check, and is never the same as another NULL
+        connection
+            .execute("insert into accounts (name, balance) values ('cy', null)")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select id, name, balance, kind from accounts"),
+            [
+                ["1", "ann", "0", "basic"],
+                ["2", "bob", "10", "gold"],
+                ["3", "cy", "NULL", "basic"]
+            ]
+        );
+
+        let failures = [
+            (
+                "insert into accounts (balance) values (1)",
+                "NOT NULL constraint failed: accounts.name",
+            ),
+            (
+                "insert into accounts (name, balance) values ('dee', -1)",
+                "CHECK constraint failed: accounts.balance",
+            ),
+            (
+                "insert into accounts (name) values ('dee'), ('ann')",
+                "UNIQUE constraint failed: accounts.name",
+            ),
+            (
+                "update accounts set name = 'bob' where id = 1",
+                "UNIQUE constraint failed: accounts.name",
+            ),
+            (
+                "update accounts set balance = balance - 5",
+                "CHECK constraint failed: accounts.balance",
+            ),
+            (
+                "update accounts set name = null where id = 3",
+                "NOT NULL constraint failed: accounts.name",
+            ),
+            (
+                "update accounts set code = 'toolong' where id = 3",
+                "CHECK constraint failed: short (accounts.code)",
+            ),
+        ];
+        for (sql, message) in failures {
+            let error = connection.execute(sql).unwrap_err();
+            assert_eq!(error.to_string(), message, "{}", sql);
+        }
+        // a statement that fails changes nothing, even the rows it got to first
+        assert_eq!(
+            texts(&connection, "select name, balance from accounts"),
+            [["ann", "0"], ["bob", "10"], ["cy", "NULL"]]
+        );
+        // a row keeps its own unique value
+        connection
+            .execute("update accounts set name = name, balance = 1")
+            .unwrap();
+
+        // a unique index made later checks the rows already there, and every one after
+        let error = connection
+            .execute("create unique index by_balance on accounts (balance)")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "UNIQUE constraint failed: accounts.balance"
+        );
+        connection
+            .execute("create unique index by_kind_code on accounts (kind, code)")
+            .unwrap();
+        connection
+            .execute("update accounts set code = 'x' where id = 1")
+            .unwrap();
+        let error = connection
+            .execute("update accounts set code = 'x' where id = 3")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "UNIQUE constraint failed: accounts.kind, accounts.code"
+        );
+        assert_eq!(
+            plan(&connection, "select * from accounts where name = 'bob'"),
+            ["SEARCH accounts USING INDEX autoindex_accounts_1 (name=?)"]
+        );
+
+        let error = connection
+            .execute("create table bad (a integer default (a + 1))")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "default value of column [a] is not constant"
+        );
+        let error = connection
+            .execute("create table bad (a integer check (b > 0))")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no such column: b");
+        connection.close().unwrap();
+
+        // constraints are kept in the schema
+        let connection = Connection::open(test_file_name).unwrap();
+        let error = connection
+            .execute("insert into accounts (name, balance) values ('ann', 1)")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "UNIQUE constraint failed: accounts.name");
+        assert_eq!(
+            texts(&connection, "pragma table_info(accounts)"),
+            [
+                ["0", "id", "integer", "0", "", "1"],
+                ["1", "name", "text", "1", "", "0"],
+                ["2", "balance", "integer", "0", "0", "0"],
+                ["3", "kind", "text", "0", "('basic' || '')", "0"],
+                ["4", "code", "text", "0", "", "0"]
+            ]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("constraints_are_checked_on_insert_and_update", test)();
+}
+
+#[test]
+fn foreign_keys_keep_rows_referring_to_rows_that_are_there() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        for sql in [
+            "create table artists (id integer primary key, name text unique)",
+            "create table albums (id integer primary key, \
+             artist integer references artists on delete cascade on update cascade, title text)",
+            "create table tracks (album integer references albums (id) on delete set null, \
+             artist text references artists (name) on update restrict, title text)",
+            "insert into artists values (1, 'ann'), (2, 'bob'), (3, 'cy')",
+            "insert into albums values (10, 1, 'first'), (11, 2, 'second'), (12, null, 'loose')",
+            "insert into tracks values (10, 'ann', 'a'), (11, 'bob', 'b'), (11, null, 'c')",
+        ] {
+            connection.execute(sql).unwrap();
+        }
+
+        let failures = [
+            "insert into albums values (13, 9, 'nobody')",
+            "insert into tracks values (99, null, 'nowhere')",
+            "update albums set artist = 9 where id = 10",
+            // restrict fails as soon as the key changes
+            "update artists set name = 'ann2' where id = 1",
+            // no action fails when rows are left referring to the row that went
+            "delete from artists where name = 'bob'",
+        ];
+        for sql in failures {
+            let error = connection.execute(sql).unwrap_err();
+            assert_eq!(error.code(), ErrorCode::Constraint, "{}", sql);
+            assert_eq!(
+                error.to_string(),
+                "FOREIGN KEY constraint failed",
+                "{}",
+                sql
+            );
+        }
+
+        // the rows referring to a key follow it when it changes, and go when it goes
+        connection
+            .execute("update artists set id = 5 where id = 1")
+            .unwrap();
+        connection
+            .execute("update tracks set artist = null where artist = 'bob'")
+            .unwrap();
+        connection
+            .execute("delete from artists where name = 'bob'")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select id, artist, title from albums"),
+            [["10", "5", "first"], ["12", "NULL", "loose"]]
+        );
+        assert_eq!(
+            texts(&connection, "select album, artist, title from tracks"),
+            [
+                ["10", "ann", "a"],
+                ["NULL", "NULL", "b"],
+                ["NULL", "NULL", "c"]
+            ]
+        );
+        // a row referred to can be put in by the same statement as the row referring to it
+        connection
+            .execute(
+                "create table nodes (id integer primary key, \
+                 parent integer references nodes on delete cascade)",
+            )
+            .unwrap();
+        connection
+            .execute("insert into nodes values (2, 1), (1, null), (3, 1), (4, 4)")
+            .unwrap();
+        // deleting goes on down however many rows refer to each other in turn
+        connection
+            .execute("insert into nodes values (5, 3), (6, 5), (7, 6), (8, 4)")
+            .unwrap();
+        connection
+            .execute("delete from nodes where id = 1")
+            .unwrap();
+        assert_eq!(texts(&connection, "select id from nodes"), [["4"], ["8"]]);
+        connection
+            .execute("delete from nodes where id = 4")
+            .unwrap();
+        assert_eq!(texts(&connection, "select id from nodes").len(), 0);
+        connection
+            .execute(
+                "create table s (id integer primary key, \
+                 parent integer references s(id) on delete cascade)",
+            )
+            .unwrap();
+        connection
+            .execute("insert into s values (1, null), (2, 1), (3, 2)")
+            .unwrap();
+        connection.execute("delete from s where id = 1").unwrap();
+        assert_eq!(texts(&connection, "select id from s").len(), 0);
+
+        connection
+            .execute("create table plays (track text references tracks)")
+            .unwrap_err();
+        let error = connection
+            .execute("create table plays (album text references albums (title))")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "foreign key mismatch - \"plays\" referencing \"albums\""
+        );
+        connection.close().unwrap();
+
+        // a deferred key is checked when the transaction commits, once the statement is done
+        let connection = Connection::open(test_file_name).unwrap();
+        connection
+            .execute(
+                "create table reviews (album integer not null \
+                 references albums deferrable initially deferred)",
+            )
+            .unwrap();
+        let error = connection
+            .execute("insert into reviews values (11)")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "FOREIGN KEY constraint failed");
+        connection
+            .execute("insert into reviews values (10)")
+            .unwrap();
+        let error = connection.execute("delete from albums").unwrap_err();
+        assert_eq!(error.to_string(), "FOREIGN KEY constraint failed");
+        assert_eq!(
+            texts(&connection, "select id from albums"),
+            [["10"], ["12"]]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test(
+        "foreign_keys_keep_rows_referring_to_rows_that_are_there",
+        test,
+    )();
+}
+
+#[test]
+fn alter_table_changes_columns_and_names() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        for sql in [
+            "create table artists (id integer primary key, name text unique)",
+            "create table albums (id integer primary key, \
+             artist integer references artists (id), title text check (title <> ''))",
+            "create index albums_title on albums (title)",
+            "create view titles as select title from albums",
+            "create view credits as select a.title as album, r.name as artist \
+             from albums as a join artists r on a.artist = r.id",
+            "create trigger renamed after update on artists begin \
+             update albums set title = title || '!' where artist = new.id; end",
+            "insert into artists values (1, 'ann'), (2, 'bob')",
+            "insert into albums values (10, 1, 'first'), (11, 2, 'second')",
+        ] {
+            connection.execute(sql).unwrap();
+        }
+
+        // a column added with no default is NULL in the rows already there, without rewriting
+        // them; one with a default has it written into each of them
+        connection
+            .execute("alter table albums add column year integer")
+            .unwrap();
+        connection
+            .execute("alter table albums add rating integer not null default 3 check (rating > 0)")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select id, year, rating from albums"),
+            [["10", "NULL", "3"], ["11", "NULL", "3"]]
+        );
+
+        // the index, the foreign key, the views and the trigger follow a column to its new name
+        connection
+            .execute("alter table albums rename column title to name")
+            .unwrap();
+        connection
+            .execute("alter table artists rename id to artist_id")
+            .unwrap();
+        connection
+            .execute("update artists set name = 'bob' where artist_id = 2")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select name from titles"),
+            [["first"], ["second!"]]
+        );
+        assert_eq!(
+            texts(&connection, "select album, artist from credits"),
+            [["first", "ann"], ["second!", "bob"]]
+        );
+        connection
+            .execute("insert into albums (id, artist, name) values (12, 1, 'third')")
+            .unwrap();
+        let error = connection
+            .execute("insert into albums (id, name) values (13, '')")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "CHECK constraint failed: albums.name");
+        let error = connection
+            .execute("insert into albums (id, artist, name) values (13, 9, 'x')")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "FOREIGN KEY constraint failed");
+        assert_eq!(
+            texts(&connection, "select id from albums where name = 'second!'"),
+            [["11"]]
+        );
+
+        // dropping a column rewrites every row without it
+        connection
+            .execute("alter table albums drop column year")
+            .unwrap();
+        assert_eq!(
+            texts(&connection, "select * from albums"),
+            [
+                ["10", "1", "first", "3"],
+                ["11", "2", "second!", "3"],
+                ["12", "1", "third", "3"]
+            ]
+        );
+        connection
+            .execute("alter table albums rename to records")
+            .unwrap();
+        connection.execute("select * from albums").unwrap_err();
+        connection.close().unwrap();
+
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(
+            texts(
+                &connection,
+                "select records.name, artists.name from records \
+                 join artists on records.artist = artists.artist_id where records.id = 12"
+            ),
+            [["third", "ann"]]
+        );
+        assert_eq!(
+            texts(
+                &connection,
+                "select album from credits where artist = 'ann'"
+            ),
+            [["first"], ["third"]]
+        );
+        connection
+            .execute("delete from artists where artist_id = 2")
+            .unwrap_err();
+
+        let failures = [
+            (
+                "alter table records add name text",
+                "duplicate column name: name",
+            ),
+            (
+                "alter table records rename column nope to x",
+                "no such column: nope",
+            ),
+            (
+                "alter table records add grade text not null",
+                "cannot add a NOT NULL column with default value NULL",
+            ),
+            (
+                "alter table records add code text unique",
+                "cannot add a UNIQUE column",
+            ),
+            (
+                "alter table records drop column id",
+                "cannot drop PRIMARY KEY column: \"id\"",
+            ),
+            (
+                "alter table artists drop column name",
+                "cannot drop UNIQUE column: \"name\"",
+            ),
+            (
+                "alter table records drop column name",
+                "error in index albums_title: no such column: name",
+            ),
+            (
+                "alter table records drop column artist_id",
+                "no such column: artist_id",
+            ),
+            (
+                "alter table records rename to artists",
+                "artists already exists",
+            ),
+            (
+                "alter table users rename to people",
+                "table users may not be altered",
+            ),
+        ];
+        for (sql, message) in failures {
+            let error = connection.execute(sql).unwrap_err();
+            assert_eq!(error.to_string(), message, "{}", sql);
+        }
+        assert_eq!(texts(&connection, "pragma integrity_check"), [["ok"]]);
+        connection.close().unwrap();
+    };
+
+    clean_test("alter_table_changes_columns_and_names", test)();
+}
+
+#[test]
+fn tables_grow_past_interior_node_splits() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        // small pages fill quickly, so a few thousand rows take three levels of interior nodes
+        connection.execute("pragma page_size = 512").unwrap();
+        connection.execute("pragma synchronous = off").unwrap();
+        connection
+            .execute("create table big (id integer primary key, v integer)")
+            .unwrap();
+        connection.execute("create index big_v on big (v)").unwrap();
+        for batch in 0..60 {
+            let values: Vec<String> = (1..=100)
+                .map(|i| format!("({}, {})", batch * 100 + i, i))
+                .collect();
+            connection
+                .execute(&format!("insert into big values {}", values.join(", ")))
+                .unwrap();
+        }
+        connection.close().unwrap();
+
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(
+            connection.query("select id from big").unwrap().count(),
+            6000
+        );
+        assert_eq!(
+            texts(&connection, "select id, v from big where id = 5999"),
+            [["5999", "99"]]
+        );
+        assert_eq!(texts(&connection, "pragma integrity_check"), [["ok"]]);
+        connection.close().unwrap();
+
+        // a page that isn't a node any more is found by walking the trees
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(test_file_name)
+            .unwrap();
+        file.seek(SeekFrom::Start(512 * 20)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+        let connection = Connection::open(test_file_name).unwrap();
+        let problems = texts(&connection, "pragma integrity_check");
+        assert!(
+            problems.contains(&vec!["page 20 is a node of unknown kind 255".to_string()]),
+            "{:?}",
+            problems
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("tables_grow_past_interior_node_splits", test)();
+}
+
+#[test]
+fn values_too_big_for_a_page_go_to_overflow_pages() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        // at this page size the schema entry alone takes more than a quarter of a page
+        connection.execute("pragma page_size = 512").unwrap();
+        let columns: Vec<String> = (1..=10).map(|i| format!("note{} text", i)).collect();
+        connection
+            .execute(&format!(
+                "create table p (id integer primary key, name text not null unique, {})",
+                columns.join(", ")
+            ))
+            .unwrap();
+        let long = "a".repeat(1100);
+        let longer = "b".repeat(100_000);
+        connection
+            .execute(&format!(
+                "insert into p (id, name, note1, note10) values (1, 'one', '{}', '{}')",
+                long, longer
+            ))
+            .unwrap();
+        connection
+            .execute("insert into p (id, name, note1) values (2, 'two', 'short')")
+            .unwrap();
+        assert_eq!(
+            texts(
+                &connection,
+                "select id, length(note1), length(note10) from p"
+            ),
+            [["1", "1100", "100000"], ["2", "5", "NULL"]]
+        );
+        connection.close().unwrap();
+
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(
+            texts(&connection, "select note1 = '', note10 from p where id = 1"),
+            [["0".to_string(), longer.clone()]]
+        );
+        assert_eq!(texts(&connection, "pragma freelist_count"), [["0"]]);
+
+        // the pages a value no longer needs are reused by the next one that does
+        connection
+            .execute("update p set note10 = null where id = 1")
+            .unwrap();
+        assert_ne!(texts(&connection, "pragma freelist_count"), [["0"]]);
+        assert_eq!(texts(&connection, "pragma integrity_check"), [["ok"]]);
+        let page_count = texts(&connection, "pragma page_count");
+        connection
+            .execute(&format!("update p set note10 = '{}' where id = 2", longer))
+            .unwrap();
+        assert_eq!(texts(&connection, "pragma page_count"), page_count);
+        connection.execute("delete from p where id = 1").unwrap();
+        assert_ne!(texts(&connection, "pragma freelist_count"), [["0"]]);
+        assert_eq!(texts(&connection, "pragma integrity_check"), [["ok"]]);
+        assert_eq!(
+            texts(&connection, "select id, length(note10) from p"),
+            [["2", "100000"]]
+        );
+
+        // keys are searched through in place, so they still have to fit
+        let error = connection
+            .execute(&format!(
+                "insert into p (id, name) values (3, '{}')",
+                "c".repeat(200)
+            ))
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::TooBig);
+        assert_eq!(error.to_string(), "key is too big to fit in a page");
+        connection.close().unwrap();
+    };
+
+    clean_test("values_too_big_for_a_page_go_to_overflow_pages", test)();
+}
+
+#[test]
+fn new_rowids_are_found_below_the_largest_one() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection
+            .execute("create table r (id integer primary key, v integer)")
+            .unwrap();
+        connection
+            .execute("insert into r (id, v) values (9223372036854775807, 1), (1, 2), (3, 3)")
+            .unwrap();
+        connection.execute("insert into r (v) values (4)").unwrap();
+        connection.execute("insert into r (v) values (5)").unwrap();
+        assert_eq!(
+            texts(&connection, "select id, v from r where v > 3"),
+            [["2", "4"], ["4", "5"]]
+        );
+        connection.close().unwrap();
+    };
+
+    clean_test("new_rowids_are_found_below_the_largest_one", test)();
+}
+
+#[test]
+fn files_without_a_header_are_given_one() {
+    let test = |test_file_name: &str| {
+        // rows the way they were written before files had a header: 14 to a page of 4096
+        // bytes from the start of the file, with the last page cut short after its last row
+        let mut file = Vec::new();
+        for id in 1..=20u32 {
+            if id == 15 {
+                file.resize(4096, 0);
+            }
+            let mut row = vec![0u8; 291];
+            row[..4].copy_from_slice(&id.to_be_bytes());
+            let username = format!("user{}", id);
+            row[4..4 + username.len()].copy_from_slice(username.as_bytes());
+            let email = format!("person{}@example.com", id);
+            row[36..36 + email.len()].copy_from_slice(email.as_bytes());
+            file.extend_from_slice(&row);
+        }
+        std::fs::write(test_file_name, &file).unwrap();
+
+        // nothing is rewritten unless the caller asks for it
+        let error = Connection::open(test_file_name).err().unwrap();
+        assert_eq!(error.code(), ErrorCode::NotADb);
+        assert_eq!(std::fs::read(test_file_name).unwrap(), file);
+
+        let connection = Connection::open_with(test_file_name, upgrade_headerless()).unwrap();
+        let rows: Vec<Vec<Value>> = select_all(&connection)
+            .iter()
+            .map(|row| row.values().to_vec())
+            .collect();
+        assert_eq!(rows, (1..=20).map(user).collect::<Vec<_>>());
+        assert_eq!(texts(&connection, "pragma integrity_check"), [["ok"]]);
+        insert_user(&connection, 21);
+        connection.close().unwrap();
+
+        let upgraded = std::fs::read(test_file_name).unwrap();
+        assert_eq!(upgraded.len(), 3 * 4096);
+        assert_eq!(&upgraded[4096..4096 + file.len()], &file[..]);
+        let connection = Connection::open(test_file_name).unwrap();
+        assert_eq!(select_all(&connection).len(), 21);
+        connection.close().unwrap();
+
+        // anything else still isn't taken for a database
+        std::fs::write(test_file_name, b"not rows at all").unwrap();
+        let error = Connection::open_with(test_file_name, upgrade_headerless())
+            .err()
+            .unwrap();
+        assert_eq!(error.code(), ErrorCode::NotADb);
+        assert_eq!(std::fs::read(test_file_name).unwrap(), b"not rows at all");
+    };
+
+    clean_test("files_without_a_header_are_given_one", test)();
+}
+
+fn upgrade_headerless() -> PagerOptions {
+    PagerOptions {
+        upgrade_headerless: true,
+        ..PagerOptions::default()
+    }
+}
+
+#[test]
+fn files_the_length_of_whole_rows_are_only_upgraded_if_they_hold_rows() {
+    let test = |test_file_name: &str| {
+        // two rows' worth of bytes, without a NUL anywhere
+        let notes: Vec<u8> = "remember to water the plants\n"
+            .bytes()
+            .cycle()
+            .take(2 * 291)
+            .collect();
+        // a row whose username has something after the NULs padding it
+        let mut mangled = vec![0u8; 291];
+        mangled[4..9].copy_from_slice(b"user1");
+        mangled[20] = b'x';
+
+        for file in [notes, mangled] {
+            std::fs::write(test_file_name, &file).unwrap();
+            for options in [PagerOptions::default(), upgrade_headerless()] {
+                let error = Connection::open_with(test_file_name, options)
+                    .err()
+                    .unwrap();
+                assert_eq!(error.code(), ErrorCode::NotADb);
+                assert_eq!(std::fs::read(test_file_name).unwrap(), file);
+            }
+        }
+    };
+
+    clean_test(
+        "files_the_length_of_whole_rows_are_only_upgraded_if_they_hold_rows",
+        test,
+    )();
+}
+
+#[test]
+fn writers_never_take_the_lock_from_running_readers() {
+    let test = |test_file_name: &str| {
+        let lock_file_name = format!("{}-lock", test_file_name);
+        // connections opened on their own share nothing, the way other processes wouldn't
+        let first = Connection::open(test_file_name).unwrap();
+        let second = Connection::open(test_file_name).unwrap();
+        let third = Connection::open(test_file_name).unwrap();
+        for connection in [&first, &second, &third] {
+            connection.execute("pragma busy_timeout = 50").unwrap();
+        }
+        for id in 1..=20 {
+            insert_user(&first, id);
+        }
+
+        // the writer gives up on the lock the other reader shares, without losing its own
+        let mut first_rows = first.query("select").unwrap();
+        first_rows.next().unwrap().unwrap();
+        let mut second_rows = second.query("select").unwrap();
+        second_rows.next().unwrap().unwrap();
+        let writer = first.clone();
+        let error = std::thread::spawn(move || {
+            writer
+                .execute("insert 21 user21 person21@example.com")
+                .unwrap_err()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(error.code(), ErrorCode::Busy);
+        let error = third
+            .execute("insert 21 user21 person21@example.com")
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Busy);
+        assert_eq!(second_rows.count(), 19);
+
+        // with only its own reader left it upgrades, and once it has committed the lock goes back
+        // to being shared: others can read again, but not write while that reader runs
+        let writer = first.clone();
+        std::thread::spawn(move || insert_user(&writer, 21))
+            .join()
+            .unwrap();
+        assert_eq!(select_all(&third).len(), 21);
+        let error = third
+            .execute("insert 22 user22 person22@example.com")
+            .unwrap_err();
+        assert_eq!(error.code(), ErrorCode::Busy);
+        assert_eq!(first_rows.count(), 19);
+        assert!(!Path::new(&lock_file_name).exists());
+
+        // a writer waiting on the lock file doesn't hold up readers in its own process
+        let lock_file = std::fs::File::create(&lock_file_name).unwrap();
+        lock_file.lock().unwrap();
+        first.execute("pragma busy_timeout = 1000").unwrap();
+        let writer = first.clone();
+        let waiting = std::thread::spawn(move || insert_user(&writer, 22));
+        assert_eq!(select_all(&first).len(), 21);
+        std::fs::remove_file(&lock_file_name).unwrap();
+        lock_file.unlock().unwrap();
+        waiting.join().unwrap();
+        assert_eq!(select_all(&second).len(), 22);
+
+        for connection in [first, second, third] {
+            connection.close().unwrap();
+        }
+        ensure_clean_fs(&lock_file_name);
+    };
+
+    clean_test("writers_never_take_the_lock_from_running_readers", test)();
+}
+
+#[test]
+fn readers_fail_once_the_file_is_changed_under_them() {
+    let test = |test_file_name: &str| {
+        let connection = Connection::open(test_file_name).unwrap();
+        connection.execute("pragma synchronous = off").unwrap();
+        for id in 1..=20 {
+            insert_user(&connection, id);
+        }
+        let mut rows = connection.query("select").unwrap();
+        assert_eq!(rows.next().unwrap().unwrap().values(), &user(1)[..]);
+
+        // something that ignores the lock bumps the change counter, and the next writer notices
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(test_file_name)
+            .unwrap();
+        file.seek(SeekFrom::Start(24)).unwrap();
+        file.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        drop(file);
+        insert_user(&connection, 21);
+
+        let error = rows.find_map(Result::err).unwrap();
+        assert_eq!(error.code(), ErrorCode::Busy);
+        drop(rows);
+        assert_eq!(select_all(&connection).len(), 21);
+        connection.close().unwrap();
+    };
+
+    clean_test("readers_fail_once_the_file_is_changed_under_them", test)();
+}